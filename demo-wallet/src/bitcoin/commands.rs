@@ -35,6 +35,9 @@ pub enum BitcoinSubCommands {
 
     /// Backup verification
     Verify(VerifyStruct),
+
+    /// Refresh the secret shares of the wallet with the server
+    Rotate(RotateStruct),
 }
 
 // const GOTHAM_ARG_HELP: &str = "Gotham server (url:port)";
@@ -90,6 +93,9 @@ pub struct VerifyStruct {
     pub escrow_path: String,
 }
 
+#[derive(Args)]
+pub struct RotateStruct {}
+
 #[derive(Args)]
 pub struct GetBalanceStruct {}
 
@@ -216,19 +222,30 @@ pub async fn bitcoin_commands(
         },
 
          */
+        BitcoinSubCommands::Rotate(_rotate_struct) => {
+            let wallet_file = settings
+                .wallet_file
+                .clone()
+                .expect("Missing 'wallet_file' in settings.toml");
+
+            let wallet = load_wallet_from_file(&settings);
+
+            let client_shim = client_lib::ClientShim::new(
+                settings
+                    .gotham_server_url
+                    .expect("Missing 'gotham_server_url' in settings.toml"),
+                None,
+            );
 
-        /* Rotation is not up to date
-        WalletCommands::Rotate => {
             println!("Rotating secret shares");
 
             let now = Instant::now();
             let wallet = wallet.rotate(&client_shim);
-            wallet.save();
+            wallet.save_to(&wallet_file);
             let elapsed = now.elapsed();
 
             println!("key rotation complete, (Took: {:?})", elapsed);
-        },
-         */
+        }
         BitcoinSubCommands::Send(send_struct) => {
             let wallet_file = settings
                 .wallet_file
@@ -135,10 +135,21 @@ impl BitcoinWallet {
         }
     }
 
-    // Rotation is not up to date
-    // pub fn rotate<C: Client>(self, client_shim: &ClientShim<C>) -> Self {
-    //     ecdsa::rotate_master_key(self, client_shim)
-    // }
+    pub fn rotate<C: Client>(self, client_shim: &ClientShim<C>) -> Self {
+        let private_share = ecdsa::rotate_master_key(client_shim, &self.private_share)
+            .expect("Key rotation failed");
+
+        let mut wallet_after_rotate = BitcoinWallet {
+            id: self.id,
+            network: self.network,
+            private_share,
+            last_derived_pos: self.last_derived_pos,
+            addresses_derivation_map: HashMap::new(),
+        };
+        wallet_after_rotate.derived();
+
+        wallet_after_rotate
+    }
 
     pub fn backup(&self, escrow_service: escrow::Escrow, path: &str) {
         let g: GE = ECPoint::generator();
@@ -215,11 +226,10 @@ impl BitcoinWallet {
 
             let client_master_key_recovered =
                 MasterKey2::recover_master_key(sk.unwrap(), public_data, chain_code2);
-            let pos_old: u32 =
-                client_shim.post(&format!("ecdsa/{}/recover", key_id)).unwrap();
+            let recovery = ecdsa::get_recovery_data(client_shim, &key_id).unwrap();
 
-            let pos_old = if pos_old < 10 { 10 } else { pos_old };
-            //TODO: temporary, server will keep updated pos, to do so we need to send update to server for every get_new_address
+            // addresses derived after the last signature are not known to the server
+            let pos_old = (recovery.last_derived_pos as u32).max(10);
 
             let id = Uuid::new_v4().to_string();
             let addresses_derivation_map = HashMap::new(); //TODO: add a fucntion to recreate
@@ -321,35 +331,66 @@ impl BitcoinWallet {
 
         let mut signed_transaction = transaction.clone();
 
-        for (idx, item) in selected.iter().enumerate() {
-            let address_derivation = self.addresses_derivation_map.get(&item.address).unwrap();
-
-            let mk = &address_derivation.mk;
-            let pk = mk.public.q.get_element();
-
-            let script_code = &Address::p2pkh(
-                &Self::to_bitcoin_public_key(&pk),
-                self.get_bitcoin_network(),
-            )
-            .script_pubkey();
-
-            let mut cash = SigHashCache::new(&transaction);
-            let sig_hash = cash.signature_hash(
-                idx,
-                script_code,
-                (item.value as u64).into(),
-                SigHashType::All,
-            );
-
-            let signature = ecdsa::sign(
-                client_shim,
-                BigInt::from(&sig_hash[..]),
-                mk,
-                BigInt::from(0u32),
-                BigInt::from(address_derivation.pos),
-                &self.private_share.id,
-            )
-            .unwrap();
+        let derivations: Vec<&AddressDerivation> = selected
+            .iter()
+            .map(|item| self.addresses_derivation_map.get(&item.address).unwrap())
+            .collect();
+
+        // sent with every input, so that the server policy sees what the transaction pays
+        let unsigned_transaction = hex::encode(serialize(&transaction));
+        let prevout_amounts: Vec<u64> = selected.iter().map(|item| item.value as u64).collect();
+        let change_position = ecdsa::ChangePosition {
+            x_pos: BigInt::from(0u32),
+            y_pos: BigInt::from(
+                self.addresses_derivation_map
+                    .get(&change_address.to_string())
+                    .unwrap()
+                    .pos,
+            ),
+        };
+
+        let mut cash = SigHashCache::new(&transaction);
+        let messages: Vec<ecdsa::BatchMessage> = selected
+            .iter()
+            .zip(&derivations)
+            .enumerate()
+            .map(|(idx, (item, address_derivation))| {
+                let mk = &address_derivation.mk;
+                let script_code = &Address::p2pkh(
+                    &Self::to_bitcoin_public_key(&mk.public.q.get_element()),
+                    self.get_bitcoin_network(),
+                )
+                .script_pubkey();
+                let sig_hash = cash.signature_hash(
+                    idx,
+                    script_code,
+                    (item.value as u64).into(),
+                    SigHashType::All,
+                );
+
+                ecdsa::BatchMessage {
+                    message: BigInt::from(&sig_hash[..]),
+                    child_key: mk,
+                    x_pos: BigInt::from(0u32),
+                    y_pos: BigInt::from(address_derivation.pos),
+                    transaction: Some(ecdsa::UnsignedTransaction::Bitcoin {
+                        network: self.get_bitcoin_network().to_string(),
+                        transaction: unsigned_transaction.clone(),
+                        input_index: idx,
+                        prevout_amounts: prevout_amounts.clone(),
+                        change_positions: vec![change_position.clone()],
+                    }),
+                }
+            })
+            .collect();
+
+        // one round trip pair signs every input
+        let signatures = ecdsa::sign_batch(client_shim, messages, &self.private_share.id).unwrap();
+
+        for (idx, (signature, address_derivation)) in
+            signatures.iter().zip(&derivations).enumerate()
+        {
+            let pk = address_derivation.mk.public.q.get_element();
 
             let mut v = BigInt::to_vec(&signature.r);
             v.extend(BigInt::to_vec(&signature.s));
@@ -5,7 +5,7 @@ use std::path::Path;
 use std::sync::Arc;
 
 use client_lib as GothamClient;
-use GothamClient::ecdsa::PrivateShare;
+use GothamClient::ecdsa::{PrivateShare, UnsignedTransaction};
 use GothamClient::{BigInt, Converter, ECPoint};
 
 use ethers::prelude::transaction::eip2718::TypedTransaction;
@@ -101,6 +101,15 @@ impl<C: GothamClient::Client> std::fmt::Debug for GothamSigner<C> {
 
 impl<'w, C: GothamClient::Client> GothamSigner<C> {
     pub fn sign_hash(&self, hash: H256) -> Result<Signature, GothamSignerError> {
+        self.sign_digest(hash, None)
+    }
+
+    /// Signs `hash`, the digest of `transaction` when there is one
+    fn sign_digest(
+        &self,
+        hash: H256,
+        transaction: Option<UnsignedTransaction>,
+    ) -> Result<Signature, GothamSignerError> {
         let message: BigInt = BigInt::from(hash.as_ref());
 
         let x_pos = BigInt::from(self.wallet.hd_path[0]);
@@ -112,13 +121,14 @@ impl<'w, C: GothamClient::Client> GothamSigner<C> {
             .master_key
             .get_child(vec![x_pos.clone(), y_pos.clone()]);
 
-        let signature = GothamClient::ecdsa::sign(
+        let signature = GothamClient::ecdsa::sign_transaction(
             &self.gotham_client_shim,
             message,
             &child_master_key,
             x_pos,
             y_pos,
             &self.wallet.private_share.id,
+            transaction,
         )
         .unwrap();
 
@@ -168,8 +178,14 @@ impl<'w, C: GothamClient::Client + Sync + Send> Signer for GothamSigner<C> {
         tx_with_chain.set_chain_id(chain_id);
 
         let sighash = tx_with_chain.sighash();
+        // sent along so that the server policy sees the destination and value
+        let transaction = UnsignedTransaction::Ethereum {
+            chain_id: Some(chain_id),
+            transaction: serde_json::to_value(&tx_with_chain)
+                .expect("Error while serializing transaction"),
+        };
 
-        let mut signature = self.sign_hash(sighash)?;
+        let mut signature = self.sign_digest(sighash, Some(transaction))?;
 
         // Modify the v value of a signature to conform to eip155
         // signature.v = to_eip155_v(signature.v as u8, chain_id);
@@ -0,0 +1,91 @@
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use two_party_ecdsa::party_one;
+
+use crate::{Client, ClientShim, Result};
+
+/// Seconds a status request waits for the decision of an approver
+const WAIT_SECONDS: u64 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalState {
+    Pending,
+    Approved,
+    Rejected,
+    Expired,
+    Signed,
+    Failed,
+}
+
+/// Status of a signing request waiting for an approver
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApprovalStatus {
+    pub approval_id: String,
+    pub status: ApprovalState,
+    pub message_hash: String,
+    pub expires_at: i64,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub signature: Option<party_one::SignatureRecid>,
+}
+
+/// Answer of the second signing round: a signature, or the approval it waits for
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum SignResponse {
+    Signed(party_one::SignatureRecid),
+    Pending(ApprovalStatus),
+}
+
+impl SignResponse {
+    /// The signature, waiting for the approval first when there is one
+    pub(crate) fn signature<C: Client>(
+        self,
+        client_shim: &ClientShim<C>,
+    ) -> Result<party_one::SignatureRecid> {
+        match self {
+            SignResponse::Signed(signature) => Ok(signature),
+            SignResponse::Pending(approval) => {
+                wait_for_approval(client_shim, &approval.approval_id)
+            }
+        }
+    }
+}
+
+/// Status of the approval, waiting up to `wait_seconds` for a decision. The first request
+/// after the approval gets the signature.
+pub fn get_approval_status<C: Client>(
+    client_shim: &ClientShim<C>,
+    approval_id: &str,
+    wait_seconds: u64,
+) -> Result<ApprovalStatus> {
+    let status: ApprovalStatus = client_shim.post(&format!(
+        "ecdsa/approvals/{}?wait={}",
+        approval_id, wait_seconds
+    ))?;
+    Ok(status)
+}
+
+/// Polls the approval until it is decided, returning the signature once approved
+pub fn wait_for_approval<C: Client>(
+    client_shim: &ClientShim<C>,
+    approval_id: &str,
+) -> Result<party_one::SignatureRecid> {
+    loop {
+        let status = get_approval_status(client_shim, approval_id, WAIT_SECONDS)?;
+        match (status.status, status.signature) {
+            (ApprovalState::Pending, _) | (ApprovalState::Approved, None) => continue,
+            (ApprovalState::Signed, Some(signature)) => return Ok(signature),
+            (state, _) => {
+                return Err(format_err!(
+                    "signing request {} is {:?}: {}",
+                    approval_id,
+                    state,
+                    status.reason.unwrap_or_default()
+                ))
+            }
+        }
+    }
+}
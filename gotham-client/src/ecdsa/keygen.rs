@@ -8,6 +8,7 @@
 //
 
 use floating_duration::TimeFormat;
+use serde::{Deserialize, Serialize};
 
 use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::*;
 use two_party_ecdsa::party_one;
@@ -19,7 +20,7 @@ use std::os::raw::c_char;
 use std::time::Instant;
 
 use super::types::PrivateShare;
-use crate::{Client, ClientShim};
+use crate::{Client, ClientError, ClientShim};
 
 // Android bindings
 
@@ -34,11 +35,38 @@ use std::ops::Deref;
 
 const KG_PATH_PRE: &str = "ecdsa/keygen";
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ActiveKey {
+    pub id: String,
+    pub activated_at: i64,
+}
+
+/// Generates the first key of the customer. The server refuses it when an active key exists.
 pub fn get_master_key<C: Client>(client_shim: &ClientShim<C>) -> PrivateShare {
+    key_gen(client_shim, &format!("{}/first", KG_PATH_PRE))
+}
+
+/// Generates a key that replaces the active key of the customer
+pub fn get_new_master_key<C: Client>(client_shim: &ClientShim<C>) -> PrivateShare {
+    key_gen(client_shim, &format!("{}/first?new_key=true", KG_PATH_PRE))
+}
+
+/// Id of the active key of the customer, if any
+pub fn get_active_key_id<C: Client>(
+    client_shim: &ClientShim<C>,
+) -> Result<Option<String>, ClientError> {
+    match client_shim.post::<ActiveKey>(&format!("{}/active", KG_PATH_PRE)) {
+        Ok(active_key) => Ok(Some(active_key.id)),
+        Err(e) if e.code() == Some("not_found") => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn key_gen<C: Client>(client_shim: &ClientShim<C>, first_path: &str) -> PrivateShare {
     let start = Instant::now();
 
     let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
-        client_shim.post(&format!("{}/first", KG_PATH_PRE)).unwrap();
+        client_shim.post(first_path).unwrap();
 
     let (kg_party_two_first_message, kg_ec_key_pair_party2) = MasterKey2::key_gen_first_message();
 
@@ -6,11 +6,20 @@
 // License as published by the Free Software Foundation, either
 // version 3 of the License, or (at your option) any later version.
 //
+pub mod approval;
 pub mod keygen;
+pub mod presign;
 pub mod recover;
+pub mod rotate;
 pub mod sign;
+pub mod transaction;
 pub mod types;
 
-pub use keygen::get_master_key;
-pub use sign::sign;
+pub use approval::{get_approval_status, wait_for_approval, ApprovalState, ApprovalStatus};
+pub use keygen::{get_active_key_id, get_master_key, get_new_master_key};
+pub use presign::{Presignature, PresignaturePool};
+pub use recover::{get_recovery_data, KeyRecovery};
+pub use rotate::rotate_master_key;
+pub use sign::{sign, sign_batch, sign_transaction, BatchMessage};
+pub use transaction::{ChangePosition, UnsignedTransaction};
 pub use types::PrivateShare;
@@ -0,0 +1,128 @@
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use two_party_ecdsa::kms::ecdsa::two_party::{party2, MasterKey2};
+use two_party_ecdsa::{curv::BigInt, party_one, party_two};
+
+use super::approval::SignResponse;
+use crate::{Client, ClientShim, Result};
+
+/// Ephemeral message of the server for one presignature
+#[derive(Serialize, Deserialize, Debug)]
+struct ServerPresignature {
+    id: String,
+    eph_key_gen_first_message_party_one: party_one::EphKeyGenFirstMsg,
+}
+
+/// Ephemeral keys of both parties for one signature, computed ahead of time
+#[derive(Serialize, Deserialize)]
+pub struct Presignature {
+    pub id: String,
+    pub eph_comm_witness: party_two::EphCommWitness,
+    pub eph_ec_key_pair_party2: party_two::EphEcKeyPair,
+    pub eph_key_gen_first_message_party_one: party_one::EphKeyGenFirstMsg,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PresignedSignRequest {
+    presignature_id: String,
+    message: BigInt,
+    party_two_sign_message: party2::SignMessage,
+    x_pos_child_key: BigInt,
+    y_pos_child_key: BigInt,
+}
+
+/// Presignatures of a key, each used for a single signature. Save the pool after every
+/// signature, so that a presignature is never used again after a restart.
+#[derive(Serialize, Deserialize)]
+pub struct PresignaturePool {
+    pub key_id: String,
+    presignatures: VecDeque<Presignature>,
+}
+
+impl PresignaturePool {
+    pub fn new(key_id: &str) -> Self {
+        PresignaturePool {
+            key_id: key_id.to_string(),
+            presignatures: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.presignatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.presignatures.is_empty()
+    }
+
+    /// Adds `count` presignatures, in one request
+    pub fn fill<C: Client>(&mut self, client_shim: &ClientShim<C>, count: usize) -> Result<()> {
+        let mut eph_key_gen_first_messages_party_two = Vec::with_capacity(count);
+        let mut ephemeral_keys = Vec::with_capacity(count);
+        for _ in 0..count {
+            let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+                MasterKey2::sign_first_message();
+            eph_key_gen_first_messages_party_two.push(eph_key_gen_first_message_party_two);
+            ephemeral_keys.push((eph_comm_witness, eph_ec_key_pair_party2));
+        }
+
+        let server_presignatures: Vec<ServerPresignature> = client_shim.postb(
+            &format!("ecdsa/sign/{}/presign", self.key_id),
+            &eph_key_gen_first_messages_party_two,
+        )?;
+        if server_presignatures.len() != count {
+            return Err(format_err!(
+                "expected {} presignatures, got {}",
+                count,
+                server_presignatures.len()
+            ));
+        }
+
+        self.presignatures
+            .extend(server_presignatures.into_iter().zip(ephemeral_keys).map(
+                |(server_presignature, (eph_comm_witness, eph_ec_key_pair_party2))| Presignature {
+                    id: server_presignature.id,
+                    eph_comm_witness,
+                    eph_ec_key_pair_party2,
+                    eph_key_gen_first_message_party_one:
+                        server_presignature.eph_key_gen_first_message_party_one,
+                },
+            ));
+        Ok(())
+    }
+
+    /// Signs `message` with the child key `mk` at `(x_pos, y_pos)` in a single request,
+    /// consuming a presignature even when signing fails
+    pub fn sign<C: Client>(
+        &mut self,
+        client_shim: &ClientShim<C>,
+        message: BigInt,
+        mk: &MasterKey2,
+        x_pos: BigInt,
+        y_pos: BigInt,
+    ) -> Result<party_one::SignatureRecid> {
+        let presignature = self
+            .presignatures
+            .pop_front()
+            .ok_or_else(|| format_err!("the presignature pool of key {} is empty", self.key_id))?;
+
+        let party_two_sign_message = mk.sign_second_message(
+            &presignature.eph_ec_key_pair_party2,
+            presignature.eph_comm_witness,
+            &presignature.eph_key_gen_first_message_party_one,
+            &message,
+        );
+        let request = PresignedSignRequest {
+            presignature_id: presignature.id,
+            message,
+            party_two_sign_message,
+            x_pos_child_key: x_pos,
+            y_pos_child_key: y_pos,
+        };
+
+        let response: SignResponse =
+            client_shim.postb(&format!("ecdsa/sign/{}/presigned", self.key_id), &request)?;
+        response.signature(client_shim)
+    }
+}
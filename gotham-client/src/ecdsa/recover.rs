@@ -7,6 +7,7 @@
 // version 3 of the License, or (at your option) any later version.
 //
 
+use serde::{Deserialize, Serialize};
 use serde_json::Error;
 use two_party_ecdsa::centipede::juggling::proof_system::Helgamalsegmented;
 use two_party_ecdsa::centipede::juggling::segmentation::Msegmentation;
@@ -29,6 +30,27 @@ use jni::{
 };
 use std::ops::Deref;
 
+use crate::{Client, ClientError, ClientShim};
+
+/// Public data the server keeps of a key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyRecovery {
+    pub id: String,
+    pub public_key: GE,
+    pub public_share: GE,
+    pub chain_code: BigInt,
+    /// Highest `y_pos` the key signed with, `0` when it never signed
+    pub last_derived_pos: u64,
+}
+
+/// Data needed, with a restored client share, to rebuild the addresses of key `id`
+pub fn get_recovery_data<C: Client>(
+    client_shim: &ClientShim<C>,
+    id: &str,
+) -> Result<KeyRecovery, ClientError> {
+    client_shim.post(&format!("ecdsa/{}/recover", id))
+}
+
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "C" fn decrypt_party_one_master_key(
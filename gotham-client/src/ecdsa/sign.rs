@@ -15,6 +15,8 @@ use jni::{
 };
 use std::ops::Deref;
 
+use super::approval::SignResponse;
+use super::transaction::UnsignedTransaction;
 use crate::{utilities::error_to_c_string, Client, ClientShim, Result};
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -23,6 +25,8 @@ pub struct SignSecondMsgRequest {
     pub party_two_sign_message: party2::SignMessage,
     pub x_pos_child_key: BigInt,
     pub y_pos_child_key: BigInt,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<UnsignedTransaction>,
 }
 
 pub fn sign<C: Client>(
@@ -32,16 +36,27 @@ pub fn sign<C: Client>(
     x_pos: BigInt,
     y_pos: BigInt,
     id: &str,
+) -> Result<party_one::SignatureRecid> {
+    sign_transaction(client_shim, message, mk, x_pos, y_pos, id, None)
+}
+
+/// Signs `message`, the digest of `transaction`, so that the signing policy of the server
+/// sees what the transaction pays
+pub fn sign_transaction<C: Client>(
+    client_shim: &ClientShim<C>,
+    message: BigInt,
+    mk: &MasterKey2,
+    x_pos: BigInt,
+    y_pos: BigInt,
+    id: &str,
+    transaction: Option<UnsignedTransaction>,
 ) -> Result<party_one::SignatureRecid> {
     let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
         MasterKey2::sign_first_message();
 
     let request: party_two::EphKeyGenFirstMsg = eph_key_gen_first_message_party_two;
     let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
-        match client_shim.postb(&format!("/ecdsa/sign/{}/first", id), &request) {
-            Some(s) => s,
-            None => return Err(failure::err_msg("party1 sign first message request failed")),
-        };
+        client_shim.postb(&format!("ecdsa/sign/{}/first", id), &request)?;
 
     let party_two_sign_message = mk.sign_second_message(
         &eph_ec_key_pair_party2,
@@ -50,19 +65,90 @@ pub fn sign<C: Client>(
         &message,
     );
 
-    let signature = match get_signature(
+    get_signature(
         client_shim,
         message,
         party_two_sign_message,
         x_pos,
         y_pos,
         id,
-    ) {
-        Ok(s) => s,
-        Err(e) => return Err(format_err!("ecdsa::get_signature failed failed: {}", e)),
-    };
+        transaction,
+    )
+}
 
-    Ok(signature)
+/// Message to sign in a batch, with the child key signing it and its position
+pub struct BatchMessage<'a> {
+    pub message: BigInt,
+    pub child_key: &'a MasterKey2,
+    pub x_pos: BigInt,
+    pub y_pos: BigInt,
+    /// Unsigned transaction whose digest is the message
+    pub transaction: Option<UnsignedTransaction>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SignBatchItem {
+    pub message: BigInt,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+    pub eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<UnsignedTransaction>,
+}
+
+/// Signs every message with two requests, whatever their number. The signatures are in the
+/// order of the messages.
+pub fn sign_batch<C: Client>(
+    client_shim: &ClientShim<C>,
+    messages: Vec<BatchMessage>,
+    id: &str,
+) -> Result<Vec<party_one::SignatureRecid>> {
+    let mut items = Vec::with_capacity(messages.len());
+    let mut ephemeral_keys = Vec::with_capacity(messages.len());
+    for message in &messages {
+        let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        items.push(SignBatchItem {
+            message: message.message.clone(),
+            x_pos_child_key: message.x_pos.clone(),
+            y_pos_child_key: message.y_pos.clone(),
+            eph_key_gen_first_message_party_two,
+            transaction: message.transaction.clone(),
+        });
+        ephemeral_keys.push((eph_comm_witness, eph_ec_key_pair_party2));
+    }
+
+    let sign_party_one_first_messages: Vec<party_one::EphKeyGenFirstMsg> =
+        client_shim.postb(&format!("ecdsa/sign/{}/batch/first", id), &items)?;
+    if sign_party_one_first_messages.len() != messages.len() {
+        return Err(format_err!(
+            "expected {} first messages, got {}",
+            messages.len(),
+            sign_party_one_first_messages.len()
+        ));
+    }
+
+    let party_two_sign_messages: Vec<party2::SignMessage> = messages
+        .iter()
+        .zip(ephemeral_keys)
+        .zip(&sign_party_one_first_messages)
+        .map(
+            |((message, (eph_comm_witness, eph_ec_key_pair_party2)), first_message)| {
+                message.child_key.sign_second_message(
+                    &eph_ec_key_pair_party2,
+                    eph_comm_witness,
+                    first_message,
+                    &message.message,
+                )
+            },
+        )
+        .collect();
+
+    let signatures: Vec<party_one::SignatureRecid> = client_shim.postb(
+        &format!("ecdsa/sign/{}/batch/second", id),
+        &party_two_sign_messages,
+    )?;
+    Ok(signatures)
 }
 
 fn get_signature<C: Client>(
@@ -72,25 +158,20 @@ fn get_signature<C: Client>(
     x_pos_child_key: BigInt,
     y_pos_child_key: BigInt,
     id: &str,
+    transaction: Option<UnsignedTransaction>,
 ) -> Result<party_one::SignatureRecid> {
     let request: SignSecondMsgRequest = SignSecondMsgRequest {
         message,
         party_two_sign_message,
         x_pos_child_key,
         y_pos_child_key,
+        transaction,
     };
 
-    let signature: party_one::SignatureRecid =
-        match client_shim.postb(&format!("/ecdsa/sign/{}/second", id), &request) {
-            Some(s) => s,
-            None => {
-                return Err(failure::err_msg(
-                    "party1 sign second message request failed",
-                ))
-            }
-        };
-
-    Ok(signature)
+    // requests needing approval are answered once an approver decides
+    let response: SignResponse =
+        client_shim.postb(&format!("ecdsa/sign/{}/second", id), &request)?;
+    response.signature(client_shim)
 }
 
 /// # Safety
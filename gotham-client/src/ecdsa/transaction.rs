@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+use two_party_ecdsa::curv::BigInt;
+
+/// Unsigned transaction whose digest is the message to sign. The server computes the digest
+/// again, and its signing policy sees what the transaction pays.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "chain", rename_all = "snake_case")]
+pub enum UnsignedTransaction {
+    /// Transaction spending P2WPKH outputs of the child key, signed with `SIGHASH_ALL`
+    Bitcoin {
+        /// `bitcoin`, `testnet`, `signet` or `regtest`
+        network: String,
+        /// Consensus encoding of the transaction, hex encoded
+        transaction: String,
+        input_index: usize,
+        /// Amounts, in satoshi, of the outputs spent by every input
+        prevout_amounts: Vec<u64>,
+        /// Derivation positions of the child keys whose P2WPKH outputs are change, which the
+        /// signing policy does not count as payments
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        change_positions: Vec<ChangePosition>,
+    },
+    Ethereum {
+        /// Chain id of the digest (EIP-155)
+        chain_id: Option<u64>,
+        /// JSON of an ethers `TypedTransaction`
+        transaction: serde_json::Value,
+    },
+}
+
+/// Derivation position of a child key of the signing key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChangePosition {
+    pub x_pos: BigInt,
+    pub y_pos: BigInt,
+}
@@ -6,21 +6,119 @@
 // License as published by the Free Software Foundation, either
 // version 3 of the License, or (at your option) any later version.
 //
+use base64::Engine;
 use floating_duration::TimeFormat;
-use log::info;
-use serde::{de::DeserializeOwned, Serialize};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
 use std::time::Instant;
 pub mod ecdsa;
+pub mod websocket;
 
 mod utilities;
 
 type Result<T> = std::result::Result<T, failure::Error>;
 
+/// Times a request failing before reaching the server is sent again, with the same
+/// idempotency key
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Error body of a failed request, as sent by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerError {
+    /// Stable error code, e.g. `not_found` or `policy_denied`
+    pub code: String,
+    pub message: String,
+    pub status: u16,
+    pub request_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The server answered with an error
+    Server(ServerError),
+    /// The key is frozen and refuses to sign until an administrator unfreezes it
+    KeyFrozen(ServerError),
+    /// The key is revoked and never signs again
+    KeyRevoked(ServerError),
+    /// The request could not be sent or its response could not be read
+    Transport(String),
+    /// The server answered successfully with an unexpected body
+    InvalidResponse(String),
+}
+
+impl ClientError {
+    /// Error code sent by the server, if it answered
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            ClientError::Server(e) | ClientError::KeyFrozen(e) | ClientError::KeyRevoked(e) => {
+                Some(&e.code)
+            }
+            _ => None,
+        }
+    }
+
+    /// Error of a response with an error `status`
+    pub fn from_response(status: u16, body: &str) -> Self {
+        match serde_json::from_str::<ServerError>(body) {
+            Ok(e) if e.code == "key_frozen" => ClientError::KeyFrozen(e),
+            Ok(e) if e.code == "key_revoked" => ClientError::KeyRevoked(e),
+            Ok(e) => ClientError::Server(e),
+            Err(_) => ClientError::Server(ServerError {
+                code: "http_error".to_string(),
+                message: body.to_string(),
+                status,
+                request_id: String::new(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Server(e) => write!(
+                f,
+                "server error {} (status {}, request {}): {}",
+                e.code, e.status, e.request_id, e.message
+            ),
+            ClientError::KeyFrozen(e) => write!(f, "key frozen: {}", e.message),
+            ClientError::KeyRevoked(e) => write!(f, "key revoked: {}", e.message),
+            ClientError::Transport(e) => write!(f, "request failed: {}", e),
+            ClientError::InvalidResponse(e) => write!(f, "invalid response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// Value of a response with `status` and `body`, or its error
+pub fn decode_response<V: DeserializeOwned>(
+    status: u16,
+    body: &str,
+) -> std::result::Result<V, ClientError> {
+    if status >= 400 {
+        return Err(ClientError::from_response(status, body));
+    }
+    serde_json::from_str(body).map_err(|e| ClientError::InvalidResponse(e.to_string()))
+}
+
+/// `Content-Digest` header value of `body` (RFC 9530), which the server requires next to an
+/// `Idempotency-Key` header
+pub fn content_digest(body: &[u8]) -> String {
+    format!(
+        "sha-256=:{}:",
+        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(body))
+    )
+}
+
 #[derive(Debug)]
 pub struct ClientShim<C: Client> {
     pub client: C,
     pub auth_token: Option<String>,
     pub endpoint: String,
+    pub retries: u32,
 }
 
 impl ClientShim<reqwest::Client> {
@@ -30,6 +128,7 @@ impl ClientShim<reqwest::Client> {
             client,
             auth_token,
             endpoint,
+            retries: DEFAULT_RETRIES,
         }
     }
 }
@@ -40,29 +139,42 @@ impl<C: Client> ClientShim<C> {
             client,
             auth_token,
             endpoint,
+            retries: DEFAULT_RETRIES,
         }
     }
-    pub fn post<V>(&self, path: &str) -> Option<V>
+    pub fn post<V>(&self, path: &str) -> std::result::Result<V, ClientError>
     where
         V: serde::de::DeserializeOwned,
     {
-        let start = Instant::now();
-        let res = self
-            .client
-            .post(&self.endpoint, path, self.auth_token.clone(), "{}");
-        info!("(req {}, took: {:?})", path, TimeFormat(start.elapsed()));
-        res
+        self.postb(path, "{}")
     }
 
-    pub fn postb<T, V>(&self, path: &str, body: T) -> Option<V>
+    /// Sends `body` under a new idempotency key, sending it again with the same key when it
+    /// fails before reaching the server, so that a retry is never run twice
+    pub fn postb<T, V>(&self, path: &str, body: T) -> std::result::Result<V, ClientError>
     where
         T: serde::ser::Serialize,
         V: serde::de::DeserializeOwned,
     {
         let start = Instant::now();
-        let res = self
-            .client
-            .post(&self.endpoint, path, self.auth_token.clone(), body);
+        let idempotency_key = uuid::Uuid::new_v4().to_string();
+        let mut attempt = 0;
+        let res = loop {
+            let res = self.client.post_idempotent(
+                &self.endpoint,
+                path,
+                self.auth_token.clone(),
+                &idempotency_key,
+                &body,
+            );
+            match res {
+                Err(ClientError::Transport(e)) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("(req {}, retry {}: {})", path, attempt, e);
+                }
+                res => break res,
+            }
+        };
         info!("(req {}, took: {:?})", path, TimeFormat(start.elapsed()));
         res
     }
@@ -75,7 +187,20 @@ pub trait Client: Sized {
         uri: &str,
         bearer_token: Option<String>,
         body: T,
-    ) -> Option<V>;
+    ) -> std::result::Result<V, ClientError>;
+
+    /// Sends `body` with an `Idempotency-Key` header, so that the server answers a retry with
+    /// the response of the first request. Clients unable to set headers send a plain request.
+    fn post_idempotent<V: DeserializeOwned, T: Serialize>(
+        &self,
+        endpoint: &str,
+        uri: &str,
+        bearer_token: Option<String>,
+        _idempotency_key: &str,
+        body: T,
+    ) -> std::result::Result<V, ClientError> {
+        self.post(endpoint, uri, bearer_token, body)
+    }
 }
 
 impl Client for reqwest::Client {
@@ -85,13 +210,45 @@ impl Client for reqwest::Client {
         uri: &str,
         bearer_token: Option<String>,
         body: T,
-    ) -> Option<V> {
+    ) -> std::result::Result<V, ClientError> {
+        let mut b = self.post(&format!("{}/{}", endpoint, uri));
+        if let Some(token) = bearer_token {
+            b = b.bearer_auth(token);
+        }
+        let mut response = b
+            .json(&body)
+            .send()
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+        let value = response
+            .text()
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+        decode_response(response.status().as_u16(), value.as_str())
+    }
+
+    fn post_idempotent<V: DeserializeOwned, T: Serialize>(
+        &self,
+        endpoint: &str,
+        uri: &str,
+        bearer_token: Option<String>,
+        idempotency_key: &str,
+        body: T,
+    ) -> std::result::Result<V, ClientError> {
+        let body = serde_json::to_vec(&body).map_err(|e| ClientError::Transport(e.to_string()))?;
         let mut b = self.post(&format!("{}/{}", endpoint, uri));
         if let Some(token) = bearer_token {
             b = b.bearer_auth(token);
         }
-        let value = b.json(&body).send().ok()?.text().ok()?;
-        serde_json::from_str(value.as_str()).ok()
+        let mut response = b
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", idempotency_key)
+            .header("Content-Digest", content_digest(&body))
+            .body(body)
+            .send()
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+        let value = response
+            .text()
+            .map_err(|e| ClientError::Transport(e.to_string()))?;
+        decode_response(response.status().as_u16(), value.as_str())
     }
 }
 
@@ -0,0 +1,157 @@
+//! WebSocket session transport
+//!
+//! A [`WebSocketClient`] sends every request of a [`ClientShim`](crate::ClientShim) over one
+//! connection to the `/ecdsa/session` endpoint of the server, authenticated once when it opens,
+//! instead of one HTTP request per round. The server keeps the state of a keygen, signature or
+//! rotation in the memory of the session, so a protocol has to run to its end on the same
+//! connection; a lost connection fails with `ClientError::Transport` and the protocol is started
+//! again on a new one. The endpoint and token given to the shim are not used.
+//!
+//! The server answers plain `ws://` on its `websocket_port`, which is only fit for a local setup;
+//! deployments expose it as `wss://` through a proxy terminating TLS, and `wss://` URLs are
+//! connected to with TLS.
+//!
+//! ```no_run
+//! use client_lib::websocket::WebSocketClient;
+//! use client_lib::{ecdsa, ClientShim};
+//!
+//! let url = "ws://127.0.0.1:8001/ecdsa/session";
+//! let client = WebSocketClient::connect(url, Some("token")).expect("session opens");
+//! let client_shim = ClientShim::new_with_client(String::new(), None, client);
+//! let private_share = ecdsa::get_master_key(&client_shim);
+//! ```
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::HeaderValue;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+use crate::{decode_response, Client, ClientError};
+
+#[derive(Serialize)]
+struct SessionRequest<'a, T> {
+    path: &'a str,
+    body: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+struct SessionResponse {
+    status: u16,
+    body: serde_json::Value,
+}
+
+fn transport(e: impl std::fmt::Display) -> ClientError {
+    ClientError::Transport(e.to_string())
+}
+
+pub struct WebSocketClient {
+    socket: Mutex<WebSocket<MaybeTlsStream<TcpStream>>>,
+}
+
+impl WebSocketClient {
+    /// Opens a session at `url`, e.g. `ws://127.0.0.1:8001/ecdsa/session` or, through a proxy
+    /// terminating TLS, `wss://gotham.example.com/ecdsa/session`, authenticated by `auth_token`
+    pub fn connect(url: &str, auth_token: Option<&str>) -> Result<Self, ClientError> {
+        let mut request = url.into_client_request().map_err(transport)?;
+        if let Some(token) = auth_token {
+            let authorization =
+                HeaderValue::from_str(&format!("Bearer {}", token)).map_err(transport)?;
+            request.headers_mut().insert("Authorization", authorization);
+        }
+        match tungstenite::connect(request) {
+            Ok((socket, _)) => Ok(WebSocketClient {
+                socket: Mutex::new(socket),
+            }),
+            // the server refused the session, e.g. for an invalid token
+            Err(tungstenite::Error::Http(response)) => {
+                let body = response
+                    .body()
+                    .as_deref()
+                    .map(String::from_utf8_lossy)
+                    .unwrap_or_default();
+                Err(ClientError::from_response(
+                    response.status().as_u16(),
+                    &body,
+                ))
+            }
+            Err(e) => Err(transport(e)),
+        }
+    }
+}
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        if let Ok(socket) = self.socket.get_mut() {
+            let _ = socket.close(None);
+            let _ = socket.flush();
+        }
+    }
+}
+
+impl WebSocketClient {
+    fn send<V: DeserializeOwned, T: Serialize>(
+        &self,
+        request: SessionRequest<T>,
+    ) -> Result<V, ClientError> {
+        let request = serde_json::to_string(&request).map_err(transport)?;
+        let mut socket = self
+            .socket
+            .lock()
+            .map_err(|_| transport("the session failed in another thread"))?;
+        socket.send(Message::Text(request)).map_err(transport)?;
+        let response = loop {
+            match socket.read().map_err(transport)? {
+                Message::Text(text) => break text,
+                // e.g. when the token of the session expired
+                Message::Close(Some(frame)) => {
+                    return Err(transport(format!(
+                        "the server closed the session: {}",
+                        frame.reason
+                    )))
+                }
+                Message::Close(None) => return Err(transport("the server closed the session")),
+                // pings are answered while reading
+                _ => continue,
+            }
+        };
+        let response: SessionResponse = serde_json::from_str(&response)
+            .map_err(|e| ClientError::InvalidResponse(e.to_string()))?;
+        decode_response(response.status, &response.body.to_string())
+    }
+}
+
+impl Client for WebSocketClient {
+    fn post<V: DeserializeOwned, T: Serialize>(
+        &self,
+        _: &str,
+        uri: &str,
+        _: Option<String>,
+        body: T,
+    ) -> Result<V, ClientError> {
+        self.send(SessionRequest {
+            path: uri,
+            body,
+            idempotency_key: None,
+        })
+    }
+
+    fn post_idempotent<V: DeserializeOwned, T: Serialize>(
+        &self,
+        _: &str,
+        uri: &str,
+        _: Option<String>,
+        idempotency_key: &str,
+        body: T,
+    ) -> Result<V, ClientError> {
+        self.send(SessionRequest {
+            path: uri,
+            body,
+            idempotency_key: Some(idempotency_key),
+        })
+    }
+}
@@ -0,0 +1,325 @@
+//! Offline administration of the key store, behind `server_exec admin`
+//!
+//! Commands open the configured store directly, so they run with the server stopped. Deletion
+//! receipts are appended to the audit log of `audit_log_path`.
+
+use chrono::{TimeZone, Utc};
+use clap::{Args, Subcommand};
+use std::sync::Arc;
+
+use two_party_ecdsa::curv::arithmetic::traits::Converter;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::audit::{AuditError, AuditLog};
+use crate::db::find_value;
+use crate::deletion::{self, DeletionError, DeletionReceipt};
+use crate::error::ApiError;
+use crate::keygen::{ActiveKey, KeygenStruct, ACTIVE_KEY_ID};
+use crate::keys::{self, KeyState, StateChange};
+use crate::policy::{Policy, PolicyEngine};
+use crate::public_gotham::PublicGotham;
+use crate::storage::schema::{self, RecordKey};
+use crate::storage::{Store, StoreError};
+
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    #[command(about = "List the keys of every customer")]
+    List,
+
+    #[command(about = "Show the public metadata of a key")]
+    Show(KeyArgs),
+
+    #[command(about = "Refuse signing and rotation with a key")]
+    Freeze(StateArgs),
+
+    #[command(about = "Allow a frozen key to sign again")]
+    Unfreeze(StateArgs),
+
+    #[command(about = "Refuse signing and rotation with a key for good")]
+    Revoke(StateArgs),
+
+    #[command(about = "Delete every record of a key for good, leaving a tombstone")]
+    Delete(StateArgs),
+
+    #[command(about = "Reclaim the space of deleted and replaced records")]
+    Compact,
+
+    #[command(about = "Write a consistent copy of the store to a new directory (RocksDB)")]
+    Checkpoint(CheckpointArgs),
+}
+
+#[derive(Args)]
+pub struct KeyArgs {
+    pub customer_id: String,
+    pub id: String,
+}
+
+#[derive(Args)]
+pub struct StateArgs {
+    pub customer_id: String,
+    pub id: String,
+    #[arg(long, help = "Why the state of the key changes, or why it is deleted")]
+    pub reason: String,
+    #[arg(
+        long,
+        help = "Who changes the state of the key or deletes it, $USER by default"
+    )]
+    pub actor: Option<String>,
+}
+
+impl StateArgs {
+    fn actor(&self) -> String {
+        self.actor
+            .clone()
+            .or_else(|| std::env::var("USER").ok())
+            .unwrap_or_else(|| "admin".to_string())
+    }
+}
+
+#[derive(Args)]
+pub struct CheckpointArgs {
+    pub path: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("customer {customer_id} has no key {id}")]
+    UnknownKey { customer_id: String, id: String },
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Deletion(#[from] DeletionError),
+    #[error(transparent)]
+    Audit(#[from] AuditError),
+    #[error("{0}")]
+    Db(String),
+}
+
+impl From<ApiError> for AdminError {
+    fn from(e: ApiError) -> Self {
+        AdminError::Db(e.message)
+    }
+}
+
+/// Public, non secret, metadata of a key
+#[derive(Debug)]
+pub struct KeyInfo {
+    pub customer_id: String,
+    pub id: String,
+    /// Compressed public key, hex encoded
+    pub public_key: String,
+    pub chain_code: String,
+    pub created_at: Option<i64>,
+    pub last_signed_at: Option<i64>,
+    pub state: KeyState,
+    pub state_changes: Vec<StateChange>,
+    pub active: bool,
+}
+
+pub struct Admin {
+    db: PublicGotham,
+    store: Arc<dyn Store>,
+    audit: AuditLog,
+}
+
+fn index(customer_id: &str, id: &str) -> DbIndex {
+    DbIndex {
+        customerId: customer_id.to_string(),
+        id: id.to_string(),
+    }
+}
+
+impl Admin {
+    pub fn new(store: Box<dyn Store>) -> Self {
+        let db = PublicGotham::with_store(store, Arc::new(PolicyEngine::new(Policy::default())));
+        Admin {
+            store: db.store(),
+            db,
+            audit: AuditLog::disabled(),
+        }
+    }
+
+    /// Appends the receipts of deletions to `audit`
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// `(customer id, key id)` of every key, ordered
+    pub async fn keys(&self) -> Result<Vec<(String, String)>, AdminError> {
+        let prefix = schema::table_prefix(&EcdsaStruct::Party1MasterKey.to_string());
+        Ok(self
+            .store
+            .scan(&prefix)
+            .await?
+            .into_iter()
+            .filter_map(|(key, _)| RecordKey::decode(&key))
+            .map(|key| (key.customer_id, key.id))
+            .collect())
+    }
+
+    async fn master_key(&self, key: &DbIndex) -> Result<MasterKey1, AdminError> {
+        find_value(&self.db, key, &EcdsaStruct::Party1MasterKey)
+            .await?
+            .ok_or_else(|| AdminError::UnknownKey {
+                customer_id: key.customerId.clone(),
+                id: key.id.clone(),
+            })
+    }
+
+    /// Master key of party one, secret share included
+    pub async fn master_key_of(
+        &self,
+        customer_id: &str,
+        id: &str,
+    ) -> Result<MasterKey1, AdminError> {
+        self.master_key(&index(customer_id, id)).await
+    }
+
+    async fn active_key(&self, customer_id: &str) -> Result<Option<ActiveKey>, AdminError> {
+        let key = index(customer_id, ACTIVE_KEY_ID);
+        Ok(find_value(&self.db, &key, &KeygenStruct::ActiveKey).await?)
+    }
+
+    pub async fn key_info(&self, customer_id: &str, id: &str) -> Result<KeyInfo, AdminError> {
+        let key = index(customer_id, id);
+        let master_key = self.master_key(&key).await?;
+        let metadata = keys::get_metadata(&self.db, &key).await?;
+        let active = self.active_key(customer_id).await?;
+        Ok(KeyInfo {
+            customer_id: customer_id.to_string(),
+            id: id.to_string(),
+            public_key: hex::encode(master_key.public.q.get_element().serialize()),
+            chain_code: master_key.chain_code.to_hex(),
+            created_at: metadata.created_at,
+            last_signed_at: metadata.last_signed_at,
+            state: metadata.state(),
+            state_changes: metadata.state_changes,
+            active: active.map_or(false, |active| active.id == id),
+        })
+    }
+
+    /// Moves the key to `state`, recording `reason` and `actor`
+    pub async fn set_state(
+        &self,
+        customer_id: &str,
+        id: &str,
+        state: KeyState,
+        reason: &str,
+        actor: &str,
+    ) -> Result<(), AdminError> {
+        let key = index(customer_id, id);
+        self.master_key(&key).await?;
+        keys::set_state(&self.db, &key, state, reason, actor).await?;
+        Ok(())
+    }
+
+    /// Deletes every record of the key for good, see [`deletion`], and audits the receipt
+    pub async fn delete_key(
+        &self,
+        customer_id: &str,
+        id: &str,
+        reason: &str,
+        actor: &str,
+    ) -> Result<DeletionReceipt, AdminError> {
+        let receipt = deletion::delete_key(
+            &self.db,
+            self.store.as_ref(),
+            customer_id,
+            id,
+            reason,
+            actor,
+        )
+        .await?;
+        self.audit.append(receipt.audit_record()).await?;
+        Ok(receipt)
+    }
+
+    pub async fn compact(&self) -> Result<(), AdminError> {
+        Ok(self.store.compact().await?)
+    }
+
+    pub async fn checkpoint(&self, path: &str) -> Result<(), AdminError> {
+        Ok(self.store.checkpoint(path).await?)
+    }
+}
+
+fn format_time(time: Option<i64>) -> String {
+    time.and_then(|time| Utc.timestamp_opt(time, 0).single())
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+async fn set_state(admin: &Admin, args: &StateArgs, state: KeyState) -> Result<(), AdminError> {
+    admin
+        .set_state(
+            &args.customer_id,
+            &args.id,
+            state,
+            &args.reason,
+            &args.actor(),
+        )
+        .await?;
+    println!(
+        "Key {} of {} is {}",
+        args.id,
+        args.customer_id,
+        state.as_str()
+    );
+    Ok(())
+}
+
+pub async fn run(admin: &Admin, command: AdminCommand) -> Result<(), AdminError> {
+    match command {
+        AdminCommand::List => {
+            for (customer_id, id) in admin.keys().await? {
+                println!("{}\t{}", customer_id, id);
+            }
+        }
+        AdminCommand::Show(args) => {
+            let info = admin.key_info(&args.customer_id, &args.id).await?;
+            println!("customer:    {}", info.customer_id);
+            println!("key:         {}", info.id);
+            println!("public key:  {}", info.public_key);
+            println!("chain code:  {}", info.chain_code);
+            println!("created:     {}", format_time(info.created_at));
+            println!("last signed: {}", format_time(info.last_signed_at));
+            println!("state:       {}", info.state.as_str());
+            println!("active:      {}", info.active);
+            for change in &info.state_changes {
+                println!(
+                    "  {} {} by {}: {}",
+                    format_time(Some(change.changed_at)),
+                    change.state.as_str(),
+                    change.actor,
+                    change.reason
+                );
+            }
+        }
+        AdminCommand::Freeze(args) => set_state(admin, &args, KeyState::Frozen).await?,
+        AdminCommand::Unfreeze(args) => set_state(admin, &args, KeyState::Active).await?,
+        AdminCommand::Revoke(args) => set_state(admin, &args, KeyState::Revoked).await?,
+        AdminCommand::Delete(args) => {
+            let receipt = admin
+                .delete_key(&args.customer_id, &args.id, &args.reason, &args.actor())
+                .await?;
+            println!("Deleted key {} of {}", args.id, args.customer_id);
+            for (table, count) in &receipt.records {
+                println!("  {}: {} records", table, count);
+            }
+            println!("record keys sha256: {}", receipt.record_keys_sha256);
+        }
+        AdminCommand::Compact => {
+            admin.compact().await?;
+            println!("Store compacted");
+        }
+        AdminCommand::Checkpoint(args) => {
+            admin.checkpoint(&args.path).await?;
+            println!("Checkpoint written to {}", args.path);
+        }
+    }
+    Ok(())
+}
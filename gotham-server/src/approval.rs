@@ -0,0 +1,705 @@
+//! Human approval of signing requests
+//!
+//! When the policy of a customer has `require_approval`, `sign/second` and `sign/<id>/presigned`
+//! do not sign an allowed request: the request and its ephemeral keys are parked under an
+//! approval id, and the route answers `202` with the [`ApprovalStatus`] of the request.
+//! Approvers list the pending requests and approve or reject them, with a reason, through the
+//! `/admin/approvals` routes, naming the hash of the message they decide on. The client polls
+//! `ecdsa/approvals/<approval id>`, waiting up to `wait` seconds for a decision; the first poll
+//! after an approval signs and returns the signature, later ones return it again. The policy is
+//! checked again when signing, and an approved request it denies by then, over a rate limit or
+//! a message denied since, fails with the reason of the denial.
+//!
+//! Requests not decided and signed within `approval_ttl` seconds expire, and their records are
+//! deleted one `approval_ttl` later. Approvers authenticate with a bearer token whose SHA-256
+//! is listed in the TOML or JSON file configured with `approvers_path`:
+//!
+//! ```toml
+//! [[approvers]]
+//! name = "alice"
+//! token_sha256 = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! ```
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, status, Responder};
+use rocket::serde::json::Json;
+use rocket::{get, post, Request, State};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+
+use two_party_ecdsa::curv::arithmetic::traits::Converter;
+use two_party_ecdsa::party_one;
+
+use gotham_engine::types::SignSecondMsgRequest;
+
+use crate::audit::{AuditLog, AuditRecord, Decision};
+use crate::auth::Customer;
+use crate::db::SharedDb;
+use crate::error::{ApiError, ErrorCode};
+use crate::policy::{normalize_hex, PolicyEngine, SignContext};
+use crate::sign::{self, Ephemeral, SignFirst};
+use crate::storage::schema::{self, Record, RecordKey};
+use crate::storage::{Store, StoreError};
+use crate::transaction::TransactionSummary;
+
+/// Table of the parked requests
+pub const APPROVALS_TABLE: &str = "SignApproval";
+/// Longest a status request waits for a decision, in seconds
+pub const MAX_WAIT: u64 = 25;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(3600);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApprovalError {
+    #[error("cannot load approvers file {0}: {1}")]
+    Load(String, String),
+    #[error("invalid setting approval_ttl: {0}")]
+    Invalid(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalState {
+    Pending,
+    Approved,
+    Rejected,
+    Expired,
+    Signed,
+    /// Approved, but signing failed
+    Failed,
+}
+
+impl ApprovalState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApprovalState::Pending => "pending",
+            ApprovalState::Approved => "approved",
+            ApprovalState::Rejected => "rejected",
+            ApprovalState::Expired => "expired",
+            ApprovalState::Signed => "signed",
+            ApprovalState::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApproverEntry {
+    pub name: String,
+    /// SHA-256 of the bearer token of the approver, hex encoded
+    pub token_sha256: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApproverList {
+    #[serde(default)]
+    pub approvers: Vec<ApproverEntry>,
+}
+
+impl ApproverList {
+    pub fn from_file(path: &Path) -> Result<Self, ApprovalError> {
+        let mut settings = config::Config::default();
+        settings
+            .merge(config::File::from(path))
+            .map_err(|e| ApprovalError::Load(path.display().to_string(), e.to_string()))?;
+        settings
+            .try_into()
+            .map_err(|e| ApprovalError::Load(path.display().to_string(), e.to_string()))
+    }
+}
+
+/// Signing request parked until an approver decides
+#[derive(Serialize, Deserialize)]
+pub struct ParkedSign {
+    pub id: String,
+    pub customer_id: String,
+    pub key_id: String,
+    pub request: SignSecondMsgRequest,
+    /// What the transaction the message is the digest of pays, when the client sent it
+    #[serde(default)]
+    pub transaction: Option<TransactionSummary>,
+    /// Ephemeral keys of the request, cleared once it is signed, failed or rejected
+    #[serde(default)]
+    pub first: Option<SignFirst>,
+    pub state: ApprovalState,
+    pub requested_at: i64,
+    pub expires_at: i64,
+    pub approver: Option<String>,
+    pub reason: Option<String>,
+    pub signature: Option<party_one::SignatureRecid>,
+}
+
+/// What the client learns of a parked request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApprovalStatus {
+    pub approval_id: String,
+    pub status: ApprovalState,
+    /// Hash of the message to sign, hex encoded
+    pub message_hash: String,
+    pub expires_at: i64,
+    /// Reason given by the approver, or of the signing failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<party_one::SignatureRecid>,
+}
+
+/// What approvers see of a parked request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalRequest {
+    pub customer_id: String,
+    pub approval_id: String,
+    pub key_id: String,
+    pub message_hash: String,
+    /// Derivation position of the child key, hex encoded
+    pub x_pos: String,
+    pub y_pos: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<TransactionSummary>,
+    pub status: ApprovalState,
+    pub requested_at: i64,
+    pub expires_at: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approver: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Decision of an approver on the request signing `message_hash`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApprovalDecision {
+    pub message_hash: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl ParkedSign {
+    pub fn message_hash(&self) -> String {
+        self.request.message.to_hex()
+    }
+
+    /// State at `now`, when undecided and unsigned requests may have expired
+    pub fn state_at(&self, now: DateTime<Utc>) -> ApprovalState {
+        match self.state {
+            ApprovalState::Pending | ApprovalState::Approved
+                if now.timestamp() > self.expires_at =>
+            {
+                ApprovalState::Expired
+            }
+            state => state,
+        }
+    }
+
+    pub fn status(self, now: DateTime<Utc>) -> ApprovalStatus {
+        ApprovalStatus {
+            approval_id: self.id.clone(),
+            status: self.state_at(now),
+            message_hash: self.message_hash(),
+            expires_at: self.expires_at,
+            reason: self.reason,
+            signature: self.signature,
+        }
+    }
+
+    pub fn summary(&self, now: DateTime<Utc>) -> ApprovalRequest {
+        ApprovalRequest {
+            customer_id: self.customer_id.clone(),
+            approval_id: self.id.clone(),
+            key_id: self.key_id.clone(),
+            message_hash: self.message_hash(),
+            x_pos: self.request.x_pos_child_key.to_hex(),
+            y_pos: self.request.y_pos_child_key.to_hex(),
+            transaction: self.transaction.clone(),
+            status: self.state_at(now),
+            requested_at: self.requested_at,
+            expires_at: self.expires_at,
+            approver: self.approver.clone(),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+/// Answer of the sign routes: a signature, or the approval the request waits for
+pub enum SignOutcome {
+    Signed(party_one::SignatureRecid),
+    Pending(ApprovalStatus),
+}
+
+impl<'r> Responder<'r, 'static> for SignOutcome {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            SignOutcome::Signed(signature) => Json(signature).respond_to(request),
+            SignOutcome::Pending(approval) => {
+                status::Accepted(Some(Json(approval))).respond_to(request)
+            }
+        }
+    }
+}
+
+pub struct Approvals {
+    ttl: Duration,
+    /// Approver names by the SHA-256 of their token
+    approvers: HashMap<String, String>,
+    store: Arc<dyn Store>,
+    /// Notified whenever an approver decides
+    decided: Notify,
+    /// Serializes the changes of approval records
+    lock: Mutex<()>,
+}
+
+impl Approvals {
+    pub fn new(ttl: Duration, approvers: ApproverList, store: Arc<dyn Store>) -> Self {
+        Approvals {
+            ttl,
+            approvers: approvers
+                .approvers
+                .into_iter()
+                .map(|approver| (approver.token_sha256.to_lowercase(), approver.name))
+                .collect(),
+            store,
+            decided: Notify::new(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Approvals configured by `approvers_path` and `approval_ttl`, in seconds. Without
+    /// approvers file nobody can approve.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+        store: Arc<dyn Store>,
+    ) -> Result<Self, ApprovalError> {
+        let approvers = match settings.get("approvers_path") {
+            Some(path) if !path.is_empty() => ApproverList::from_file(Path::new(path))?,
+            _ => ApproverList::default(),
+        };
+        let ttl = match settings.get("approval_ttl").map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => value
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|e| ApprovalError::Invalid(e.to_string()))?,
+            _ => DEFAULT_TTL,
+        };
+        Ok(Self::new(ttl, approvers, store))
+    }
+
+    /// Name of the approver holding `token`
+    pub fn approver(&self, token: &str) -> Option<&str> {
+        let hash = hex::encode(Sha256::digest(token.as_bytes()));
+        self.approvers.get(&hash).map(|name| name.as_str())
+    }
+
+    fn ttl_seconds(&self) -> i64 {
+        i64::try_from(self.ttl.as_secs()).unwrap_or(i64::MAX)
+    }
+
+    async fn load(&self, customer_id: &str, approval_id: &str) -> Result<ParkedSign, ApiError> {
+        let key = RecordKey::new(APPROVALS_TABLE, customer_id, approval_id).encode();
+        let value = self
+            .store
+            .get(&key)
+            .await
+            .map_err(|e| ApiError::storage(format!("Failed to read approval: {}", e)))?
+            .ok_or_else(|| ApiError::not_found(format!("No approval {}", approval_id)))?;
+        serde_json::from_slice::<Record<ParkedSign>>(&value)
+            .map(|record| record.value)
+            .map_err(|e| ApiError::storage(format!("Corrupted approval {}: {}", approval_id, e)))
+    }
+
+    async fn save(&self, parked: &ParkedSign) -> Result<(), ApiError> {
+        let key = RecordKey::new(APPROVALS_TABLE, &parked.customer_id, &parked.id).encode();
+        let value = serde_json::to_vec(&Record::new(APPROVALS_TABLE, parked))
+            .map_err(|e| ApiError::internal(format!("Cannot serialize approval: {}", e)))?;
+        self.store
+            .put(&key, &value)
+            .await
+            .map_err(|e| ApiError::storage(format!("Failed to write approval: {}", e)))
+    }
+
+    /// Parks `request` and its ephemeral keys until an approver decides
+    pub async fn park(
+        &self,
+        customer_id: &str,
+        key_id: &str,
+        request: SignSecondMsgRequest,
+        transaction: Option<TransactionSummary>,
+        first: SignFirst,
+        now: DateTime<Utc>,
+    ) -> Result<ApprovalStatus, ApiError> {
+        let parked = ParkedSign {
+            id: uuid::Uuid::new_v4().to_string(),
+            customer_id: customer_id.to_string(),
+            key_id: key_id.to_string(),
+            request,
+            transaction,
+            first: Some(first),
+            state: ApprovalState::Pending,
+            requested_at: now.timestamp(),
+            expires_at: now.timestamp().saturating_add(self.ttl_seconds()),
+            approver: None,
+            reason: None,
+            signature: None,
+        };
+        self.save(&parked).await?;
+        info!(
+            "Signing with key {} of {} waits for approval {}",
+            key_id, customer_id, parked.id
+        );
+        Ok(parked.status(now))
+    }
+
+    /// Every request waiting for a decision at `now`
+    pub async fn pending(&self, now: DateTime<Utc>) -> Result<Vec<ApprovalRequest>, ApiError> {
+        let records = self
+            .store
+            .scan(&schema::table_prefix(APPROVALS_TABLE))
+            .await
+            .map_err(|e| ApiError::storage(format!("Failed to read approvals: {}", e)))?;
+        Ok(records
+            .iter()
+            .filter_map(|(_, value)| serde_json::from_slice::<Record<ParkedSign>>(value).ok())
+            .map(|record| record.value)
+            .filter(|parked| parked.state_at(now) == ApprovalState::Pending)
+            .map(|parked| parked.summary(now))
+            .collect())
+    }
+
+    /// Approves or rejects a pending request, when `decision` names its message
+    pub async fn decide(
+        &self,
+        customer_id: &str,
+        approval_id: &str,
+        approver: &str,
+        decision: ApprovalDecision,
+        approve: bool,
+    ) -> Result<ParkedSign, ApiError> {
+        let _lock = self.lock.lock().await;
+        let now = Utc::now();
+        let mut parked = self.load(customer_id, approval_id).await?;
+        let state = parked.state_at(now);
+        if state != ApprovalState::Pending {
+            return Err(ApiError::new(
+                Status::Conflict,
+                ErrorCode::ApprovalClosed,
+                format!("Approval {} is {}", approval_id, state.as_str()),
+            ));
+        }
+        if normalize_hex(&decision.message_hash) != normalize_hex(&parked.message_hash()) {
+            return Err(ApiError::new(
+                Status::UnprocessableEntity,
+                ErrorCode::UnprocessableEntity,
+                format!(
+                    "Approval {} is for message {}",
+                    approval_id,
+                    parked.message_hash()
+                ),
+            ));
+        }
+
+        parked.state = if approve {
+            ApprovalState::Approved
+        } else {
+            ApprovalState::Rejected
+        };
+        parked.approver = Some(approver.to_string());
+        parked.reason = decision.reason;
+        if !approve {
+            parked.first = None;
+        }
+        self.save(&parked).await?;
+        self.decided.notify_waiters();
+        info!(
+            "{} {} approval {} of {}",
+            approver,
+            parked.state.as_str(),
+            approval_id,
+            customer_id
+        );
+        Ok(parked)
+    }
+
+    /// Signs an approved request, once. A request the policy denies by then fails with the
+    /// reason of the denial.
+    async fn complete(
+        &self,
+        state: &State<SharedDb>,
+        policy: &PolicyEngine,
+        audit: &AuditLog,
+        customer: &Customer,
+        approval_id: &str,
+    ) -> Result<ApprovalStatus, ApiError> {
+        let _lock = self.lock.lock().await;
+        let mut parked = self.load(&customer.id, approval_id).await?;
+        if parked.state_at(Utc::now()) != ApprovalState::Approved {
+            return Ok(parked.status(Utc::now()));
+        }
+
+        let first = parked.first.take().ok_or_else(|| {
+            ApiError::internal(format!("Approval {} has no ephemeral keys", approval_id))
+        })?;
+        let request = &parked.request;
+        let context = SignContext {
+            customer_id: customer.id.clone(),
+            message: request.message.to_hex(),
+            position: sign::position(&request.x_pos_child_key, &request.y_pos_child_key),
+            time: Utc::now(),
+            transaction: parked.transaction.clone(),
+        };
+        let signature = sign::sign(
+            state,
+            policy,
+            customer,
+            &parked.key_id,
+            request,
+            &context,
+            Ephemeral::Parked(first),
+        )
+        .await;
+        let approver = parked.approver.as_deref().unwrap_or("unknown approver");
+        let (decision, reason) = match &signature {
+            Err(e) if e.code == ErrorCode::PolicyDenied => (
+                Decision::Denied,
+                format!("approved by {}, then denied: {}", approver, e.message),
+            ),
+            _ => (Decision::Allowed, format!("approved by {}", approver)),
+        };
+        let record = AuditRecord {
+            decision,
+            reason: Some(reason),
+            signed: signature.is_ok(),
+            ..sign::audit_record(
+                &context,
+                &parked.key_id,
+                &request.x_pos_child_key,
+                &request.y_pos_child_key,
+            )
+        };
+        let audited = audit.append(record).await;
+        match (signature, audited) {
+            (Ok(signature), Ok(())) => {
+                parked.state = ApprovalState::Signed;
+                parked.signature = Some(signature);
+            }
+            (Ok(_), Err(e)) => {
+                // a signature is only returned once it is audited
+                error!("Cannot audit approved signing {}: {}", approval_id, e);
+                return Err(ApiError::internal("Cannot write the audit log"));
+            }
+            (Err(e), audited) => {
+                if let Err(audit_error) = audited {
+                    error!(
+                        "Cannot audit failed approved signing {}: {}",
+                        approval_id, audit_error
+                    );
+                }
+                parked.state = ApprovalState::Failed;
+                parked.reason = Some(e.message.clone());
+                self.save(&parked).await?;
+                return Err(e);
+            }
+        }
+        self.save(&parked).await?;
+        Ok(parked.status(Utc::now()))
+    }
+
+    /// Deletes the records of requests expired for one `approval_ttl`, returning how many were
+    pub async fn sweep(&self, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        let mut swept = 0;
+        for (key, value) in self
+            .store
+            .scan(&schema::table_prefix(APPROVALS_TABLE))
+            .await?
+        {
+            let expires_at = serde_json::from_slice::<Record<ParkedSign>>(&value)
+                .map(|record| record.value.expires_at)
+                .unwrap_or(i64::MIN);
+            if now.timestamp() > expires_at.saturating_add(self.ttl_seconds()) {
+                self.store.delete(&key).await?;
+                swept += 1;
+            }
+        }
+        Ok(swept)
+    }
+
+    /// Sweeps the approval records periodically, until the runtime shuts down
+    pub fn spawn_sweeper(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match self.sweep(Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(swept) => info!("Deleted {} expired approvals", swept),
+                    Err(e) => error!("Cannot delete expired approvals: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Approver authenticated by the bearer token of the request
+pub struct Approver {
+    pub name: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Approver {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let approvals = match request.rocket().state::<Arc<Approvals>>() {
+            Some(approvals) => approvals,
+            None => return Outcome::Failure((Status::InternalServerError, "no approvals")),
+        };
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match token.and_then(|token| approvals.approver(token)) {
+            Some(name) => Outcome::Success(Approver {
+                name: name.to_string(),
+            }),
+            None => {
+                warn!("Rejected approver request to {}", request.uri());
+                Outcome::Failure((Status::Unauthorized, "unknown approver token"))
+            }
+        }
+    }
+}
+
+/// Status of a parked request, waiting up to `wait` seconds for a decision. The first request
+/// after an approval signs.
+#[post("/ecdsa/approvals/<approval_id>?<wait>", format = "json")]
+pub async fn approval_status(
+    state: &State<SharedDb>,
+    policy: &State<Arc<PolicyEngine>>,
+    audit: &State<AuditLog>,
+    approvals: &State<Arc<Approvals>>,
+    customer: Customer,
+    approval_id: String,
+    wait: Option<u64>,
+) -> Result<Json<ApprovalStatus>, ApiError> {
+    let deadline = Instant::now() + Duration::from_secs(wait.unwrap_or(0).min(MAX_WAIT));
+    loop {
+        // created before reading, so that no decision is missed
+        let decided = approvals.decided.notified();
+        let parked = approvals.load(&customer.id, &approval_id).await?;
+        match parked.state_at(Utc::now()) {
+            ApprovalState::Pending => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(Json(parked.status(Utc::now())));
+                }
+                let _ = tokio::time::timeout(deadline - now, decided).await;
+            }
+            ApprovalState::Approved => {
+                return approvals
+                    .complete(state, policy, audit, &customer, &approval_id)
+                    .await
+                    .map(Json)
+            }
+            _ => return Ok(Json(parked.status(Utc::now()))),
+        }
+    }
+}
+
+#[get("/admin/approvals")]
+pub async fn list_approvals(
+    approvals: &State<Arc<Approvals>>,
+    _approver: Approver,
+) -> Result<Json<Vec<ApprovalRequest>>, ApiError> {
+    approvals.pending(Utc::now()).await.map(Json)
+}
+
+/// Audit record of the decision of an approver, before anything is signed
+fn decision_record(summary: &ApprovalRequest, decision: Decision, reason: String) -> AuditRecord {
+    AuditRecord {
+        time: Utc::now(),
+        customer_id: summary.customer_id.clone(),
+        key_id: summary.key_id.clone(),
+        x_pos: summary.x_pos.clone(),
+        y_pos: summary.y_pos.clone(),
+        message_hash: summary.message_hash.clone(),
+        decision,
+        reason: Some(reason),
+        signed: false,
+        deletion: None,
+    }
+}
+
+#[post(
+    "/admin/approvals/<customer_id>/<approval_id>/approve",
+    format = "json",
+    data = "<decision>"
+)]
+pub async fn approve(
+    approvals: &State<Arc<Approvals>>,
+    audit: &State<AuditLog>,
+    approver: Approver,
+    customer_id: String,
+    approval_id: String,
+    decision: Json<ApprovalDecision>,
+) -> Result<Json<ApprovalRequest>, ApiError> {
+    let parked = approvals
+        .decide(
+            &customer_id,
+            &approval_id,
+            &approver.name,
+            decision.into_inner(),
+            true,
+        )
+        .await?;
+
+    let summary = parked.summary(Utc::now());
+    let reason = match summary.reason.as_deref() {
+        Some(reason) if !reason.is_empty() => format!("approved by {}: {}", approver.name, reason),
+        _ => format!("approved by {}", approver.name),
+    };
+    let record = decision_record(&summary, Decision::Allowed, reason);
+    if let Err(e) = audit.append(record).await {
+        error!("Cannot audit approval {}: {}", approval_id, e);
+    }
+    Ok(Json(summary))
+}
+
+/// Rejects a pending request; the reason is shown to the customer
+#[post(
+    "/admin/approvals/<customer_id>/<approval_id>/reject",
+    format = "json",
+    data = "<decision>"
+)]
+pub async fn reject(
+    approvals: &State<Arc<Approvals>>,
+    audit: &State<AuditLog>,
+    approver: Approver,
+    customer_id: String,
+    approval_id: String,
+    decision: Json<ApprovalDecision>,
+) -> Result<Json<ApprovalRequest>, ApiError> {
+    let decision = decision.into_inner();
+    if decision.reason.as_deref().map_or(true, str::is_empty) {
+        return Err(ApiError::bad_request("A rejection needs a reason"));
+    }
+    let parked = approvals
+        .decide(&customer_id, &approval_id, &approver.name, decision, false)
+        .await?;
+
+    let summary = parked.summary(Utc::now());
+    let reason = format!(
+        "rejected by {}: {}",
+        approver.name,
+        summary.reason.as_deref().unwrap_or_default()
+    );
+    let record = decision_record(&summary, Decision::Denied, reason);
+    if let Err(e) = audit.append(record).await {
+        error!("Cannot audit rejected approval {}: {}", approval_id, e);
+    }
+    Ok(Json(summary))
+}
@@ -0,0 +1,248 @@
+//! Append-only, hash chained audit log of signing requests
+//!
+//! Every `sign/second` request appends a JSON line to the file at `audit_log_path`, holding its
+//! decision and the hash of the previous line. The receipt of every deleted key is appended too.
+//! The sequence number and hash of the last entry are also written to `<audit_log_path>.head`, so
+//! that [`verify`] detects modified, removed and reordered entries as well as a truncated log.
+//! Keep a copy of the last hash elsewhere to also detect a rewrite of both files.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::deletion::DeletionReceipt;
+
+/// Previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    #[error("cannot access audit log {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("audit log entry {line} is invalid: {reason}")]
+    Invalid { line: usize, reason: String },
+    #[error("audit log is truncated: {0}")]
+    Truncated(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allowed,
+    Denied,
+    /// Allowed by the policy, waiting for an approver
+    Pending,
+    /// Every record of the key was deleted
+    Deleted,
+}
+
+/// What is recorded of a signing request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    pub customer_id: String,
+    pub key_id: String,
+    /// Derivation position of the child key, hex encoded
+    pub x_pos: String,
+    pub y_pos: String,
+    /// Hash of the signed message, hex encoded
+    pub message_hash: String,
+    pub decision: Decision,
+    /// Reason of a denial
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Whether a signature was returned
+    pub signed: bool,
+    /// Receipt of the deletion of the key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion: Option<DeletionReceipt>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub prev_hash: String,
+    #[serde(flatten)]
+    pub record: AuditRecord,
+    /// SHA-256 of the entry without this field
+    pub hash: String,
+}
+
+#[derive(Serialize)]
+struct HashedFields<'a> {
+    seq: u64,
+    prev_hash: &'a str,
+    #[serde(flatten)]
+    record: &'a AuditRecord,
+}
+
+fn entry_hash(seq: u64, prev_hash: &str, record: &AuditRecord) -> String {
+    let fields = serde_json::to_vec(&HashedFields {
+        seq,
+        prev_hash,
+        record,
+    })
+    .expect("audit records serialize");
+    hex::encode(Sha256::digest(&fields))
+}
+
+/// Sequence number and hash of the last entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Head {
+    pub seq: u64,
+    pub hash: String,
+}
+
+impl Head {
+    fn genesis() -> Self {
+        Head {
+            seq: 0,
+            hash: GENESIS_HASH.to_string(),
+        }
+    }
+}
+
+fn head_path(path: &Path) -> PathBuf {
+    let mut head = path.as_os_str().to_owned();
+    head.push(".head");
+    PathBuf::from(head)
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> AuditError + '_ {
+    move |e| AuditError::Io(path.display().to_string(), e)
+}
+
+/// Checks every entry of the log at `path` against its predecessor and the head file,
+/// returning the head of the log. The log may end one entry after the head file, when the
+/// server stopped while appending.
+pub fn verify(path: &Path) -> Result<Head, AuditError> {
+    let head_path = head_path(path);
+    let recorded = match fs::read_to_string(&head_path) {
+        Ok(recorded) => serde_json::from_str::<Head>(&recorded).map_err(|e| {
+            AuditError::Truncated(format!("{} is invalid: {}", head_path.display(), e))
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Head::genesis(),
+        Err(e) => return Err(io_error(&head_path)(e)),
+    };
+
+    let mut head = Head::genesis();
+    if path.exists() {
+        let file = File::open(path).map_err(io_error(path))?;
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(io_error(path))?;
+            let invalid = |reason: String| AuditError::Invalid {
+                line: index + 1,
+                reason,
+            };
+            let entry: AuditEntry =
+                serde_json::from_str(&line).map_err(|e| invalid(e.to_string()))?;
+            if entry.seq != head.seq + 1 {
+                return Err(invalid(format!(
+                    "sequence number {} follows {}",
+                    entry.seq, head.seq
+                )));
+            }
+            if entry.prev_hash != head.hash {
+                return Err(invalid("previous hash does not match".to_string()));
+            }
+            if entry.hash != entry_hash(entry.seq, &entry.prev_hash, &entry.record) {
+                return Err(invalid("hash does not match its content".to_string()));
+            }
+            if entry.seq == recorded.seq && entry.hash != recorded.hash {
+                return Err(invalid("hash does not match the head file".to_string()));
+            }
+            head = Head {
+                seq: entry.seq,
+                hash: entry.hash,
+            };
+        }
+    }
+
+    if head.seq < recorded.seq || head.seq > recorded.seq + 1 {
+        return Err(AuditError::Truncated(format!(
+            "the log ends at entry {} while the head file records entry {}",
+            head.seq, recorded.seq
+        )));
+    }
+    Ok(head)
+}
+
+/// Clones append to the same log
+#[derive(Clone)]
+pub struct AuditLog {
+    /// `None` when auditing is disabled
+    path: Option<PathBuf>,
+    head: Arc<Mutex<Head>>,
+}
+
+impl AuditLog {
+    pub fn disabled() -> Self {
+        AuditLog {
+            path: None,
+            head: Arc::new(Mutex::new(Head::genesis())),
+        }
+    }
+
+    /// Log at `path`, appended to after checking its chain
+    pub fn open(path: PathBuf) -> Result<Self, AuditError> {
+        let head = verify(&path)?;
+        Ok(AuditLog {
+            path: Some(path),
+            head: Arc::new(Mutex::new(head)),
+        })
+    }
+
+    /// Log at the `audit_log_path` setting, disabled when it is empty
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Self, AuditError> {
+        match settings.get("audit_log_path") {
+            Some(path) if !path.is_empty() => Self::open(PathBuf::from(path)),
+            _ => Ok(Self::disabled()),
+        }
+    }
+
+    /// Appends `record`, durably, once every previous entry is written
+    pub async fn append(&self, record: AuditRecord) -> Result<(), AuditError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut head = self.head.lock().await;
+        let seq = head.seq + 1;
+        let hash = entry_hash(seq, &head.hash, &record);
+        let entry = AuditEntry {
+            seq,
+            prev_hash: head.hash.clone(),
+            record,
+            hash: hash.clone(),
+        };
+        let mut line = serde_json::to_string(&entry).expect("audit entries serialize");
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(io_error(path))?;
+        file.write_all(line.as_bytes()).map_err(io_error(path))?;
+        file.sync_data().map_err(io_error(path))?;
+
+        let new_head = Head { seq, hash };
+        let head_path = head_path(path);
+        let temporary = head_path.with_extension("head.tmp");
+        fs::write(
+            &temporary,
+            serde_json::to_vec(&new_head).expect("heads serialize"),
+        )
+        .map_err(io_error(&temporary))?;
+        fs::rename(&temporary, &head_path).map_err(io_error(&head_path))?;
+
+        *head = new_head;
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! JWT bearer authentication of protocol requests
+//!
+//! Tokens are verified against a JWKS file configured with `jwks_path`, and must carry the
+//! configured `issuer` and `audience`. The token subject is the customer id under which every
+//! share of the request is stored. The server refuses to start when none of the three settings
+//! is set, unless `auth_passthrough` is `true`: it then runs in passthrough mode and serves all
+//! requests as a single customer, which is only meant for local setups.
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::warn;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Customer id of every request when authentication is disabled
+pub const PASSTHROUGH_SUBJECT: &str = "passthrough";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("authentication is misconfigured: {0}")]
+    Configuration(String),
+    #[error("missing bearer token")]
+    MissingToken,
+    #[error("unsupported token algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("token header has no key id")]
+    MissingKeyId,
+    #[error("unknown key id {0}")]
+    UnknownKeyId(String),
+    #[error("invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: usize,
+}
+
+#[derive(Clone)]
+pub struct Authorizer {
+    issuer: String,
+    audience: String,
+    jwks: Option<JwkSet>,
+}
+
+impl Authorizer {
+    pub fn passthrough() -> Self {
+        Authorizer {
+            issuer: String::new(),
+            audience: String::new(),
+            jwks: None,
+        }
+    }
+
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Self, AuthError> {
+        let setting = |name: &str| settings.get(name).cloned().unwrap_or_default();
+        let issuer = setting("issuer");
+        let audience = setting("audience");
+        let jwks_path = setting("jwks_path");
+        let passthrough = match setting("auth_passthrough").trim() {
+            "" => false,
+            value => value.parse::<bool>().map_err(|e| {
+                AuthError::Configuration(format!("invalid setting auth_passthrough: {}", e))
+            })?,
+        };
+
+        let unset = issuer.is_empty() && audience.is_empty() && jwks_path.is_empty();
+        match (passthrough, unset) {
+            (true, true) => return Ok(Self::passthrough()),
+            (true, false) => {
+                return Err(AuthError::Configuration(
+                    "auth_passthrough cannot be set with issuer, audience and jwks_path"
+                        .to_string(),
+                ))
+            }
+            (false, true) => {
+                return Err(AuthError::Configuration(
+                    "issuer, audience and jwks_path must be set, or auth_passthrough to disable \
+                     authentication"
+                        .to_string(),
+                ))
+            }
+            (false, false) => {}
+        }
+        if issuer.is_empty() || audience.is_empty() || jwks_path.is_empty() {
+            return Err(AuthError::Configuration(
+                "issuer, audience and jwks_path must be set together".to_string(),
+            ));
+        }
+
+        let jwks = std::fs::read_to_string(&jwks_path)
+            .map_err(|e| AuthError::Configuration(format!("cannot read {}: {}", jwks_path, e)))?;
+        let jwks: JwkSet = serde_json::from_str(&jwks)
+            .map_err(|e| AuthError::Configuration(format!("cannot parse {}: {}", jwks_path, e)))?;
+
+        Ok(Authorizer {
+            issuer,
+            audience,
+            jwks: Some(jwks),
+        })
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.jwks.is_none()
+    }
+
+    /// Makes sure tokens can be verified: the JWKS must hold at least one key
+    pub fn check_keys(&self) -> Result<(), AuthError> {
+        match &self.jwks {
+            Some(jwks) if jwks.keys.is_empty() => Err(AuthError::Configuration(
+                "the JWKS holds no key".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Verifies signature, issuer, audience and expiry of `token`
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let jwks = match &self.jwks {
+            Some(jwks) => jwks,
+            None => {
+                return Ok(Claims {
+                    sub: PASSTHROUGH_SUBJECT.to_string(),
+                    exp: 0,
+                })
+            }
+        };
+
+        let header = decode_header(token)?;
+        if header.alg != Algorithm::RS256 && header.alg != Algorithm::ES256 {
+            return Err(AuthError::UnsupportedAlgorithm(header.alg));
+        }
+        let kid = header.kid.ok_or(AuthError::MissingKeyId)?;
+        let jwk = jwks
+            .find(&kid)
+            .ok_or(AuthError::UnknownKeyId(kid.clone()))?;
+        let key = DecodingKey::from_jwk(jwk)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+
+        Ok(decode::<Claims>(token, &key, &validation)?.claims)
+    }
+}
+
+/// Request guard resolving the customer id from the bearer token
+pub struct Customer {
+    pub id: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Customer {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let authorizer = match request.rocket().state::<Authorizer>() {
+            Some(authorizer) => authorizer,
+            None => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    AuthError::Configuration("no authorizer is managed".to_string()),
+                ))
+            }
+        };
+        if authorizer.is_passthrough() {
+            return Outcome::Success(Customer {
+                id: PASSTHROUGH_SUBJECT.to_string(),
+            });
+        }
+
+        let claims = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingToken)
+            .and_then(|token| authorizer.verify(token));
+        match claims {
+            Ok(claims) => Outcome::Success(Customer { id: claims.sub }),
+            Err(e) => {
+                warn!("Rejected request to {}: {}", request.uri(), e);
+                Outcome::Failure((Status::Unauthorized, e))
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Typed access to the values stored through the `Db` trait
+
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use two_party_ecdsa::party_one::Value;
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::auth::Customer;
+use crate::error::ApiError;
+use crate::storage::schema::RecordKey;
+use crate::storage::Store;
+
+/// `Db` of the routes and of the WebSocket sessions, whose lock serializes their protocol rounds
+pub type SharedDb = Arc<Mutex<Box<dyn Db>>>;
+
+pub fn db_index(customer: &Customer, id: &str) -> DbIndex {
+    DbIndex {
+        customerId: customer.id.to_string(),
+        id: id.to_string(),
+    }
+}
+
+pub async fn get_value<T: Clone + 'static>(
+    db: &dyn Db,
+    key: &DbIndex,
+    table_name: &dyn MPCStruct,
+) -> Result<T, ApiError> {
+    find_value(db, key, table_name).await?.ok_or_else(|| {
+        ApiError::not_found(format!(
+            "No data for {} with id {}",
+            table_name.to_string(),
+            key.id
+        ))
+    })
+}
+
+/// Like [`get_value`], for values that may be missing
+pub async fn find_value<T: Clone + 'static>(
+    db: &dyn Db,
+    key: &DbIndex,
+    table_name: &dyn MPCStruct,
+) -> Result<Option<T>, ApiError> {
+    let value = db.get(key, table_name).await.map_err(|e| {
+        ApiError::storage(format!(
+            "Failed to read {} from db: {}",
+            table_name.to_string(),
+            e
+        ))
+    })?;
+    value
+        .map(|value| {
+            value.as_any().downcast_ref::<T>().cloned().ok_or_else(|| {
+                ApiError::storage(format!("Corrupted data for {}", table_name.to_string()))
+            })
+        })
+        .transpose()
+}
+
+/// Like [`get_value`], deleting the value so that it is read only once
+pub async fn take_value<T: Clone + 'static>(
+    db: &dyn Db,
+    store: &dyn Store,
+    key: &DbIndex,
+    table_name: &dyn MPCStruct,
+) -> Result<T, ApiError> {
+    let value = get_value(db, key, table_name).await?;
+    delete_value(store, key, table_name).await?;
+    Ok(value)
+}
+
+/// Deletes the value of `key` in `table_name`, if any
+pub async fn delete_value(
+    store: &dyn Store,
+    key: &DbIndex,
+    table_name: &dyn MPCStruct,
+) -> Result<(), ApiError> {
+    let record_key = RecordKey::new(&table_name.to_string(), &key.customerId, &key.id);
+    store.delete(&record_key.encode()).await.map_err(|e| {
+        ApiError::storage(format!(
+            "Failed to delete {} from db: {}",
+            table_name.to_string(),
+            e
+        ))
+    })
+}
+
+pub async fn insert_value(
+    db: &dyn Db,
+    key: &DbIndex,
+    table_name: &dyn MPCStruct,
+    value: &dyn Value,
+) -> Result<(), ApiError> {
+    db.insert(key, table_name, value).await.map_err(|e| {
+        ApiError::storage(format!(
+            "Failed to write {} to db: {}",
+            table_name.to_string(),
+            e
+        ))
+    })
+}
@@ -0,0 +1,335 @@
+//! Permanent deletion of keys, for customers closing their account
+//!
+//! Deleting a key removes every record stored under its id: the party one master key, its
+//! metadata, the records of keygen, signing and rotation sessions and its presignatures, as well
+//! as the active key record pointing to it, the signing requests parked for it and the responses
+//! stored for the idempotency keys of its requests. A tombstone then takes the place of the key,
+//! so that its id is never used again, and the store is compacted so that the share is dropped
+//! from disk rather than only hidden. The records are looked up by their keys, and the
+//! presignatures, parked requests and stored responses by the prefix of their table and
+//! customer, never scanning the whole store. They are looked up once more to check that nothing
+//! of the key is left, and the receipt of the deletion, listing how many records of each table
+//! were removed, is appended to the audit log.
+//!
+//! Escrow backups are files written outside of the store: those made before the deletion still
+//! hold the share of the key, and must be destroyed by the operator.
+//!
+//! Approvers delete keys with `admin/keys/<customer id>/<id>/delete`, and `server_exec admin
+//! delete` does with the server stopped.
+
+use chrono::Utc;
+use log::{error, info};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::approval::{Approver, ParkedSign, APPROVALS_TABLE};
+use crate::audit::{AuditLog, AuditRecord, Decision};
+use crate::db::{find_value, SharedDb};
+use crate::error::{ApiError, ErrorCode};
+use crate::idempotency::{StoredResponse, RESPONSES_TABLE};
+use crate::keygen::{ActiveKey, KeygenStruct, ACTIVE_KEY_ID};
+use crate::keys::KeyStruct;
+use crate::presign::{self, PresignStruct};
+use crate::sessions;
+use crate::storage::schema::{self, Record, RecordKey};
+use crate::storage::{Store, StoreError};
+
+/// Table of the tombstones of deleted keys
+pub const TOMBSTONES_TABLE: &str = "KeyTombstone";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeletionError {
+    #[error("customer {customer_id} has no key {id}")]
+    UnknownKey { customer_id: String, id: String },
+    #[error("key {0} is already deleted")]
+    Deleted(String),
+    #[error("a deletion needs a reason")]
+    MissingReason,
+    #[error("{remaining} records of key {id} are left after its deletion")]
+    Incomplete { id: String, remaining: usize },
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("{0}")]
+    Db(String),
+}
+
+impl From<ApiError> for DeletionError {
+    fn from(e: ApiError) -> Self {
+        DeletionError::Db(e.message)
+    }
+}
+
+impl From<DeletionError> for ApiError {
+    fn from(e: DeletionError) -> Self {
+        match e {
+            DeletionError::UnknownKey { .. } => ApiError::not_found(e.to_string()),
+            DeletionError::Deleted(_) => {
+                ApiError::new(Status::Gone, ErrorCode::KeyDeleted, e.to_string())
+            }
+            DeletionError::MissingReason => ApiError::bad_request(e.to_string()),
+            DeletionError::Incomplete { .. } => ApiError::internal(e.to_string()),
+            DeletionError::Store(_) | DeletionError::Db(_) => ApiError::storage(e.to_string()),
+        }
+    }
+}
+
+/// What is left of a deleted key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Unix time of the deletion
+    pub deleted_at: i64,
+    pub actor: String,
+    pub reason: String,
+}
+
+/// Proof of the deletion of a key, appended to the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletionReceipt {
+    pub customer_id: String,
+    pub key_id: String,
+    /// Unix time of the deletion
+    pub deleted_at: i64,
+    /// Who deleted the key
+    pub actor: String,
+    pub reason: String,
+    /// Number of deleted records, by table
+    pub records: BTreeMap<String, usize>,
+    /// SHA-256 of the sorted keys of the deleted records, hex encoded
+    pub record_keys_sha256: String,
+}
+
+impl DeletionReceipt {
+    /// Entry of the audit log recording the deletion
+    pub fn audit_record(&self) -> AuditRecord {
+        AuditRecord {
+            time: Utc::now(),
+            customer_id: self.customer_id.clone(),
+            key_id: self.key_id.clone(),
+            x_pos: String::new(),
+            y_pos: String::new(),
+            message_hash: String::new(),
+            decision: Decision::Deleted,
+            reason: Some(format!("deleted by {}: {}", self.actor, self.reason)),
+            signed: false,
+            deletion: Some(self.clone()),
+        }
+    }
+}
+
+fn tombstone_key(customer_id: &str, id: &str) -> Vec<u8> {
+    RecordKey::new(TOMBSTONES_TABLE, customer_id, id).encode()
+}
+
+/// Whether key `id` of the customer was deleted
+pub async fn is_deleted(
+    store: &dyn Store,
+    customer_id: &str,
+    id: &str,
+) -> Result<bool, StoreError> {
+    Ok(store.get(&tombstone_key(customer_id, id)).await?.is_some())
+}
+
+/// Tables holding a record under the id of a key
+fn key_tables() -> Vec<String> {
+    let mut tables = vec![
+        EcdsaStruct::Party1MasterKey.to_string(),
+        KeyStruct::Metadata.to_string(),
+    ];
+    tables.extend(sessions::session_tables());
+    tables
+}
+
+/// Records of key `id` of the customer, with their table
+async fn records_of(
+    store: &dyn Store,
+    customer_id: &str,
+    id: &str,
+    active: Option<&ActiveKey>,
+) -> Result<Vec<(Vec<u8>, String)>, StoreError> {
+    let mut records = Vec::new();
+    let mut record_keys = key_tables()
+        .into_iter()
+        .map(|table| RecordKey::new(&table, customer_id, id))
+        .collect::<Vec<_>>();
+    if active.map_or(false, |active| active.id == id) {
+        record_keys.push(RecordKey::new(
+            &KeygenStruct::ActiveKey.to_string(),
+            customer_id,
+            ACTIVE_KEY_ID,
+        ));
+    }
+    for record_key in record_keys {
+        let key = record_key.encode();
+        if store.get(&key).await?.is_some() {
+            records.push((key, record_key.table));
+        }
+    }
+
+    let presignatures = PresignStruct::Presignature.to_string();
+    for (key, _) in store
+        .scan(&schema::customer_prefix(&presignatures, customer_id))
+        .await?
+    {
+        if RecordKey::decode(&key).map_or(false, |record_key| {
+            presign::is_presignature_of(&record_key.id, id)
+        }) {
+            records.push((key, presignatures.clone()));
+        }
+    }
+    for (key, value) in store
+        .scan(&schema::customer_prefix(APPROVALS_TABLE, customer_id))
+        .await?
+    {
+        if serde_json::from_slice::<Record<ParkedSign>>(&value)
+            .map_or(false, |record| record.value.key_id == id)
+        {
+            records.push((key, APPROVALS_TABLE.to_string()));
+        }
+    }
+    for (key, value) in store
+        .scan(&schema::customer_prefix(RESPONSES_TABLE, customer_id))
+        .await?
+    {
+        if serde_json::from_slice::<Record<StoredResponse>>(&value)
+            .map_or(false, |record| record.value.is_of_key(id))
+        {
+            records.push((key, RESPONSES_TABLE.to_string()));
+        }
+    }
+    Ok(records)
+}
+
+/// Deletes every record of key `id` of the customer, leaves a tombstone in its place and
+/// compacts the store
+pub async fn delete_key(
+    db: &dyn Db,
+    store: &dyn Store,
+    customer_id: &str,
+    id: &str,
+    reason: &str,
+    actor: &str,
+) -> Result<DeletionReceipt, DeletionError> {
+    if reason.trim().is_empty() {
+        return Err(DeletionError::MissingReason);
+    }
+    if is_deleted(store, customer_id, id).await? {
+        return Err(DeletionError::Deleted(id.to_string()));
+    }
+    let key = DbIndex {
+        customerId: customer_id.to_string(),
+        id: id.to_string(),
+    };
+    find_value::<MasterKey1>(db, &key, &EcdsaStruct::Party1MasterKey)
+        .await?
+        .ok_or_else(|| DeletionError::UnknownKey {
+            customer_id: customer_id.to_string(),
+            id: id.to_string(),
+        })?;
+    let active_index = DbIndex {
+        customerId: customer_id.to_string(),
+        id: ACTIVE_KEY_ID.to_string(),
+    };
+    let active: Option<ActiveKey> = find_value(db, &active_index, &KeygenStruct::ActiveKey).await?;
+
+    let mut records = records_of(store, customer_id, id, active.as_ref()).await?;
+    records.sort();
+    let mut counts = BTreeMap::new();
+    let mut hasher = Sha256::new();
+    for (record_key, table) in &records {
+        store.delete(record_key).await?;
+        *counts.entry(table.clone()).or_insert(0) += 1;
+        hasher.update(record_key);
+    }
+
+    let tombstone = Tombstone {
+        deleted_at: Utc::now().timestamp(),
+        actor: actor.to_string(),
+        reason: reason.to_string(),
+    };
+    let value = serde_json::to_vec(&Record::new(TOMBSTONES_TABLE, &tombstone))
+        .map_err(|e| DeletionError::Db(format!("Cannot serialize tombstone: {}", e)))?;
+    store.put(&tombstone_key(customer_id, id), &value).await?;
+    store.compact().await?;
+
+    let remaining = records_of(store, customer_id, id, active.as_ref())
+        .await?
+        .len();
+    if remaining > 0 {
+        return Err(DeletionError::Incomplete {
+            id: id.to_string(),
+            remaining,
+        });
+    }
+    info!(
+        "{} deleted key {} of {} ({} records): {}",
+        actor,
+        id,
+        customer_id,
+        records.len(),
+        reason
+    );
+    Ok(DeletionReceipt {
+        customer_id: customer_id.to_string(),
+        key_id: id.to_string(),
+        deleted_at: tombstone.deleted_at,
+        actor: tombstone.actor,
+        reason: tombstone.reason,
+        records: counts,
+        record_keys_sha256: hex::encode(hasher.finalize()),
+    })
+}
+
+/// Why an approver deletes a key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeletionRequest {
+    pub reason: String,
+}
+
+/// Deletes every record of the key for good, answering the receipt written to the audit log
+#[post(
+    "/admin/keys/<customer_id>/<id>/delete",
+    format = "json",
+    data = "<request>"
+)]
+pub async fn delete(
+    db: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    audit: &State<AuditLog>,
+    approver: Approver,
+    customer_id: String,
+    id: String,
+    request: Json<DeletionRequest>,
+) -> Result<Json<DeletionReceipt>, ApiError> {
+    // no protocol round of the key runs while its records are deleted
+    let db = db.lock().await;
+    let receipt = delete_key(
+        db.as_ref(),
+        store.inner().as_ref(),
+        &customer_id,
+        &id,
+        &request.reason,
+        &approver.name,
+    )
+    .await?;
+    if let Err(e) = audit.append(receipt.audit_record()).await {
+        error!(
+            "Cannot audit deletion of key {} of {}: {}",
+            id, customer_id, e
+        );
+        return Err(ApiError::internal(format!(
+            "Key {} is deleted, but its receipt could not be written to the audit log",
+            id
+        )));
+    }
+    Ok(Json(receipt))
+}
@@ -0,0 +1,279 @@
+//! JSON error responses
+//!
+//! Every error response carries an [`ErrorBody`] with a stable error code, and every response
+//! carries the id of its request in the `X-Request-Id` header. Errors of the routes of the server
+//! are [`ApiError`]s, and catchers answer with the error recorded by the failing guard, or else
+//! with the code of their status.
+
+use log::error;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::{catch, Data, Request};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::sync::OnceLock;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    UnprocessableEntity,
+    PolicyDenied,
+    KeyFrozen,
+    KeyRevoked,
+    KeyDeleted,
+    RateLimited,
+    IdempotencyMismatch,
+    IdempotencyInProgress,
+    ApprovalClosed,
+    TransactionMismatch,
+    ProtocolError,
+    StorageError,
+    InternalError,
+    HttpError,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::UnprocessableEntity => "unprocessable_entity",
+            ErrorCode::PolicyDenied => "policy_denied",
+            ErrorCode::KeyFrozen => "key_frozen",
+            ErrorCode::KeyRevoked => "key_revoked",
+            ErrorCode::KeyDeleted => "key_deleted",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::IdempotencyMismatch => "idempotency_mismatch",
+            ErrorCode::IdempotencyInProgress => "idempotency_in_progress",
+            ErrorCode::ApprovalClosed => "approval_closed",
+            ErrorCode::TransactionMismatch => "transaction_mismatch",
+            ErrorCode::ProtocolError => "protocol_error",
+            ErrorCode::StorageError => "storage_error",
+            ErrorCode::InternalError => "internal_error",
+            ErrorCode::HttpError => "http_error",
+        }
+    }
+
+    /// Code of an error known only by its status
+    pub fn for_status(status: Status) -> Self {
+        match status.code {
+            400 => ErrorCode::BadRequest,
+            401 => ErrorCode::Unauthorized,
+            403 => ErrorCode::Forbidden,
+            404 => ErrorCode::NotFound,
+            422 => ErrorCode::UnprocessableEntity,
+            429 => ErrorCode::RateLimited,
+            500 => ErrorCode::InternalError,
+            _ => ErrorCode::HttpError,
+        }
+    }
+}
+
+/// Body of every error response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+    pub status: u16,
+    pub request_id: String,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: Status, code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(Status::BadRequest, ErrorCode::BadRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(Status::NotFound, ErrorCode::NotFound, message)
+    }
+
+    /// A message of party two failed verification
+    pub fn protocol(message: impl Into<String>) -> Self {
+        Self::new(Status::BadRequest, ErrorCode::ProtocolError, message)
+    }
+
+    pub fn storage(message: impl Into<String>) -> Self {
+        Self::new(
+            Status::InternalServerError,
+            ErrorCode::StorageError,
+            message,
+        )
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(
+            Status::InternalServerError,
+            ErrorCode::InternalError,
+            message,
+        )
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}): {}",
+            self.code.as_str(),
+            self.status,
+            self.message
+        )
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if self.status.code >= 500 {
+            error!(
+                "Request {} to {} failed: {}",
+                request_id(request),
+                request.uri(),
+                self
+            );
+        }
+        record_error_code(request, self.code);
+        let body = ErrorBody {
+            code: self.code.as_str().to_string(),
+            message: self.message,
+            status: self.status.code,
+            request_id: request_id(request),
+        };
+        Response::build_from(Json(body).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+struct RequestId(String);
+
+/// Id of `request`, taken from its `X-Request-Id` header when it is a plausible one
+pub fn request_id(request: &Request<'_>) -> String {
+    request
+        .local_cache(|| {
+            let id = request
+                .headers()
+                .get_one(REQUEST_ID_HEADER)
+                .filter(|id| {
+                    !id.is_empty()
+                        && id.len() <= 64
+                        && id
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                })
+                .map(|id| id.to_string())
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+            RequestId(id)
+        })
+        .0
+        .clone()
+}
+
+struct RecordedErrorCode(OnceLock<ErrorCode>);
+
+fn record_error_code(request: &Request<'_>, code: ErrorCode) {
+    let _ = request
+        .local_cache(|| RecordedErrorCode(OnceLock::new()))
+        .0
+        .set(code);
+}
+
+/// Code of the error `request` was answered with, if any
+pub fn error_code(request: &Request<'_>) -> Option<ErrorCode> {
+    request
+        .local_cache(|| RecordedErrorCode(OnceLock::new()))
+        .0
+        .get()
+        .copied()
+}
+
+/// Error a request guard failed with
+struct GuardFailure(OnceLock<(ErrorCode, String)>);
+
+/// Records why a request guard of `request` fails, for the catcher to answer with, returning
+/// the status to fail with
+pub fn fail_guard(request: &Request<'_>, error: &ApiError) -> Status {
+    let _ = request
+        .local_cache(|| GuardFailure(OnceLock::new()))
+        .0
+        .set((error.code, error.message.clone()));
+    error.status
+}
+
+#[catch(default)]
+pub fn default_catcher(status: Status, request: &Request) -> ApiError {
+    if let Some((code, message)) = request
+        .local_cache(|| GuardFailure(OnceLock::new()))
+        .0
+        .get()
+    {
+        return ApiError::new(status, *code, message.clone());
+    }
+    let message = match status.code {
+        404 => format!("Unknown route '{}'.", request.uri()),
+        _ => status.reason().unwrap_or("Unknown error").to_string(),
+    };
+    ApiError::new(status, ErrorCode::for_status(status), message)
+}
+
+/// Fairing tagging every response with its request id, and turning plain text error responses
+/// into an [`ErrorBody`]
+pub struct ApiErrors;
+
+#[rocket::async_trait]
+impl Fairing for ApiErrors {
+    fn info(&self) -> Info {
+        Info {
+            name: "JSON errors and request ids",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request_id(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = request_id(request);
+        response.set_raw_header(REQUEST_ID_HEADER, id.clone());
+
+        let status = response.status();
+        if status.code < 400 || response.content_type() == Some(ContentType::JSON) {
+            return;
+        }
+        record_error_code(request, ErrorCode::for_status(status));
+        let message = response.body_mut().to_string().await.unwrap_or_default();
+        let body = ErrorBody {
+            code: ErrorCode::for_status(status).as_str().to_string(),
+            message,
+            status: status.code,
+            request_id: id,
+        };
+        let body = serde_json::to_string(&body).unwrap_or_default();
+        response.set_header(ContentType::JSON);
+        response.set_sized_body(body.len(), Cursor::new(body));
+    }
+}
@@ -0,0 +1,236 @@
+//! Verifiable backup of the party one shares to an operator escrow key
+//!
+//! The secret share of every master key is split into segments, each ElGamal encrypted under
+//! the escrow public key, with a proof that the segments add up to the secret of the public
+//! share. Anyone holding the public key can check a backup; only the escrow private key opens it,
+//! as the client does with its own backups (see `gotham-client/src/ecdsa/recover.rs`).
+//!
+//! Signing also needs the Paillier private key of party one, which the proof cannot cover. The
+//! whole private part of the master key is therefore sealed as well, with AES-256-GCM under a key
+//! agreed by Diffie-Hellman with the escrow key, and [`KeyBackup::open`] rebuilds the master key.
+//! Only the escrow private key can check the sealed part: [`KeyBackup::verify`] does not.
+
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, Nonce};
+use chrono::{DateTime, Utc};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use two_party_ecdsa::centipede::juggling::proof_system::{Helgamalsegmented, Proof};
+use two_party_ecdsa::curv::elliptic::curves::secp256_k1::{FE, GE};
+use two_party_ecdsa::curv::elliptic::curves::traits::{ECPoint, ECScalar};
+use two_party_ecdsa::curv::BigInt;
+use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey1, Party1Public};
+use two_party_ecdsa::party_one::Party1Private;
+
+use crate::admin::{Admin, AdminError};
+
+/// Bits per segment, as in the client backups
+pub const SEGMENT_SIZE: usize = 8;
+pub const NUM_SEGMENTS: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EscrowError {
+    #[error("cannot access {0}: {1}")]
+    Io(String, std::io::Error),
+    #[error("{0} is invalid: {1}")]
+    Invalid(String, serde_json::Error),
+    #[error("the backup was made for another escrow key")]
+    OtherEscrowKey,
+    #[error("the private share of key {0} cannot be opened with this escrow key")]
+    Sealed(String),
+    #[error(transparent)]
+    Admin(#[from] AdminError),
+}
+
+/// Private part of a master key, encrypted under a key shared with the escrow key
+#[derive(Serialize, Deserialize)]
+pub struct SealedPrivate {
+    /// Ephemeral public key of the Diffie-Hellman agreement with the escrow key
+    pub ephemeral_key: GE,
+    /// Hex encoded AES-256-GCM nonce
+    pub nonce: String,
+    /// Hex encoded JSON of the private part, bound to the customer and key ids
+    pub ciphertext: String,
+}
+
+/// Context of the sealing keys, so that they are not used for anything else
+const SEALING_INFO: &[u8] = b"gotham escrow backup sealing key";
+
+/// AES key derived with HKDF-SHA256 from `shared`, the product of the escrow key and the
+/// ephemeral key, salted with the ephemeral key
+fn sealing_cipher(shared: &GE, ephemeral_key: &GE) -> Aes256Gcm {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(
+        Some(&ephemeral_key.pk_to_key_slice()),
+        &shared.pk_to_key_slice(),
+    )
+    .expand(SEALING_INFO, &mut key)
+    .expect("32 bytes is a valid HKDF-SHA256 output length");
+    Aes256Gcm::new_from_slice(&key).expect("32 byte keys are AES-256 keys")
+}
+
+fn sealing_aad(customer_id: &str, id: &str) -> Vec<u8> {
+    format!("{}/{}", customer_id, id).into_bytes()
+}
+
+/// Encrypted secret share of a master key, with everything else needed to rebuild it
+#[derive(Serialize, Deserialize)]
+pub struct KeyBackup {
+    pub customer_id: String,
+    pub id: String,
+    pub public: Party1Public,
+    pub chain_code: BigInt,
+    pub encryptions: Helgamalsegmented,
+    pub proof: Proof,
+    /// Secret share, Paillier private key and randomness of party one
+    pub private: SealedPrivate,
+}
+
+impl KeyBackup {
+    pub fn new(customer_id: &str, id: &str, master_key: &MasterKey1, escrow_key: &GE) -> Self {
+        let g: GE = ECPoint::generator();
+        let (segments, encryptions) =
+            master_key
+                .private
+                .to_encrypted_segment(&SEGMENT_SIZE, NUM_SEGMENTS, escrow_key, &g);
+        let proof = Proof::prove(&segments, &encryptions, &g, escrow_key, &SEGMENT_SIZE);
+
+        let ephemeral_secret: FE = ECScalar::new_random();
+        let ephemeral_key = g * ephemeral_secret;
+        let nonce = Aes256Gcm::generate_nonce(OsRng);
+        let private = serde_json::to_vec(&master_key.private).expect("private shares serialize");
+        let ciphertext = sealing_cipher(&(*escrow_key * ephemeral_secret), &ephemeral_key)
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &private,
+                    aad: &sealing_aad(customer_id, id),
+                },
+            )
+            .expect("AES-GCM encrypts any message");
+        KeyBackup {
+            customer_id: customer_id.to_string(),
+            id: id.to_string(),
+            public: master_key.public.clone(),
+            chain_code: master_key.chain_code.clone(),
+            encryptions,
+            proof,
+            private: SealedPrivate {
+                ephemeral_key,
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            },
+        }
+    }
+
+    /// Rebuilds the master key with the escrow private key
+    pub fn open(&self, escrow_secret: &FE) -> Result<MasterKey1, EscrowError> {
+        let sealed = || EscrowError::Sealed(self.id.clone());
+        let nonce = hex::decode(&self.private.nonce).map_err(|_| sealed())?;
+        let ciphertext = hex::decode(&self.private.ciphertext).map_err(|_| sealed())?;
+        if nonce.len() != 12 {
+            return Err(sealed());
+        }
+        let private = sealing_cipher(
+            &(self.private.ephemeral_key * *escrow_secret),
+            &self.private.ephemeral_key,
+        )
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: &sealing_aad(&self.customer_id, &self.id),
+            },
+        )
+        .map_err(|_| sealed())?;
+        let private: Party1Private = serde_json::from_slice(&private).map_err(|_| sealed())?;
+        Ok(MasterKey1 {
+            public: self.public.clone(),
+            private,
+            chain_code: self.chain_code.clone(),
+        })
+    }
+
+    /// Whether the encryptions hold the secret of the public share of party one.
+    ///
+    /// The sealed private part, the Paillier private key included, is not checked: it can only
+    /// be decrypted, and its integrity checked, with the escrow private key by [`KeyBackup::open`].
+    pub fn verify(&self, escrow_key: &GE) -> bool {
+        let g: GE = ECPoint::generator();
+        self.proof
+            .verify(
+                &self.encryptions,
+                &g,
+                escrow_key,
+                &self.public.p1,
+                &SEGMENT_SIZE,
+            )
+            .is_ok()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EscrowBackup {
+    pub escrow_key: GE,
+    pub created_at: DateTime<Utc>,
+    pub keys: Vec<KeyBackup>,
+}
+
+impl EscrowBackup {
+    /// Backup of every key of the store
+    pub async fn create(admin: &Admin, escrow_key: &GE) -> Result<Self, EscrowError> {
+        let mut keys = Vec::new();
+        for (customer_id, id) in admin.keys().await? {
+            let master_key = admin.master_key_of(&customer_id, &id).await?;
+            keys.push(KeyBackup::new(&customer_id, &id, &master_key, escrow_key));
+        }
+        Ok(EscrowBackup {
+            escrow_key: *escrow_key,
+            created_at: Utc::now(),
+            keys,
+        })
+    }
+
+    /// `(customer id, key id)` of the keys whose backup does not verify
+    pub fn verify(&self, escrow_key: &GE) -> Result<Vec<(String, String)>, EscrowError> {
+        if self.escrow_key != *escrow_key {
+            return Err(EscrowError::OtherEscrowKey);
+        }
+        Ok(self
+            .keys
+            .iter()
+            .filter(|key| !key.verify(escrow_key))
+            .map(|key| (key.customer_id.clone(), key.id.clone()))
+            .collect())
+    }
+}
+
+/// Reads a JSON file, such as the escrow public key or a backup
+pub fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, EscrowError> {
+    let name = path.display().to_string();
+    let content = fs::read_to_string(path).map_err(|e| EscrowError::Io(name.clone(), e))?;
+    serde_json::from_str(&content).map_err(|e| EscrowError::Invalid(name, e))
+}
+
+/// Writes a JSON file readable by its owner only, since backups hold sealed secret shares
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), EscrowError> {
+    let content = serde_json::to_string(value).expect("backups serialize");
+    let io_error = |e| EscrowError::Io(path.display().to_string(), e);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).map_err(io_error)?;
+    // an existing file keeps its mode when opened
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+        .map_err(io_error)?;
+    file.write_all(content.as_bytes()).map_err(io_error)
+}
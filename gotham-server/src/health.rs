@@ -0,0 +1,102 @@
+//! Liveness and readiness probes for orchestrators
+//!
+//! `/health/live` answers as long as the server serves requests. `/health/ready` answers `200`
+//! only when the store can be written and read back, the settings, policy and JWKS files are
+//! still valid, and tokens can be verified; otherwise `503`, with the failing checks.
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{get, State};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::auth::Authorizer;
+use crate::policy::PolicyEngine;
+use crate::public_gotham::get_settings_as_map;
+use crate::storage::{schema, Store};
+
+const PROBE_NAME: &str = "health_probe";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<E: std::fmt::Display> From<Result<(), E>> for Check {
+    fn from(result: Result<(), E>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Health {
+    /// `live`, `ready` or `not_ready`
+    pub status: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<String, Check>,
+}
+
+#[get("/health/live")]
+pub fn live() -> Json<Health> {
+    Json(Health {
+        status: "live".to_string(),
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Writes, reads back and deletes a probe entry
+async fn check_store(store: &dyn Store) -> Result<(), String> {
+    let key = schema::metadata_key(PROBE_NAME);
+    let value = uuid::Uuid::new_v4().to_string();
+    store
+        .put(&key, value.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let read = store.get(&key).await.map_err(|e| e.to_string())?;
+    store.delete(&key).await.map_err(|e| e.to_string())?;
+    if read.as_deref() != Some(value.as_bytes()) {
+        return Err("the probe entry was not read back".to_string());
+    }
+    Ok(())
+}
+
+/// Loads the settings and the files they name again, as a restart would
+fn check_settings() -> Result<(), String> {
+    let settings = get_settings_as_map().map_err(|e| e.to_string())?;
+    PolicyEngine::from_settings(&settings).map_err(|e| e.to_string())?;
+    Authorizer::from_settings(&settings).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[get("/health/ready")]
+pub async fn ready(
+    store: &State<Arc<dyn Store>>,
+    authorizer: &State<Authorizer>,
+) -> (Status, Json<Health>) {
+    let checks = BTreeMap::from([
+        (
+            "storage".to_string(),
+            check_store(store.inner().as_ref()).await.into(),
+        ),
+        ("settings".to_string(), check_settings().into()),
+        ("auth".to_string(), authorizer.check_keys().into()),
+    ]);
+    let (status, label) = if checks.values().all(|check: &Check| check.ok) {
+        (Status::Ok, "ready")
+    } else {
+        (Status::ServiceUnavailable, "not_ready")
+    };
+    (
+        status,
+        Json(Health {
+            status: label.to_string(),
+            checks,
+        }),
+    )
+}
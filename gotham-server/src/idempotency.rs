@@ -0,0 +1,597 @@
+//! Idempotency keys of protocol requests
+//!
+//! A `POST` to an `/ecdsa/` route sent with an `Idempotency-Key` header must also carry the
+//! SHA-256 digest of its body in a `Content-Digest` header (RFC 9530). The response is stored
+//! under the customer and key for `idempotency_window` seconds, and a retry with the same key,
+//! route and digest is answered with it, with an `Idempotent-Replayed: true` header, without
+//! running the route again. A request reusing a key for another route or body is rejected with
+//! `422 idempotency_mismatch`. A retry sent while the first request is still served waits for
+//! its response, and is only rejected with `409 idempotency_in_progress` when that takes longer
+//! than [`IN_FLIGHT_WAIT`].
+//!
+//! Routes read their body with [`DigestedJson`], which answers a body that does not match its
+//! declared digest with `422 idempotency_mismatch`, and a retry is answered with the stored
+//! response only once its body is checked the same way. Server errors and rate limited requests
+//! are not stored.
+//!
+//! The requests of [WebSocket sessions](crate::websocket) carry their key in the frame, and their
+//! digest is taken over their body as the server serializes it again.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use rocket::data::{self, FromData, Limits};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{ContentType, Method, Status};
+use rocket::request::Outcome;
+use rocket::response::{self, Responder, Response};
+use rocket::{post, Data, Request};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::auth::Customer;
+use crate::error::{self, ApiError, ErrorCode};
+use crate::storage::schema::{self, Record, RecordKey};
+use crate::storage::{Store, StoreError};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+pub const CONTENT_DIGEST_HEADER: &str = "Content-Digest";
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+/// Table of the stored responses
+pub const RESPONSES_TABLE: &str = "IdempotentResponse";
+/// Longest accepted idempotency key
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// Route requests that are not run are sent to
+const ANSWERED_PATH: &str = "/idempotent";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(600);
+/// Longest a retry waits for the request in flight with its key
+pub const IN_FLIGHT_WAIT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("invalid setting idempotency_window: {0}")]
+    Invalid(String),
+}
+
+/// Response stored for an idempotency key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoredResponse {
+    /// Digest of the method, route and body of the request
+    pub fingerprint: String,
+    /// Path of the request, so that the responses of a deleted key can be found
+    #[serde(default)]
+    pub path: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl StoredResponse {
+    /// Whether the response answers a request of key `id`, or names it, as the first keygen
+    /// round does
+    pub fn is_of_key(&self, id: &str) -> bool {
+        self.path.split('/').any(|segment| segment == id)
+            || (!id.is_empty()
+                && self
+                    .body
+                    .windows(id.len())
+                    .any(|window| window == id.as_bytes()))
+    }
+}
+
+/// Key, customer and fingerprint of a request the route runs for
+struct Pending(Option<(String, String, String)>);
+
+/// Answer of a request the route does not run for
+pub(crate) enum Answer {
+    Replay(StoredResponse),
+    Reject(Status, ErrorCode, String),
+}
+
+struct Answered(Answer);
+
+/// Set when the response of the request must not be stored
+struct Unstored(OnceLock<()>);
+
+fn skip_storing(request: &Request<'_>) {
+    let _ = request.local_cache(|| Unstored(OnceLock::new())).0.set(());
+}
+
+fn is_stored(request: &Request<'_>) -> bool {
+    request
+        .local_cache(|| Unstored(OnceLock::new()))
+        .0
+        .get()
+        .is_none()
+}
+
+pub struct Idempotency {
+    /// `None` when idempotency keys are ignored
+    window: Option<Duration>,
+    store: Arc<dyn Store>,
+    /// Requests being served, notifying the retries waiting for them when they are released
+    in_flight: Mutex<HashMap<(String, String), Arc<Notify>>>,
+}
+
+impl Idempotency {
+    pub fn new(window: Option<Duration>, store: Arc<dyn Store>) -> Self {
+        Idempotency {
+            window,
+            store,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Window set by `idempotency_window`, in seconds. An empty or zero window ignores
+    /// idempotency keys.
+    pub fn from_settings(
+        settings: &HashMap<String, String>,
+        store: Arc<dyn Store>,
+    ) -> Result<Self, IdempotencyError> {
+        let window = match settings.get("idempotency_window").map(|value| value.trim()) {
+            Some(value) if !value.is_empty() => value
+                .parse::<u64>()
+                .map_err(|e| IdempotencyError::Invalid(e.to_string()))?,
+            _ => 0,
+        };
+        Ok(Self::new(
+            Some(Duration::from_secs(window)).filter(|window| !window.is_zero()),
+            store,
+        ))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.window.is_some()
+    }
+
+    fn is_expired(&self, written_at: Option<i64>, now: DateTime<Utc>) -> bool {
+        let window = match self.window {
+            Some(window) => window,
+            None => return true,
+        };
+        match written_at {
+            Some(written_at) => {
+                now.timestamp().saturating_sub(written_at)
+                    > i64::try_from(window.as_secs()).unwrap_or(i64::MAX)
+            }
+            None => true,
+        }
+    }
+
+    /// Response stored for `key` of `customer_id`, if it is still in the window
+    pub async fn lookup(
+        &self,
+        customer_id: &str,
+        key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<StoredResponse>, StoreError> {
+        let record_key = RecordKey::new(RESPONSES_TABLE, customer_id, key).encode();
+        let value = match self.store.get(&record_key).await? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let record: Record<StoredResponse> = serde_json::from_slice(&value)
+            .map_err(|e| StoreError::Backend(format!("invalid stored response: {}", e)))?;
+        if self.is_expired(record.written_at, now) {
+            return Ok(None);
+        }
+        Ok(Some(record.value))
+    }
+
+    pub async fn save(
+        &self,
+        customer_id: &str,
+        key: &str,
+        response: StoredResponse,
+    ) -> Result<(), StoreError> {
+        let record_key = RecordKey::new(RESPONSES_TABLE, customer_id, key).encode();
+        let value = serde_json::to_vec(&Record::new(RESPONSES_TABLE, response))
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        self.store.put(&record_key, &value).await
+    }
+
+    /// Deletes the responses out of the window, returning how many were
+    pub async fn sweep(&self, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        if !self.is_enabled() {
+            return Ok(0);
+        }
+        let mut swept = 0;
+        for (key, value) in self
+            .store
+            .scan(&schema::table_prefix(RESPONSES_TABLE))
+            .await?
+        {
+            let written_at = serde_json::from_slice::<Record<serde::de::IgnoredAny>>(&value)
+                .ok()
+                .and_then(|record| record.written_at);
+            if self.is_expired(written_at, now) {
+                self.store.delete(&key).await?;
+                swept += 1;
+            }
+        }
+        Ok(swept)
+    }
+
+    /// Sweeps the stored responses periodically, until the runtime shuts down
+    pub fn spawn_sweeper(self: Arc<Self>) {
+        if !self.is_enabled() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                match self.sweep(Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(swept) => info!("Deleted {} expired idempotent responses", swept),
+                    Err(e) => error!("Cannot delete expired idempotent responses: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Whether the request may run, or how it is answered instead. A request that may run holds
+    /// its key until it is [released](Self::release); a retry of it waits until then.
+    pub(crate) async fn admit(
+        &self,
+        customer_id: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<(), Answer> {
+        let in_flight_key = (customer_id.to_string(), key.to_string());
+        loop {
+            let notify = match self.in_flight.lock().unwrap().entry(in_flight_key.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(Arc::new(Notify::new()));
+                    break;
+                }
+                Entry::Occupied(entry) => entry.get().clone(),
+            };
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            // released before the notification was enabled
+            let released = !matches!(
+                self.in_flight.lock().unwrap().get(&in_flight_key),
+                Some(current) if Arc::ptr_eq(current, &notify)
+            );
+            if !released
+                && tokio::time::timeout(IN_FLIGHT_WAIT, notified)
+                    .await
+                    .is_err()
+            {
+                return Err(Answer::Reject(
+                    Status::Conflict,
+                    ErrorCode::IdempotencyInProgress,
+                    format!("A request with idempotency key {} is in progress", key),
+                ));
+            }
+        }
+        let answer = match self.lookup(customer_id, key, Utc::now()).await {
+            Ok(None) => return Ok(()),
+            Ok(Some(response)) if response.fingerprint == fingerprint => Answer::Replay(response),
+            Ok(Some(_)) => Answer::Reject(
+                Status::UnprocessableEntity,
+                ErrorCode::IdempotencyMismatch,
+                format!("Idempotency key {} was used for another route or body", key),
+            ),
+            Err(e) => Answer::Reject(
+                Status::InternalServerError,
+                ErrorCode::StorageError,
+                format!(
+                    "Failed to read the response of idempotency key {}: {}",
+                    key, e
+                ),
+            ),
+        };
+        self.release(customer_id, key);
+        Err(answer)
+    }
+
+    pub(crate) fn release(&self, customer_id: &str, key: &str) {
+        let released = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .remove(&(customer_id.to_string(), key.to_string()));
+        if let Some(notify) = released {
+            notify.notify_waiters();
+        }
+    }
+}
+
+pub(crate) fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// `Content-Digest` header value of `body`
+pub fn content_digest(body: &[u8]) -> String {
+    format!("sha-256=:{}:", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// Reads the body of `request`, up to the `json` limit
+async fn read_body(request: &Request<'_>, data: Data<'_>) -> Result<Vec<u8>, ApiError> {
+    let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+    match data.open(limit).into_bytes().await {
+        Ok(body) if body.is_complete() => Ok(body.into_inner()),
+        Ok(_) => Err(ApiError::new(
+            Status::PayloadTooLarge,
+            ErrorCode::HttpError,
+            format!("The request body is larger than {}", limit),
+        )),
+        Err(e) => Err(ApiError::bad_request(format!(
+            "Cannot read the request body: {}",
+            e
+        ))),
+    }
+}
+
+fn digest_mismatch() -> ApiError {
+    ApiError::new(
+        Status::UnprocessableEntity,
+        ErrorCode::IdempotencyMismatch,
+        format!(
+            "The request body does not match its {}",
+            CONTENT_DIGEST_HEADER
+        ),
+    )
+}
+
+/// Server errors and rate limited requests may succeed when retried, so their response is not
+/// stored
+pub(crate) fn is_stored_status(status: Status) -> bool {
+    status.code < 500 && status != Status::TooManyRequests
+}
+
+/// Digest binding a response to the method, route and declared body digest of its request
+pub(crate) fn fingerprint(method: Method, uri: &str, content_digest: &str) -> String {
+    let mut hasher = Sha256::new();
+    for part in [method.as_str(), uri, content_digest] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Fairing answering retries of protocol requests with their stored response
+pub struct IdempotencyKeys(pub Arc<Idempotency>);
+
+impl IdempotencyKeys {
+    fn answer(request: &mut Request<'_>, answer: Answer) {
+        request.local_cache(|| Answered(answer));
+        request.set_uri(Origin::parse(ANSWERED_PATH).unwrap());
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for IdempotencyKeys {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency keys",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !self.0.is_enabled()
+            || request.method() != Method::Post
+            || !request.uri().path().starts_with("/ecdsa/")
+        {
+            return;
+        }
+        let key = match request.headers().get_one(IDEMPOTENCY_KEY_HEADER) {
+            Some(key) => key.to_string(),
+            None => return,
+        };
+        // unauthenticated requests are rejected by their route
+        let customer_id = match request.guard::<Customer>().await {
+            Outcome::Success(customer) => customer.id,
+            _ => return,
+        };
+        if !is_valid_key(&key) {
+            return Self::answer(
+                request,
+                Answer::Reject(
+                    Status::BadRequest,
+                    ErrorCode::BadRequest,
+                    format!(
+                        "{} must be 1 to {} printable ASCII characters",
+                        IDEMPOTENCY_KEY_HEADER, MAX_KEY_LENGTH
+                    ),
+                ),
+            );
+        }
+        let content_digest = match request.headers().get_one(CONTENT_DIGEST_HEADER) {
+            Some(digest) if digest.starts_with("sha-256=") => digest.to_string(),
+            _ => {
+                return Self::answer(
+                    request,
+                    Answer::Reject(
+                        Status::BadRequest,
+                        ErrorCode::BadRequest,
+                        format!(
+                            "Requests with an {} need a sha-256 {} header",
+                            IDEMPOTENCY_KEY_HEADER, CONTENT_DIGEST_HEADER
+                        ),
+                    ),
+                )
+            }
+        };
+
+        let fingerprint = fingerprint(
+            request.method(),
+            &request.uri().to_string(),
+            &content_digest,
+        );
+        match self.0.admit(&customer_id, &key, &fingerprint).await {
+            Ok(()) => {
+                request.local_cache(|| Pending(Some((customer_id, key, fingerprint))));
+            }
+            Err(answer) => Self::answer(request, answer),
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let (customer_id, key, fingerprint) = match &request.local_cache(|| Pending(None)).0 {
+            Some(pending) => pending.clone(),
+            None => return,
+        };
+        let status = response.status();
+        if is_stored_status(status) && is_stored(request) {
+            let body = match response.body_mut().to_bytes().await {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("Cannot read the response of {}: {}", request.uri(), e);
+                    self.0.release(&customer_id, &key);
+                    return;
+                }
+            };
+            let stored = StoredResponse {
+                fingerprint,
+                path: request.uri().path().to_string(),
+                status: status.code,
+                content_type: response.content_type().map(|c| c.to_string()),
+                body: body.clone(),
+            };
+            if let Err(e) = self.0.save(&customer_id, &key, stored).await {
+                error!(
+                    "Cannot store the response of idempotency key {}: {}",
+                    key, e
+                );
+            }
+            response.set_sized_body(body.len(), Cursor::new(body));
+        }
+        self.0.release(&customer_id, &key);
+    }
+}
+
+/// JSON body whose digest is checked against the `Content-Digest` header of a request sent with
+/// an idempotency key
+pub struct DigestedJson<T>(pub T);
+
+impl<T> DigestedJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for DigestedJson<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned> FromData<'r> for DigestedJson<T> {
+    type Error = ApiError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let body = match read_body(request, data).await {
+            Ok(body) => body,
+            Err(e) => return data::Outcome::Failure((error::fail_guard(request, &e), e)),
+        };
+        if request.local_cache(|| Pending(None)).0.is_some()
+            && request.headers().get_one(CONTENT_DIGEST_HEADER)
+                != Some(content_digest(&body).as_str())
+        {
+            // the response answers another body than the one the key is bound to
+            skip_storing(request);
+            let e = digest_mismatch();
+            return data::Outcome::Failure((error::fail_guard(request, &e), e));
+        }
+        match serde_json::from_slice(&body) {
+            Ok(value) => data::Outcome::Success(DigestedJson(value)),
+            Err(e) => {
+                let e = if e.is_data() {
+                    ApiError::new(
+                        Status::UnprocessableEntity,
+                        ErrorCode::UnprocessableEntity,
+                        e.to_string(),
+                    )
+                } else {
+                    ApiError::bad_request(e.to_string())
+                };
+                data::Outcome::Failure((error::fail_guard(request, &e), e))
+            }
+        }
+    }
+}
+
+/// Digest of the body of a request answered without running its route
+pub struct BodyDigest(String);
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for BodyDigest {
+    type Error = ApiError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match read_body(request, data).await {
+            Ok(body) => data::Outcome::Success(BodyDigest(content_digest(&body))),
+            Err(e) => data::Outcome::Failure((error::fail_guard(request, &e), e)),
+        }
+    }
+}
+
+/// Stored response or rejection of a request sent with an idempotency key
+pub struct IdempotentAnswer {
+    /// Digest of the body of the request
+    content_digest: String,
+}
+
+impl<'r> Responder<'r, 'static> for IdempotentAnswer {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let answer = request.local_cache(|| {
+            Answered(Answer::Reject(
+                Status::NotFound,
+                ErrorCode::NotFound,
+                "No request was answered".to_string(),
+            ))
+        });
+        match &answer.0 {
+            Answer::Replay(_)
+                if request.headers().get_one(CONTENT_DIGEST_HEADER)
+                    != Some(self.content_digest.as_str()) =>
+            {
+                digest_mismatch().respond_to(request)
+            }
+            Answer::Replay(stored) => {
+                let mut response = Response::build();
+                response
+                    .status(Status::new(stored.status))
+                    .raw_header(REPLAYED_HEADER, "true")
+                    .sized_body(stored.body.len(), Cursor::new(stored.body.clone()));
+                if let Some(content_type) = stored
+                    .content_type
+                    .as_deref()
+                    .and_then(ContentType::parse_flexible)
+                {
+                    response.header(content_type);
+                }
+                response.ok()
+            }
+            Answer::Reject(status, code, message) => {
+                ApiError::new(*status, *code, message.clone()).respond_to(request)
+            }
+        }
+    }
+}
+
+#[post("/idempotent", data = "<body>")]
+pub fn idempotent(body: BodyDigest) -> IdempotentAnswer {
+    IdempotentAnswer {
+        content_digest: body.0,
+    }
+}
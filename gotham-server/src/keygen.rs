@@ -0,0 +1,393 @@
+//! Keygen and chain code rounds, and bookkeeping of the active key of every customer
+//!
+//! Party one keeps the state of a keygen between its rounds in the tables of [`KeygenStruct`],
+//! each round consuming the record of the previous one, and stores the master key once the
+//! chain code is agreed on. The rounds themselves are functions of the state left by the previous
+//! one, shared with the WebSocket sessions, which keep that state in memory.
+//!
+//! A customer has a single active key: the last one whose party one master key was stored.
+//! Keygen is refused while it exists, unless the first message is sent with `?new_key=true`.
+
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::sync::Arc;
+
+use two_party_ecdsa::curv::cryptographic_primitives::proofs::sigma_dlog::DLogProof;
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm as dh;
+use two_party_ecdsa::curv::elliptic::curves::secp256_k1::GE;
+use two_party_ecdsa::curv::BigInt;
+use two_party_ecdsa::kms::chain_code::two_party::party1::ChainCode1;
+use two_party_ecdsa::kms::ecdsa::two_party::{party1, MasterKey1};
+use two_party_ecdsa::party_one::Value;
+use two_party_ecdsa::{party_one, party_two};
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::auth::Customer;
+use crate::db::{db_index, get_value, insert_value, take_value, SharedDb};
+use crate::error::{ApiError, ErrorCode};
+use crate::idempotency::DigestedJson;
+use crate::storage::Store;
+
+/// Id under which the active key record of a customer is stored
+pub const ACTIVE_KEY_ID: &str = "active";
+
+#[derive(Debug)]
+pub enum KeygenStruct {
+    ActiveKey,
+    /// State of a keygen after each of its rounds
+    First,
+    Second,
+    Third,
+    Fourth,
+    ChainCode,
+}
+
+impl MPCStruct for KeygenStruct {
+    fn to_string(&self) -> String {
+        match self {
+            KeygenStruct::ActiveKey => "ActiveKey".to_string(),
+            KeygenStruct::First => "KeygenFirst".to_string(),
+            KeygenStruct::Second => "KeygenSecond".to_string(),
+            KeygenStruct::Third => "KeygenThird".to_string(),
+            KeygenStruct::Fourth => "KeygenFourth".to_string(),
+            KeygenStruct::ChainCode => "KeygenChainCode".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ActiveKey {
+    pub id: String,
+    /// Unix time at which the key became active
+    pub activated_at: i64,
+}
+
+/// Commitment opening and key pair of party one, kept until the second round
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeygenFirst {
+    pub comm_witness: party_one::CommWitness,
+    pub ec_key_pair: party_one::EcKeyPair,
+}
+
+/// Party one shares of a keygen, once party two sent its public share
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeygenShares {
+    pub public_share: GE,
+    pub party2_public: GE,
+    pub paillier_key_pair: party_one::PaillierKeyPair,
+    pub party_one_private: party_one::Party1Private,
+}
+
+/// PDL decommitment of party one and the first PDL message of party two
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeygenThird {
+    pub shares: KeygenShares,
+    pub party2_pdl_first_message: party_two::PDLFirstMessage,
+    pub pdl_decommit: party_one::PDLdecommit,
+    pub alpha: BigInt,
+}
+
+/// Commitment opening and key pair of party one for the chain code
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeygenChainCode {
+    pub shares: KeygenShares,
+    pub comm_witness: dh::CommWitness,
+    pub ec_key_pair: dh::EcKeyPair,
+}
+
+#[typetag::serde]
+impl Value for ActiveKey {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl Value for KeygenFirst {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl Value for KeygenShares {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl Value for KeygenThird {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl Value for KeygenChainCode {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Refuses a keygen to a customer with an active key, unless they ask for a new one
+pub async fn ensure_keygen_allowed(
+    db: &dyn Db,
+    customer: &Customer,
+    new_key: bool,
+) -> Result<(), ApiError> {
+    if !new_key
+        && db
+            .has_active_share(&customer.id)
+            .await
+            .map_err(ApiError::storage)?
+    {
+        return Err(ApiError::new(
+            Status::Forbidden,
+            ErrorCode::Forbidden,
+            "The customer has an active key, ask for a new one with new_key=true",
+        ));
+    }
+    Ok(())
+}
+
+/// Commits to the share of party one
+pub fn first_round() -> (party_one::KeyGenFirstMsg, KeygenFirst) {
+    let (key_gen_first_message, comm_witness, ec_key_pair) = MasterKey1::key_gen_first_message();
+    (
+        key_gen_first_message,
+        KeygenFirst {
+            comm_witness,
+            ec_key_pair,
+        },
+    )
+}
+
+/// Opens the commitment of party one once party two sent its public share
+pub fn second_round(
+    first: KeygenFirst,
+    d_log_proof: &DLogProof,
+) -> (party1::KeyGenParty1Message2, KeygenShares) {
+    let public_share = first.comm_witness.public_share;
+    let (key_gen_second_message, paillier_key_pair, party_one_private) =
+        MasterKey1::key_gen_second_message(first.comm_witness, &first.ec_key_pair, d_log_proof);
+    (
+        key_gen_second_message,
+        KeygenShares {
+            public_share,
+            party2_public: d_log_proof.pk,
+            paillier_key_pair,
+            party_one_private,
+        },
+    )
+}
+
+pub fn third_round(
+    shares: KeygenShares,
+    party2_pdl_first_message: party_two::PDLFirstMessage,
+) -> (party_one::PDLFirstMessage, KeygenThird) {
+    let (pdl_first_message, pdl_decommit, alpha) =
+        MasterKey1::key_gen_third_message(&party2_pdl_first_message, &shares.party_one_private);
+    (
+        pdl_first_message,
+        KeygenThird {
+            shares,
+            party2_pdl_first_message,
+            pdl_decommit,
+            alpha,
+        },
+    )
+}
+
+/// Verifies the PDL proof of party two, which must hold before the chain code is agreed on
+pub fn fourth_round(
+    id: &str,
+    third: KeygenThird,
+    party2_pdl_second_message: &party_two::PDLSecondMessage,
+) -> Result<(party_one::PDLSecondMessage, KeygenShares), ApiError> {
+    let pdl_second_message = MasterKey1::key_gen_fourth_message(
+        &third.party2_pdl_first_message,
+        party2_pdl_second_message,
+        third.shares.party_one_private.clone(),
+        third.pdl_decommit,
+        third.alpha,
+    )
+    .map_err(|_| ApiError::protocol(format!("Keygen of key {} failed: invalid PDL proof", id)))?;
+    Ok((pdl_second_message, third.shares))
+}
+
+pub fn chain_code_first_round(shares: KeygenShares) -> (dh::Party1FirstMessage, KeygenChainCode) {
+    let (chain_code_first_message, comm_witness, ec_key_pair) =
+        ChainCode1::chain_code_first_message();
+    (
+        chain_code_first_message,
+        KeygenChainCode {
+            shares,
+            comm_witness,
+            ec_key_pair,
+        },
+    )
+}
+
+/// Agrees on the chain code, completing the master key of party one
+pub fn chain_code_second_round(
+    chain_code: KeygenChainCode,
+    d_log_proof: &DLogProof,
+) -> (dh::Party1SecondMessage, MasterKey1) {
+    let chain_code_second_message =
+        ChainCode1::chain_code_second_message(chain_code.comm_witness, d_log_proof);
+    let shares = chain_code.shares;
+    let master_key = MasterKey1::set_master_key(
+        &ChainCode1::compute_chain_code(&chain_code.ec_key_pair, &d_log_proof.pk).chain_code,
+        shares.party_one_private,
+        &shares.public_share,
+        &shares.party2_public,
+        shares.paillier_key_pair,
+    );
+    (chain_code_second_message, master_key)
+}
+
+/// Starts a keygen under a new id. A customer with an active key may only generate another one
+/// when asking for a new key.
+#[post("/ecdsa/keygen/first?<new_key>", format = "json")]
+pub async fn keygen_first(
+    state: &State<SharedDb>,
+    customer: Customer,
+    new_key: Option<bool>,
+) -> Result<Json<(String, party_one::KeyGenFirstMsg)>, ApiError> {
+    let db = state.lock().await;
+    ensure_keygen_allowed(db.as_ref(), &customer, new_key.unwrap_or(false)).await?;
+    let id = uuid::Uuid::new_v4().to_string();
+    let (key_gen_first_message, first) = first_round();
+    insert_value(
+        db.as_ref(),
+        &db_index(&customer, &id),
+        &KeygenStruct::First,
+        &first,
+    )
+    .await?;
+    Ok(Json((id, key_gen_first_message)))
+}
+
+#[post("/ecdsa/keygen/<id>/second", format = "json", data = "<d_log_proof>")]
+pub async fn keygen_second(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    d_log_proof: DigestedJson<DLogProof>,
+) -> Result<Json<party1::KeyGenParty1Message2>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let first: KeygenFirst =
+        take_value(db.as_ref(), store.as_ref(), &key, &KeygenStruct::First).await?;
+    let (key_gen_second_message, shares) = second_round(first, &d_log_proof);
+    insert_value(db.as_ref(), &key, &KeygenStruct::Second, &shares).await?;
+    Ok(Json(key_gen_second_message))
+}
+
+#[post(
+    "/ecdsa/keygen/<id>/third",
+    format = "json",
+    data = "<party2_pdl_first_message>"
+)]
+pub async fn keygen_third(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    party2_pdl_first_message: DigestedJson<party_two::PDLFirstMessage>,
+) -> Result<Json<party_one::PDLFirstMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let shares: KeygenShares =
+        take_value(db.as_ref(), store.as_ref(), &key, &KeygenStruct::Second).await?;
+    let (pdl_first_message, third) = third_round(shares, party2_pdl_first_message.into_inner());
+    insert_value(db.as_ref(), &key, &KeygenStruct::Third, &third).await?;
+    Ok(Json(pdl_first_message))
+}
+
+#[post(
+    "/ecdsa/keygen/<id>/fourth",
+    format = "json",
+    data = "<party2_pdl_second_message>"
+)]
+pub async fn keygen_fourth(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    party2_pdl_second_message: DigestedJson<party_two::PDLSecondMessage>,
+) -> Result<Json<party_one::PDLSecondMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let third: KeygenThird =
+        take_value(db.as_ref(), store.as_ref(), &key, &KeygenStruct::Third).await?;
+    let (pdl_second_message, shares) = fourth_round(&id, third, &party2_pdl_second_message)?;
+    insert_value(db.as_ref(), &key, &KeygenStruct::Fourth, &shares).await?;
+    Ok(Json(pdl_second_message))
+}
+
+#[post("/ecdsa/keygen/<id>/chaincode/first", format = "json")]
+pub async fn chain_code_first(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+) -> Result<Json<dh::Party1FirstMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let shares: KeygenShares =
+        take_value(db.as_ref(), store.as_ref(), &key, &KeygenStruct::Fourth).await?;
+    let (chain_code_first_message, chain_code) = chain_code_first_round(shares);
+    insert_value(db.as_ref(), &key, &KeygenStruct::ChainCode, &chain_code).await?;
+    Ok(Json(chain_code_first_message))
+}
+
+/// Last keygen round, storing the master key, which becomes the active key of the customer
+#[post(
+    "/ecdsa/keygen/<id>/chaincode/second",
+    format = "json",
+    data = "<d_log_proof>"
+)]
+pub async fn chain_code_second(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    d_log_proof: DigestedJson<DLogProof>,
+) -> Result<Json<dh::Party1SecondMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let chain_code: KeygenChainCode =
+        take_value(db.as_ref(), store.as_ref(), &key, &KeygenStruct::ChainCode).await?;
+    let (chain_code_second_message, master_key) = chain_code_second_round(chain_code, &d_log_proof);
+    insert_value(
+        db.as_ref(),
+        &key,
+        &EcdsaStruct::Party1MasterKey,
+        &master_key,
+    )
+    .await?;
+    Ok(Json(chain_code_second_message))
+}
+
+/// Returns the active key of the customer, so that a client can reattach to it
+#[post("/ecdsa/keygen/active", format = "json")]
+pub async fn active_key(
+    state: &State<SharedDb>,
+    customer: Customer,
+) -> Result<Json<ActiveKey>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, ACTIVE_KEY_ID);
+    match get_value::<ActiveKey>(db.as_ref(), &key, &KeygenStruct::ActiveKey).await {
+        Ok(active_key) => Ok(Json(active_key)),
+        Err(e) if e.code == ErrorCode::NotFound => Err(ApiError::not_found("No active key")),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,372 @@
+//! Metadata of every key: when it was created and last used, and whether it may sign
+//!
+//! The record is written with the party one master key, and updated by every signature.
+//! Keys created before it existed have no metadata and are treated as usable.
+//!
+//! A key is `active`, `frozen` or `revoked`. Frozen keys refuse to sign until they are
+//! unfrozen, revoked keys refuse to sign for good, e.g. after the device holding the client
+//! share was stolen. Every change of state is kept with its reason and who made it.
+//! Approvers change states with `admin/keys/<customer id>/<id>/{freeze,unfreeze,revoke}`, and
+//! `server_exec admin` does with the server stopped.
+//!
+//! `ecdsa/<id>/recover` returns the public part of a key and the highest derivation position it
+//! signed with, so that a client restoring its share from a backup can rebuild its addresses.
+
+use chrono::Utc;
+use log::info;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+use two_party_ecdsa::curv::elliptic::curves::secp256_k1::GE;
+use two_party_ecdsa::curv::BigInt;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+use two_party_ecdsa::party_one::Value;
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::approval::Approver;
+use crate::auth::Customer;
+use crate::db::{db_index, find_value, get_value, insert_value, SharedDb};
+use crate::error::{ApiError, ErrorCode};
+
+#[derive(Debug)]
+pub enum KeyStruct {
+    Metadata,
+}
+
+impl MPCStruct for KeyStruct {
+    fn to_string(&self) -> String {
+        match self {
+            KeyStruct::Metadata => "KeyMetadata".to_string(),
+        }
+    }
+}
+
+/// Whether a key may sign
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyState {
+    #[default]
+    Active,
+    /// Refuses to sign until it is unfrozen
+    Frozen,
+    /// Refuses to sign for good
+    Revoked,
+}
+
+impl KeyState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyState::Active => "active",
+            KeyState::Frozen => "frozen",
+            KeyState::Revoked => "revoked",
+        }
+    }
+}
+
+/// Change of the state of a key by an administrator
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateChange {
+    pub state: KeyState,
+    pub reason: String,
+    /// Who changed the state
+    pub actor: String,
+    /// Unix time of the change
+    pub changed_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KeyMetadata {
+    /// Unix time at which the key was generated
+    pub created_at: Option<i64>,
+    /// Unix time of the last signature
+    pub last_signed_at: Option<i64>,
+    /// Whether the key was frozen, in metadata written before key states
+    #[serde(default, rename = "frozen", skip_serializing)]
+    legacy_frozen: bool,
+    #[serde(default)]
+    state: KeyState,
+    /// Every change of `state`, oldest first
+    #[serde(default)]
+    pub state_changes: Vec<StateChange>,
+    /// Highest `y_pos` of the child keys that signed
+    #[serde(default)]
+    pub last_derived_pos: Option<u64>,
+}
+
+#[typetag::serde]
+impl Value for KeyMetadata {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl KeyMetadata {
+    pub fn created_now() -> Self {
+        KeyMetadata {
+            created_at: Some(Utc::now().timestamp()),
+            ..KeyMetadata::default()
+        }
+    }
+
+    pub fn state(&self) -> KeyState {
+        match self.state {
+            KeyState::Active if self.legacy_frozen => KeyState::Frozen,
+            state => state,
+        }
+    }
+
+    /// Moves the key to `state`, returning whether it was in another state. A revoked key stays
+    /// revoked.
+    pub fn change_state(
+        &mut self,
+        state: KeyState,
+        reason: &str,
+        actor: &str,
+    ) -> Result<bool, KeyStateError> {
+        if reason.trim().is_empty() {
+            return Err(KeyStateError::MissingReason);
+        }
+        match self.state() {
+            current if current == state => return Ok(false),
+            KeyState::Revoked => return Err(KeyStateError::Revoked),
+            _ => {}
+        }
+        self.legacy_frozen = false;
+        self.state = state;
+        self.state_changes.push(StateChange {
+            state,
+            reason: reason.to_string(),
+            actor: actor.to_string(),
+            changed_at: Utc::now().timestamp(),
+        });
+        Ok(true)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyStateError {
+    #[error("the key is revoked for good")]
+    Revoked,
+    #[error("a change of state needs a reason")]
+    MissingReason,
+}
+
+/// Metadata of the key at `key`, the default one for keys created without it
+pub async fn get_metadata(db: &dyn Db, key: &DbIndex) -> Result<KeyMetadata, ApiError> {
+    Ok(find_value(db, key, &KeyStruct::Metadata)
+        .await?
+        .unwrap_or_default())
+}
+
+/// Refuses to use a frozen or revoked key
+pub async fn ensure_usable(db: &dyn Db, key: &DbIndex) -> Result<KeyMetadata, ApiError> {
+    let metadata = get_metadata(db, key).await?;
+    let code = match metadata.state() {
+        KeyState::Active => return Ok(metadata),
+        KeyState::Frozen => ErrorCode::KeyFrozen,
+        KeyState::Revoked => ErrorCode::KeyRevoked,
+    };
+    Err(ApiError::new(
+        Status::Forbidden,
+        code,
+        format!("Key {} is {}", key.id, metadata.state().as_str()),
+    ))
+}
+
+/// Master key at `key`, when it may sign or rotate
+pub async fn usable_master_key(db: &dyn Db, key: &DbIndex) -> Result<MasterKey1, ApiError> {
+    let master_key = get_value(db, key, &EcdsaStruct::Party1MasterKey).await?;
+    ensure_usable(db, key).await?;
+    Ok(master_key)
+}
+
+/// Moves the key at `key` to `state`, recording why and by whom
+pub async fn set_state(
+    db: &dyn Db,
+    key: &DbIndex,
+    state: KeyState,
+    reason: &str,
+    actor: &str,
+) -> Result<KeyMetadata, ApiError> {
+    get_value::<MasterKey1>(db, key, &EcdsaStruct::Party1MasterKey).await?;
+    let mut metadata = get_metadata(db, key).await?;
+    let changed = metadata
+        .change_state(state, reason, actor)
+        .map_err(|e| match e {
+            KeyStateError::Revoked => ApiError::new(
+                Status::Forbidden,
+                ErrorCode::KeyRevoked,
+                format!("Key {} is revoked", key.id),
+            ),
+            KeyStateError::MissingReason => ApiError::bad_request(e.to_string()),
+        })?;
+    if changed {
+        insert_value(db, key, &KeyStruct::Metadata, &metadata).await?;
+        info!(
+            "{} set key {} of {} {}: {}",
+            actor,
+            key.id,
+            key.customerId,
+            state.as_str(),
+            reason
+        );
+    }
+    Ok(metadata)
+}
+
+/// Records signatures by the child keys at `positions`, those that fit in `(u64, u64)`
+pub async fn record_signature(
+    db: &dyn Db,
+    key: &DbIndex,
+    mut metadata: KeyMetadata,
+    positions: impl IntoIterator<Item = (u64, u64)>,
+) -> Result<(), ApiError> {
+    metadata.last_signed_at = Some(Utc::now().timestamp());
+    for (_, y_pos) in positions {
+        metadata.last_derived_pos = metadata.last_derived_pos.max(Some(y_pos));
+    }
+    insert_value(db, key, &KeyStruct::Metadata, &metadata).await
+}
+
+/// Public data of a key, enough with the client share to rebuild the wallet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyRecovery {
+    pub id: String,
+    /// Joint public key
+    pub public_key: GE,
+    /// Public share of party one
+    pub public_share: GE,
+    pub chain_code: BigInt,
+    /// Highest `y_pos` of the child keys that signed, `0` when none did
+    pub last_derived_pos: u64,
+}
+
+#[post("/ecdsa/<id>/recover", format = "json")]
+pub async fn recover(
+    state: &State<SharedDb>,
+    customer: Customer,
+    id: String,
+) -> Result<Json<KeyRecovery>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let master_key: MasterKey1 =
+        get_value(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?;
+    let metadata = get_metadata(db.as_ref(), &key).await?;
+    Ok(Json(KeyRecovery {
+        id,
+        public_key: master_key.public.q,
+        public_share: master_key.public.p1,
+        chain_code: master_key.chain_code,
+        last_derived_pos: metadata.last_derived_pos.unwrap_or(0),
+    }))
+}
+
+/// Why an administrator changes the state of a key
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StateChangeRequest {
+    pub reason: String,
+}
+
+/// State of a key and how it got there
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyStatus {
+    pub id: String,
+    pub state: KeyState,
+    pub state_changes: Vec<StateChange>,
+}
+
+async fn change_state(
+    db: &State<SharedDb>,
+    approver: Approver,
+    customer_id: String,
+    id: String,
+    request: StateChangeRequest,
+    state: KeyState,
+) -> Result<Json<KeyStatus>, ApiError> {
+    let db = db.lock().await;
+    let key = DbIndex {
+        customerId: customer_id,
+        id,
+    };
+    let metadata = set_state(db.as_ref(), &key, state, &request.reason, &approver.name).await?;
+    Ok(Json(KeyStatus {
+        id: key.id,
+        state: metadata.state(),
+        state_changes: metadata.state_changes,
+    }))
+}
+
+#[post(
+    "/admin/keys/<customer_id>/<id>/freeze",
+    format = "json",
+    data = "<request>"
+)]
+pub async fn freeze(
+    db: &State<SharedDb>,
+    approver: Approver,
+    customer_id: String,
+    id: String,
+    request: Json<StateChangeRequest>,
+) -> Result<Json<KeyStatus>, ApiError> {
+    change_state(
+        db,
+        approver,
+        customer_id,
+        id,
+        request.into_inner(),
+        KeyState::Frozen,
+    )
+    .await
+}
+
+#[post(
+    "/admin/keys/<customer_id>/<id>/unfreeze",
+    format = "json",
+    data = "<request>"
+)]
+pub async fn unfreeze(
+    db: &State<SharedDb>,
+    approver: Approver,
+    customer_id: String,
+    id: String,
+    request: Json<StateChangeRequest>,
+) -> Result<Json<KeyStatus>, ApiError> {
+    change_state(
+        db,
+        approver,
+        customer_id,
+        id,
+        request.into_inner(),
+        KeyState::Active,
+    )
+    .await
+}
+
+/// Refuses every later signature with the key, for good
+#[post(
+    "/admin/keys/<customer_id>/<id>/revoke",
+    format = "json",
+    data = "<request>"
+)]
+pub async fn revoke(
+    db: &State<SharedDb>,
+    approver: Approver,
+    customer_id: String,
+    id: String,
+    request: Json<StateChangeRequest>,
+) -> Result<Json<KeyStatus>, ApiError> {
+    change_state(
+        db,
+        approver,
+        customer_id,
+        id,
+        request.into_inner(),
+        KeyState::Revoked,
+    )
+    .await
+}
@@ -1,3 +1,26 @@
+pub mod admin;
+pub mod approval;
+pub mod audit;
+pub mod auth;
+pub mod db;
+pub mod deletion;
+pub mod error;
+pub mod escrow;
+pub mod health;
+pub mod idempotency;
+pub mod keygen;
+pub mod keys;
+pub mod metrics;
+pub mod openapi;
+pub mod policy;
+pub mod presign;
 pub mod public_gotham;
+pub mod rate_limit;
+pub mod rotate;
 pub mod server;
+pub mod sessions;
+pub mod sign;
+pub mod storage;
 pub mod tests;
+pub mod transaction;
+pub mod websocket;
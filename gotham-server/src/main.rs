@@ -1,9 +1,276 @@
+mod admin;
+mod approval;
+mod audit;
+mod auth;
+mod db;
+mod deletion;
+mod error;
+mod escrow;
+mod health;
+mod idempotency;
+mod keygen;
+mod keys;
+mod metrics;
+mod openapi;
+mod policy;
+mod presign;
 mod public_gotham;
+mod rate_limit;
+mod rotate;
 mod server;
+mod sessions;
+mod sign;
+mod storage;
+mod transaction;
+mod websocket;
 
-use std::collections::HashMap;
+use clap::{Args, Parser, Subcommand};
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-#[rocket::launch]
-fn rocket() -> _ {
-    crate::server::get_server()
+use crate::admin::{Admin, AdminCommand};
+use crate::audit::AuditLog;
+use crate::escrow::EscrowBackup;
+use crate::public_gotham::get_settings_as_map;
+use crate::storage::schema::{self, SCHEMA_VERSION};
+use crate::storage::{EncryptedStore, Kek};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Serve the Gotham API (default)")]
+    Serve,
+
+    #[command(
+        about = "Wrap the data key of every record with a new key-encryption key",
+        long_about = "Wrap the data key of every record with a new key-encryption key. \n \
+    Records are read with the keys of `kek_path` and `previous_kek_path`, and plaintext records \n \
+    are encrypted. Run it with the server stopped, then set `kek_path` to the new key. \n \
+    An interrupted run can be started again."
+    )]
+    RotateKek(RotateKekArgs),
+
+    #[command(
+        about = "Upgrade the stored records to the current storage schema",
+        long_about = "Upgrade the stored records to the current storage schema. \n \
+    Run it with the server stopped; the server refuses to start on an outdated store. \n \
+    An interrupted run can be started again."
+    )]
+    Migrate(MigrateArgs),
+
+    #[command(
+        about = "Check the hash chain of the audit log",
+        long_about = "Check the hash chain of the audit log. \n \
+    Fails on a modified, removed, reordered or truncated entry, and prints the hash of the last \n \
+    entry, to compare with a copy kept elsewhere."
+    )]
+    VerifyAuditLog(VerifyAuditLogArgs),
+
+    #[command(
+        about = "Encrypt the secret share of every key to an escrow public key",
+        long_about = "Encrypt the secret share of every key to an escrow public key. \n \
+    Each share is split into segments ElGamal encrypted under the key, with a proof that they \n \
+    hold the secret of the public share; only the escrow private key decrypts them."
+    )]
+    EscrowBackup(EscrowBackupArgs),
+
+    #[command(
+        about = "Check the proofs of an escrow backup against the escrow public key",
+        long_about = "Check the proofs of an escrow backup against the escrow public key. \n \
+    The proofs show that the encrypted segments hold the secret share of each key. The sealed \n \
+    private part, with the Paillier private key, can only be checked by opening it with the \n \
+    escrow private key."
+    )]
+    VerifyEscrowBackup(VerifyEscrowBackupArgs),
+
+    #[command(about = "Inspect and manage the stored keys, with the server stopped")]
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Args)]
+pub struct MigrateArgs {
+    #[arg(long, help = "Only report the records that would be migrated")]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct VerifyAuditLogArgs {
+    #[arg(
+        long,
+        help = "Audit log to check, instead of the one of `audit_log_path`"
+    )]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct EscrowBackupArgs {
+    #[arg(
+        long,
+        help = "JSON file holding the escrow public key (a secp256k1 point)"
+    )]
+    pub escrow_key_path: PathBuf,
+    #[arg(long, help = "File the backup is written to")]
+    pub output: PathBuf,
+}
+
+#[derive(Args)]
+pub struct VerifyEscrowBackupArgs {
+    #[arg(
+        long,
+        help = "JSON file holding the escrow public key (a secp256k1 point)"
+    )]
+    pub escrow_key_path: PathBuf,
+    #[arg(long, help = "Backup written by `escrow-backup`")]
+    pub backup: PathBuf,
+}
+
+#[derive(Args)]
+pub struct RotateKekArgs {
+    #[arg(
+        long,
+        help = "File holding the new key-encryption key (64 hex characters)"
+    )]
+    pub new_kek_path: String,
+}
+
+#[rocket::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("server_exec: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            crate::server::get_server()?.launch().await?;
+        }
+        Command::RotateKek(args) => rotate_kek(&args).await?,
+        Command::Migrate(args) => migrate(&args).await?,
+        Command::VerifyAuditLog(args) => verify_audit_log(&args)?,
+        Command::EscrowBackup(args) => escrow_backup(&args).await?,
+        Command::VerifyEscrowBackup(args) => verify_escrow_backup(&args)?,
+        Command::Admin { command } => {
+            let settings = get_settings_as_map()?;
+            let admin = Admin::new(storage::open(&settings)?)
+                .with_audit_log(AuditLog::from_settings(&settings)?);
+            admin::run(&admin, command).await?
+        }
+    }
+
+    Ok(())
+}
+
+async fn rotate_kek(args: &RotateKekArgs) -> Result<(), Box<dyn Error>> {
+    let settings = get_settings_as_map()?;
+    let new_kek = Kek::from_file(&args.new_kek_path)?;
+    let previous = [
+        Kek::from_settings(&settings)?,
+        Kek::previous_from_settings(&settings)?,
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    println!("Wrapping all records with KEK {}", new_kek.id());
+    let store = EncryptedStore::new(storage::open_backend(&settings)?, new_kek, previous);
+    let report = store.rewrap().await?;
+    println!(
+        "{} rewrapped, {} encrypted, {} already up to date",
+        report.rewrapped, report.encrypted, report.unchanged
+    );
+    Ok(())
+}
+
+async fn migrate(args: &MigrateArgs) -> Result<(), Box<dyn Error>> {
+    let store = storage::open(&get_settings_as_map()?)?;
+    let report = schema::migrate(store.as_ref(), args.dry_run).await?;
+
+    match report.from_version {
+        Some(version) => println!("Storage schema version {}", version),
+        None => println!("Storage is empty"),
+    }
+    let verb = if args.dry_run {
+        "Would migrate"
+    } else {
+        "Migrated"
+    };
+    for (table, count) in &report.migrated {
+        println!("{} {} {} records", verb, count, table);
+    }
+    for key in &report.unrecognized {
+        println!(
+            "Left unrecognized record {}",
+            String::from_utf8_lossy(key).escape_debug()
+        );
+    }
+    if !args.dry_run {
+        println!("Storage schema is now at version {}", SCHEMA_VERSION);
+    }
+    Ok(())
+}
+
+fn verify_audit_log(args: &VerifyAuditLogArgs) -> Result<(), Box<dyn Error>> {
+    let path = match &args.path {
+        Some(path) => path.clone(),
+        None => match get_settings_as_map()?.get("audit_log_path") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => return Err("audit_log_path is not set, pass --path".into()),
+        },
+    };
+    let head = audit::verify(&path)?;
+    println!("{} entries verified", head.seq);
+    println!("Last hash {}", head.hash);
+    Ok(())
+}
+
+async fn escrow_backup(args: &EscrowBackupArgs) -> Result<(), Box<dyn Error>> {
+    let escrow_key = escrow::read_json(&args.escrow_key_path)?;
+    let admin = Admin::new(storage::open(&get_settings_as_map()?)?);
+    let backup = EscrowBackup::create(&admin, &escrow_key).await?;
+    escrow::write_json(&args.output, &backup)?;
+    println!(
+        "Backed up {} keys to {}",
+        backup.keys.len(),
+        args.output.display()
+    );
+    Ok(())
+}
+
+fn verify_escrow_backup(args: &VerifyEscrowBackupArgs) -> Result<(), Box<dyn Error>> {
+    let escrow_key = escrow::read_json(&args.escrow_key_path)?;
+    let backup: EscrowBackup = escrow::read_json(&args.backup)?;
+    let failed = backup.verify(&escrow_key)?;
+    for (customer_id, id) in &failed {
+        println!("Backup of key {} of {} does not verify", id, customer_id);
+    }
+    if !failed.is_empty() {
+        return Err(format!(
+            "{} of {} backups do not verify",
+            failed.len(),
+            backup.keys.len()
+        )
+        .into());
+    }
+    println!(
+        "{} backups verified, made at {}",
+        backup.keys.len(),
+        backup.created_at.to_rfc3339()
+    );
+    println!("The proofs cover the secret shares only: the sealed Paillier keys are not checked");
+    Ok(())
 }
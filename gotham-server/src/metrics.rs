@@ -0,0 +1,201 @@
+//! Prometheus metrics, exported at `/metrics`
+//!
+//! The [`RequestMetrics`] fairing times every request by route and counts its outcome: `ok`, or
+//! the error code of its [`ErrorBody`](crate::error::ErrorBody). The requests of
+//! [WebSocket sessions](crate::websocket) are counted under the name of the HTTP route they
+//! stand for. Store operations are timed by [`MeteredStore`](crate::storage::MeteredStore). The
+//! keygen, signing and rotation sessions in progress are counted from their records when the
+//! metrics are scraped.
+
+use chrono::Utc;
+use log::error;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::{get, Data, Request, Response, State};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::error;
+use crate::sessions::Sessions;
+use crate::storage::Store;
+
+/// Protocol messages take from milliseconds (signing) to tens of seconds (Paillier keygen)
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+const STORE_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    request_duration: HistogramVec,
+    requests: IntCounterVec,
+    in_flight: IntGauge,
+    sessions_in_flight: IntGaugeVec,
+    store_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "gotham_request_duration_seconds",
+                "Time taken to serve a request, by route",
+            )
+            .buckets(REQUEST_BUCKETS.to_vec()),
+            &["route"],
+        )
+        .unwrap();
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "gotham_requests_total",
+                "Requests served, by route and outcome (`ok` or error code)",
+            ),
+            &["route", "outcome"],
+        )
+        .unwrap();
+        let in_flight =
+            IntGauge::new("gotham_requests_in_flight", "Requests being served").unwrap();
+        let sessions_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "gotham_sessions_in_flight",
+                "Keygen, signing and rotation sessions opened and not yet finished, by protocol",
+            ),
+            &["protocol"],
+        )
+        .unwrap();
+        let store_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "gotham_store_operation_duration_seconds",
+                "Time taken by store operations, by operation and table",
+            )
+            .buckets(STORE_BUCKETS.to_vec()),
+            &["operation", "table"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(in_flight.clone())).unwrap();
+        registry
+            .register(Box::new(sessions_in_flight.clone()))
+            .unwrap();
+        registry.register(Box::new(store_duration.clone())).unwrap();
+
+        Metrics {
+            registry,
+            request_duration,
+            requests,
+            in_flight,
+            sessions_in_flight,
+            store_duration,
+        }
+    }
+
+    /// Histogram of store operations, labelled by `operation` and `table`
+    pub fn store_duration(&self) -> HistogramVec {
+        self.store_duration.clone()
+    }
+
+    /// Counts a request in flight until it is [finished](Self::finish_request)
+    pub fn start_request(&self) -> Instant {
+        self.in_flight.inc();
+        Instant::now()
+    }
+
+    /// Times and counts a request of `route` started at `start`
+    pub fn finish_request(&self, route: &str, start: Instant, outcome: &str) {
+        self.in_flight.dec();
+        self.request_duration
+            .with_label_values(&[route])
+            .observe(start.elapsed().as_secs_f64());
+        self.requests.with_label_values(&[route, outcome]).inc();
+    }
+
+    /// Sets the number of sessions in progress of every protocol
+    pub fn set_sessions_in_flight(&self, open_sessions: &BTreeMap<&'static str, usize>) {
+        for (protocol, open) in open_sessions {
+            self.sessions_in_flight
+                .with_label_values(&[protocol])
+                .set(*open as i64);
+        }
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics(
+    metrics: &State<Arc<Metrics>>,
+    sessions: &State<Arc<Sessions>>,
+    store: &State<Arc<dyn Store>>,
+) -> (ContentType, String) {
+    match sessions
+        .open_sessions(store.inner().as_ref(), Utc::now())
+        .await
+    {
+        Ok(open_sessions) => metrics.set_sessions_in_flight(&open_sessions),
+        Err(e) => error!("Cannot count the sessions in progress: {}", e),
+    }
+    (
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        metrics.encode(),
+    )
+}
+
+struct RequestStart(Instant);
+
+/// Fairing timing and counting every request. Attach it after
+/// [`ApiErrors`](crate::error::ApiErrors), which sets the error code of plain text errors.
+pub struct RequestMetrics(pub Arc<Metrics>);
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let start = self.0.start_request();
+        request.local_cache(|| RequestStart(start));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        let outcome = match error::error_code(request) {
+            Some(code) => code.as_str(),
+            None if response.status().code >= 400 => "http_error",
+            None => "ok",
+        };
+        self.0.finish_request(route, start.0, outcome);
+    }
+}
@@ -0,0 +1,561 @@
+//! OpenAPI 3.1 description of the protocol routes, served at `/openapi.json`
+//!
+//! It covers every keygen, chain code and signing route, with JSON schemas of the messages of
+//! both parties, so that clients can be written without reading the server. Big integers and
+//! scalars serialize as hex strings, and points as the hex strings of their affine coordinates.
+//! Tests check that the documented routes are the mounted ones and that the messages of a
+//! keygen and a signature match their schemas.
+
+use rocket::get;
+use rocket::serde::json::Json;
+use serde_json::{json, Map, Value};
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn array_of(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// Object schema whose properties are all required
+fn object(description: &str, properties: Value) -> Value {
+    let required: Vec<&String> = properties
+        .as_object()
+        .map(|properties| properties.keys().collect())
+        .unwrap_or_default();
+    json!({
+        "type": "object",
+        "description": description,
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn json_body(schema: Value) -> Value {
+    json!({ "content": { "application/json": { "schema": schema } } })
+}
+
+struct Operation {
+    path: &'static str,
+    operation_id: &'static str,
+    summary: &'static str,
+    tag: &'static str,
+    request: Option<Value>,
+    response: Value,
+}
+
+fn operation(operation: &Operation) -> Value {
+    let mut parameters = vec![
+        json!({ "$ref": "#/components/parameters/IdempotencyKey" }),
+        json!({ "$ref": "#/components/parameters/ContentDigest" }),
+    ];
+    if operation.path.contains("{id}") {
+        parameters.insert(0, json!({ "$ref": "#/components/parameters/KeyId" }));
+    }
+    if operation.operation_id == "keygen_first" {
+        parameters.push(json!({
+            "name": "new_key",
+            "in": "query",
+            "required": false,
+            "description": "Generates a key replacing the active key of the customer",
+            "schema": { "type": "boolean" },
+        }));
+    }
+    let mut responses = json!({
+        "200": {
+            "description": "Message of party one",
+            "content": { "application/json": { "schema": operation.response } },
+        },
+        "default": {
+            "description": "Error",
+            "content": { "application/json": { "schema": schema("ErrorBody") } },
+        },
+    });
+    if matches!(operation.operation_id, "sign_second" | "sign_presigned") {
+        responses["202"] = json!({
+            "description": "Signing waits for an approver, poll `/ecdsa/approvals/{approval_id}`",
+            "content": { "application/json": { "schema": schema("ApprovalStatus") } },
+        });
+    }
+    let mut value = json!({
+        "operationId": operation.operation_id,
+        "summary": operation.summary,
+        "tags": [operation.tag],
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if let Some(request) = &operation.request {
+        value["requestBody"] = json_body(request.clone());
+        value["requestBody"]["required"] = json!(true);
+    }
+    value
+}
+
+fn operations() -> Vec<Operation> {
+    vec![
+        Operation {
+            path: "/ecdsa/keygen/first",
+            operation_id: "keygen_first",
+            summary:
+                "Starts a keygen, answering the id of the key and the commitments of party one",
+            tag: "keygen",
+            request: None,
+            response: json!({
+                "type": "array",
+                "prefixItems": [{ "type": "string" }, schema("Party1KeyGenFirstMsg")],
+                "minItems": 2,
+                "maxItems": 2,
+            }),
+        },
+        Operation {
+            path: "/ecdsa/keygen/{id}/second",
+            operation_id: "keygen_second",
+            summary: "Proof of knowledge of the share of party two",
+            tag: "keygen",
+            request: Some(schema("DLogProof")),
+            response: schema("KeyGenParty1Message2"),
+        },
+        Operation {
+            path: "/ecdsa/keygen/{id}/third",
+            operation_id: "keygen_third",
+            summary: "First message of the PDL proof",
+            tag: "keygen",
+            request: Some(schema("Party2PDLFirstMessage")),
+            response: schema("Party1PDLFirstMessage"),
+        },
+        Operation {
+            path: "/ecdsa/keygen/{id}/fourth",
+            operation_id: "keygen_fourth",
+            summary: "Decommitment of the PDL proof; party one stores its master key",
+            tag: "keygen",
+            request: Some(schema("Party2PDLSecondMessage")),
+            response: schema("Party1PDLSecondMessage"),
+        },
+        Operation {
+            path: "/ecdsa/keygen/active",
+            operation_id: "keygen_active",
+            summary: "Active key of the customer, `404 not_found` when there is none",
+            tag: "keygen",
+            request: None,
+            response: schema("ActiveKey"),
+        },
+        Operation {
+            path: "/ecdsa/keygen/{id}/chaincode/first",
+            operation_id: "chain_code_first",
+            summary: "Commitments of party one to its chain code share",
+            tag: "chain code",
+            request: None,
+            response: schema("ChainCodeParty1FirstMessage"),
+        },
+        Operation {
+            path: "/ecdsa/keygen/{id}/chaincode/second",
+            operation_id: "chain_code_second",
+            summary: "Proof of knowledge of the chain code share of party two",
+            tag: "chain code",
+            request: Some(schema("DLogProof")),
+            response: schema("ChainCodeParty1SecondMessage"),
+        },
+        Operation {
+            path: "/ecdsa/sign/{id}/first",
+            operation_id: "sign_first",
+            summary: "Exchanges the ephemeral keys of a signature",
+            tag: "sign",
+            request: Some(schema("Party2EphKeyGenFirstMsg")),
+            response: schema("Party1EphKeyGenFirstMsg"),
+        },
+        Operation {
+            path: "/ecdsa/sign/{id}/second",
+            operation_id: "sign_second",
+            summary: "Completes the partial signature of party two",
+            tag: "sign",
+            request: Some(schema("SignSecondMsgRequest")),
+            response: schema("SignatureRecid"),
+        },
+        Operation {
+            path: "/ecdsa/sign/{id}/batch/first",
+            operation_id: "sign_batch_first",
+            summary: "Exchanges the ephemeral keys of every message of a batch",
+            tag: "sign",
+            request: Some(array_of(schema("SignBatchItem"))),
+            response: array_of(schema("Party1EphKeyGenFirstMsg")),
+        },
+        Operation {
+            path: "/ecdsa/sign/{id}/batch/second",
+            operation_id: "sign_batch_second",
+            summary: "Signs every message of the batch, in order",
+            tag: "sign",
+            request: Some(array_of(schema("SignMessage"))),
+            response: array_of(schema("SignatureRecid")),
+        },
+        Operation {
+            path: "/ecdsa/sign/{id}/presign",
+            operation_id: "presign",
+            summary: "Adds presignatures to the pool of the key",
+            tag: "sign",
+            request: Some(array_of(schema("Party2EphKeyGenFirstMsg"))),
+            response: array_of(schema("Presignature")),
+        },
+        Operation {
+            path: "/ecdsa/sign/{id}/presigned",
+            operation_id: "sign_presigned",
+            summary: "Signs in one round trip with a presignature, which is consumed",
+            tag: "sign",
+            request: Some(schema("PresignedSignRequest")),
+            response: schema("SignatureRecid"),
+        },
+    ]
+}
+
+fn schemas() -> Value {
+    let big_int = schema("BigInt");
+    let point = schema("Point");
+    let transaction = json!({
+        "type": "object",
+        "description": "Unsigned transaction whose digest is the message, checked by the server",
+        "properties": {
+            "chain": { "type": "string", "enum": ["bitcoin", "ethereum"] },
+        },
+        "required": ["chain"],
+    });
+    let mut schemas = Map::new();
+    schemas.insert("BigInt".to_string(), json!({ "type": "string", "description": "Hex encoded integer", "pattern": "^-?[0-9a-f]*$" }));
+    schemas.insert("Scalar".to_string(), json!({ "type": "string", "description": "Hex encoded secp256k1 scalar", "pattern": "^[0-9a-f]*$" }));
+    schemas.insert(
+        "Point".to_string(),
+        object("secp256k1 point", json!({ "x": big_int, "y": big_int })),
+    );
+    schemas.insert(
+        "DLogProof".to_string(),
+        object(
+            "Schnorr proof of knowledge of the discrete log of `pk`",
+            json!({
+                "pk": point,
+                "pk_t_rand_commitment": point,
+                "challenge_response": schema("Scalar"),
+            }),
+        ),
+    );
+    schemas.insert(
+        "ECDDHProof".to_string(),
+        object(
+            "Proof that two points share their discrete log",
+            json!({
+                "a1": point,
+                "a2": point,
+                "z": schema("Scalar"),
+            }),
+        ),
+    );
+    schemas.insert(
+        "CommWitness".to_string(),
+        object(
+            "Opening of the commitments of party one",
+            json!({
+                "pk_commitment_blind_factor": big_int,
+                "zk_pok_blind_factor": big_int,
+                "public_share": point,
+                "d_log_proof": schema("DLogProof"),
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party1KeyGenFirstMsg".to_string(),
+        object(
+            "`party_one::KeyGenFirstMsg`",
+            json!({
+                "pk_commitment": big_int,
+                "zk_pok_commitment": big_int,
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party1KeyGenSecondMsg".to_string(),
+        object(
+            "`party_one::KeyGenSecondMsg`",
+            json!({
+                "comm_witness": schema("CommWitness"),
+            }),
+        ),
+    );
+    schemas.insert(
+        "EncryptionKey".to_string(),
+        json!({
+            "type": "object",
+            "description": "Paillier public key of party one",
+            "properties": { "n": big_int },
+            "required": ["n"],
+        }),
+    );
+    schemas.insert("KeyGenParty1Message2".to_string(), json!({
+        "type": "object",
+        "description": "`party1::KeyGenParty1Message2`: the opening of the commitments of party one, \
+            and its share encrypted under its Paillier key with proofs that the key and the \
+            ciphertext are well formed",
+        "properties": {
+            "ecdh_second_message": schema("Party1KeyGenSecondMsg"),
+            "ek": schema("EncryptionKey"),
+            "c_key": big_int,
+            "correct_key_proof": { "type": "object", "description": "`NICorrectKeyProof` of the Paillier key" },
+            "range_proof": { "type": "object", "description": "`RangeProofNi` of the encrypted share" },
+        },
+        "required": ["ecdh_second_message", "ek", "c_key", "correct_key_proof", "range_proof"],
+    }));
+    schemas.insert(
+        "Party2PDLFirstMessage".to_string(),
+        object(
+            "`party_two::PDLFirstMessage`",
+            json!({
+                "c_tag": big_int,
+                "c_tag_tag": big_int,
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party1PDLFirstMessage".to_string(),
+        object(
+            "`party_one::PDLFirstMessage`",
+            json!({
+                "c_hat": big_int,
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party2PDLSecondMessage".to_string(),
+        object(
+            "`party_two::PDLSecondMessage`",
+            json!({
+                "decommit": object("`party_two::PDLdecommit`", json!({
+                    "a": big_int,
+                    "b": big_int,
+                    "blindness": big_int,
+                })),
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party1PDLSecondMessage".to_string(),
+        object(
+            "`party_one::PDLSecondMessage`",
+            json!({
+                "decommit": object("`party_one::PDLdecommit`", json!({
+                    "q_hat": point,
+                    "blindness": big_int,
+                })),
+            }),
+        ),
+    );
+    schemas.insert(
+        "ActiveKey".to_string(),
+        object(
+            "Key the customer signs with",
+            json!({
+                "id": { "type": "string" },
+                "activated_at": { "type": "integer", "description": "Unix time" },
+            }),
+        ),
+    );
+    schemas.insert(
+        "ChainCodeParty1FirstMessage".to_string(),
+        object(
+            "`Party1FirstMessage` of the chain code exchange",
+            json!({
+                "pk_commitment": big_int,
+                "zk_pok_commitment": big_int,
+            }),
+        ),
+    );
+    schemas.insert(
+        "ChainCodeParty1SecondMessage".to_string(),
+        object(
+            "`Party1SecondMessage` of the chain code exchange",
+            json!({
+                "comm_witness": schema("CommWitness"),
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party2EphKeyGenFirstMsg".to_string(),
+        object(
+            "`party_two::EphKeyGenFirstMsg`",
+            json!({
+                "pk_commitment": big_int,
+                "zk_pok_commitment": big_int,
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party1EphKeyGenFirstMsg".to_string(),
+        object(
+            "`party_one::EphKeyGenFirstMsg`",
+            json!({
+                "d_log_proof": schema("ECDDHProof"),
+                "public_share": point,
+                "c": point,
+            }),
+        ),
+    );
+    schemas.insert(
+        "Party2EphCommWitness".to_string(),
+        object(
+            "`party_two::EphCommWitness`",
+            json!({
+                "pk_commitment_blind_factor": big_int,
+                "zk_pok_blind_factor": big_int,
+                "public_share": point,
+                "d_log_proof": schema("ECDDHProof"),
+                "c": point,
+            }),
+        ),
+    );
+    schemas.insert(
+        "SignMessage".to_string(),
+        object(
+            "`party2::SignMessage`: partial signature of party two",
+            json!({
+                "partial_sig": object("`party_two::PartialSig`", json!({ "c3": big_int })),
+                "second_message": object("`party_two::EphKeyGenSecondMsg`", json!({
+                    "comm_witness": schema("Party2EphCommWitness"),
+                })),
+            }),
+        ),
+    );
+    schemas.insert("UnsignedTransaction".to_string(), transaction);
+    schemas.insert("SignSecondMsgRequest".to_string(), json!({
+        "type": "object",
+        "description": "Message to sign, by the child key at `(x_pos_child_key, y_pos_child_key)`",
+        "properties": {
+            "message": big_int,
+            "party_two_sign_message": schema("SignMessage"),
+            "x_pos_child_key": big_int,
+            "y_pos_child_key": big_int,
+            "transaction": schema("UnsignedTransaction"),
+        },
+        "required": ["message", "party_two_sign_message", "x_pos_child_key", "y_pos_child_key"],
+    }));
+    schemas.insert("SignBatchItem".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "message": big_int,
+            "x_pos_child_key": big_int,
+            "y_pos_child_key": big_int,
+            "eph_key_gen_first_message_party_two": schema("Party2EphKeyGenFirstMsg"),
+            "transaction": schema("UnsignedTransaction"),
+        },
+        "required": ["message", "x_pos_child_key", "y_pos_child_key", "eph_key_gen_first_message_party_two"],
+    }));
+    schemas.insert(
+        "Presignature".to_string(),
+        object(
+            "Ephemeral message of party one for one presignature",
+            json!({
+                "id": { "type": "string" },
+                "eph_key_gen_first_message_party_one": schema("Party1EphKeyGenFirstMsg"),
+            }),
+        ),
+    );
+    schemas.insert("PresignedSignRequest".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "presignature_id": { "type": "string" },
+            "message": big_int,
+            "party_two_sign_message": schema("SignMessage"),
+            "x_pos_child_key": big_int,
+            "y_pos_child_key": big_int,
+            "transaction": schema("UnsignedTransaction"),
+        },
+        "required": ["presignature_id", "message", "party_two_sign_message", "x_pos_child_key", "y_pos_child_key"],
+    }));
+    schemas.insert(
+        "SignatureRecid".to_string(),
+        object(
+            "ECDSA signature with its recovery id",
+            json!({
+                "r": big_int,
+                "s": big_int,
+                "recid": { "type": "integer", "minimum": 0, "maximum": 3 },
+            }),
+        ),
+    );
+    schemas.insert("ApprovalStatus".to_string(), json!({
+        "type": "object",
+        "properties": {
+            "approval_id": { "type": "string" },
+            "status": { "type": "string", "enum": ["pending", "approved", "rejected", "expired", "signed", "failed"] },
+            "message_hash": { "type": "string" },
+            "expires_at": { "type": "integer" },
+            "reason": { "type": "string" },
+            "signature": schema("SignatureRecid"),
+        },
+        "required": ["approval_id", "status", "message_hash", "expires_at"],
+    }));
+    schemas.insert(
+        "ErrorBody".to_string(),
+        object(
+            "Body of every error response",
+            json!({
+                "code": { "type": "string" },
+                "message": { "type": "string" },
+                "status": { "type": "integer" },
+                "request_id": { "type": "string" },
+            }),
+        ),
+    );
+    Value::Object(schemas)
+}
+
+/// The OpenAPI document of the protocol routes
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for operation in operations() {
+        paths.insert(
+            operation.path.to_string(),
+            json!({ "post": self::operation(&operation) }),
+        );
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Gotham server",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Two party ECDSA keygen and signing (Lindell 2017). Party two is the \
+                client, party one the server.",
+        },
+        "paths": paths,
+        "security": [{ "bearer": [] }],
+        "components": {
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+            },
+            "parameters": {
+                "KeyId": {
+                    "name": "id",
+                    "in": "path",
+                    "required": true,
+                    "description": "Id of the key, answered by `keygen/first`",
+                    "schema": { "type": "string" },
+                },
+                "IdempotencyKey": {
+                    "name": "Idempotency-Key",
+                    "in": "header",
+                    "required": false,
+                    "description": "Replays the response of an earlier request with the same key",
+                    "schema": { "type": "string" },
+                },
+                "ContentDigest": {
+                    "name": "Content-Digest",
+                    "in": "header",
+                    "required": false,
+                    "description": "`sha-256=:<base64>:` digest of the body, required with `Idempotency-Key`",
+                    "schema": { "type": "string" },
+                },
+            },
+            "schemas": schemas(),
+        },
+    })
+}
+
+#[get("/openapi.json")]
+pub fn openapi() -> Json<Value> {
+    Json(document())
+}
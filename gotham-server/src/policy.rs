@@ -0,0 +1,617 @@
+//! Rule based authorization of signing requests
+//!
+//! Policies are loaded from a TOML or JSON file (chosen by extension) configured with
+//! `policy_path`, and are reloaded when the file changed, checked every `policy_reload_interval`
+//! seconds. Rules of the `default` entry apply
+//! to every customer; a `customers` entry with a matching `id` overrides them field by field.
+//!
+//! ```toml
+//! [default]
+//! max_signatures_per_hour = 60
+//!
+//! [[customers]]
+//! id = "customer-1"
+//! max_signatures_per_day = 100
+//! allowed_positions = [{ x = 0, y_from = 0, y_to = 1000 }]
+//! denied_messages = ["deadbeef"]
+//! business_hours = { start_hour = 9, end_hour = 17, days = ["Mon", "Tue", "Wed", "Thu", "Fri"] }
+//! require_approval = true
+//!
+//! [[customers]]
+//! id = "customer-2"
+//! allowed_destinations = ["bc1q...", "0x52908400098527886e0f7030069857d2e4169ee7"]
+//! max_value_sats = 1000000
+//! max_value_gwei = 500000000
+//! ```
+//!
+//! Requests allowed by a policy with `require_approval` wait for an approver, see
+//! [`approval`](crate::approval). Destination and value rules only allow requests carrying the
+//! unsigned [`transaction`](crate::transaction) the message is the digest of, and apply to its
+//! payments: change outputs back to the signing key are left out. They deny contract calls other
+//! than ERC-20 token payments, whose recipient is their destination; value rules, which are in
+//! the currency of the chain, deny token payments.
+//!
+//! The timestamps of the signatures of the last day are kept in the store, so that rate limits
+//! hold across restarts.
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, Timelike, Utc, Weekday};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration as StdDuration, SystemTime};
+
+use crate::storage::schema::{Record, RecordKey};
+use crate::storage::Store;
+use crate::transaction::{Chain, TransactionSummary};
+
+/// Table of the signature timestamps of each customer, under an empty id
+pub const SIGNATURES_TABLE: &str = "PolicySignatures";
+
+const WEI_PER_GWEI: u128 = 1_000_000_000;
+const DEFAULT_RELOAD_INTERVAL: StdDuration = StdDuration::from_secs(30);
+
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyError {
+    #[error("cannot load policy file {0}: {1}")]
+    Load(String, String),
+    #[error("invalid policy: {0}")]
+    Invalid(String),
+    #[error("cannot access signature counters: {0}")]
+    Storage(String),
+}
+
+/// Allowed `y` range (inclusive) of child keys derived at position `x`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionRange {
+    pub x: u64,
+    pub y_from: u64,
+    pub y_to: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BusinessHours {
+    /// First hour of the day (0-23) at which signing is allowed
+    pub start_hour: u32,
+    /// Hour of the day (1-24) from which signing is no longer allowed
+    pub end_hour: u32,
+    /// Allowed days, e.g. `["Mon", "Tue"]`. Empty means every day.
+    #[serde(default)]
+    pub days: Vec<String>,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rules {
+    pub max_signatures_per_hour: Option<u32>,
+    pub max_signatures_per_day: Option<u32>,
+    pub allowed_positions: Option<Vec<PositionRange>>,
+    /// Hex encoded message hashes. When set, only these may be signed.
+    pub allowed_messages: Option<Vec<String>>,
+    /// Hex encoded message hashes that may never be signed
+    pub denied_messages: Option<Vec<String>>,
+    pub business_hours: Option<BusinessHours>,
+    /// Whether allowed requests must also be approved by an approver
+    pub require_approval: Option<bool>,
+    /// Bitcoin addresses and `0x` prefixed ethereum addresses transactions may pay to, change
+    /// outputs aside
+    pub allowed_destinations: Option<Vec<String>>,
+    /// Most satoshis a bitcoin transaction may pay, change left out
+    pub max_value_sats: Option<u64>,
+    /// Most gwei an ethereum transaction may pay
+    pub max_value_gwei: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomerRules {
+    pub id: String,
+    #[serde(flatten)]
+    pub rules: Rules,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub default: Rules,
+    #[serde(default)]
+    pub customers: Vec<CustomerRules>,
+}
+
+/// What is about to be signed
+#[derive(Debug, Clone)]
+pub struct SignContext {
+    pub customer_id: String,
+    /// Hex encoded message hash
+    pub message: String,
+    /// Derivation position of the child key, `None` when unknown to the caller
+    pub position: Option<(u64, u64)>,
+    pub time: DateTime<Utc>,
+    /// What the transaction the message is the digest of pays, when the caller sent it
+    pub transaction: Option<TransactionSummary>,
+}
+
+/// Reason of a denied signing request, meant to be shown to the customer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Denial {
+    pub reason: String,
+}
+
+impl Denial {
+    fn new(reason: String) -> Self {
+        Denial { reason }
+    }
+}
+
+pub(crate) fn normalize_hex(hex: &str) -> String {
+    let hex = hex.trim().to_lowercase();
+    let hex = hex.strip_prefix("0x").unwrap_or(&hex);
+    let hex = hex.trim_start_matches('0');
+    if hex.is_empty() {
+        "0".to_string()
+    } else {
+        hex.to_string()
+    }
+}
+
+/// Ethereum addresses are compared regardless of their checksum case
+fn same_destination(allowed: &str, destination: &str) -> bool {
+    if allowed.starts_with("0x") || allowed.starts_with("0X") {
+        allowed.eq_ignore_ascii_case(destination)
+    } else {
+        allowed == destination
+    }
+}
+
+fn parse_weekday(day: &str) -> Result<Weekday, PolicyError> {
+    day.parse::<Weekday>()
+        .map_err(|_| PolicyError::Invalid(format!("unknown day {}", day)))
+}
+
+impl Rules {
+    /// Rules of `self`, each overridden by the one of `other` when set
+    fn merge(&self, other: &Rules) -> Rules {
+        Rules {
+            max_signatures_per_hour: other
+                .max_signatures_per_hour
+                .or(self.max_signatures_per_hour),
+            max_signatures_per_day: other.max_signatures_per_day.or(self.max_signatures_per_day),
+            allowed_positions: other
+                .allowed_positions
+                .clone()
+                .or_else(|| self.allowed_positions.clone()),
+            allowed_messages: other
+                .allowed_messages
+                .clone()
+                .or_else(|| self.allowed_messages.clone()),
+            denied_messages: other
+                .denied_messages
+                .clone()
+                .or_else(|| self.denied_messages.clone()),
+            business_hours: other
+                .business_hours
+                .clone()
+                .or_else(|| self.business_hours.clone()),
+            require_approval: other.require_approval.or(self.require_approval),
+            allowed_destinations: other
+                .allowed_destinations
+                .clone()
+                .or_else(|| self.allowed_destinations.clone()),
+            max_value_sats: other.max_value_sats.or(self.max_value_sats),
+            max_value_gwei: other.max_value_gwei.or(self.max_value_gwei),
+        }
+    }
+
+    fn validate(&self) -> Result<(), PolicyError> {
+        if let Some(hours) = &self.business_hours {
+            if hours.start_hour >= hours.end_hour || hours.end_hour > 24 {
+                return Err(PolicyError::Invalid(format!(
+                    "business hours {}-{} are not a valid range",
+                    hours.start_hour, hours.end_hour
+                )));
+            }
+            for day in &hours.days {
+                parse_weekday(day)?;
+            }
+        }
+        for range in self.allowed_positions.iter().flatten() {
+            if range.y_from > range.y_to {
+                return Err(PolicyError::Invalid(format!(
+                    "position range {}-{} at x = {} is empty",
+                    range.y_from, range.y_to, range.x
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_transaction(&self, transaction: Option<&TransactionSummary>) -> Result<(), Denial> {
+        if self.allowed_destinations.is_none()
+            && self.max_value_sats.is_none()
+            && self.max_value_gwei.is_none()
+        {
+            return Ok(());
+        }
+        let transaction = transaction.ok_or_else(|| {
+            Denial::new("the unsigned transaction is required by policy".to_string())
+        })?;
+        if transaction.contract_call {
+            return Err(Denial::new(
+                "contract calls other than token payments cannot be checked by policy".to_string(),
+            ));
+        }
+
+        if let Some(allowed) = &self.allowed_destinations {
+            for payment in transaction
+                .payments
+                .iter()
+                .filter(|payment| !payment.change)
+            {
+                match &payment.destination {
+                    Some(destination)
+                        if allowed.iter().any(|a| same_destination(a, destination)) => {}
+                    Some(destination) => {
+                        return Err(Denial::new(format!(
+                            "destination {} is not allowed",
+                            destination
+                        )))
+                    }
+                    None => {
+                        return Err(Denial::new(
+                            "payments without a destination address are not allowed".to_string(),
+                        ))
+                    }
+                }
+            }
+        }
+
+        let (max, unit) = match transaction.chain {
+            Chain::Bitcoin => (self.max_value_sats.map(u128::from), "sats"),
+            Chain::Ethereum => (
+                self.max_value_gwei
+                    .map(|gwei| u128::from(gwei) * WEI_PER_GWEI),
+                "wei",
+            ),
+        };
+        if max.is_some()
+            && transaction
+                .payments
+                .iter()
+                .any(|payment| payment.token.is_some() && !payment.change)
+        {
+            return Err(Denial::new(format!(
+                "token amounts cannot be checked against a value limit in {}",
+                unit
+            )));
+        }
+        match max {
+            Some(max) if transaction.value() > max => Err(Denial::new(format!(
+                "value {} {} is above the limit of {} {}",
+                transaction.value(),
+                unit,
+                max,
+                unit
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    fn check(&self, context: &SignContext, history: &VecDeque<i64>) -> Result<(), Denial> {
+        let message = normalize_hex(&context.message);
+
+        if let Some(denied) = &self.denied_messages {
+            if denied.iter().any(|m| normalize_hex(m) == message) {
+                return Err(Denial::new(format!("message {} is denied", message)));
+            }
+        }
+        if let Some(allowed) = &self.allowed_messages {
+            if !allowed.iter().any(|m| normalize_hex(m) == message) {
+                return Err(Denial::new(format!("message {} is not allowed", message)));
+            }
+        }
+
+        if let Some(ranges) = &self.allowed_positions {
+            match context.position {
+                Some((x, y)) => {
+                    if !ranges
+                        .iter()
+                        .any(|r| r.x == x && r.y_from <= y && y <= r.y_to)
+                    {
+                        return Err(Denial::new(format!(
+                            "derivation position ({}, {}) is not allowed",
+                            x, y
+                        )));
+                    }
+                }
+                None => {
+                    return Err(Denial::new(
+                        "derivation position is required by policy".to_string(),
+                    ))
+                }
+            }
+        }
+
+        self.check_transaction(context.transaction.as_ref())?;
+
+        if let Some(hours) = &self.business_hours {
+            let offset = FixedOffset::east_opt(hours.utc_offset_minutes * 60)
+                .unwrap_or_else(|| FixedOffset::east_opt(0).unwrap());
+            let local = context.time.with_timezone(&offset);
+            let day_allowed = hours.days.is_empty()
+                || hours
+                    .days
+                    .iter()
+                    .filter_map(|d| parse_weekday(d).ok())
+                    .any(|d| d == local.weekday());
+            if !day_allowed || local.hour() < hours.start_hour || local.hour() >= hours.end_hour {
+                return Err(Denial::new(format!(
+                    "signing is only allowed between {}:00 and {}:00",
+                    hours.start_hour, hours.end_hour
+                )));
+            }
+        }
+
+        let now = context.time.timestamp();
+        let count_since = |seconds: i64| history.iter().filter(|t| **t > now - seconds).count();
+        if let Some(max) = self.max_signatures_per_hour {
+            if count_since(Duration::hours(1).num_seconds()) >= max as usize {
+                return Err(Denial::new(format!(
+                    "limit of {} signatures per hour reached",
+                    max
+                )));
+            }
+        }
+        if let Some(max) = self.max_signatures_per_day {
+            if count_since(Duration::days(1).num_seconds()) >= max as usize {
+                return Err(Denial::new(format!(
+                    "limit of {} signatures per day reached",
+                    max
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Policy {
+    pub fn from_file(path: &Path) -> Result<Self, PolicyError> {
+        let mut settings = config::Config::default();
+        settings
+            .merge(config::File::from(path))
+            .map_err(|e| PolicyError::Load(path.display().to_string(), e.to_string()))?;
+        let policy: Policy = settings
+            .try_into()
+            .map_err(|e| PolicyError::Load(path.display().to_string(), e.to_string()))?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn validate(&self) -> Result<(), PolicyError> {
+        self.default.validate()?;
+        for customer in &self.customers {
+            customer.rules.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn rules_for(&self, customer_id: &str) -> Rules {
+        match self.customers.iter().find(|c| c.id == customer_id) {
+            Some(customer) => self.default.merge(&customer.rules),
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// Evaluates signing requests against the current policy and counts granted signatures
+///
+/// Counters are read from the store once per customer, by [`PolicyEngine::load_signatures`],
+/// and written through by [`PolicyEngine::record_signature`]. Without a store they only live in
+/// memory.
+pub struct PolicyEngine {
+    path: Option<PathBuf>,
+    policy: RwLock<Arc<Policy>>,
+    modified: Mutex<Option<SystemTime>>,
+    reload_interval: StdDuration,
+    history: Mutex<HashMap<String, VecDeque<i64>>>,
+    store: Option<Arc<dyn Store>>,
+}
+
+impl PolicyEngine {
+    pub fn new(policy: Policy) -> Self {
+        PolicyEngine {
+            path: None,
+            policy: RwLock::new(Arc::new(policy)),
+            modified: Mutex::new(None),
+            reload_interval: DEFAULT_RELOAD_INTERVAL,
+            history: Mutex::new(HashMap::new()),
+            store: None,
+        }
+    }
+
+    /// Engine loading its policy from `path`, see [`PolicyEngine::spawn_reloader`]
+    pub fn from_file(path: PathBuf) -> Result<Self, PolicyError> {
+        let engine = PolicyEngine {
+            path: Some(path),
+            ..Self::new(Policy::default())
+        };
+        engine.reload()?;
+        Ok(engine)
+    }
+
+    /// Engine configured by the `policy_path` and `policy_reload_interval` settings, allowing
+    /// everything when `policy_path` is empty
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Self, PolicyError> {
+        let mut engine = match settings.get("policy_path") {
+            Some(path) if !path.is_empty() => Self::from_file(PathBuf::from(path))?,
+            _ => Self::new(Policy::default()),
+        };
+        match settings.get("policy_reload_interval") {
+            Some(seconds) if !seconds.is_empty() => {
+                let seconds = seconds
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| {
+                        PolicyError::Invalid(format!(
+                            "policy_reload_interval must be a positive number of seconds, got {}",
+                            seconds
+                        ))
+                    })?;
+                engine.reload_interval = StdDuration::from_secs(seconds);
+            }
+            _ => {}
+        }
+        Ok(engine)
+    }
+
+    /// Keeps the signature counters in `store`
+    pub fn with_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn policy(&self) -> Arc<Policy> {
+        self.policy.read().unwrap().clone()
+    }
+
+    /// Loads the policy file again. On error the current policy stays in place.
+    pub fn reload(&self) -> Result<(), PolicyError> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let policy = Policy::from_file(path)?;
+        *self.policy.write().unwrap() = Arc::new(policy);
+        *self.modified.lock().unwrap() = modified;
+        info!("Loaded signing policy from {}", path.display());
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != *self.modified.lock().unwrap() {
+            if let Err(e) = self.reload() {
+                error!("Keeping previous signing policy: {}", e);
+            }
+        }
+    }
+
+    /// Reloads the policy file every `policy_reload_interval` when it changed, until the
+    /// runtime shuts down
+    pub fn spawn_reloader(self: Arc<Self>) {
+        if self.path.is_none() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.reload_interval);
+            loop {
+                interval.tick().await;
+                self.reload_if_changed();
+            }
+        });
+    }
+
+    /// Reads the signature counters of `customer_id` from the store, unless already loaded.
+    /// [`PolicyEngine::authorize`] counts the signatures of customers not loaded from zero.
+    pub async fn load_signatures(&self, customer_id: &str) -> Result<(), PolicyError> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        if self.history.lock().unwrap().contains_key(customer_id) {
+            return Ok(());
+        }
+        let key = RecordKey::new(SIGNATURES_TABLE, customer_id, "").encode();
+        let timestamps = match store
+            .get(&key)
+            .await
+            .map_err(|e| PolicyError::Storage(e.to_string()))?
+        {
+            Some(value) => serde_json::from_slice::<Record<VecDeque<i64>>>(&value)
+                .map(|record| record.value)
+                .map_err(|e| PolicyError::Storage(e.to_string()))?,
+            None => VecDeque::new(),
+        };
+        self.history
+            .lock()
+            .unwrap()
+            .entry(customer_id.to_string())
+            .or_insert(timestamps);
+        Ok(())
+    }
+
+    pub fn authorize(&self, context: &SignContext) -> Result<(), Denial> {
+        let rules = self.policy().rules_for(&context.customer_id);
+        let history = self.history.lock().unwrap();
+        let empty = VecDeque::new();
+        rules.check(context, history.get(&context.customer_id).unwrap_or(&empty))
+    }
+
+    /// Whether a request allowed by the policy must also wait for an approver
+    pub fn requires_approval(&self, context: &SignContext) -> bool {
+        self.policy()
+            .rules_for(&context.customer_id)
+            .require_approval
+            .unwrap_or(false)
+    }
+
+    /// Authorizes the messages of a batch as if each was signed after the previous ones,
+    /// returning the index of the first denied message
+    pub fn authorize_batch(&self, contexts: &[SignContext]) -> Result<(), (usize, Denial)> {
+        let history = self.history.lock().unwrap();
+        let mut histories: HashMap<&str, VecDeque<i64>> = HashMap::new();
+        for (index, context) in contexts.iter().enumerate() {
+            let rules = self.policy().rules_for(&context.customer_id);
+            let timestamps = histories.entry(&context.customer_id).or_insert_with(|| {
+                history
+                    .get(&context.customer_id)
+                    .cloned()
+                    .unwrap_or_default()
+            });
+            rules
+                .check(context, timestamps)
+                .map_err(|denial| (index, denial))?;
+            timestamps.push_back(context.time.timestamp());
+        }
+        Ok(())
+    }
+
+    /// Counts a signature produced for `customer_id` towards the rate limits
+    pub async fn record_signature(
+        &self,
+        customer_id: &str,
+        time: DateTime<Utc>,
+    ) -> Result<(), PolicyError> {
+        let now = time.timestamp();
+        let timestamps = {
+            let mut history = self.history.lock().unwrap();
+            let timestamps = history.entry(customer_id.to_string()).or_default();
+            timestamps.push_back(now);
+            while let Some(oldest) = timestamps.front() {
+                if *oldest > now - Duration::days(1).num_seconds() {
+                    break;
+                }
+                timestamps.pop_front();
+            }
+            timestamps.clone()
+        };
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        let key = RecordKey::new(SIGNATURES_TABLE, customer_id, "").encode();
+        let value = serde_json::to_vec(&Record::new(SIGNATURES_TABLE, &timestamps))
+            .map_err(|e| PolicyError::Storage(e.to_string()))?;
+        store
+            .put(&key, &value)
+            .await
+            .map_err(|e| PolicyError::Storage(e.to_string()))
+    }
+}
@@ -0,0 +1,206 @@
+//! Pools of presignatures, making online signing a single round trip
+//!
+//! `sign/<id>/presign` runs the ephemeral key exchange of `sign/first` ahead of time for many
+//! signatures at once, and keeps the ephemeral keys of the server under a presignature id.
+//! `sign/<id>/presigned` then signs with the keys of one presignature, which is deleted before
+//! signing: it is consumed even when signing fails, and an ephemeral key is never used twice.
+//! Presignatures are taken with the lock of the `Db`, which serializes them within a server;
+//! servers sharing a store must not serve the same key.
+
+use rocket::serde::json::Json;
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use two_party_ecdsa::curv::BigInt;
+use two_party_ecdsa::kms::ecdsa::two_party::{party2, MasterKey1};
+use two_party_ecdsa::{party_one, party_two};
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::approval::{Approvals, SignOutcome};
+use crate::audit::AuditLog;
+use crate::auth::Customer;
+use crate::db::{db_index, get_value, insert_value, take_value, SharedDb};
+use crate::error::ApiError;
+use crate::idempotency::DigestedJson;
+use crate::keys;
+use crate::policy::PolicyEngine;
+use crate::sign::{self, Ephemeral, SignFirst};
+use crate::storage::schema::{self, RecordKey};
+use crate::storage::Store;
+use crate::transaction::UnsignedTransaction;
+
+/// Most presignatures kept for a key
+pub const MAX_POOL_SIZE: usize = 100;
+
+#[derive(Debug)]
+pub enum PresignStruct {
+    Presignature,
+}
+
+impl MPCStruct for PresignStruct {
+    fn to_string(&self) -> String {
+        match self {
+            PresignStruct::Presignature => "Presignature".to_string(),
+        }
+    }
+}
+
+/// Ephemeral message of the server for one presignature
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Presignature {
+    pub id: String,
+    pub eph_key_gen_first_message_party_one: party_one::EphKeyGenFirstMsg,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PresignedSignRequest {
+    pub presignature_id: String,
+    pub message: BigInt,
+    pub party_two_sign_message: party2::SignMessage,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<UnsignedTransaction>,
+}
+
+/// Presignatures of key `id` are stored under `<id>/<presignature id>`
+fn presignature_index(key: &DbIndex, presignature_id: &str) -> DbIndex {
+    DbIndex {
+        customerId: key.customerId.clone(),
+        id: format!("{}/{}", key.id, presignature_id),
+    }
+}
+
+/// Whether `record_id` is the id of a presignature of key `key_id`
+pub fn is_presignature_of(record_id: &str, key_id: &str) -> bool {
+    record_id
+        .strip_prefix(key_id)
+        .map_or(false, |rest| rest.starts_with('/'))
+}
+
+async fn pool_size(store: &dyn Store, key: &DbIndex) -> Result<usize, ApiError> {
+    let prefix = schema::table_prefix(&PresignStruct::Presignature.to_string());
+    let records = store
+        .scan(&prefix)
+        .await
+        .map_err(|e| ApiError::storage(format!("Failed to read presignatures: {}", e)))?;
+    Ok(records
+        .iter()
+        .filter_map(|(record_key, _)| RecordKey::decode(record_key))
+        .filter(|record_key| {
+            record_key.customer_id == key.customerId && is_presignature_of(&record_key.id, &key.id)
+        })
+        .count())
+}
+
+/// Removes the presignature from the store and returns its ephemeral keys
+pub(crate) async fn take(
+    db: &dyn Db,
+    store: &dyn Store,
+    key: &DbIndex,
+    presignature_id: &str,
+) -> Result<SignFirst, ApiError> {
+    let index = presignature_index(key, presignature_id);
+    take_value(db, store, &index, &PresignStruct::Presignature)
+        .await
+        .map_err(|e| match e.status.code {
+            404 => ApiError::not_found(format!(
+                "No presignature {} for key {}, or it was used",
+                presignature_id, key.id
+            )),
+            _ => e,
+        })
+}
+
+/// Adds a presignature to the pool of the key for every ephemeral message of party two
+#[post(
+    "/ecdsa/sign/<id>/presign",
+    format = "json",
+    data = "<eph_key_gen_first_messages_party_two>"
+)]
+pub async fn presign(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    eph_key_gen_first_messages_party_two: DigestedJson<Vec<party_two::EphKeyGenFirstMsg>>,
+) -> Result<Json<Vec<Presignature>>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    get_value::<MasterKey1>(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?;
+    keys::ensure_usable(db.as_ref(), &key).await?;
+
+    let requested = eph_key_gen_first_messages_party_two.len();
+    let available = MAX_POOL_SIZE.saturating_sub(pool_size(store.as_ref(), &key).await?);
+    if requested == 0 || requested > available {
+        return Err(ApiError::bad_request(format!(
+            "Key {} may get 1 to {} more presignatures",
+            id, available
+        )));
+    }
+
+    let mut presignatures = Vec::with_capacity(requested);
+    for eph_key_gen_first_message_party_two in eph_key_gen_first_messages_party_two.into_inner() {
+        let (eph_key_gen_first_message_party_one, eph_ec_key_pair_party1) =
+            MasterKey1::sign_first_message();
+        let presignature_id = uuid::Uuid::new_v4().to_string();
+        insert_value(
+            db.as_ref(),
+            &presignature_index(&key, &presignature_id),
+            &PresignStruct::Presignature,
+            &SignFirst {
+                eph_key_gen_first_message_party_two,
+                eph_ec_key_pair_party1,
+            },
+        )
+        .await?;
+        presignatures.push(Presignature {
+            id: presignature_id,
+            eph_key_gen_first_message_party_one,
+        });
+    }
+
+    Ok(Json(presignatures))
+}
+
+/// Signs with a presignature of the pool, as `sign/second` does after `sign/first`
+#[post("/ecdsa/sign/<id>/presigned", format = "json", data = "<request>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn sign_presigned(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    policy: &State<Arc<PolicyEngine>>,
+    audit: &State<AuditLog>,
+    approvals: &State<Arc<Approvals>>,
+    customer: Customer,
+    id: String,
+    request: DigestedJson<PresignedSignRequest>,
+) -> Result<SignOutcome, ApiError> {
+    let request = request.into_inner();
+    let transaction = request.transaction;
+    let ephemeral = Ephemeral::Presignature {
+        store: store.inner().clone(),
+        id: request.presignature_id,
+    };
+    let request = SignSecondMsgRequest {
+        message: request.message,
+        party_two_sign_message: request.party_two_sign_message,
+        x_pos_child_key: request.x_pos_child_key,
+        y_pos_child_key: request.y_pos_child_key,
+    };
+    sign::sign_audited(
+        state,
+        policy,
+        audit,
+        approvals,
+        &customer,
+        &id,
+        request,
+        transaction,
+        ephemeral,
+    )
+    .await
+}
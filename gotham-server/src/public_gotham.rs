@@ -1,8 +1,11 @@
 //!Public gotham implementation
 
+use chrono::Utc;
+use log::{error, warn};
 use rocket::async_trait;
 use std::collections::HashMap;
 use std::string::String;
+use std::sync::Arc;
 
 use two_party_ecdsa::party_one::Value;
 
@@ -11,36 +14,88 @@ use gotham_engine::sign::Sign;
 use gotham_engine::traits::*;
 use gotham_engine::types::*;
 
+use crate::deletion;
+use crate::keygen::{ActiveKey, KeygenStruct, ACTIVE_KEY_ID};
+use crate::keys::{KeyMetadata, KeyStruct};
+use crate::metrics::Metrics;
+use crate::policy::{PolicyEngine, SignContext};
+use crate::server::StartupError;
+use crate::sessions::Sessions;
+use crate::storage::schema::{Record, RecordKey};
+use crate::storage::{self, MeteredStore, Store};
+
+#[derive(Clone)]
 pub struct PublicGotham {
-    rocksdb_client: rocksdb::DB,
+    store: Arc<dyn Store>,
+    policy: Arc<PolicyEngine>,
+    sessions: Arc<Sessions>,
 }
-pub struct Authorizer {}
 
-fn get_settings_as_map() -> HashMap<String, String> {
+pub(crate) fn get_settings_as_map() -> Result<HashMap<String, String>, config::ConfigError> {
     let config_file = include_str!("../Settings.toml");
     let mut settings = config::Config::default();
     settings
         .merge(config::File::from_str(
             config_file,
             config::FileFormat::Toml,
-        ))
-        .unwrap()
-        .merge(config::Environment::new())
-        .unwrap();
+        ))?
+        .merge(config::Environment::new())?;
 
-    settings.try_into::<HashMap<String, String>>().unwrap()
+    settings.try_into::<HashMap<String, String>>()
 }
 
 impl PublicGotham {
-    pub fn new() -> Self {
-        let settings = get_settings_as_map();
-        let db_name = settings.get("db_name").unwrap_or(&"db".to_string()).clone();
-        if !db_name.chars().all(|e| char::is_ascii_alphanumeric(&e)) {
-            panic!("DB name is illegal, may only contain alphanumeric characters");
+    pub fn new(
+        settings: &HashMap<String, String>,
+        metrics: &Metrics,
+    ) -> Result<Self, StartupError> {
+        let store = storage::open(settings)?;
+        let store: Arc<dyn Store> = Arc::new(MeteredStore::new(store, metrics.store_duration()));
+        let policy = Arc::new(PolicyEngine::from_settings(settings)?.with_store(store.clone()));
+        let sessions = Arc::new(Sessions::from_settings(settings)?);
+
+        Ok(Self::with_store(Box::new(store), policy).with_sessions(sessions))
+    }
+
+    pub fn with_store(store: Box<dyn Store>, policy: Arc<PolicyEngine>) -> Self {
+        PublicGotham {
+            store: Arc::from(store),
+            policy,
+            sessions: Arc::new(Sessions::disabled()),
         }
-        let rocksdb_client = rocksdb::DB::open_default(format!("./{}", db_name)).unwrap();
+    }
+
+    /// Expires session records according to `sessions`
+    pub fn with_sessions(mut self, sessions: Arc<Sessions>) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    pub fn store(&self) -> Arc<dyn Store> {
+        self.store.clone()
+    }
+
+    pub fn policy(&self) -> Arc<PolicyEngine> {
+        self.policy.clone()
+    }
+
+    pub fn sessions(&self) -> Arc<Sessions> {
+        self.sessions.clone()
+    }
 
-        PublicGotham { rocksdb_client }
+    async fn put(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        let table_name = table_name.to_string();
+        let record =
+            serde_json::to_vec(&Record::new(&table_name, value)).map_err(database_error)?;
+        self.store
+            .put(&record_key(key, &table_name), &record)
+            .await
+            .map_err(database_error)
     }
 }
 
@@ -49,8 +104,14 @@ impl KeyGen for PublicGotham {}
 impl Sign for PublicGotham {}
 
 #[inline(always)]
-fn idify(user_id: String, id: String, name: &dyn MPCStruct) -> String {
-    format!("{}_{}_{}", user_id, id, name.to_string())
+fn record_key(key: &DbIndex, table_name: &str) -> Vec<u8> {
+    RecordKey::new(table_name, &key.customerId, &key.id).encode()
+}
+
+/// Reports a storage failure through the error type of the engine
+fn database_error(e: impl std::fmt::Display) -> DatabaseError {
+    error!("Storage failure: {}", e);
+    DatabaseError::QueryError(e.to_string())
 }
 
 #[async_trait]
@@ -61,9 +122,35 @@ impl Db for PublicGotham {
         table_name: &dyn MPCStruct,
         value: &dyn Value,
     ) -> Result<(), DatabaseError> {
-        let identifier = idify(key.clone().customerId, key.clone().id, table_name);
-        let v_string = serde_json::to_string(&value).unwrap();
-        let _ = self.rocksdb_client.put(identifier, v_string.clone());
+        // the id of a deleted key is never used again
+        if deletion::is_deleted(self.store.as_ref(), &key.customerId, &key.id)
+            .await
+            .map_err(database_error)?
+        {
+            return Err(DatabaseError::QueryError(format!(
+                "key {} was deleted",
+                key.id
+            )));
+        }
+        self.put(key, table_name, value).await?;
+        // a stored master key becomes the active key of the customer
+        if table_name.to_string() == EcdsaStruct::Party1MasterKey.to_string() {
+            // a rotated key keeps its metadata
+            if self.get(key, &KeyStruct::Metadata).await?.is_none() {
+                self.put(key, &KeyStruct::Metadata, &KeyMetadata::created_now())
+                    .await?;
+            }
+            let active_key = ActiveKey {
+                id: key.id.clone(),
+                activated_at: Utc::now().timestamp(),
+            };
+            let active_key_index = DbIndex {
+                customerId: key.customerId.clone(),
+                id: ACTIVE_KEY_ID.to_string(),
+            };
+            self.put(&active_key_index, &KeygenStruct::ActiveKey, &active_key)
+                .await?;
+        }
         Ok(())
     }
 
@@ -72,28 +159,69 @@ impl Db for PublicGotham {
         key: &DbIndex,
         table_name: &dyn MPCStruct,
     ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
-        let identifier = idify(key.clone().customerId, key.clone().id, table_name);
-        // debug!("Getting from db ({})", identifier);
-        let result = self.rocksdb_client.get(identifier.clone()).unwrap();
-        let vec_option: Option<Vec<u8>> = result.map(|v| v.to_vec());
+        let table_name = table_name.to_string();
+        let identifier = record_key(key, &table_name);
+        let vec_option = self.store.get(&identifier).await.map_err(database_error)?;
         match vec_option {
             Some(vec) => {
-                let final_val: Box<dyn Value> = serde_json::from_str(
-                    String::from_utf8(vec.clone())
-                        .expect("Found invalid UTF-8")
-                        .as_str(),
-                )
-                .unwrap();
-                Ok(Option::from(final_val))
+                let record: Record<Box<dyn Value>> =
+                    serde_json::from_slice(&vec).map_err(database_error)?;
+                // the next round of an abandoned session finds nothing to continue
+                if self
+                    .sessions
+                    .is_expired(&table_name, record.written_at, Utc::now())
+                {
+                    warn!("Session record {} of key {} expired", table_name, key.id);
+                    self.store
+                        .delete(&identifier)
+                        .await
+                        .map_err(database_error)?;
+                    return Ok(None);
+                }
+                Ok(Some(record.value))
             }
             None => Ok(None),
         }
     }
-    /// the granted function implements the logic of tx authorization. If no tx authorization is needed the function returns always true
+    /// the granted function implements the logic of tx authorization by evaluating the signing policy.
+    /// The derivation position and the transaction are not known here, so policies restricting
+    /// positions, destinations or values deny.
     fn granted(&self, message: &str, customer_id: &str) -> Result<bool, DatabaseError> {
-        Ok(true)
+        let context = SignContext {
+            customer_id: customer_id.to_string(),
+            message: message.to_string(),
+            position: None,
+            time: Utc::now(),
+            transaction: None,
+        };
+        match self.policy.authorize(&context) {
+            // requests waiting for an approver are only parked by the sign routes
+            Ok(()) if self.policy.requires_approval(&context) => {
+                warn!(
+                    "Denied signing for {}: approval required",
+                    context.customer_id
+                );
+                Ok(false)
+            }
+            Ok(()) => Ok(true),
+            Err(denial) => {
+                warn!(
+                    "Denied signing for {}: {}",
+                    context.customer_id, denial.reason
+                );
+                Ok(false)
+            }
+        }
     }
-    async fn has_active_share(&self, _user_id: &str) -> Result<bool, String> {
-        Ok(false)
+    /// Whether the customer has an active key
+    async fn has_active_share(&self, user_id: &str) -> Result<bool, String> {
+        let active_key_index = DbIndex {
+            customerId: user_id.to_string(),
+            id: ACTIVE_KEY_ID.to_string(),
+        };
+        self.get(&active_key_index, &KeygenStruct::ActiveKey)
+            .await
+            .map(|active_key| active_key.is_some())
+            .map_err(|e| format!("Failed to read active key from db: {}", e))
     }
 }
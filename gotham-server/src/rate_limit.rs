@@ -0,0 +1,344 @@
+//! Token bucket rate limiting of the keygen, rotation and sign routes
+//!
+//! Every route class has its own limits, per customer id and per source IP, set in the
+//! settings as `<requests>/<seconds>`: `rate_limit_keygen_customer = "10/3600"` lets a
+//! customer send 10 keygen messages at once, then one every 6 minutes. Rotation rounds count
+//! as keygen messages. Rejected requests get a `429` with the `rate_limited` code and a
+//! `Retry-After` header.
+//!
+//! The source IP is the peer address of the connection. Only when the peer is one of the
+//! comma separated `trusted_proxies` is the `X-Real-IP` header it sets used instead.
+
+use log::warn;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
+use rocket::http::{Method, Status};
+use rocket::request::Outcome;
+use rocket::response::{self, Responder, Response};
+use rocket::{get, Data, Request};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::Customer;
+use crate::error::{ApiError, ErrorCode};
+
+/// Route rejected requests are sent to
+const RATE_LIMITED_PATH: &str = "/rate-limited";
+/// Number of buckets above which refilled buckets are forgotten
+const MAX_BUCKETS: usize = 100_000;
+/// Buckets looked at for eviction by each request, once there are more than `MAX_BUCKETS`
+const EVICTED_PER_REQUEST: usize = 8;
+/// Header holding the client address set by a trusted proxy
+pub(crate) const REAL_IP_HEADER: &str = "X-Real-IP";
+
+#[derive(Debug, thiserror::Error)]
+pub enum RateLimitError {
+    #[error("invalid rate limit {0}: {1}")]
+    Invalid(String, String),
+    #[error("invalid trusted proxy {0}")]
+    TrustedProxy(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Keygen,
+    Sign,
+}
+
+impl RouteClass {
+    pub fn of(path: &str) -> Option<Self> {
+        if path == "/ecdsa/keygen/active" {
+            None
+        } else if path.starts_with("/ecdsa/keygen/") || path.starts_with("/ecdsa/rotate/") {
+            Some(RouteClass::Keygen)
+        } else if path.starts_with("/ecdsa/sign/") {
+            Some(RouteClass::Sign)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RouteClass::Keygen => "keygen",
+            RouteClass::Sign => "sign",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Customer,
+    Ip,
+}
+
+impl Scope {
+    fn name(&self) -> &'static str {
+        match self {
+            Scope::Customer => "customer",
+            Scope::Ip => "ip",
+        }
+    }
+}
+
+/// Bucket of `capacity` tokens, refilled at `per_second`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub capacity: f64,
+    pub per_second: f64,
+}
+
+impl Limit {
+    /// `<requests>/<seconds>`
+    pub fn parse(limit: &str) -> Result<Self, String> {
+        let (requests, seconds) = limit
+            .split_once('/')
+            .ok_or_else(|| "expected <requests>/<seconds>".to_string())?;
+        let requests: u32 = requests.trim().parse().map_err(|e| format!("{}", e))?;
+        let seconds: u32 = seconds.trim().parse().map_err(|e| format!("{}", e))?;
+        if requests == 0 || seconds == 0 {
+            return Err("requests and seconds must be positive".to_string());
+        }
+        Ok(Limit {
+            capacity: requests as f64,
+            per_second: requests as f64 / seconds as f64,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.capacity);
+        self.updated = now;
+    }
+}
+
+type BucketKey = (RouteClass, Scope, String);
+
+/// Buckets, and the order in which they are looked at for eviction
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<BucketKey, Bucket>,
+    order: VecDeque<BucketKey>,
+}
+
+pub struct RateLimiter {
+    limits: HashMap<(RouteClass, Scope), Limit>,
+    trusted_proxies: Vec<IpAddr>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: HashMap<(RouteClass, Scope), Limit>) -> Self {
+        RateLimiter {
+            limits,
+            trusted_proxies: Vec::new(),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes the client address from the `X-Real-IP` header of requests sent by `proxies`
+    pub fn with_trusted_proxies(mut self, proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// Limits set by the `rate_limit_<class>_<scope>` settings; empty ones do not limit
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Self, RateLimitError> {
+        let mut limits = HashMap::new();
+        for class in [RouteClass::Keygen, RouteClass::Sign] {
+            for scope in [Scope::Customer, Scope::Ip] {
+                let name = format!("rate_limit_{}_{}", class.name(), scope.name());
+                match settings.get(&name).map(|limit| limit.trim()) {
+                    Some(limit) if !limit.is_empty() => {
+                        let limit =
+                            Limit::parse(limit).map_err(|e| RateLimitError::Invalid(name, e))?;
+                        limits.insert((class, scope), limit);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let trusted_proxies = settings
+            .get("trusted_proxies")
+            .map(|proxies| proxies.split(',').map(str::trim))
+            .into_iter()
+            .flatten()
+            .filter(|proxy| !proxy.is_empty())
+            .map(|proxy| {
+                proxy
+                    .parse::<IpAddr>()
+                    .map_err(|_| RateLimitError::TrustedProxy(proxy.to_string()))
+            })
+            .collect::<Result<Vec<IpAddr>, RateLimitError>>()?;
+        Ok(Self::new(limits).with_trusted_proxies(trusted_proxies))
+    }
+
+    /// Address of the client of a connection from `peer`: the `real_ip` set by a trusted proxy,
+    /// or the peer itself
+    pub fn client_ip(&self, peer: Option<IpAddr>, real_ip: Option<&str>) -> Option<IpAddr> {
+        match (peer, real_ip) {
+            (Some(peer), Some(real_ip)) if self.trusted_proxies.contains(&peer) => {
+                real_ip.trim().parse().ok().or(Some(peer))
+            }
+            (peer, _) => peer,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.limits.is_empty()
+    }
+
+    /// Takes a token from the bucket of every limited scope of the request, or returns the
+    /// time after which they will all have one. Nothing is taken from a rejected request.
+    pub fn check(
+        &self,
+        class: RouteClass,
+        customer_id: Option<&str>,
+        ip: Option<&str>,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let keys: Vec<(BucketKey, Limit)> = [(Scope::Customer, customer_id), (Scope::Ip, ip)]
+            .into_iter()
+            .filter_map(|(scope, id)| {
+                let limit = self.limits.get(&(class, scope))?;
+                Some(((class, scope, id?.to_string()), *limit))
+            })
+            .collect();
+
+        let mut guard = self.buckets.lock().unwrap();
+        let Buckets { buckets, order } = &mut *guard;
+        let mut wait = Duration::ZERO;
+        for (key, limit) in &keys {
+            let bucket = buckets.entry(key.clone()).or_insert_with(|| {
+                order.push_back(key.clone());
+                Bucket {
+                    tokens: limit.capacity,
+                    updated: now,
+                }
+            });
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64(
+                    (1.0 - bucket.tokens) / limit.per_second,
+                ));
+            }
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        for (key, _) in &keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        // the oldest buckets are looked at a few at a time, refilled ones are forgotten
+        if buckets.len() > MAX_BUCKETS {
+            for _ in 0..EVICTED_PER_REQUEST {
+                let key = match order.pop_front() {
+                    Some(key) => key,
+                    None => break,
+                };
+                let limit = &self.limits[&(key.0, key.1)];
+                let refilled = match buckets.get_mut(&key) {
+                    Some(bucket) => {
+                        bucket.refill(limit, now);
+                        bucket.tokens >= limit.capacity
+                    }
+                    None => continue,
+                };
+                if refilled {
+                    buckets.remove(&key);
+                } else {
+                    order.push_back(key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Seconds after which a rate limited request may be sent again
+struct RetryAfter(u64);
+
+/// Fairing sending requests over their limits to the route answering `429`
+pub struct RateLimits(pub Arc<RateLimiter>);
+
+#[rocket::async_trait]
+impl Fairing for RateLimits {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limits",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !self.0.is_enabled() {
+            return;
+        }
+        let class = match RouteClass::of(request.uri().path().as_str()) {
+            Some(class) => class,
+            None => return,
+        };
+        // unauthenticated requests are only limited by IP, and rejected by their route
+        let customer_id = match request.guard::<Customer>().await {
+            Outcome::Success(customer) => Some(customer.id),
+            _ => None,
+        };
+        let ip = self
+            .0
+            .client_ip(
+                request.remote().map(|remote| remote.ip()),
+                request.headers().get_one(REAL_IP_HEADER),
+            )
+            .map(|ip| ip.to_string());
+
+        if let Err(wait) =
+            self.0
+                .check(class, customer_id.as_deref(), ip.as_deref(), Instant::now())
+        {
+            warn!(
+                "Rate limited {} request of {} from {}",
+                class.name(),
+                customer_id.as_deref().unwrap_or("unknown customer"),
+                ip.as_deref().unwrap_or("unknown address")
+            );
+            let retry_after = wait.as_secs_f64().ceil() as u64;
+            request.local_cache(|| RetryAfter(retry_after.max(1)));
+            request.set_method(Method::Get);
+            request.set_uri(Origin::parse(RATE_LIMITED_PATH).unwrap());
+        }
+    }
+}
+
+/// `429` answer, with the `Retry-After` of the rejected request
+pub struct RateLimited;
+
+impl<'r> Responder<'r, 'static> for RateLimited {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let retry_after = request.local_cache(|| RetryAfter(1)).0;
+        let error = ApiError::new(
+            Status::TooManyRequests,
+            ErrorCode::RateLimited,
+            format!("Too many requests, retry in {} seconds", retry_after),
+        );
+        Response::build_from(error.respond_to(request)?)
+            .raw_header("Retry-After", retry_after.to_string())
+            .ok()
+    }
+}
+
+#[get("/rate-limited")]
+pub fn rate_limited() -> RateLimited {
+    RateLimited
+}
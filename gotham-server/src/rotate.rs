@@ -0,0 +1,282 @@
+//! Two party key rotation (coin flip + PDL rounds) for an existing party one master key
+//!
+//! The rounds are functions of the state left by the previous one, kept in the tables of
+//! [`RotateStruct`] by the HTTP routes and in memory by the WebSocket sessions.
+
+use rocket::serde::json::Json;
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::sync::Arc;
+
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::curv::elliptic::curves::secp256_k1::FE;
+use two_party_ecdsa::curv::BigInt;
+use two_party_ecdsa::kms::ecdsa::two_party::{party1, MasterKey1};
+use two_party_ecdsa::kms::rotation::two_party::party1::Rotation1;
+use two_party_ecdsa::kms::rotation::two_party::Rotation;
+use two_party_ecdsa::party_one::Value;
+use two_party_ecdsa::{party_one, party_two};
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::auth::Customer;
+use crate::db::{db_index, delete_value, get_value, insert_value, take_value, SharedDb};
+use crate::error::ApiError;
+use crate::idempotency::DigestedJson;
+use crate::keys;
+use crate::storage::Store;
+
+/// Tables holding the intermediate state of a rotation session
+#[derive(Debug)]
+pub enum RotateStruct {
+    CoinFlip,
+    First,
+    Third,
+}
+
+impl MPCStruct for RotateStruct {
+    fn to_string(&self) -> String {
+        match self {
+            RotateStruct::CoinFlip => "RotateCoinFlip".to_string(),
+            RotateStruct::First => "RotateFirst".to_string(),
+            RotateStruct::Third => "RotateThird".to_string(),
+        }
+    }
+}
+
+/// Party one coin flip commitment opening, kept until the second round
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RotateCoinFlip {
+    pub m1: FE,
+    pub r1: FE,
+}
+
+/// Shared randomness and the refreshed party one private share
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RotateFirst {
+    pub random1: Rotation,
+    pub rotation_party_one_first_message: party1::RotationParty1Message1,
+    pub party_one_private_new: party_one::Party1Private,
+}
+
+/// PDL decommitment of party one and the first PDL message of party two
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RotateThird {
+    pub rotation_party_two_first_message: party_two::PDLFirstMessage,
+    pub party_one_pdl_decommit: party_one::PDLdecommit,
+    pub alpha: BigInt,
+}
+
+#[typetag::serde]
+impl Value for RotateCoinFlip {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl Value for RotateFirst {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[typetag::serde]
+impl Value for RotateThird {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Commits to the coin flip of party one
+pub fn first_round() -> (coin_flip_optimal_rounds::Party1FirstMessage, RotateCoinFlip) {
+    let (party1_coin_flip_first_message, m1, r1) = Rotation1::key_rotate_first_message();
+    (party1_coin_flip_first_message, RotateCoinFlip { m1, r1 })
+}
+
+/// Completes the coin flip and refreshes the private share of party one
+pub fn second_round(
+    party_one_master_key: &MasterKey1,
+    coin_flip: RotateCoinFlip,
+    party2_first_message: &coin_flip_optimal_rounds::Party2FirstMessage,
+) -> (
+    (
+        coin_flip_optimal_rounds::Party1SecondMessage,
+        party1::RotationParty1Message1,
+    ),
+    RotateFirst,
+) {
+    let (party1_second_message, random1) =
+        Rotation1::key_rotate_second_message(party2_first_message, &coin_flip.m1, &coin_flip.r1);
+    let (rotation_party_one_first_message, party_one_private_new) =
+        party_one_master_key.rotation_first_message(&random1);
+    (
+        (
+            party1_second_message,
+            rotation_party_one_first_message.clone(),
+        ),
+        RotateFirst {
+            random1,
+            rotation_party_one_first_message,
+            party_one_private_new,
+        },
+    )
+}
+
+pub fn third_round(
+    first: &RotateFirst,
+    rotation_party_two_first_message: party_two::PDLFirstMessage,
+) -> (party_one::PDLFirstMessage, RotateThird) {
+    let (rotation_party_one_second_message, party_one_pdl_decommit, alpha) =
+        MasterKey1::rotation_second_message(
+            &rotation_party_two_first_message,
+            &first.party_one_private_new,
+        );
+    (
+        rotation_party_one_second_message,
+        RotateThird {
+            rotation_party_two_first_message,
+            party_one_pdl_decommit,
+            alpha,
+        },
+    )
+}
+
+/// Rotates the master key of party one once party two's PDL proof verifies
+pub fn fourth_round(
+    id: &str,
+    party_one_master_key: MasterKey1,
+    first: RotateFirst,
+    third: RotateThird,
+    rotation_party_two_second_message: &party_two::PDLSecondMessage,
+) -> Result<(party_one::PDLSecondMessage, MasterKey1), ApiError> {
+    party_one_master_key
+        .rotation_third_message(
+            &first.rotation_party_one_first_message,
+            first.party_one_private_new,
+            &first.random1,
+            &third.rotation_party_two_first_message,
+            rotation_party_two_second_message,
+            third.party_one_pdl_decommit,
+            third.alpha,
+        )
+        .map_err(|_| {
+            ApiError::protocol(format!("Rotation of key {} failed: invalid PDL proof", id))
+        })
+}
+
+#[post("/ecdsa/rotate/<id>/first", format = "json")]
+pub async fn rotate_first(
+    state: &State<SharedDb>,
+    customer: Customer,
+    id: String,
+) -> Result<Json<coin_flip_optimal_rounds::Party1FirstMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    // make sure the key exists before committing to a coin flip
+    keys::usable_master_key(db.as_ref(), &key).await?;
+
+    let (party1_coin_flip_first_message, coin_flip) = first_round();
+    insert_value(db.as_ref(), &key, &RotateStruct::CoinFlip, &coin_flip).await?;
+
+    Ok(Json(party1_coin_flip_first_message))
+}
+
+#[post(
+    "/ecdsa/rotate/<id>/second",
+    format = "json",
+    data = "<party2_first_message>"
+)]
+pub async fn rotate_second(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    party2_first_message: DigestedJson<coin_flip_optimal_rounds::Party2FirstMessage>,
+) -> Result<
+    Json<(
+        coin_flip_optimal_rounds::Party1SecondMessage,
+        party1::RotationParty1Message1,
+    )>,
+    ApiError,
+> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let party_one_master_key = keys::usable_master_key(db.as_ref(), &key).await?;
+    let coin_flip: RotateCoinFlip =
+        take_value(db.as_ref(), store.as_ref(), &key, &RotateStruct::CoinFlip).await?;
+
+    let (messages, first) = second_round(&party_one_master_key, coin_flip, &party2_first_message);
+    insert_value(db.as_ref(), &key, &RotateStruct::First, &first).await?;
+
+    Ok(Json(messages))
+}
+
+#[post(
+    "/ecdsa/rotate/<id>/third",
+    format = "json",
+    data = "<rotation_party_two_first_message>"
+)]
+pub async fn rotate_third(
+    state: &State<SharedDb>,
+    customer: Customer,
+    id: String,
+    rotation_party_two_first_message: DigestedJson<party_two::PDLFirstMessage>,
+) -> Result<Json<party_one::PDLFirstMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    keys::ensure_usable(db.as_ref(), &key).await?;
+    let first: RotateFirst = get_value(db.as_ref(), &key, &RotateStruct::First).await?;
+
+    let (rotation_party_one_second_message, third) =
+        third_round(&first, rotation_party_two_first_message.into_inner());
+    insert_value(db.as_ref(), &key, &RotateStruct::Third, &third).await?;
+
+    Ok(Json(rotation_party_one_second_message))
+}
+
+/// Last rotation round. The rotated master key replaces the previous one under the same id
+/// only once party two's PDL proof verifies; the db lock is held for the whole round so no
+/// signing session can observe a half rotated key. The state of the session is deleted even
+/// when the proof does not verify, so that a rotation runs its fourth round once.
+#[post(
+    "/ecdsa/rotate/<id>/fourth",
+    format = "json",
+    data = "<rotation_party_two_second_message>"
+)]
+pub async fn rotate_fourth(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    customer: Customer,
+    id: String,
+    rotation_party_two_second_message: DigestedJson<party_two::PDLSecondMessage>,
+) -> Result<Json<party_one::PDLSecondMessage>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let party_one_master_key = keys::usable_master_key(db.as_ref(), &key).await?;
+    let first: RotateFirst =
+        take_value(db.as_ref(), store.as_ref(), &key, &RotateStruct::First).await?;
+    let third: RotateThird =
+        take_value(db.as_ref(), store.as_ref(), &key, &RotateStruct::Third).await?;
+    delete_value(store.as_ref(), &key, &RotateStruct::CoinFlip).await?;
+
+    let (rotation_party_one_third_message, party_one_master_key_rotated) = fourth_round(
+        &id,
+        party_one_master_key,
+        first,
+        third,
+        &rotation_party_two_second_message,
+    )?;
+
+    insert_value(
+        db.as_ref(),
+        &key,
+        &EcdsaStruct::Party1MasterKey,
+        &party_one_master_key_rotated,
+    )
+    .await?;
+
+    Ok(Json(rotation_party_one_third_message))
+}
@@ -1,39 +1,188 @@
-use crate::public_gotham::PublicGotham;
-use rocket::{self, catch, catchers, routes, Build, Request, Rocket};
+use crate::approval::{self, ApprovalError, Approvals};
+use crate::audit::{AuditError, AuditLog};
+use crate::auth::{AuthError, Authorizer};
+use crate::db::SharedDb;
+use crate::deletion;
+use crate::error::{default_catcher, ApiErrors};
+use crate::health;
+use crate::idempotency::{self, Idempotency, IdempotencyError, IdempotencyKeys};
+use crate::keygen;
+use crate::keys;
+use crate::metrics::{self, Metrics, RequestMetrics};
+use crate::openapi;
+use crate::policy::PolicyError;
+use crate::presign;
+use crate::public_gotham::{get_settings_as_map, PublicGotham};
+use crate::rate_limit::{self, RateLimitError, RateLimiter, RateLimits};
+use crate::rotate;
+use crate::sessions::SessionError;
+use crate::sign;
+use crate::storage::{schema, StoreError};
+use crate::websocket::{self, SessionServer, WebSocketError};
+use log::{error, warn};
+use rocket::fairing::AdHoc;
+use rocket::{self, catchers, routes, Build, Rocket};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::Mutex;
 
-#[catch(500)]
-fn internal_error() -> &'static str {
-    "Internal server error"
+/// Invalid configuration, reported instead of launching the server
+#[derive(Debug, thiserror::Error)]
+pub enum StartupError {
+    #[error("cannot read settings: {0}")]
+    Settings(#[from] config::ConfigError),
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error(transparent)]
+    Policy(#[from] PolicyError),
+    #[error(transparent)]
+    RateLimit(#[from] RateLimitError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Audit(#[from] AuditError),
+    #[error(transparent)]
+    Session(#[from] SessionError),
+    #[error(transparent)]
+    Idempotency(#[from] IdempotencyError),
+    #[error(transparent)]
+    Approval(#[from] ApprovalError),
+    #[error(transparent)]
+    WebSocket(#[from] WebSocketError),
 }
 
-#[catch(400)]
-fn bad_request() -> &'static str {
-    "Bad request"
+pub fn get_server() -> Result<Rocket<Build>, StartupError> {
+    get_server_with_settings(get_settings_as_map()?)
 }
 
-#[catch(404)]
-fn not_found(req: &Request) -> String {
-    format!("Unknown route '{}'.", req.uri())
-}
-
-pub fn get_server() -> Rocket<Build> {
-    let x = PublicGotham::new();
-    rocket::Rocket::build()
-        .register("/", catchers![internal_error, not_found, bad_request])
+/// Server configured by `settings` instead of Settings.toml and the environment
+pub fn get_server_with_settings(
+    settings: HashMap<String, String>,
+) -> Result<Rocket<Build>, StartupError> {
+    let authorizer = Authorizer::from_settings(&settings)?;
+    if authorizer.is_passthrough() {
+        warn!("Authentication is disabled, every request is served as a single customer");
+    }
+    let rate_limiter = Arc::new(RateLimiter::from_settings(&settings)?);
+    let audit_log = AuditLog::from_settings(&settings)?;
+    let metrics = Arc::new(Metrics::new());
+    let x = PublicGotham::new(&settings, &metrics)?;
+    let policy = x.policy();
+    let policy_reloader = policy.clone();
+    let store = x.store();
+    let schema_store = store.clone();
+    let sessions = x.sessions();
+    let swept_sessions = sessions.clone();
+    let sweeper_store = store.clone();
+    let idempotent_responses = Arc::new(Idempotency::from_settings(&settings, store.clone())?);
+    let idempotency_sweeper = idempotent_responses.clone();
+    let approvals = Arc::new(Approvals::from_settings(&settings, store.clone())?);
+    let approval_sweeper = approvals.clone();
+    let websocket_port = websocket::port_from_settings(&settings)?;
+    let websocket_address = websocket::address_from_settings(&settings)?;
+    let db: SharedDb = Arc::new(Mutex::new(Box::new(x)));
+    let session_server = Arc::new(SessionServer::new(
+        db.clone(),
+        authorizer.clone(),
+        policy.clone(),
+        audit_log.clone(),
+        approvals.clone(),
+        rate_limiter.clone(),
+        metrics.clone(),
+        idempotent_responses.clone(),
+    ));
+    let websocket_sessions = session_server.clone();
+    Ok(rocket::Rocket::build()
+        .attach(ApiErrors)
+        .attach(RequestMetrics(metrics.clone()))
+        .attach(RateLimits(rate_limiter))
+        .attach(IdempotencyKeys(idempotent_responses))
+        // refuse to start on a store written with another schema
+        .attach(AdHoc::try_on_ignite(
+            "Storage schema",
+            move |rocket| async move {
+                match schema::check_schema(schema_store.as_ref()).await {
+                    Ok(()) => Ok(rocket),
+                    Err(e) => {
+                        error!("{}", e);
+                        Err(rocket)
+                    }
+                }
+            },
+        ))
+        .attach(AdHoc::on_liftoff("Session sweeper", move |_| {
+            Box::pin(async move { swept_sessions.spawn_sweeper(sweeper_store) })
+        }))
+        .attach(AdHoc::on_liftoff(
+            "Idempotent response sweeper",
+            move |_| Box::pin(async move { idempotency_sweeper.spawn_sweeper() }),
+        ))
+        .attach(AdHoc::on_liftoff("Policy reloader", move |_| {
+            Box::pin(async move { policy_reloader.spawn_reloader() })
+        }))
+        .attach(AdHoc::on_liftoff("Approval sweeper", move |_| {
+            Box::pin(async move { approval_sweeper.spawn_sweeper() })
+        }))
+        .attach(AdHoc::on_liftoff("WebSocket sessions", move |rocket| {
+            let tls_enabled = rocket.config().tls_enabled();
+            Box::pin(async move {
+                if let Some(port) = websocket_port {
+                    if tls_enabled {
+                        warn!(
+                            "WebSocket sessions on port {} do not use TLS, put a proxy terminating it in front",
+                            port
+                        );
+                    }
+                    websocket_sessions.spawn(SocketAddr::new(websocket_address, port))
+                }
+            })
+        }))
+        .register("/", catchers![default_catcher])
         .mount(
             "/",
             routes![
-                gotham_engine::routes::wrap_keygen_first,
-                gotham_engine::routes::wrap_keygen_second,
-                gotham_engine::routes::wrap_keygen_third,
-                gotham_engine::routes::wrap_keygen_fourth,
-                gotham_engine::routes::wrap_chain_code_first_message,
-                gotham_engine::routes::wrap_chain_code_second_message,
-                gotham_engine::routes::wrap_sign_first,
-                gotham_engine::routes::wrap_sign_second,
+                health::live,
+                health::ready,
+                metrics::metrics,
+                openapi::openapi,
+                rate_limit::rate_limited,
+                idempotency::idempotent,
+                keygen::keygen_first,
+                keygen::keygen_second,
+                keygen::keygen_third,
+                keygen::keygen_fourth,
+                keygen::chain_code_first,
+                keygen::chain_code_second,
+                keygen::active_key,
+                keys::recover,
+                keys::freeze,
+                keys::unfreeze,
+                keys::revoke,
+                deletion::delete,
+                sign::sign_first,
+                sign::sign_second,
+                sign::sign_batch_first,
+                sign::sign_batch_second,
+                presign::presign,
+                presign::sign_presigned,
+                approval::approval_status,
+                approval::list_approvals,
+                approval::approve,
+                approval::reject,
+                rotate::rotate_first,
+                rotate::rotate_second,
+                rotate::rotate_third,
+                rotate::rotate_fourth,
             ],
         )
-        .manage(Mutex::new(Box::new(x) as Box<dyn gotham_engine::traits::Db>))
+        .manage(authorizer)
+        .manage(policy)
+        .manage(store)
+        .manage(metrics)
+        .manage(sessions)
+        .manage(audit_log)
+        .manage(approvals)
+        .manage(session_server)
+        .manage(db))
 }
@@ -0,0 +1,239 @@
+//! Expiry of the intermediate state of keygen, chain code, signing and rotation sessions
+//!
+//! Every record carries the time it was written. Records of the session tables below that are
+//! older than `session_ttl` seconds read as absent, so the next round of an abandoned session
+//! is rejected, and are deleted every `session_sweep_interval` seconds. Master keys, their
+//! metadata and the active keys are never expired.
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::de::IgnoredAny;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use gotham_engine::traits::MPCStruct;
+use gotham_engine::types::EcdsaStruct;
+
+use crate::keygen::KeygenStruct;
+use crate::rotate::RotateStruct;
+use crate::sign::SignStruct;
+use crate::storage::schema::{self, Record, RecordKey};
+use crate::storage::{Store, StoreError};
+
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("invalid setting {0}: {1}")]
+    Invalid(String, String),
+}
+
+/// Protocols whose sessions write intermediate records
+pub const PROTOCOLS: [&str; 3] = ["keygen", "sign", "rotate"];
+
+/// Tables only read by a later round of the session that wrote them, with its protocol
+fn protocol_tables() -> Vec<(&'static str, String)> {
+    let keygen: [&dyn MPCStruct; 19] = [
+        &KeygenStruct::First,
+        &KeygenStruct::Second,
+        &KeygenStruct::Third,
+        &KeygenStruct::Fourth,
+        &KeygenStruct::ChainCode,
+        // written by the keygen routes of the engine, which older servers served
+        &EcdsaStruct::KeyGenFirstMsg,
+        &EcdsaStruct::CommWitness,
+        &EcdsaStruct::EcKeyPair,
+        &EcdsaStruct::PaillierKeyPair,
+        &EcdsaStruct::Party1Private,
+        &EcdsaStruct::Party2Public,
+        &EcdsaStruct::PDLProver,
+        &EcdsaStruct::PDLDecommit,
+        &EcdsaStruct::Alpha,
+        &EcdsaStruct::Party2PDLFirstMsg,
+        &EcdsaStruct::CCKeyGenFirstMsg,
+        &EcdsaStruct::CCCommWitness,
+        &EcdsaStruct::CCEcKeyPair,
+        &EcdsaStruct::CC,
+    ];
+    let sign: [&dyn MPCStruct; 2] = [&SignStruct::First, &SignStruct::BatchFirst];
+    let rotate: [&dyn MPCStruct; 3] = [
+        &RotateStruct::CoinFlip,
+        &RotateStruct::First,
+        &RotateStruct::Third,
+    ];
+    let tables = |protocol: &'static str, tables: &[&dyn MPCStruct]| {
+        tables
+            .iter()
+            .map(|table| (protocol, table.to_string()))
+            .collect::<Vec<_>>()
+    };
+    [
+        tables("keygen", &keygen),
+        tables("sign", &sign),
+        tables("rotate", &rotate),
+    ]
+    .concat()
+}
+
+/// Tables only read by a later round of the session that wrote them
+pub fn session_tables() -> Vec<String> {
+    protocol_tables()
+        .into_iter()
+        .map(|(_, table)| table)
+        .collect()
+}
+
+fn written_at(value: &[u8]) -> Option<i64> {
+    serde_json::from_slice::<Record<IgnoredAny>>(value)
+        .ok()
+        .and_then(|record| record.written_at)
+}
+
+pub struct Sessions {
+    /// `None` when sessions never expire
+    ttl: Option<Duration>,
+    sweep_interval: Duration,
+    /// Session tables, with the protocol writing them
+    tables: HashMap<String, &'static str>,
+}
+
+impl Sessions {
+    pub fn new(ttl: Option<Duration>, sweep_interval: Duration) -> Self {
+        Sessions {
+            ttl,
+            sweep_interval,
+            tables: protocol_tables()
+                .into_iter()
+                .map(|(protocol, table)| (table, protocol))
+                .collect(),
+        }
+    }
+
+    /// Sessions that never expire
+    pub fn disabled() -> Self {
+        Self::new(None, DEFAULT_SWEEP_INTERVAL)
+    }
+
+    /// Expiry set by `session_ttl` and `session_sweep_interval`, in seconds. An empty or zero
+    /// `session_ttl` disables it.
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Self, SessionError> {
+        let seconds = |name: &str| -> Result<Option<u64>, SessionError> {
+            match settings.get(name).map(|value| value.trim()) {
+                Some(value) if !value.is_empty() => value
+                    .parse::<u64>()
+                    .map(|seconds| Some(seconds).filter(|seconds| *seconds > 0))
+                    .map_err(|e| SessionError::Invalid(name.to_string(), e.to_string())),
+                _ => Ok(None),
+            }
+        };
+        Ok(Self::new(
+            seconds("session_ttl")?.map(Duration::from_secs),
+            seconds("session_sweep_interval")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SWEEP_INTERVAL),
+        ))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ttl.is_some()
+    }
+
+    pub fn is_session_table(&self, table: &str) -> bool {
+        self.tables.contains_key(table)
+    }
+
+    /// Whether a record of `table` written at `written_at` is expired at `now`. Session records
+    /// written before times were recorded are.
+    pub fn is_expired(&self, table: &str, written_at: Option<i64>, now: DateTime<Utc>) -> bool {
+        let ttl = match self.ttl {
+            Some(ttl) if self.is_session_table(table) => ttl,
+            _ => return false,
+        };
+        match written_at {
+            Some(written_at) => {
+                now.timestamp().saturating_sub(written_at)
+                    > i64::try_from(ttl.as_secs()).unwrap_or(i64::MAX)
+            }
+            None => true,
+        }
+    }
+
+    /// Deletes the expired session records of `store`, returning how many were
+    pub async fn sweep(&self, store: &dyn Store, now: DateTime<Utc>) -> Result<usize, StoreError> {
+        if !self.is_enabled() {
+            return Ok(0);
+        }
+        let mut swept = 0;
+        for table in self.tables.keys() {
+            for (key, value) in store.scan(&schema::table_prefix(table)).await? {
+                if self.is_expired(table, written_at(&value), now) {
+                    store.delete(&key).await?;
+                    swept += 1;
+                }
+            }
+        }
+        Ok(swept)
+    }
+
+    /// Number of sessions opened and neither finished nor expired, by protocol. A keygen or a
+    /// rotation is finished once the master key of its id is written after its last round, and
+    /// a signature once its ephemeral keys are consumed.
+    pub async fn open_sessions(
+        &self,
+        store: &dyn Store,
+        now: DateTime<Utc>,
+    ) -> Result<BTreeMap<&'static str, usize>, StoreError> {
+        let mut last_rounds: HashMap<(&'static str, String, String), Option<i64>> = HashMap::new();
+        for (table, protocol) in &self.tables {
+            for (key, value) in store.scan(&schema::table_prefix(table)).await? {
+                let record_key = match RecordKey::decode(&key) {
+                    Some(record_key) => record_key,
+                    None => continue,
+                };
+                let written_at = written_at(&value);
+                if self.is_expired(table, written_at, now) {
+                    continue;
+                }
+                let last_round = last_rounds
+                    .entry((*protocol, record_key.customer_id, record_key.id))
+                    .or_insert(written_at);
+                *last_round = (*last_round).max(written_at);
+            }
+        }
+
+        let mut open: BTreeMap<&'static str, usize> =
+            PROTOCOLS.iter().map(|protocol| (*protocol, 0)).collect();
+        let master_keys = EcdsaStruct::Party1MasterKey.to_string();
+        for ((protocol, customer_id, id), last_round) in last_rounds {
+            if protocol != "sign" {
+                let master_key = RecordKey::new(&master_keys, &customer_id, &id);
+                if let Some(value) = store.get(&master_key.encode()).await? {
+                    if written_at(&value) >= last_round {
+                        continue;
+                    }
+                }
+            }
+            *open.entry(protocol).or_insert(0) += 1;
+        }
+        Ok(open)
+    }
+
+    /// Sweeps `store` every `session_sweep_interval`, until the runtime shuts down
+    pub fn spawn_sweeper(self: Arc<Self>, store: Arc<dyn Store>) {
+        if !self.is_enabled() {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.sweep_interval);
+            loop {
+                interval.tick().await;
+                match self.sweep(store.as_ref(), Utc::now()).await {
+                    Ok(0) => {}
+                    Ok(swept) => info!("Deleted {} expired session records", swept),
+                    Err(e) => error!("Cannot delete expired session records: {}", e),
+                }
+            }
+        });
+    }
+}
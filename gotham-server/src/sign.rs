@@ -0,0 +1,643 @@
+//! Two party signing routes, authorized by the signing policy
+
+use chrono::Utc;
+use log::{error, warn};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::{post, State};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::sync::Arc;
+
+use two_party_ecdsa::curv::arithmetic::traits::Converter;
+use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
+use two_party_ecdsa::curv::BigInt;
+use two_party_ecdsa::kms::ecdsa::two_party::{party2, MasterKey1};
+use two_party_ecdsa::party_one::Value;
+use two_party_ecdsa::{party_one, party_two};
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::approval::{Approvals, SignOutcome};
+use crate::audit::{AuditLog, AuditRecord, Decision};
+use crate::auth::Customer;
+use crate::db::{db_index, get_value, insert_value, take_value, SharedDb};
+use crate::error::{ApiError, ErrorCode};
+use crate::idempotency::DigestedJson;
+use crate::keys;
+use crate::policy::{Denial, PolicyEngine, SignContext};
+use crate::presign;
+use crate::storage::Store;
+use crate::transaction::{TransactionError, TransactionSummary, UnsignedTransaction};
+
+/// Most messages signed in one batch
+pub const MAX_BATCH_SIZE: usize = 100;
+
+/// Tables holding the intermediate state of a signing session
+#[derive(Debug)]
+pub enum SignStruct {
+    First,
+    BatchFirst,
+}
+
+impl MPCStruct for SignStruct {
+    fn to_string(&self) -> String {
+        match self {
+            SignStruct::First => "SignFirst".to_string(),
+            SignStruct::BatchFirst => "SignBatchFirst".to_string(),
+        }
+    }
+}
+
+/// Ephemeral keys of both parties, kept until the second round
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignFirst {
+    pub eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+    pub eph_ec_key_pair_party1: party_one::EphEcKeyPair,
+}
+
+#[typetag::serde]
+impl Value for SignFirst {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Message of a batch, with the child key signing it and the ephemeral commitment of party two
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignBatchItem {
+    pub message: BigInt,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+    pub eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<UnsignedTransaction>,
+}
+
+/// Messages of a batch and the ephemeral keys of party one, kept until the second round
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SignBatchFirst {
+    pub items: Vec<SignBatchItem>,
+    pub eph_ec_key_pairs_party1: Vec<party_one::EphEcKeyPair>,
+}
+
+#[typetag::serde]
+impl Value for SignBatchFirst {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Second signing round, with the unsigned transaction the message is the digest of when sent
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignSecondRequest {
+    pub message: BigInt,
+    pub party_two_sign_message: party2::SignMessage,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<UnsignedTransaction>,
+}
+
+impl SignSecondRequest {
+    /// Second message of party two, and the transaction sent with it
+    pub fn into_parts(self) -> (SignSecondMsgRequest, Option<UnsignedTransaction>) {
+        (
+            SignSecondMsgRequest {
+                message: self.message,
+                party_two_sign_message: self.party_two_sign_message,
+                x_pos_child_key: self.x_pos_child_key,
+                y_pos_child_key: self.y_pos_child_key,
+            },
+            self.transaction,
+        )
+    }
+}
+
+pub(crate) fn position(x_pos: &BigInt, y_pos: &BigInt) -> Option<(u64, u64)> {
+    let x = u64::from_str_radix(&x_pos.to_hex(), 16).ok()?;
+    let y = u64::from_str_radix(&y_pos.to_hex(), 16).ok()?;
+    Some((x, y))
+}
+
+/// Summary of the transaction sent with a message, refusing messages that are not its digest
+fn inspect_transaction(
+    master_key: &MasterKey1,
+    message: &BigInt,
+    x_pos: &BigInt,
+    y_pos: &BigInt,
+    transaction: &UnsignedTransaction,
+) -> Result<TransactionSummary, ApiError> {
+    let child_key = |x_pos: &BigInt, y_pos: &BigInt| {
+        master_key
+            .get_child(vec![x_pos.clone(), y_pos.clone()])
+            .public
+            .q
+            .get_element()
+    };
+    let change_keys: Vec<_> = transaction
+        .change_positions()
+        .iter()
+        .map(|position| child_key(&position.x_pos, &position.y_pos))
+        .collect();
+    transaction
+        .inspect(message, &child_key(x_pos, y_pos), &change_keys)
+        .map_err(|e| match e {
+            TransactionError::Invalid(_) => ApiError::bad_request(e.to_string()),
+            TransactionError::Mismatch { .. } => ApiError::new(
+                Status::UnprocessableEntity,
+                ErrorCode::TransactionMismatch,
+                e.to_string(),
+            ),
+        })
+}
+
+/// Audit record of an allowed, unsigned, request; callers set the outcome
+pub(crate) fn audit_record(
+    context: &SignContext,
+    key_id: &str,
+    x_pos: &BigInt,
+    y_pos: &BigInt,
+) -> AuditRecord {
+    AuditRecord {
+        time: context.time,
+        customer_id: context.customer_id.clone(),
+        key_id: key_id.to_string(),
+        x_pos: x_pos.to_hex(),
+        y_pos: y_pos.to_hex(),
+        message_hash: context.message.clone(),
+        decision: Decision::Allowed,
+        reason: None,
+        signed: false,
+        deletion: None,
+    }
+}
+
+/// Ephemeral key pair of party one, kept with the commitment of party two until the second round
+pub fn first_round(
+    eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+) -> (party_one::EphKeyGenFirstMsg, SignFirst) {
+    let (sign_party_one_first_message, eph_ec_key_pair_party1) = MasterKey1::sign_first_message();
+    (
+        sign_party_one_first_message,
+        SignFirst {
+            eph_key_gen_first_message_party_two,
+            eph_ec_key_pair_party1,
+        },
+    )
+}
+
+#[post(
+    "/ecdsa/sign/<id>/first",
+    format = "json",
+    data = "<eph_key_gen_first_message_party_two>"
+)]
+pub async fn sign_first(
+    state: &State<SharedDb>,
+    customer: Customer,
+    id: String,
+    eph_key_gen_first_message_party_two: DigestedJson<party_two::EphKeyGenFirstMsg>,
+) -> Result<Json<party_one::EphKeyGenFirstMsg>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    keys::usable_master_key(db.as_ref(), &key).await?;
+
+    let (sign_party_one_first_message, first) =
+        first_round(eph_key_gen_first_message_party_two.into_inner());
+    insert_value(db.as_ref(), &key, &SignStruct::First, &first).await?;
+
+    Ok(Json(sign_party_one_first_message))
+}
+
+/// Signs, or parks the request until it is approved when the policy requires approval
+#[post("/ecdsa/sign/<id>/second", format = "json", data = "<request>")]
+pub async fn sign_second(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    policy: &State<Arc<PolicyEngine>>,
+    audit: &State<AuditLog>,
+    approvals: &State<Arc<Approvals>>,
+    customer: Customer,
+    id: String,
+    request: DigestedJson<SignSecondRequest>,
+) -> Result<SignOutcome, ApiError> {
+    let (request, transaction) = request.into_inner().into_parts();
+    sign_audited(
+        state,
+        policy,
+        audit,
+        approvals,
+        &customer,
+        &id,
+        request,
+        transaction,
+        Ephemeral::Session {
+            store: store.inner().clone(),
+        },
+    )
+    .await
+}
+
+/// Where the ephemeral keys of a signature come from
+pub(crate) enum Ephemeral {
+    /// The last `sign/first` round of the key, consumed by the signature
+    Session { store: Arc<dyn Store> },
+    /// A presignature of the pool of the key, consumed by the signature
+    Presignature { store: Arc<dyn Store>, id: String },
+    /// The keys of a request parked until it was approved
+    Parked(SignFirst),
+    /// The keys of the `sign/first` round of a WebSocket session, kept in its memory
+    InMemory(SignFirst),
+}
+
+/// Ephemeral keys of party one, consuming them when they are one-time keys
+async fn ephemeral_keys(
+    db: &dyn Db,
+    key: &DbIndex,
+    ephemeral: Ephemeral,
+) -> Result<SignFirst, ApiError> {
+    match ephemeral {
+        Ephemeral::Session { store } => {
+            take_value(db, store.as_ref(), key, &SignStruct::First).await
+        }
+        Ephemeral::Presignature {
+            store,
+            id: presignature_id,
+        } => presign::take(db, store.as_ref(), key, &presignature_id).await,
+        Ephemeral::Parked(first) | Ephemeral::InMemory(first) => Ok(first),
+    }
+}
+
+/// Authorizes, signs and audits the second signing round, or parks it until approved
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn sign_audited(
+    state: &State<SharedDb>,
+    policy: &PolicyEngine,
+    audit: &AuditLog,
+    approvals: &Approvals,
+    customer: &Customer,
+    id: &str,
+    request: SignSecondMsgRequest,
+    transaction: Option<UnsignedTransaction>,
+    ephemeral: Ephemeral,
+) -> Result<SignOutcome, ApiError> {
+    let transaction = match transaction {
+        Some(transaction) => {
+            let master_key: MasterKey1 = {
+                let db = state.lock().await;
+                let key = db_index(customer, id);
+                get_value(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?
+            };
+            Some(inspect_transaction(
+                &master_key,
+                &request.message,
+                &request.x_pos_child_key,
+                &request.y_pos_child_key,
+                &transaction,
+            )?)
+        }
+        None => None,
+    };
+    let context = SignContext {
+        customer_id: customer.id.clone(),
+        message: request.message.to_hex(),
+        position: position(&request.x_pos_child_key, &request.y_pos_child_key),
+        time: Utc::now(),
+        transaction,
+    };
+    let record = |decision: Decision, reason: Option<String>, signed: bool| AuditRecord {
+        decision,
+        reason,
+        signed,
+        ..audit_record(
+            &context,
+            id,
+            &request.x_pos_child_key,
+            &request.y_pos_child_key,
+        )
+    };
+
+    load_signatures(policy, &customer.id).await?;
+    if let Err(denial) = policy.authorize(&context) {
+        warn!(
+            "Denied signing with key {} of {}: {}",
+            id, customer.id, denial.reason
+        );
+        if let Err(e) = audit
+            .append(record(Decision::Denied, Some(denial.reason.clone()), false))
+            .await
+        {
+            error!("Cannot audit denied signing with key {}: {}", id, e);
+        }
+        return Err(policy_denied(denial));
+    }
+
+    if policy.requires_approval(&context) {
+        let first = {
+            let db = state.lock().await;
+            let key = db_index(customer, id);
+            get_value::<MasterKey1>(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?;
+            keys::ensure_usable(db.as_ref(), &key).await?;
+            ephemeral_keys(db.as_ref(), &key, ephemeral).await?
+        };
+        audit
+            .append(record(Decision::Pending, None, false))
+            .await
+            .map_err(|e| {
+                error!("Cannot audit parked signing with key {}: {}", id, e);
+                ApiError::internal("Cannot write the audit log")
+            })?;
+        let approval = approvals
+            .park(
+                &customer.id,
+                id,
+                request,
+                context.transaction.clone(),
+                first,
+                context.time,
+            )
+            .await?;
+        return Ok(SignOutcome::Pending(approval));
+    }
+
+    let signature = sign(state, policy, customer, id, &request, &context, ephemeral).await;
+    let audited = match &signature {
+        Err(e) if e.code == ErrorCode::PolicyDenied => {
+            audit
+                .append(record(Decision::Denied, Some(e.message.clone()), false))
+                .await
+        }
+        signature => {
+            audit
+                .append(record(Decision::Allowed, None, signature.is_ok()))
+                .await
+        }
+    };
+    match (signature, audited) {
+        (Ok(signature), Ok(())) => Ok(SignOutcome::Signed(signature)),
+        (Ok(_), Err(e)) => {
+            // a signature is only returned once it is audited
+            error!("Cannot audit signing with key {}: {}", id, e);
+            Err(ApiError::internal("Cannot write the audit log"))
+        }
+        (Err(e), audited) => {
+            if let Err(audit_error) = audited {
+                error!(
+                    "Cannot audit failed signing with key {}: {}",
+                    id, audit_error
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+pub(crate) fn policy_denied(denial: Denial) -> ApiError {
+    ApiError::new(Status::Forbidden, ErrorCode::PolicyDenied, denial.reason)
+}
+
+/// Reads the signature counters of `customer_id` before the policy is evaluated
+async fn load_signatures(policy: &PolicyEngine, customer_id: &str) -> Result<(), ApiError> {
+    policy
+        .load_signatures(customer_id)
+        .await
+        .map_err(|e| ApiError::storage(e.to_string()))
+}
+
+/// Second signing round of an authorized request, authorized again under the lock of the
+/// database: signatures concurrent with it count towards the rate limits, and the policy may
+/// have changed since a parked request was approved
+pub(crate) async fn sign(
+    state: &State<SharedDb>,
+    policy: &PolicyEngine,
+    customer: &Customer,
+    id: &str,
+    request: &SignSecondMsgRequest,
+    context: &SignContext,
+    ephemeral: Ephemeral,
+) -> Result<party_one::SignatureRecid, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(customer, id);
+
+    let master_key: MasterKey1 =
+        get_value(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?;
+    let metadata = keys::ensure_usable(db.as_ref(), &key).await?;
+    load_signatures(policy, &customer.id).await?;
+    policy.authorize(context).map_err(policy_denied)?;
+    let first = ephemeral_keys(db.as_ref(), &key, ephemeral).await?;
+
+    let child_master_key = master_key.get_child(vec![
+        request.x_pos_child_key.clone(),
+        request.y_pos_child_key.clone(),
+    ]);
+    let signature = child_master_key
+        .sign_second_message(
+            &request.party_two_sign_message,
+            &first.eph_key_gen_first_message_party_two,
+            &first.eph_ec_key_pair_party1,
+            &request.message,
+        )
+        .map_err(|_| ApiError::protocol(format!("Signature validation of key {} failed", id)))?;
+
+    policy
+        .record_signature(&customer.id, context.time)
+        .await
+        .map_err(|e| ApiError::storage(e.to_string()))?;
+    keys::record_signature(db.as_ref(), &key, metadata, context.position).await?;
+
+    Ok(signature)
+}
+
+#[post("/ecdsa/sign/<id>/batch/first", format = "json", data = "<items>")]
+pub async fn sign_batch_first(
+    state: &State<SharedDb>,
+    customer: Customer,
+    id: String,
+    items: DigestedJson<Vec<SignBatchItem>>,
+) -> Result<Json<Vec<party_one::EphKeyGenFirstMsg>>, ApiError> {
+    let items = items.into_inner();
+    if items.is_empty() || items.len() > MAX_BATCH_SIZE {
+        return Err(ApiError::bad_request(format!(
+            "A batch holds 1 to {} messages",
+            MAX_BATCH_SIZE
+        )));
+    }
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    get_value::<MasterKey1>(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?;
+    keys::ensure_usable(db.as_ref(), &key).await?;
+
+    let (first_messages, eph_ec_key_pairs_party1): (Vec<_>, Vec<_>) = items
+        .iter()
+        .map(|_| MasterKey1::sign_first_message())
+        .unzip();
+    insert_value(
+        db.as_ref(),
+        &key,
+        &SignStruct::BatchFirst,
+        &SignBatchFirst {
+            items,
+            eph_ec_key_pairs_party1,
+        },
+    )
+    .await?;
+
+    Ok(Json(first_messages))
+}
+
+/// Signs every message of the batch opened by `batch/first`, in its order. The batch is
+/// refused as a whole when the policy denies one of its messages, and is consumed once signed.
+#[post("/ecdsa/sign/<id>/batch/second", format = "json", data = "<messages>")]
+pub async fn sign_batch_second(
+    state: &State<SharedDb>,
+    store: &State<Arc<dyn Store>>,
+    policy: &State<Arc<PolicyEngine>>,
+    audit: &State<AuditLog>,
+    customer: Customer,
+    id: String,
+    messages: DigestedJson<Vec<party2::SignMessage>>,
+) -> Result<Json<Vec<party_one::SignatureRecid>>, ApiError> {
+    let db = state.lock().await;
+    let key = db_index(&customer, &id);
+    let master_key: MasterKey1 =
+        get_value(db.as_ref(), &key, &EcdsaStruct::Party1MasterKey).await?;
+    let metadata = keys::ensure_usable(db.as_ref(), &key).await?;
+    let batch: SignBatchFirst = get_value(db.as_ref(), &key, &SignStruct::BatchFirst).await?;
+    if messages.len() != batch.items.len() {
+        return Err(ApiError::bad_request(format!(
+            "Expected {} messages, the size of the batch",
+            batch.items.len()
+        )));
+    }
+
+    let time = Utc::now();
+    let contexts = batch
+        .items
+        .iter()
+        .map(|item| {
+            let transaction = item
+                .transaction
+                .as_ref()
+                .map(|transaction| {
+                    inspect_transaction(
+                        &master_key,
+                        &item.message,
+                        &item.x_pos_child_key,
+                        &item.y_pos_child_key,
+                        transaction,
+                    )
+                })
+                .transpose()?;
+            Ok(SignContext {
+                customer_id: customer.id.clone(),
+                message: item.message.to_hex(),
+                position: position(&item.x_pos_child_key, &item.y_pos_child_key),
+                time,
+                transaction,
+            })
+        })
+        .collect::<Result<Vec<SignContext>, ApiError>>()?;
+    let records: Vec<AuditRecord> = batch
+        .items
+        .iter()
+        .zip(&contexts)
+        .map(|(item, context)| {
+            audit_record(context, &id, &item.x_pos_child_key, &item.y_pos_child_key)
+        })
+        .collect();
+
+    load_signatures(policy, &customer.id).await?;
+    let denied = policy.authorize_batch(&contexts).and_then(|()| {
+        // a batch cannot wait for approvals, its messages are signed one by one instead
+        match contexts
+            .iter()
+            .position(|context| policy.requires_approval(context))
+        {
+            Some(index) => Err((
+                index,
+                Denial {
+                    reason: "signing requires approval, sign the message on its own".to_string(),
+                },
+            )),
+            None => Ok(()),
+        }
+    });
+    if let Err((index, denial)) = denied {
+        warn!(
+            "Denied batch signing with key {} of {}, message {}: {}",
+            id, customer.id, index, denial.reason
+        );
+        let denied = AuditRecord {
+            decision: Decision::Denied,
+            reason: Some(denial.reason.clone()),
+            ..records[index].clone()
+        };
+        if let Err(e) = audit.append(denied).await {
+            error!("Cannot audit denied signing with key {}: {}", id, e);
+        }
+        return Err(ApiError::new(
+            Status::Forbidden,
+            ErrorCode::PolicyDenied,
+            format!("Message {}: {}", index, denial.reason),
+        ));
+    }
+
+    // the ephemeral keys of party one never sign twice
+    take_value::<SignBatchFirst>(
+        db.as_ref(),
+        store.inner().as_ref(),
+        &key,
+        &SignStruct::BatchFirst,
+    )
+    .await?;
+    let signatures: Result<Vec<party_one::SignatureRecid>, ApiError> = batch
+        .items
+        .iter()
+        .zip(&batch.eph_ec_key_pairs_party1)
+        .zip(messages.iter())
+        .enumerate()
+        .map(|(index, ((item, eph_ec_key_pair_party1), message))| {
+            master_key
+                .get_child(vec![
+                    item.x_pos_child_key.clone(),
+                    item.y_pos_child_key.clone(),
+                ])
+                .sign_second_message(
+                    message,
+                    &item.eph_key_gen_first_message_party_two,
+                    eph_ec_key_pair_party1,
+                    &item.message,
+                )
+                .map_err(|_| {
+                    ApiError::protocol(format!(
+                        "Signature validation of message {} with key {} failed",
+                        index, id
+                    ))
+                })
+        })
+        .collect();
+
+    // a signature is only returned once every message of the batch is audited
+    let signed = signatures.is_ok();
+    for record in records {
+        if let Err(e) = audit.append(AuditRecord { signed, ..record }).await {
+            error!("Cannot audit signing with key {}: {}", id, e);
+            return Err(signatures
+                .err()
+                .unwrap_or_else(|| ApiError::internal("Cannot write the audit log")));
+        }
+    }
+    let signatures = signatures?;
+
+    for context in &contexts {
+        policy
+            .record_signature(&customer.id, context.time)
+            .await
+            .map_err(|e| ApiError::storage(e.to_string()))?;
+    }
+    let positions = contexts.iter().filter_map(|context| context.position);
+    keys::record_signature(db.as_ref(), &key, metadata, positions).await?;
+
+    Ok(Json(signatures))
+}
@@ -0,0 +1,272 @@
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{AeadCore, Aes256Gcm, Key, Nonce};
+use async_trait::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use super::{Store, StoreError};
+
+/// Prefix of every encrypted record
+const MAGIC: &[u8] = b"GKE1";
+const KEK_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+/// AES-256 data key followed by its GCM tag
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+const HEADER_LEN: usize = MAGIC.len() + KEK_ID_LEN + NONCE_LEN + WRAPPED_KEY_LEN + NONCE_LEN;
+
+/// AES-256 key-encryption key, wrapping the data key of every record
+pub struct Kek {
+    id: [u8; KEK_ID_LEN],
+    cipher: Aes256Gcm,
+}
+
+impl Kek {
+    /// Key given as 64 hex characters
+    pub fn from_hex(hex: &str) -> Result<Self, StoreError> {
+        let bytes = hex::decode(hex.trim())
+            .map_err(|e| StoreError::Configuration(format!("KEK is not hex encoded: {}", e)))?;
+        if bytes.len() != 32 {
+            return Err(StoreError::Configuration(format!(
+                "KEK must be 32 bytes long, got {}",
+                bytes.len()
+            )));
+        }
+        let mut id = [0u8; KEK_ID_LEN];
+        id.copy_from_slice(&Sha256::digest(&bytes)[..KEK_ID_LEN]);
+        Ok(Kek {
+            id,
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)),
+        })
+    }
+
+    /// Key held in the file at `path`, as 64 hex characters
+    pub fn from_file(path: &str) -> Result<Self, StoreError> {
+        let hex = std::fs::read_to_string(path)
+            .map_err(|e| StoreError::Configuration(format!("cannot read {}: {}", path, e)))?;
+        Self::from_hex(&hex)
+    }
+
+    /// Key set by `kek` (hex, meant for the environment) or `kek_path`, if any. Keys named by
+    /// `previous_kek` or `previous_kek_path` are returned by [`Kek::previous_from_settings`].
+    pub fn from_settings(settings: &HashMap<String, String>) -> Result<Option<Self>, StoreError> {
+        Self::from_named_settings(settings, "kek", "kek_path")
+    }
+
+    /// Key that was replaced by the current one and may still wrap some records
+    pub fn previous_from_settings(
+        settings: &HashMap<String, String>,
+    ) -> Result<Option<Self>, StoreError> {
+        Self::from_named_settings(settings, "previous_kek", "previous_kek_path")
+    }
+
+    fn from_named_settings(
+        settings: &HashMap<String, String>,
+        hex_name: &str,
+        path_name: &str,
+    ) -> Result<Option<Self>, StoreError> {
+        let setting = |name: &str| settings.get(name).cloned().unwrap_or_default();
+        let (hex, path) = (setting(hex_name), setting(path_name));
+        if !hex.is_empty() && !path.is_empty() {
+            return Err(StoreError::Configuration(format!(
+                "only one of {} and {} may be set",
+                hex_name, path_name
+            )));
+        }
+        if !hex.is_empty() {
+            Self::from_hex(&hex).map(Some)
+        } else if !path.is_empty() {
+            Self::from_file(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+/// Outcome of [`EncryptedStore::rewrap`]
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RewrapReport {
+    /// Records whose data key is now wrapped by the current KEK
+    pub rewrapped: usize,
+    /// Plaintext records written before encryption was enabled, now encrypted
+    pub encrypted: usize,
+    /// Records already wrapped by the current KEK
+    pub unchanged: usize,
+}
+
+/// Store encrypting every value with its own AES-256-GCM data key, wrapped by a KEK
+///
+/// A record is `MAGIC | KEK id | nonce | wrapped data key | nonce | ciphertext`, where the
+/// ciphertext is bound to the record key. Plaintext records written before encryption was
+/// enabled are still read, and encrypted by [`EncryptedStore::rewrap`].
+pub struct EncryptedStore {
+    inner: Box<dyn Store>,
+    kek: Kek,
+    /// Keys replaced by `kek`, still used to read records they wrap
+    previous: Vec<Kek>,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Box<dyn Store>, kek: Kek, previous: Vec<Kek>) -> Self {
+        EncryptedStore {
+            inner,
+            kek,
+            previous,
+        }
+    }
+
+    fn encrypt(&self, key: &[u8], value: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let value_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let ciphertext = Aes256Gcm::new(&data_key)
+            .encrypt(
+                &value_nonce,
+                Payload {
+                    msg: value,
+                    aad: key,
+                },
+            )
+            .map_err(|_| StoreError::Backend("cannot encrypt record".to_string()))?;
+
+        let mut record = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+        record.extend_from_slice(MAGIC);
+        record.extend_from_slice(&self.wrap(&data_key)?);
+        record.extend_from_slice(&value_nonce);
+        record.extend_from_slice(&ciphertext);
+        Ok(record)
+    }
+
+    /// `KEK id | nonce | wrapped data key` of `data_key` under the current KEK
+    fn wrap(&self, data_key: &Key<Aes256Gcm>) -> Result<Vec<u8>, StoreError> {
+        let key_nonce = Aes256Gcm::generate_nonce(OsRng);
+        let wrapped_key = self
+            .kek
+            .cipher
+            .encrypt(
+                &key_nonce,
+                Payload {
+                    msg: data_key.as_slice(),
+                    aad: &self.kek.id,
+                },
+            )
+            .map_err(|_| StoreError::Backend("cannot wrap data key".to_string()))?;
+        Ok([&self.kek.id[..], &key_nonce, &wrapped_key].concat())
+    }
+
+    fn unwrap(&self, record: &[u8]) -> Result<Key<Aes256Gcm>, StoreError> {
+        let kek_id = &record[MAGIC.len()..MAGIC.len() + KEK_ID_LEN];
+        let kek = std::iter::once(&self.kek)
+            .chain(self.previous.iter())
+            .find(|kek| kek.id[..] == *kek_id)
+            .ok_or_else(|| {
+                StoreError::Backend(format!(
+                    "record is wrapped by unknown KEK {}",
+                    hex::encode(kek_id)
+                ))
+            })?;
+        let key_nonce = &record[MAGIC.len() + KEK_ID_LEN..MAGIC.len() + KEK_ID_LEN + NONCE_LEN];
+        let wrapped_key = &record[MAGIC.len() + KEK_ID_LEN + NONCE_LEN..HEADER_LEN - NONCE_LEN];
+        let data_key = kek
+            .cipher
+            .decrypt(
+                Nonce::from_slice(key_nonce),
+                Payload {
+                    msg: wrapped_key,
+                    aad: &kek.id,
+                },
+            )
+            .map_err(|_| StoreError::Backend("cannot unwrap data key".to_string()))?;
+        Ok(Key::<Aes256Gcm>::clone_from_slice(&data_key))
+    }
+
+    fn decrypt(&self, key: &[u8], record: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        if !record.starts_with(MAGIC) {
+            return Ok(record);
+        }
+        if record.len() < HEADER_LEN {
+            return Err(StoreError::Backend(
+                "encrypted record is truncated".to_string(),
+            ));
+        }
+        let data_key = self.unwrap(&record)?;
+        Aes256Gcm::new(&data_key)
+            .decrypt(
+                Nonce::from_slice(&record[HEADER_LEN - NONCE_LEN..HEADER_LEN]),
+                Payload {
+                    msg: &record[HEADER_LEN..],
+                    aad: key,
+                },
+            )
+            .map_err(|_| StoreError::Backend("cannot decrypt record".to_string()))
+    }
+
+    /// Wraps the data key of every record with the current KEK, and encrypts plaintext records.
+    /// Values are not re-encrypted, and an interrupted run can be started again.
+    pub async fn rewrap(&self) -> Result<RewrapReport, StoreError> {
+        let mut report = RewrapReport::default();
+        for (key, record) in self.inner.scan(b"").await? {
+            if !record.starts_with(MAGIC) {
+                let record = self.encrypt(&key, &record)?;
+                self.inner.put(&key, &record).await?;
+                report.encrypted += 1;
+            } else if record.len() < HEADER_LEN {
+                return Err(StoreError::Backend(
+                    "encrypted record is truncated".to_string(),
+                ));
+            } else if record[MAGIC.len()..MAGIC.len() + KEK_ID_LEN] == self.kek.id {
+                report.unchanged += 1;
+            } else {
+                let data_key = self.unwrap(&record)?;
+                let mut rewrapped = MAGIC.to_vec();
+                rewrapped.extend_from_slice(&self.wrap(&data_key)?);
+                rewrapped.extend_from_slice(&record[HEADER_LEN - NONCE_LEN..]);
+                self.inner.put(&key, &rewrapped).await?;
+                report.rewrapped += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[async_trait]
+impl Store for EncryptedStore {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.inner
+            .get(key)
+            .await?
+            .map(|record| self.decrypt(key, record))
+            .transpose()
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        let record = self.encrypt(key, value)?;
+        self.inner.put(key, &record).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError> {
+        self.inner.delete(key).await
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError> {
+        self.inner
+            .scan(prefix)
+            .await?
+            .into_iter()
+            .map(|(key, record)| {
+                let value = self.decrypt(&key, record)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    async fn compact(&self) -> Result<(), StoreError> {
+        self.inner.compact().await
+    }
+
+    async fn checkpoint(&self, path: &str) -> Result<(), StoreError> {
+        self.inner.checkpoint(path).await
+    }
+}
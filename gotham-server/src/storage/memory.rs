@@ -0,0 +1,48 @@
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use super::{Store, StoreError};
+
+/// Store keeping every record in memory
+#[derive(Default)]
+pub struct MemoryStore {
+    records: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.records.read().unwrap().get(key).cloned())
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        self.records
+            .write()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError> {
+        self.records.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError> {
+        Ok(self
+            .records
+            .read()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
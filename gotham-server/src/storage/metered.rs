@@ -0,0 +1,65 @@
+use async_trait::async_trait;
+use prometheus::HistogramVec;
+use std::time::Instant;
+
+use super::schema::RecordKey;
+use super::{Store, StoreError};
+
+/// Store timing every operation of the store it wraps
+pub struct MeteredStore {
+    inner: Box<dyn Store>,
+    /// Labelled by `operation` and `table`
+    duration: HistogramVec,
+}
+
+impl MeteredStore {
+    pub fn new(inner: Box<dyn Store>, duration: HistogramVec) -> Self {
+        MeteredStore { inner, duration }
+    }
+
+    fn observe(&self, operation: &str, key: &[u8], start: Instant) {
+        let table = RecordKey::decode(key).map(|key| key.table);
+        self.duration
+            .with_label_values(&[operation, table.as_deref().unwrap_or("")])
+            .observe(start.elapsed().as_secs_f64());
+    }
+}
+
+#[async_trait]
+impl Store for MeteredStore {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        let start = Instant::now();
+        let value = self.inner.get(key).await;
+        self.observe("get", key, start);
+        value
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.inner.put(key, value).await;
+        self.observe("put", key, start);
+        result
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError> {
+        let start = Instant::now();
+        let result = self.inner.delete(key).await;
+        self.observe("delete", key, start);
+        result
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError> {
+        let start = Instant::now();
+        let records = self.inner.scan(prefix).await;
+        self.observe("scan", prefix, start);
+        records
+    }
+
+    async fn compact(&self) -> Result<(), StoreError> {
+        self.inner.compact().await
+    }
+
+    async fn checkpoint(&self, path: &str) -> Result<(), StoreError> {
+        self.inner.checkpoint(path).await
+    }
+}
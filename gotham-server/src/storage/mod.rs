@@ -0,0 +1,139 @@
+//! Key-value stores backing the `Db` implementation of the server
+//!
+//! The backend is chosen with the `db` setting:
+//! - `local`: RocksDB in the `./<db_name>` directory (feature `local`, the default)
+//! - `memory`: a process local map, lost on restart; meant for tests and benches
+//! - `sqlite` / `postgres`: a SQL database reached at `db_url` (feature `sql`)
+//!
+//! When a key-encryption key is configured (`kek` or `kek_path`) records are encrypted at rest
+//! by [`EncryptedStore`], whatever the backend.
+//!
+//! Keys and values follow the layout of [`schema`], whose version is recorded in the store.
+//!
+//! Every backend must pass the conformance suite in `tests.rs`.
+
+use async_trait::async_trait;
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+mod encrypted;
+mod memory;
+mod metered;
+#[cfg(feature = "local")]
+mod rocks;
+pub mod schema;
+#[cfg(feature = "sql")]
+mod sql;
+
+pub use encrypted::{EncryptedStore, Kek, RewrapReport};
+pub use memory::MemoryStore;
+pub use metered::MeteredStore;
+#[cfg(feature = "local")]
+pub use rocks::RocksStore;
+#[cfg(feature = "sql")]
+pub use sql::SqlStore;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("storage is misconfigured: {0}")]
+    Configuration(String),
+    #[error("storage backend failed: {0}")]
+    Backend(String),
+}
+
+/// Ordered map of byte keys to byte values
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError>;
+
+    /// Inserts `value`, replacing the one stored under `key` if any
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError>;
+
+    /// Removes `key`. Removing a missing key is not an error.
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError>;
+
+    /// All records whose key starts with `prefix`, ordered by key
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError>;
+
+    /// Reclaims the space of deleted and replaced records
+    async fn compact(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the store to the new directory `path`
+    async fn checkpoint(&self, _path: &str) -> Result<(), StoreError> {
+        Err(StoreError::Configuration(
+            "this backend does not support checkpoints".to_string(),
+        ))
+    }
+}
+
+#[async_trait]
+impl<S: Store + ?Sized> Store for Arc<S> {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.as_ref().get(key).await
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        self.as_ref().put(key, value).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError> {
+        self.as_ref().delete(key).await
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError> {
+        self.as_ref().scan(prefix).await
+    }
+
+    async fn compact(&self) -> Result<(), StoreError> {
+        self.as_ref().compact().await
+    }
+
+    async fn checkpoint(&self, path: &str) -> Result<(), StoreError> {
+        self.as_ref().checkpoint(path).await
+    }
+}
+
+/// Opens the store selected by the `db` setting, encrypted when a KEK is configured
+pub fn open(settings: &HashMap<String, String>) -> Result<Box<dyn Store>, StoreError> {
+    let store = open_backend(settings)?;
+    match Kek::from_settings(settings)? {
+        Some(kek) => {
+            let previous = Kek::previous_from_settings(settings)?.into_iter().collect();
+            Ok(Box::new(EncryptedStore::new(store, kek, previous)))
+        }
+        None => {
+            warn!("No KEK is configured, key shares are stored in plaintext");
+            Ok(store)
+        }
+    }
+}
+
+/// Opens the store selected by the `db` setting, without encryption
+pub fn open_backend(settings: &HashMap<String, String>) -> Result<Box<dyn Store>, StoreError> {
+    let setting = |name: &str| settings.get(name).cloned().unwrap_or_default();
+    match setting("db").as_str() {
+        "memory" => Ok(Box::new(MemoryStore::new())),
+        #[cfg(feature = "local")]
+        "local" | "" => {
+            let db_name = settings
+                .get("db_name")
+                .cloned()
+                .unwrap_or_else(|| "db".to_string());
+            if !db_name.chars().all(|e| char::is_ascii_alphanumeric(&e)) {
+                return Err(StoreError::Configuration(
+                    "DB name is illegal, may only contain alphanumeric characters".to_string(),
+                ));
+            }
+            Ok(Box::new(RocksStore::open(&format!("./{}", db_name))?))
+        }
+        #[cfg(feature = "sql")]
+        "sqlite" | "postgres" => Ok(Box::new(SqlStore::connect(&setting("db_url"))?)),
+        other => Err(StoreError::Configuration(format!(
+            "unsupported db backend \"{}\"",
+            other
+        ))),
+    }
+}
@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{Direction, IteratorMode, DB};
+
+use super::{Store, StoreError};
+
+/// Store backed by a RocksDB directory
+pub struct RocksStore {
+    db: DB,
+}
+
+impl RocksStore {
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let db = DB::open_default(path)
+            .map_err(|e| StoreError::Configuration(format!("cannot open {}: {}", path, e)))?;
+        Ok(RocksStore { db })
+    }
+}
+
+fn backend_error(e: rocksdb::Error) -> StoreError {
+    StoreError::Backend(e.to_string())
+}
+
+#[async_trait]
+impl Store for RocksStore {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        self.db.get(key).map_err(backend_error)
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        self.db.put(key, value).map_err(backend_error)
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError> {
+        self.db.delete(key).map_err(backend_error)
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError> {
+        let mut records = Vec::new();
+        for record in self
+            .db
+            .iterator(IteratorMode::From(prefix, Direction::Forward))
+        {
+            let (key, value) = record.map_err(backend_error)?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            records.push((key.to_vec(), value.to_vec()));
+        }
+        Ok(records)
+    }
+
+    async fn compact(&self) -> Result<(), StoreError> {
+        self.db.flush().map_err(backend_error)?;
+        self.db.compact_range(None::<&[u8]>, None::<&[u8]>);
+        Ok(())
+    }
+
+    async fn checkpoint(&self, path: &str) -> Result<(), StoreError> {
+        Checkpoint::new(&self.db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(path))
+            .map_err(backend_error)
+    }
+}
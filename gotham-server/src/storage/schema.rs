@@ -0,0 +1,217 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::{Store, StoreError};
+
+/// Version of the layout of keys and values written by this server
+///
+/// 1. `<customer id>_<id>_<table>` keys and bare JSON values
+/// 2. length prefixed keys and values tagged with schema version and table
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// First byte of the key of every record
+const RECORD_TAG: u8 = 0;
+/// First byte of the key of every metadata entry
+const METADATA_TAG: u8 = 1;
+const VERSION_NAME: &str = "schema_version";
+
+/// Identity of a record: its table, and the customer and id it belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordKey {
+    pub table: String,
+    pub customer_id: String,
+    pub id: String,
+}
+
+fn push_component(key: &mut Vec<u8>, component: &str) {
+    key.extend_from_slice(&(component.len() as u32).to_be_bytes());
+    key.extend_from_slice(component.as_bytes());
+}
+
+fn take_component(key: &[u8]) -> Option<(String, &[u8])> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    let component = key.get(4..4 + len)?;
+    Some((String::from_utf8(component.to_vec()).ok()?, &key[4 + len..]))
+}
+
+impl RecordKey {
+    pub fn new(table: &str, customer_id: &str, id: &str) -> Self {
+        RecordKey {
+            table: table.to_string(),
+            customer_id: customer_id.to_string(),
+            id: id.to_string(),
+        }
+    }
+
+    /// `RECORD_TAG | table | customer id | id`, each component prefixed by its length, so that
+    /// distinct records never share a key and the records of a table share a prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut key = vec![RECORD_TAG];
+        push_component(&mut key, &self.table);
+        push_component(&mut key, &self.customer_id);
+        push_component(&mut key, &self.id);
+        key
+    }
+
+    pub fn decode(key: &[u8]) -> Option<Self> {
+        let key = key.strip_prefix(&[RECORD_TAG])?;
+        let (table, key) = take_component(key)?;
+        let (customer_id, key) = take_component(key)?;
+        let (id, key) = take_component(key)?;
+        if !key.is_empty() {
+            return None;
+        }
+        Some(RecordKey {
+            table,
+            customer_id,
+            id,
+        })
+    }
+
+    /// Key of a schema 1 record. Ids and table names never contain `_`, so whatever precedes
+    /// the last two is the customer id.
+    pub fn decode_legacy(key: &[u8]) -> Option<Self> {
+        let key = std::str::from_utf8(key).ok()?;
+        let (rest, table) = key.rsplit_once('_')?;
+        let (customer_id, id) = rest.rsplit_once('_')?;
+        if table.is_empty() || id.is_empty() {
+            return None;
+        }
+        Some(Self::new(table, customer_id, id))
+    }
+}
+
+/// Prefix of the keys of every record of `table`
+pub fn table_prefix(table: &str) -> Vec<u8> {
+    let mut key = vec![RECORD_TAG];
+    push_component(&mut key, table);
+    key
+}
+
+/// Prefix of the keys of every record of `table` belonging to `customer_id`
+pub fn customer_prefix(table: &str, customer_id: &str) -> Vec<u8> {
+    let mut key = table_prefix(table);
+    push_component(&mut key, customer_id);
+    key
+}
+
+/// Key of the metadata entry `name`, kept apart from records
+pub fn metadata_key(name: &str) -> Vec<u8> {
+    [&[METADATA_TAG][..], name.as_bytes()].concat()
+}
+
+fn version_key() -> Vec<u8> {
+    metadata_key(VERSION_NAME)
+}
+
+/// Stored form of a value
+#[derive(Serialize, Deserialize)]
+pub struct Record<V> {
+    pub schema: u32,
+    pub table: String,
+    /// Unix time the record was written, absent from records written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_at: Option<i64>,
+    pub value: V,
+}
+
+impl<V> Record<V> {
+    pub fn new(table: &str, value: V) -> Self {
+        Record {
+            schema: SCHEMA_VERSION,
+            table: table.to_string(),
+            written_at: Some(Utc::now().timestamp()),
+            value,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SchemaVersion {
+    version: u32,
+}
+
+/// Version recorded in `store`. A store without version record is at version 1 when it holds
+/// records, and is empty otherwise.
+pub async fn schema_version(store: &dyn Store) -> Result<Option<u32>, StoreError> {
+    match store.get(&version_key()).await? {
+        Some(version) => serde_json::from_slice::<SchemaVersion>(&version)
+            .map(|v| Some(v.version))
+            .map_err(|e| StoreError::Backend(format!("invalid schema version record: {}", e))),
+        None if store.scan(b"").await?.is_empty() => Ok(None),
+        None => Ok(Some(1)),
+    }
+}
+
+async fn set_schema_version(store: &dyn Store, version: u32) -> Result<(), StoreError> {
+    let version = serde_json::to_vec(&SchemaVersion { version })
+        .map_err(|e| StoreError::Backend(e.to_string()))?;
+    store.put(&version_key(), &version).await
+}
+
+/// Makes sure the server can use `store`, recording the current version in an empty one
+pub async fn check_schema(store: &dyn Store) -> Result<(), StoreError> {
+    match schema_version(store).await? {
+        None => set_schema_version(store, SCHEMA_VERSION).await,
+        Some(SCHEMA_VERSION) => Ok(()),
+        Some(version) if version < SCHEMA_VERSION => Err(StoreError::Configuration(format!(
+            "storage schema is at version {} while this server needs {}, run `server_exec migrate`",
+            version, SCHEMA_VERSION
+        ))),
+        Some(version) => Err(StoreError::Configuration(format!(
+            "storage schema version {} was written by a newer server",
+            version
+        ))),
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    /// Version before the migration, `None` for an empty store
+    pub from_version: Option<u32>,
+    /// Number of migrated records per table
+    pub migrated: BTreeMap<String, usize>,
+    /// Keys that are not records of a known schema, left as they are
+    pub unrecognized: Vec<Vec<u8>>,
+}
+
+/// Upgrades every record of `store` to the current schema, or only reports what would be
+/// done when `dry_run` is set. An interrupted migration can be started again.
+pub async fn migrate(store: &dyn Store, dry_run: bool) -> Result<MigrationReport, StoreError> {
+    let mut report = MigrationReport {
+        from_version: schema_version(store).await?,
+        ..MigrationReport::default()
+    };
+    for (key, value) in store.scan(b"").await? {
+        if key.first() == Some(&METADATA_TAG) || RecordKey::decode(&key).is_some() {
+            continue;
+        }
+        let record_key = match RecordKey::decode_legacy(&key) {
+            Some(record_key) => record_key,
+            None => {
+                report.unrecognized.push(key);
+                continue;
+            }
+        };
+        let value: serde_json::Value = match serde_json::from_slice(&value) {
+            Ok(value) => value,
+            Err(_) => {
+                report.unrecognized.push(key);
+                continue;
+            }
+        };
+        *report.migrated.entry(record_key.table.clone()).or_default() += 1;
+        if dry_run {
+            continue;
+        }
+        let record = serde_json::to_vec(&Record::new(&record_key.table, value))
+            .map_err(|e| StoreError::Backend(e.to_string()))?;
+        store.put(&record_key.encode(), &record).await?;
+        store.delete(&key).await?;
+    }
+    if !dry_run {
+        set_schema_version(store, SCHEMA_VERSION).await?;
+    }
+    Ok(report)
+}
@@ -0,0 +1,151 @@
+use async_trait::async_trait;
+use sqlx::any::{AnyPoolOptions, AnyRow};
+use sqlx::{AnyPool, Row};
+use tokio::sync::OnceCell;
+
+use super::{Store, StoreError};
+
+const TABLE: &str = "gotham_store";
+
+/// Store backed by a single table of a SQLite or PostgreSQL database
+pub struct SqlStore {
+    pool: AnyPool,
+    binary_type: &'static str,
+    /// Set once the table is known to exist
+    ready: OnceCell<()>,
+}
+
+impl SqlStore {
+    /// Store at `url`, e.g. `sqlite:///var/lib/gotham/db.sqlite?mode=rwc` or
+    /// `postgres://gotham@localhost/gotham`. Nothing is sent to the database before first use.
+    pub fn connect(url: &str) -> Result<Self, StoreError> {
+        let binary_type = if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+            "BYTEA"
+        } else if url.starts_with("sqlite:") {
+            "BLOB"
+        } else {
+            return Err(StoreError::Configuration(
+                "db_url must be a sqlite: or postgres: url".to_string(),
+            ));
+        };
+        sqlx::any::install_default_drivers();
+        let pool = AnyPoolOptions::new()
+            .connect_lazy(url)
+            .map_err(|e| StoreError::Configuration(format!("invalid db_url: {}", e)))?;
+        Ok(SqlStore {
+            pool,
+            binary_type,
+            ready: OnceCell::new(),
+        })
+    }
+
+    async fn pool(&self) -> Result<&AnyPool, StoreError> {
+        self.ready
+            .get_or_try_init(|| async {
+                sqlx::query(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key {ty} PRIMARY KEY, value {ty} NOT NULL)",
+                    TABLE,
+                    ty = self.binary_type
+                ))
+                .execute(&self.pool)
+                .await
+                .map(|_| ())
+                .map_err(backend_error)
+            })
+            .await?;
+        Ok(&self.pool)
+    }
+}
+
+fn backend_error(e: sqlx::Error) -> StoreError {
+    StoreError::Backend(e.to_string())
+}
+
+/// Smallest key greater than every key starting with `prefix`, if any
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn record(row: AnyRow) -> Result<(Vec<u8>, Vec<u8>), StoreError> {
+    Ok((
+        row.try_get("key").map_err(backend_error)?,
+        row.try_get("value").map_err(backend_error)?,
+    ))
+}
+
+#[async_trait]
+impl Store for SqlStore {
+    async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StoreError> {
+        let row = sqlx::query(&format!("SELECT value FROM {} WHERE key = $1", TABLE))
+            .bind(key.to_vec())
+            .fetch_optional(self.pool().await?)
+            .await
+            .map_err(backend_error)?;
+        row.map(|row| row.try_get("value").map_err(backend_error))
+            .transpose()
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) -> Result<(), StoreError> {
+        sqlx::query(&format!(
+            "INSERT INTO {} (key, value) VALUES ($1, $2) \
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            TABLE
+        ))
+        .bind(key.to_vec())
+        .bind(value.to_vec())
+        .execute(self.pool().await?)
+        .await
+        .map(|_| ())
+        .map_err(backend_error)
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<(), StoreError> {
+        sqlx::query(&format!("DELETE FROM {} WHERE key = $1", TABLE))
+            .bind(key.to_vec())
+            .execute(self.pool().await?)
+            .await
+            .map(|_| ())
+            .map_err(backend_error)
+    }
+
+    async fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, StoreError> {
+        // both databases compare binary keys bytewise
+        let end = prefix_end(prefix);
+        let sql = match end {
+            Some(_) => format!(
+                "SELECT key, value FROM {} WHERE key >= $1 AND key < $2 ORDER BY key",
+                TABLE
+            ),
+            None => format!(
+                "SELECT key, value FROM {} WHERE key >= $1 ORDER BY key",
+                TABLE
+            ),
+        };
+        let mut query = sqlx::query(&sql).bind(prefix.to_vec());
+        if let Some(end) = end {
+            query = query.bind(end);
+        }
+        query
+            .fetch_all(self.pool().await?)
+            .await
+            .map_err(backend_error)?
+            .into_iter()
+            .map(record)
+            .collect()
+    }
+
+    async fn compact(&self) -> Result<(), StoreError> {
+        sqlx::query("VACUUM")
+            .execute(self.pool().await?)
+            .await
+            .map(|_| ())
+            .map_err(backend_error)
+    }
+}
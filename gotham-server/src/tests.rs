@@ -2,21 +2,69 @@
 mod tests {
     use std::collections::HashMap;
     use std::env;
-    use std::time::Instant;
+    use std::fs;
+    use chrono::{Duration, TimeZone, Utc};
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
     use floating_duration::TimeFormat;
-    use crate::server;
-    use rocket::{http::ContentType, http::{Status}, local::blocking::Client};
+    use crate::admin::Admin;
+    use crate::approval::{ApprovalRequest, ApprovalState, ApprovalStatus, ParkedSign, APPROVALS_TABLE};
+    use crate::audit::{self, AuditEntry, AuditError, AuditLog, AuditRecord, Decision};
+    use crate::auth::{AuthError, Authorizer, PASSTHROUGH_SUBJECT};
+    use crate::deletion::{DeletionReceipt, TOMBSTONES_TABLE};
+    use crate::error::{ErrorBody, REQUEST_ID_HEADER};
+    use crate::escrow::{self, EscrowBackup, EscrowError};
+    use crate::health::Health;
+    use crate::idempotency::{
+        self, CONTENT_DIGEST_HEADER, IDEMPOTENCY_KEY_HEADER, REPLAYED_HEADER, RESPONSES_TABLE,
+    };
+    use crate::openapi;
+    use crate::keygen::{ActiveKey, KeygenStruct};
+    use crate::keys::{KeyMetadata, KeyRecovery, KeyState, KeyStatus, KeyStruct};
+    use crate::metrics::Metrics;
+    use crate::policy::{
+        BusinessHours, CustomerRules, Policy, PolicyEngine, PositionRange, Rules, SignContext,
+    };
+    use crate::presign::{Presignature, PresignedSignRequest};
+    use crate::public_gotham::{get_settings_as_map, PublicGotham};
+    use crate::rate_limit::{RateLimiter, RouteClass};
+    use crate::server::{self, StartupError};
+    use crate::sign::SignBatchItem;
+    use crate::sessions::Sessions;
+    #[cfg(feature = "local")]
+    use crate::storage::RocksStore;
+    use crate::storage::schema::{self, Record, RecordKey};
+    use crate::storage::{EncryptedStore, Kek, MemoryStore, RewrapReport, Store};
+    use crate::transaction::{Chain, Payment, TransactionError, TransactionSummary, UnsignedTransaction};
+    use crate::websocket::{self, SessionResponse, SessionServer, SESSION_PATH};
+    #[cfg(feature = "sql")]
+    use crate::storage::SqlStore;
+    use gotham_engine::traits::Db;
+    use gotham_engine::types::{DbIndex, EcdsaStruct};
+    use std::sync::Arc;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use rocket::{http::ContentType, http::{Header as HttpHeader, Status}, local::blocking::Client};
+    use sha2::{Digest, Sha256};
     use two_party_ecdsa::{BigInt, party_one, party_two};
     use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{Party1FirstMessage, Party1SecondMessage};
     use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey2, party1};
     use two_party_ecdsa::kms::chain_code::two_party::party2::ChainCode2;
+    use two_party_ecdsa::kms::rotation::two_party::party2::Rotation2;
+    use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
     use two_party_ecdsa::kms::ecdsa;
     use gotham_engine::types::SignSecondMsgRequest;
-    use two_party_ecdsa::party_one::Converter;
+    use two_party_ecdsa::party_one::{Converter, Value};
+    use two_party_ecdsa::curv::elliptic::curves::secp256_k1::GE;
+    use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
+    use ethers_core::types::transaction::eip2718::TypedTransaction;
+    use ethers_core::types::TransactionRequest;
+    use rocket::futures::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
     fn key_gen(client: &Client) -> (String, MasterKey2) {
         let response = client
-            .post("/ecdsa/keygen/first")
+            .post("/ecdsa/keygen/first?new_key=true")
             .header(ContentType::JSON)
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
@@ -218,6 +266,758 @@ mod tests {
         signature_recid
     }
 
+    fn auth_settings() -> HashMap<String, String> {
+        HashMap::<String, String>::from([
+            ("issuer".to_string(), "https://auth.gotham.test".to_string()),
+            ("audience".to_string(), "gotham-city".to_string()),
+            (
+                "jwks_path".to_string(),
+                concat!(env!("CARGO_MANIFEST_DIR"), "/test-assets/jwks.json").to_string(),
+            ),
+            ("auth_passthrough".to_string(), "false".to_string()),
+        ])
+    }
+
+    fn auth_token(kid: &str, audience: &str, expires_in: i64) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(kid.to_string());
+        let claims = serde_json::json!({
+            "sub": "customer-1",
+            "iss": "https://auth.gotham.test",
+            "aud": audience,
+            "exp": now + expires_in,
+        });
+        let key = EncodingKey::from_ec_pem(include_bytes!("../test-assets/es256-key.pem")).unwrap();
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn authentication_test_valid_token() {
+        let authorizer = Authorizer::from_settings(&auth_settings()).unwrap();
+        let claims = authorizer
+            .verify(&auth_token("gotham-test", "gotham-city", 600))
+            .unwrap();
+        assert_eq!(claims.sub, "customer-1");
+    }
+
+    #[test]
+    fn authentication_test_expired_token() {
+        let authorizer = Authorizer::from_settings(&auth_settings()).unwrap();
+        let result = authorizer.verify(&auth_token("gotham-test", "gotham-city", -600));
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn authentication_test_invalid_token() {
+        let authorizer = Authorizer::from_settings(&auth_settings()).unwrap();
+        let result = authorizer.verify(&auth_token("gotham-test", "another-audience", 600));
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+        let result = authorizer.verify(&auth_token("unknown", "gotham-city", 600));
+        assert!(matches!(result, Err(AuthError::UnknownKeyId(_))));
+        let result = authorizer.verify("not a token");
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[test]
+    fn authentication_test_passthrough() {
+        let passthrough = HashMap::from([("auth_passthrough".to_string(), "true".to_string())]);
+        let authorizer = Authorizer::from_settings(&passthrough).unwrap();
+        assert!(authorizer.is_passthrough());
+        // authentication is only disabled when asked for
+        assert!(matches!(
+            Authorizer::from_settings(&HashMap::new()),
+            Err(AuthError::Configuration(_))
+        ));
+        let mut settings = auth_settings();
+        settings.extend(passthrough);
+        assert!(matches!(
+            Authorizer::from_settings(&settings),
+            Err(AuthError::Configuration(_))
+        ));
+
+        let mut settings = auth_settings();
+        settings.remove("jwks_path");
+        assert!(matches!(
+            Authorizer::from_settings(&settings),
+            Err(AuthError::Configuration(_))
+        ));
+    }
+
+    fn sign_context(customer_id: &str, message: &str, position: (u64, u64)) -> SignContext {
+        SignContext {
+            customer_id: customer_id.to_string(),
+            message: message.to_string(),
+            position: Some(position),
+            time: Utc.with_ymd_and_hms(2023, 11, 22, 10, 0, 0).unwrap(),
+            transaction: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn policy_test_rate_limits() {
+        let policy = Policy {
+            default: Rules {
+                max_signatures_per_hour: Some(2),
+                max_signatures_per_day: Some(3),
+                ..Rules::default()
+            },
+            customers: vec![],
+        };
+        let engine = PolicyEngine::new(policy);
+        let mut context = sign_context("customer-1", "1234", (0, 1));
+
+        for _ in 0..2 {
+            assert!(engine.authorize(&context).is_ok());
+            engine
+                .record_signature(&context.customer_id, context.time)
+                .await
+                .unwrap();
+        }
+        assert!(engine.authorize(&context).is_err());
+        assert!(engine
+            .authorize(&sign_context("customer-2", "1234", (0, 1)))
+            .is_ok());
+
+        context.time = context.time + Duration::hours(2);
+        assert!(engine.authorize(&context).is_ok());
+        engine
+            .record_signature(&context.customer_id, context.time)
+            .await
+            .unwrap();
+        let denial = engine.authorize(&context).unwrap_err();
+        assert_eq!(denial.reason, "limit of 3 signatures per day reached");
+
+        context.time = context.time + Duration::days(1);
+        assert!(engine.authorize(&context).is_ok());
+    }
+
+    #[rocket::async_test]
+    async fn policy_test_persisted_signatures() {
+        let policy = Policy {
+            default: Rules {
+                max_signatures_per_hour: Some(1),
+                ..Rules::default()
+            },
+            customers: vec![],
+        };
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::new());
+        let context = sign_context("customer-1", "1234", (0, 1));
+        let engine = PolicyEngine::new(policy.clone()).with_store(store.clone());
+        engine.load_signatures(&context.customer_id).await.unwrap();
+        assert!(engine.authorize(&context).is_ok());
+        engine
+            .record_signature(&context.customer_id, context.time)
+            .await
+            .unwrap();
+
+        // a restarted server still counts the signature
+        let restarted = PolicyEngine::new(policy).with_store(store);
+        restarted
+            .load_signatures(&context.customer_id)
+            .await
+            .unwrap();
+        assert!(restarted.authorize(&context).is_err());
+    }
+
+    #[test]
+    fn policy_test_customer_rules() {
+        let policy = Policy {
+            default: Rules {
+                denied_messages: Some(vec!["0xdead".to_string()]),
+                ..Rules::default()
+            },
+            customers: vec![CustomerRules {
+                id: "customer-1".to_string(),
+                rules: Rules {
+                    allowed_positions: Some(vec![PositionRange {
+                        x: 0,
+                        y_from: 0,
+                        y_to: 10,
+                    }]),
+                    allowed_messages: Some(vec!["00beef".to_string()]),
+                    ..Rules::default()
+                },
+            }],
+        };
+        let engine = PolicyEngine::new(policy);
+
+        assert!(engine
+            .authorize(&sign_context("customer-1", "beef", (0, 10)))
+            .is_ok());
+        assert!(engine
+            .authorize(&sign_context("customer-1", "beef", (0, 11)))
+            .is_err());
+        assert!(engine
+            .authorize(&sign_context("customer-1", "1234", (0, 1)))
+            .is_err());
+        assert!(engine
+            .authorize(&sign_context("customer-1", "DEAD", (0, 1)))
+            .is_err());
+        assert!(engine
+            .authorize(&sign_context("customer-2", "1234", (5, 5)))
+            .is_ok());
+        assert!(engine
+            .authorize(&sign_context("customer-2", "dead", (0, 1)))
+            .is_err());
+
+        let mut context = sign_context("customer-1", "beef", (0, 1));
+        context.position = None;
+        assert!(engine.authorize(&context).is_err());
+    }
+
+    #[test]
+    fn policy_test_business_hours() {
+        let policy = Policy {
+            default: Rules {
+                business_hours: Some(BusinessHours {
+                    start_hour: 9,
+                    end_hour: 17,
+                    days: vec!["Mon".to_string(), "Wed".to_string()],
+                    utc_offset_minutes: 120,
+                }),
+                ..Rules::default()
+            },
+            customers: vec![],
+        };
+        let engine = PolicyEngine::new(policy);
+        // Wednesday 10:00 UTC is 12:00 local time
+        let mut context = sign_context("customer-1", "1234", (0, 1));
+        assert!(engine.authorize(&context).is_ok());
+        context.time = Utc.with_ymd_and_hms(2023, 11, 22, 15, 30, 0).unwrap();
+        assert!(engine.authorize(&context).is_err());
+        context.time = Utc.with_ymd_and_hms(2023, 11, 23, 10, 0, 0).unwrap();
+        assert!(engine.authorize(&context).is_err());
+    }
+
+    #[test]
+    fn policy_test_reload() {
+        let path = env::temp_dir().join(format!("gotham-policy-{}.toml", std::process::id()));
+        fs::write(&path, "[default]\nmax_signatures_per_hour = 0\n").unwrap();
+        let engine = PolicyEngine::from_file(path.clone()).unwrap();
+        let context = sign_context("customer-1", "1234", (0, 1));
+        assert!(engine.authorize(&context).is_err());
+
+        fs::write(
+            &path,
+            "[[customers]]\nid = \"customer-1\"\nmax_signatures_per_hour = 1\n",
+        )
+        .unwrap();
+        engine.reload().unwrap();
+        assert!(engine.authorize(&context).is_ok());
+
+        fs::write(
+            &path,
+            "[default]\nbusiness_hours = { start_hour = 18, end_hour = 9 }\n",
+        )
+        .unwrap();
+        assert!(engine.reload().is_err());
+        assert!(engine.authorize(&context).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn transaction_test_digest() {
+        let public_key = GE::generator().get_element();
+
+        let to = ethers_core::types::Address::repeat_byte(0x11);
+        let ethereum: TypedTransaction = TransactionRequest::new()
+            .to(to)
+            .value(1000u64)
+            .chain_id(5u64)
+            .into();
+        let transaction = UnsignedTransaction::Ethereum {
+            chain_id: Some(5),
+            // the JSON of a transaction leaves its chain id out
+            transaction: serde_json::from_value(serde_json::to_value(&ethereum).unwrap()).unwrap(),
+        };
+        let message = BigInt::from(ethereum.sighash().as_bytes());
+        let summary = transaction.inspect(&message, &public_key, &[]).unwrap();
+        assert_eq!(summary.chain, Chain::Ethereum);
+        assert_eq!(
+            summary.payments,
+            vec![Payment {
+                destination: Some(format!("0x{}", "11".repeat(20))),
+                amount: 1000,
+                change: false,
+                token: None,
+            }]
+        );
+        assert!(!summary.contract_call);
+        assert!(matches!(
+            transaction.inspect(&BigInt::from(1234u32), &public_key, &[]),
+            Err(TransactionError::Mismatch { .. })
+        ));
+
+        // ERC-20 transfers pay their recipient, other call data is a contract call
+        let recipient = "22".repeat(20);
+        let call = |data: String| {
+            let ethereum: TypedTransaction = TransactionRequest::new()
+                .to(to)
+                .data(hex::decode(data).unwrap())
+                .chain_id(5u64)
+                .into();
+            let message = BigInt::from(ethereum.sighash().as_bytes());
+            UnsignedTransaction::Ethereum {
+                chain_id: Some(5),
+                transaction: ethereum,
+            }
+            .inspect(&message, &public_key, &[])
+        };
+        let summary = call(format!("a9059cbb{:0>64}{:064x}", recipient, 500)).unwrap();
+        assert_eq!(
+            summary.payments,
+            vec![Payment {
+                destination: Some(format!("0x{}", recipient)),
+                amount: 500,
+                change: false,
+                token: Some(format!("0x{}", "11".repeat(20))),
+            }]
+        );
+        assert!(!summary.contract_call);
+        assert_eq!(summary.value(), 0);
+        assert!(call(format!("095ea7b3{:0>64}{:064x}", recipient, 500))
+            .unwrap()
+            .payments[0]
+            .token
+            .is_some());
+        assert!(call("23b872dd".to_string()).unwrap().contract_call);
+        assert!(call(format!("a9059cbb{:f>64}{:064x}", recipient, 500)).is_err());
+
+        let key = bitcoin::PublicKey::from_slice(&public_key.serialize()).unwrap();
+        let own = bitcoin::Address::p2wpkh(&key, bitcoin::Network::Testnet).unwrap();
+        let spend = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn::default(), bitcoin::TxIn::default()],
+            output: vec![
+                bitcoin::TxOut {
+                    value: 50_000,
+                    script_pubkey: own.script_pubkey(),
+                },
+                bitcoin::TxOut {
+                    value: 40_000,
+                    script_pubkey: bitcoin::Script::new_op_return(&[1]),
+                },
+            ],
+        };
+        let digest = bitcoin::util::bip143::SigHashCache::new(&spend).signature_hash(
+            1,
+            &bitcoin::Address::p2pkh(&key, bitcoin::Network::Testnet).script_pubkey(),
+            70_000,
+            bitcoin::SigHashType::All,
+        );
+        let unsigned = |prevout_amounts: Vec<u64>| UnsignedTransaction::Bitcoin {
+            network: "testnet".to_string(),
+            transaction: hex::encode(bitcoin::consensus::serialize(&spend)),
+            input_index: 1,
+            prevout_amounts,
+            change_positions: vec![],
+        };
+        let message = BigInt::from(&digest[..]);
+        let summary = unsigned(vec![30_000, 70_000])
+            .inspect(&message, &public_key, &[])
+            .unwrap();
+        assert_eq!(summary.value(), 90_000);
+        assert_eq!(summary.payments[0].destination, Some(own.to_string()));
+        assert_eq!(summary.payments[1].destination, None);
+        // the output back to the key is change once its key is listed
+        let summary = unsigned(vec![30_000, 70_000])
+            .inspect(&message, &public_key, &[public_key])
+            .unwrap();
+        assert!(summary.payments[0].change);
+        assert!(!summary.payments[1].change);
+        assert_eq!(summary.value(), 40_000);
+        // the digest commits to the amount of the signed input
+        assert!(matches!(
+            unsigned(vec![30_000, 60_000]).inspect(&message, &public_key, &[]),
+            Err(TransactionError::Mismatch { .. })
+        ));
+        assert!(matches!(
+            unsigned(vec![70_000]).inspect(&message, &public_key, &[]),
+            Err(TransactionError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn policy_test_transaction_rules() {
+        let policy = Policy {
+            default: Rules {
+                allowed_destinations: Some(vec![
+                    "tb1qallowed".to_string(),
+                    "0x52908400098527886E0F7030069857D2E4169EE7".to_string(),
+                ]),
+                max_value_sats: Some(100_000),
+                max_value_gwei: Some(1_000_000_000),
+                ..Rules::default()
+            },
+            customers: vec![],
+        };
+        let engine = PolicyEngine::new(policy);
+        let mut context = sign_context("customer-1", "1234", (0, 1));
+        // destination rules need the transaction
+        assert!(engine.authorize(&context).is_err());
+
+        let payment = |destination: Option<&str>, amount: u128| Payment {
+            destination: destination.map(str::to_string),
+            amount,
+            change: false,
+            token: None,
+        };
+        let bitcoin_payments = |payments: Vec<Payment>| TransactionSummary {
+            chain: Chain::Bitcoin,
+            payments,
+            contract_call: false,
+        };
+        context.transaction = Some(bitcoin_payments(vec![
+            payment(Some("tb1qallowed"), 60_000),
+            payment(Some("tb1qallowed"), 40_000),
+        ]));
+        assert!(engine.authorize(&context).is_ok());
+        context.transaction = Some(bitcoin_payments(vec![payment(
+            Some("tb1qallowed"),
+            100_001,
+        )]));
+        assert!(engine.authorize(&context).is_err());
+        context.transaction = Some(bitcoin_payments(vec![payment(Some("tb1qother"), 1)]));
+        assert!(engine.authorize(&context).is_err());
+        context.transaction = Some(bitcoin_payments(vec![payment(None, 0)]));
+        assert!(engine.authorize(&context).is_err());
+        // change outputs are neither destinations nor paid value
+        let change = Payment {
+            change: true,
+            ..payment(Some("tb1qchange"), 500_000)
+        };
+        context.transaction = Some(bitcoin_payments(vec![
+            payment(Some("tb1qallowed"), 100_000),
+            change.clone(),
+        ]));
+        assert!(engine.authorize(&context).is_ok());
+        context.transaction = Some(bitcoin_payments(vec![Payment {
+            change: false,
+            ..change
+        }]));
+        assert!(engine.authorize(&context).is_err());
+
+        // ethereum addresses match whatever their checksum case, and values are capped in gwei
+        context.transaction = Some(TransactionSummary {
+            chain: Chain::Ethereum,
+            payments: vec![payment(
+                Some("0x52908400098527886e0f7030069857d2e4169ee7"),
+                10u128.pow(18),
+            )],
+            contract_call: false,
+        });
+        assert!(engine.authorize(&context).is_ok());
+        context.transaction = Some(TransactionSummary {
+            chain: Chain::Ethereum,
+            payments: vec![payment(
+                Some("0x52908400098527886e0f7030069857d2e4169ee7"),
+                10u128.pow(18) + 1,
+            )],
+            contract_call: false,
+        });
+        assert!(engine.authorize(&context).is_err());
+
+        // contract calls and token amounts escape the value rules, so they are denied
+        context.transaction = Some(TransactionSummary {
+            chain: Chain::Ethereum,
+            payments: vec![payment(
+                Some("0x52908400098527886e0f7030069857d2e4169ee7"),
+                0,
+            )],
+            contract_call: true,
+        });
+        let denial = engine.authorize(&context).unwrap_err();
+        assert!(denial.reason.contains("contract calls"));
+        context.transaction = Some(TransactionSummary {
+            chain: Chain::Ethereum,
+            payments: vec![Payment {
+                token: Some("0x11".to_string()),
+                ..payment(Some("0x52908400098527886e0f7030069857d2e4169ee7"), 1)
+            }],
+            contract_call: false,
+        });
+        assert!(engine.authorize(&context).is_err());
+    }
+
+    /// Behaviour every `Store` backend must implement
+    async fn store_conformance(store: Box<dyn Store>) {
+        assert_eq!(store.get(b"a_1").await.unwrap(), None);
+        store.put(b"a_1", b"one").await.unwrap();
+        store.put(b"a_2", b"two").await.unwrap();
+        store.put(b"b_1", b"three").await.unwrap();
+        store.put(b"a\xff", b"four").await.unwrap();
+        assert_eq!(store.get(b"a_1").await.unwrap(), Some(b"one".to_vec()));
+
+        store.put(b"a_1", b"uno").await.unwrap();
+        assert_eq!(store.get(b"a_1").await.unwrap(), Some(b"uno".to_vec()));
+
+        let records = store.scan(b"a").await.unwrap();
+        let keys: Vec<&[u8]> = records.iter().map(|(key, _)| key.as_slice()).collect();
+        assert_eq!(keys, vec![&b"a_1"[..], &b"a_2"[..], &b"a\xff"[..]]);
+        assert_eq!(records[0].1, b"uno".to_vec());
+        assert_eq!(store.scan(b"").await.unwrap().len(), 4);
+        assert!(store.scan(b"c").await.unwrap().is_empty());
+
+        store.delete(b"a_1").await.unwrap();
+        store.delete(b"missing").await.unwrap();
+        assert_eq!(store.get(b"a_1").await.unwrap(), None);
+        assert_eq!(store.scan(b"a").await.unwrap().len(), 2);
+
+        // the server's own records go through the `Db` implementation
+        for (key, _) in store.scan(b"").await.unwrap() {
+            store.delete(&key).await.unwrap();
+        }
+        let db = PublicGotham::with_store(store, Arc::new(PolicyEngine::new(Policy::default())));
+        db_conformance(&db).await;
+    }
+
+    async fn db_conformance(db: &PublicGotham) {
+        let key = DbIndex {
+            customerId: "customer_1".to_string(),
+            id: "key-1".to_string(),
+        };
+        let value = ActiveKey {
+            id: "value".to_string(),
+            activated_at: 1,
+        };
+        assert!(db
+            .get(&key, &KeygenStruct::ActiveKey)
+            .await
+            .unwrap()
+            .is_none());
+        db.insert(&key, &KeygenStruct::ActiveKey, &value)
+            .await
+            .unwrap();
+        let stored = db
+            .get(&key, &KeygenStruct::ActiveKey)
+            .await
+            .unwrap()
+            .unwrap();
+        let stored = stored.as_any().downcast_ref::<ActiveKey>().unwrap();
+        assert_eq!(stored.id, "value");
+
+        let other_customer = DbIndex {
+            customerId: "customer_2".to_string(),
+            id: "key-1".to_string(),
+        };
+        assert!(db
+            .get(&other_customer, &KeygenStruct::ActiveKey)
+            .await
+            .unwrap()
+            .is_none());
+
+        // ids containing the separator of the legacy keys do not collide
+        let shifted = DbIndex {
+            customerId: "customer".to_string(),
+            id: "1_key-1".to_string(),
+        };
+        assert!(db
+            .get(&shifted, &KeygenStruct::ActiveKey)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(!db.has_active_share("customer_1").await.unwrap());
+        db.insert(&key, &EcdsaStruct::Party1MasterKey, &value)
+            .await
+            .unwrap();
+        assert!(db.has_active_share("customer_1").await.unwrap());
+        assert!(!db.has_active_share("customer_2").await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn storage_test_memory() {
+        store_conformance(Box::new(MemoryStore::new())).await;
+    }
+
+    #[cfg(feature = "local")]
+    #[rocket::async_test]
+    async fn storage_test_rocksdb() {
+        let path = env::temp_dir().join(format!("gotham-rocksdb-{}", std::process::id()));
+        let store = RocksStore::open(path.to_str().unwrap()).unwrap();
+        store_conformance(Box::new(store)).await;
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    const TEST_KEK: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const TEST_NEW_KEK: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[rocket::async_test]
+    async fn storage_test_encrypted() {
+        let kek = Kek::from_hex(TEST_KEK).unwrap();
+        store_conformance(Box::new(EncryptedStore::new(
+            Box::new(MemoryStore::new()),
+            kek,
+            vec![],
+        )))
+        .await;
+    }
+
+    #[rocket::async_test]
+    async fn encryption_test_kek_rotation() {
+        let backend = Arc::new(MemoryStore::new());
+        backend.put(b"legacy", b"{\"plain\":1}").await.unwrap();
+        let store = EncryptedStore::new(
+            Box::new(backend.clone()),
+            Kek::from_hex(TEST_KEK).unwrap(),
+            vec![],
+        );
+        store.put(b"share", b"secret share").await.unwrap();
+        assert_eq!(
+            store.get(b"share").await.unwrap(),
+            Some(b"secret share".to_vec())
+        );
+        assert_eq!(
+            store.get(b"legacy").await.unwrap(),
+            Some(b"{\"plain\":1}".to_vec())
+        );
+
+        // the backend never sees the value, which cannot be moved to another key
+        let record = backend.get(b"share").await.unwrap().unwrap();
+        assert!(!record.windows(6).any(|w| w == b"secret"));
+        backend.put(b"moved", &record).await.unwrap();
+        assert!(store.get(b"moved").await.is_err());
+        backend.delete(b"moved").await.unwrap();
+
+        let rotated = EncryptedStore::new(
+            Box::new(backend.clone()),
+            Kek::from_hex(TEST_NEW_KEK).unwrap(),
+            vec![Kek::from_hex(TEST_KEK).unwrap()],
+        );
+        let report = rotated.rewrap().await.unwrap();
+        assert_eq!(
+            report,
+            RewrapReport {
+                rewrapped: 1,
+                encrypted: 1,
+                unchanged: 0
+            }
+        );
+        assert_eq!(rotated.rewrap().await.unwrap().unchanged, 2);
+
+        // the previous KEK is no longer needed, nor usable
+        let store = EncryptedStore::new(
+            Box::new(backend.clone()),
+            Kek::from_hex(TEST_NEW_KEK).unwrap(),
+            vec![],
+        );
+        assert_eq!(
+            store.get(b"share").await.unwrap(),
+            Some(b"secret share".to_vec())
+        );
+        assert_eq!(
+            store.get(b"legacy").await.unwrap(),
+            Some(b"{\"plain\":1}".to_vec())
+        );
+        let store = EncryptedStore::new(
+            Box::new(backend.clone()),
+            Kek::from_hex(TEST_KEK).unwrap(),
+            vec![],
+        );
+        assert!(store.get(b"share").await.is_err());
+
+        // a truncated record is reported as an error instead of a panic
+        let record = backend.get(b"share").await.unwrap().unwrap();
+        backend.put(b"truncated", &record[..10]).await.unwrap();
+        let rotated = EncryptedStore::new(
+            Box::new(backend),
+            Kek::from_hex(TEST_KEK).unwrap(),
+            vec![Kek::from_hex(TEST_NEW_KEK).unwrap()],
+        );
+        assert!(rotated.rewrap().await.is_err());
+    }
+
+    #[rocket::async_test]
+    async fn schema_test_migration() {
+        let store = Arc::new(MemoryStore::new());
+        assert_eq!(schema::schema_version(store.as_ref()).await.unwrap(), None);
+
+        let active_key = ActiveKey {
+            id: "key-1".to_string(),
+            activated_at: 1,
+        };
+        let legacy = serde_json::to_vec(&(&active_key as &dyn Value)).unwrap();
+        store
+            .put(b"customer_1_active_ActiveKey", &legacy)
+            .await
+            .unwrap();
+        store.put(b"stray", b"{}").await.unwrap();
+        assert_eq!(
+            schema::schema_version(store.as_ref()).await.unwrap(),
+            Some(1)
+        );
+        assert!(schema::check_schema(store.as_ref()).await.is_err());
+
+        let report = schema::migrate(store.as_ref(), true).await.unwrap();
+        assert_eq!(report.from_version, Some(1));
+        assert_eq!(report.migrated.get("ActiveKey"), Some(&1));
+        assert_eq!(report.unrecognized, vec![b"stray".to_vec()]);
+        assert!(store
+            .get(b"customer_1_active_ActiveKey")
+            .await
+            .unwrap()
+            .is_some());
+
+        let report = schema::migrate(store.as_ref(), false).await.unwrap();
+        assert_eq!(report.migrated.get("ActiveKey"), Some(&1));
+        assert!(store
+            .get(b"customer_1_active_ActiveKey")
+            .await
+            .unwrap()
+            .is_none());
+        schema::check_schema(store.as_ref()).await.unwrap();
+        assert!(schema::migrate(store.as_ref(), false)
+            .await
+            .unwrap()
+            .migrated
+            .is_empty());
+
+        // the migrated record is read by the server, under the customer of the legacy key
+        let db = PublicGotham::with_store(
+            Box::new(store),
+            Arc::new(PolicyEngine::new(Policy::default())),
+        );
+        assert!(db.has_active_share("customer_1").await.unwrap());
+        assert!(!db.has_active_share("customer").await.unwrap());
+
+        let key = RecordKey::new("ActiveKey", "a_b", "c");
+        assert_eq!(RecordKey::decode(&key.encode()), Some(key.clone()));
+        assert_ne!(
+            key.encode(),
+            RecordKey::new("ActiveKey", "a", "b_c").encode()
+        );
+    }
+
+    #[cfg(feature = "sql")]
+    #[rocket::async_test]
+    async fn storage_test_sqlite() {
+        let path = env::temp_dir().join(format!("gotham-sqlite-{}.db", std::process::id()));
+        let store = SqlStore::connect(&format!("sqlite://{}?mode=rwc", path.display())).unwrap();
+        store_conformance(Box::new(store)).await;
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Runs against the (emptied) database at GOTHAM_TEST_POSTGRES_URL, when set
+    #[cfg(feature = "sql")]
+    #[rocket::async_test]
+    async fn storage_test_postgres() {
+        let url = match env::var("GOTHAM_TEST_POSTGRES_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let store = SqlStore::connect(&url).unwrap();
+        for (key, _) in store.scan(b"").await.unwrap() {
+            store.delete(&key).await.unwrap();
+        }
+        store_conformance(Box::new(store)).await;
+    }
+
     #[test]
     fn unit_test_key_gen_and_sign() {
         // Passthrough mode
@@ -225,16 +1025,36 @@ mod tests {
         env::set_var("pool_id", "");
         env::set_var("issuer", "");
         env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
         // env::set_var("ELASTICACHE_URL", "127.0.0.1");
 
         let settings = HashMap::<String, String>::from([
             ("db".to_string(), "local".to_string()),
             ("db_name".to_string(), "KeyGenAndSign".to_string()),
         ]);
-        let server = server::get_server();
+        let server = server::get_server().expect("valid configuration");
         let client = Client::tracked(server).expect("valid rocket instance");
         let (id, master_key_2) = key_gen(&client);
 
+        // A second key is only generated when explicitly asked for
+        let response = client
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        assert!(response.headers().get_one(REQUEST_ID_HEADER).is_some());
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "forbidden");
+        let response = client
+            .post("/ecdsa/keygen/active")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let active_key: ActiveKey = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(active_key.id, id);
+
         let message = BigInt::from(1234u32);
 
         let signature: party_one::SignatureRecid =
@@ -247,5 +1067,2101 @@ mod tests {
             signature.recid
         );
         //test v2 sign interface with session id enabled
+
+        // Errors are JSON bodies carrying the request id
+        let (eph_key_gen_first_message_party_two, _, _) = MasterKey2::sign_first_message();
+        let response = client
+            .post("/ecdsa/sign/unknown-key/first")
+            .header(ContentType::JSON)
+            .header(HttpHeader::new(REQUEST_ID_HEADER, "request-1"))
+            .body(serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            response.headers().get_one(REQUEST_ID_HEADER),
+            Some("request-1")
+        );
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "not_found");
+        assert_eq!(error.request_id, "request-1");
+
+        let response = client.get("/no/such/route").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "not_found");
+        assert_eq!(error.message, "Unknown route '/no/such/route'.");
+
+        let response = client.get("/health/live").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/health/ready").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let health: Health = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(health.status, "ready");
+        assert_eq!(health.checks.len(), 3);
+
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let metrics = response.into_string().unwrap();
+        assert!(metrics.contains("gotham_requests_total{outcome=\"ok\",route=\"keygen_first\"} 1"));
+        assert!(
+            metrics.contains("gotham_requests_total{outcome=\"not_found\",route=\"sign_first\"} 1")
+        );
+        assert!(metrics.contains("gotham_request_duration_seconds_count{route=\"sign_second\"} 1"));
+        assert!(metrics.contains("gotham_sessions_in_flight{protocol=\"keygen\"} 0"));
+        assert!(metrics.contains("gotham_sessions_in_flight{protocol=\"sign\"} 0"));
+        assert!(metrics.contains(
+            "gotham_store_operation_duration_seconds_count{operation=\"put\",table=\"Party1MasterKey\"}"
+        ));
+    }
+
+    #[test]
+    fn admin_test_freeze_and_delete() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let store = client.rocket().state::<Arc<dyn Store>>().unwrap().clone();
+        let admin = Admin::new(Box::new(store));
+        let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+
+        let keys = runtime.block_on(admin.keys()).unwrap();
+        assert_eq!(keys, vec![(PASSTHROUGH_SUBJECT.to_string(), id.clone())]);
+        let info = runtime
+            .block_on(admin.key_info(PASSTHROUGH_SUBJECT, &id))
+            .unwrap();
+        assert_eq!(info.public_key.len(), 66);
+        assert!(info.created_at.is_some());
+        assert!(info.last_signed_at.is_none());
+        assert!(info.active && info.state == KeyState::Active);
+
+        // a frozen key refuses to sign
+        runtime
+            .block_on(admin.set_state(
+                PASSTHROUGH_SUBJECT,
+                &id,
+                KeyState::Frozen,
+                "lost phone",
+                "ops",
+            ))
+            .unwrap();
+        let (eph_key_gen_first_message_party_two, _, _) = MasterKey2::sign_first_message();
+        let response = client
+            .post(format!("/ecdsa/sign/{}/first", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "key_frozen");
+
+        runtime
+            .block_on(admin.set_state(
+                PASSTHROUGH_SUBJECT,
+                &id,
+                KeyState::Active,
+                "phone found",
+                "ops",
+            ))
+            .unwrap();
+        sign(&client, id.clone(), master_key_2, BigInt::from(1234u32));
+        let info = runtime
+            .block_on(admin.key_info(PASSTHROUGH_SUBJECT, &id))
+            .unwrap();
+        assert!(info.last_signed_at.is_some());
+        assert_eq!(info.state_changes.len(), 2);
+        assert_eq!(info.state_changes[0].actor, "ops");
+
+        let receipt = runtime
+            .block_on(admin.delete_key(PASSTHROUGH_SUBJECT, &id, "account closed", "ops"))
+            .unwrap();
+        assert_eq!(receipt.records.get("Party1MasterKey"), Some(&1));
+        assert!(runtime.block_on(admin.keys()).unwrap().is_empty());
+        assert!(runtime
+            .block_on(admin.key_info(PASSTHROUGH_SUBJECT, &id))
+            .is_err());
+        let response = client
+            .post("/ecdsa/keygen/active")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        runtime.block_on(admin.compact()).unwrap();
+    }
+
+    #[test]
+    fn startup_test_invalid_settings() {
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "unknown-backend"),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+        let result = server::get_server_with_settings(settings.clone());
+        assert!(matches!(result, Err(StartupError::Store(_))));
+
+        settings.insert("db".to_string(), "memory".to_string());
+        // sessions listen on the loopback address unless told otherwise
+        assert!(websocket::address_from_settings(&settings)
+            .unwrap()
+            .is_loopback());
+        settings.insert(
+            "websocket_address".to_string(),
+            "localhost:8001".to_string(),
+        );
+        let result = server::get_server_with_settings(settings.clone());
+        assert!(matches!(result, Err(StartupError::WebSocket(_))));
+
+        settings.remove("websocket_address");
+        settings.insert("jwks_path".to_string(), "/no/such/jwks.json".to_string());
+        let result = server::get_server_with_settings(settings);
+        assert!(matches!(result, Err(StartupError::Auth(_))));
+    }
+
+    #[test]
+    fn rate_limit_test_buckets() {
+        let settings = HashMap::from([
+            ("rate_limit_sign_customer".to_string(), "2/60".to_string()),
+            ("rate_limit_sign_ip".to_string(), "1/10".to_string()),
+        ]);
+        let limiter = RateLimiter::from_settings(&settings).unwrap();
+        let now = Instant::now();
+
+        assert!(limiter
+            .check(RouteClass::Sign, Some("customer-1"), Some("10.0.0.1"), now)
+            .is_ok());
+        // the address is over its limit, and the customer keeps its token
+        assert_eq!(
+            limiter.check(RouteClass::Sign, Some("customer-1"), Some("10.0.0.1"), now),
+            Err(std::time::Duration::from_secs(10))
+        );
+        assert!(limiter
+            .check(RouteClass::Sign, Some("customer-1"), Some("10.0.0.2"), now)
+            .is_ok());
+        assert_eq!(
+            limiter.check(RouteClass::Sign, Some("customer-1"), Some("10.0.0.3"), now),
+            Err(std::time::Duration::from_secs(30))
+        );
+        // other route classes and refilled buckets are not limited
+        assert!(limiter
+            .check(
+                RouteClass::Keygen,
+                Some("customer-1"),
+                Some("10.0.0.1"),
+                now
+            )
+            .is_ok());
+        let later = now + std::time::Duration::from_secs(30);
+        assert!(limiter
+            .check(
+                RouteClass::Sign,
+                Some("customer-1"),
+                Some("10.0.0.1"),
+                later
+            )
+            .is_ok());
+
+        let settings = HashMap::from([("rate_limit_keygen_ip".to_string(), "10".to_string())]);
+        assert!(RateLimiter::from_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn rate_limit_test_client_ip() {
+        let settings =
+            HashMap::from([("trusted_proxies".to_string(), "10.0.0.1, ::1".to_string())]);
+        let limiter = RateLimiter::from_settings(&settings).unwrap();
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        let client = Some("192.0.2.7".parse().unwrap());
+
+        assert_eq!(limiter.client_ip(proxy, Some("192.0.2.7")), client);
+        assert_eq!(limiter.client_ip(proxy, Some("garbage")), proxy);
+        assert_eq!(limiter.client_ip(proxy, None), proxy);
+        // only trusted proxies choose the address they are limited by
+        assert_eq!(limiter.client_ip(client, Some("10.0.0.9")), client);
+
+        assert_eq!(
+            RouteClass::of("/ecdsa/rotate/abc/first"),
+            Some(RouteClass::Keygen)
+        );
+        assert_eq!(RouteClass::of("/ecdsa/keygen/active"), None);
+
+        let settings = HashMap::from([("trusted_proxies".to_string(), "proxy".to_string())]);
+        assert!(RateLimiter::from_settings(&settings).is_err());
+    }
+
+    #[test]
+    fn rate_limit_test_keygen() {
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "memory"),
+            ("rate_limit_keygen_customer", "1/3600"),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+
+        let response = client
+            .post("/ecdsa/keygen/first?new_key=true")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .post("/ecdsa/keygen/first?new_key=true")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.headers().get_one("Retry-After"), Some("3600"));
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "rate_limited");
+    }
+
+    fn audit_record(key_id: &str, decision: Decision) -> AuditRecord {
+        AuditRecord {
+            time: Utc::now(),
+            customer_id: "customer".to_string(),
+            key_id: key_id.to_string(),
+            x_pos: "0".to_string(),
+            y_pos: "1".to_string(),
+            message_hash: "dead".to_string(),
+            decision,
+            reason: None,
+            signed: decision == Decision::Allowed,
+            deletion: None,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn audit_test_chain() {
+        let path = env::temp_dir().join(format!("gotham-audit-{}.log", std::process::id()));
+        let head_path = path.with_extension("log.head");
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&head_path);
+
+        let log = AuditLog::open(path.clone()).unwrap();
+        for key_id in ["key-1", "key-2", "key-3"] {
+            log.append(audit_record(key_id, Decision::Allowed))
+                .await
+                .unwrap();
+        }
+        log.append(audit_record("key-4", Decision::Denied))
+            .await
+            .unwrap();
+        assert_eq!(audit::verify(&path).unwrap().seq, 4);
+        // appending after a restart continues the chain
+        let log = AuditLog::open(path.clone()).unwrap();
+        log.append(audit_record("key-5", Decision::Allowed))
+            .await
+            .unwrap();
+        let head = audit::verify(&path).unwrap();
+        assert_eq!(head.seq, 5);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("key-2", "key-9", 1)).unwrap();
+        assert!(matches!(
+            audit::verify(&path),
+            Err(AuditError::Invalid { line: 2, .. })
+        ));
+
+        let lines: Vec<&str> = content.lines().collect();
+        fs::write(&path, format!("{}\n", lines[..3].join("\n"))).unwrap();
+        assert!(matches!(
+            audit::verify(&path),
+            Err(AuditError::Truncated(_))
+        ));
+        assert!(AuditLog::open(path.clone()).is_err());
+
+        fs::write(&path, content).unwrap();
+        assert_eq!(audit::verify(&path).unwrap(), head);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&head_path).unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn sessions_test_sweep() {
+        let sessions = Sessions::new(
+            Some(std::time::Duration::from_secs(60)),
+            std::time::Duration::from_secs(1),
+        );
+        let now = Utc::now();
+        let store = MemoryStore::new();
+        let put = |table: &str, id: &str, written_at: Option<i64>| {
+            let key = RecordKey::new(table, "customer", id).encode();
+            let record = Record {
+                schema: schema::SCHEMA_VERSION,
+                table: table.to_string(),
+                written_at,
+                value: serde_json::json!({}),
+            };
+            (key, serde_json::to_vec(&record).unwrap())
+        };
+        let old = Some((now - Duration::seconds(61)).timestamp());
+        let recent = Some((now - Duration::seconds(59)).timestamp());
+        let records = [
+            put("SignFirst", "expired", old),
+            put("SignFirst", "unknown", None),
+            put("SignFirst", "recent", recent),
+            put(&EcdsaStruct::Party1MasterKey.to_string(), "expired", old),
+            put(&EcdsaStruct::Party1MasterKey.to_string(), "unknown", None),
+        ];
+        for (key, value) in &records {
+            store.put(key, value).await.unwrap();
+        }
+
+        assert_eq!(Sessions::disabled().sweep(&store, now).await.unwrap(), 0);
+        assert_eq!(sessions.sweep(&store, now).await.unwrap(), 2);
+        let remaining: Vec<RecordKey> = store
+            .scan(b"")
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|(key, _)| RecordKey::decode(key))
+            .collect();
+        assert_eq!(remaining.len(), 3);
+        assert!(remaining
+            .iter()
+            .all(|key| key.table != "SignFirst" || key.id == "recent"));
+    }
+
+    #[rocket::async_test]
+    async fn sessions_test_in_flight() {
+        let sessions = Sessions::new(
+            Some(std::time::Duration::from_secs(60)),
+            std::time::Duration::from_secs(1),
+        );
+        let now = Utc::now();
+        let store = MemoryStore::new();
+        let put = |table: &str, id: &str, written_at: i64| {
+            let key = RecordKey::new(table, "customer", id).encode();
+            let record = Record {
+                schema: schema::SCHEMA_VERSION,
+                table: table.to_string(),
+                written_at: Some(written_at),
+                value: serde_json::json!({}),
+            };
+            (key, serde_json::to_vec(&record).unwrap())
+        };
+        let master_key = EcdsaStruct::Party1MasterKey.to_string();
+        let at = |seconds_ago: i64| (now - Duration::seconds(seconds_ago)).timestamp();
+        let records = [
+            // keygens: one finished, one in progress and one abandoned
+            put("CommWitness", "finished", at(30)),
+            put("CC", "finished", at(20)),
+            put(&master_key, "finished", at(20)),
+            put("CommWitness", "running", at(10)),
+            put("CommWitness", "abandoned", at(90)),
+            // a rotation of the finished key, and a signature with it
+            put("RotateCoinFlip", "finished", at(5)),
+            put("SignFirst", "finished", at(5)),
+        ];
+        for (key, value) in &records {
+            store.put(key, value).await.unwrap();
+        }
+
+        let open = sessions.open_sessions(&store, now).await.unwrap();
+        assert_eq!(open["keygen"], 1);
+        assert_eq!(open["rotate"], 1);
+        assert_eq!(open["sign"], 1);
+
+        // the rotation and the signature finish
+        let (key, value) = put(&master_key, "finished", at(0));
+        store.put(&key, &value).await.unwrap();
+        store
+            .delete(&RecordKey::new("SignFirst", "customer", "finished").encode())
+            .await
+            .unwrap();
+        let open = sessions.open_sessions(&store, now).await.unwrap();
+        assert_eq!(open["keygen"], 1);
+        assert_eq!(open["rotate"], 0);
+        assert_eq!(open["sign"], 0);
+    }
+
+    #[test]
+    fn escrow_test_backup() {
+        use two_party_ecdsa::centipede::juggling::segmentation::Msegmentation;
+        use two_party_ecdsa::curv::elliptic::curves::secp256_k1::{FE, GE};
+        use two_party_ecdsa::curv::elliptic::curves::traits::{ECPoint, ECScalar};
+
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, _) = key_gen(&client);
+        let store = client.rocket().state::<Arc<dyn Store>>().unwrap().clone();
+        let admin = Admin::new(Box::new(store));
+        let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+
+        let g: GE = ECPoint::generator();
+        let secret: FE = ECScalar::new_random();
+        let escrow_key = g * secret;
+        let mut backup = runtime
+            .block_on(EscrowBackup::create(&admin, &escrow_key))
+            .unwrap();
+        assert_eq!(backup.keys.len(), 1);
+        assert_eq!(backup.keys[0].id, id);
+        assert!(backup.verify(&escrow_key).unwrap().is_empty());
+
+        // the escrow private key recovers the secret share of party one
+        let recovered = Msegmentation::decrypt(
+            &backup.keys[0].encryptions,
+            &g,
+            &secret,
+            &escrow::SEGMENT_SIZE,
+        )
+        .unwrap();
+        assert_eq!(g * recovered, backup.keys[0].public.p1);
+        // and the whole master key, Paillier private key included, so that a restored server signs
+        let master_key = runtime
+            .block_on(admin.master_key_of(PASSTHROUGH_SUBJECT, &id))
+            .unwrap();
+        let opened = backup.keys[0].open(&secret).unwrap();
+        assert_eq!(
+            serde_json::to_value(&opened).unwrap(),
+            serde_json::to_value(&master_key).unwrap()
+        );
+
+        let other_secret: FE = ECScalar::new_random();
+        let other_key = g * other_secret;
+        assert!(matches!(
+            backup.keys[0].open(&other_secret),
+            Err(EscrowError::Sealed(_))
+        ));
+        assert!(matches!(
+            backup.verify(&other_key),
+            Err(EscrowError::OtherEscrowKey)
+        ));
+        backup.escrow_key = other_key;
+        assert_eq!(
+            backup.verify(&other_key).unwrap(),
+            vec![(PASSTHROUGH_SUBJECT.to_string(), id)]
+        );
+
+        // backups are only readable by their owner, even when overwriting a file
+        let path = env::temp_dir().join(format!("gotham-escrow-{}.json", std::process::id()));
+        fs::write(&path, "").unwrap();
+        escrow::write_json(&path, &backup).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let read: EscrowBackup = escrow::read_json(&path).unwrap();
+        assert_eq!(read.keys.len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recover_test_last_derived_pos() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let recover = |id: &str| {
+            client
+                .post(format!("/ecdsa/{}/recover", id))
+                .header(ContentType::JSON)
+                .dispatch()
+        };
+
+        let response = recover(&id);
+        assert_eq!(response.status(), Status::Ok);
+        let recovery: KeyRecovery = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(recovery.id, id);
+        assert_eq!(recovery.public_key, master_key_2.public.q);
+        assert_eq!(recovery.public_share, master_key_2.public.p1);
+        assert_eq!(recovery.chain_code, master_key_2.chain_code);
+        assert_eq!(recovery.last_derived_pos, 0);
+
+        // `sign` signs with the child key at (0, 21)
+        sign(&client, id.clone(), master_key_2, BigInt::from(1234u32));
+        let recovery: KeyRecovery =
+            serde_json::from_str(&recover(&id).into_string().unwrap()).unwrap();
+        assert_eq!(recovery.last_derived_pos, 21);
+
+        assert_eq!(recover("unknown").status(), Status::NotFound);
+    }
+
+    #[test]
+    fn batch_test_sign() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+
+        let response = client
+            .post(format!("/ecdsa/sign/{}/batch/first", id))
+            .header(ContentType::JSON)
+            .body("[]")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // the highest y_pos is recorded whatever the x_pos it comes with
+        let positions = [(2u32, 3u32), (0, 7), (1, 5)];
+        let mut items = Vec::new();
+        let mut ephemeral_keys = Vec::new();
+        for (i, (x_pos, y_pos)) in positions.iter().enumerate() {
+            let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+                MasterKey2::sign_first_message();
+            items.push(SignBatchItem {
+                message: BigInt::from(1000 + i as u32),
+                x_pos_child_key: BigInt::from(*x_pos),
+                y_pos_child_key: BigInt::from(*y_pos),
+                eph_key_gen_first_message_party_two,
+                transaction: None,
+            });
+            ephemeral_keys.push((eph_comm_witness, eph_ec_key_pair_party2));
+        }
+        let response = client
+            .post(format!("/ecdsa/sign/{}/batch/first", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&items).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let first_messages: Vec<party_one::EphKeyGenFirstMsg> =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(first_messages.len(), positions.len());
+
+        let second_messages: Vec<_> = items
+            .iter()
+            .zip(ephemeral_keys)
+            .zip(&first_messages)
+            .map(
+                |((item, (eph_comm_witness, eph_ec_key_pair_party2)), first_message)| {
+                    master_key_2
+                        .get_child(vec![
+                            item.x_pos_child_key.clone(),
+                            item.y_pos_child_key.clone(),
+                        ])
+                        .sign_second_message(
+                            &eph_ec_key_pair_party2,
+                            eph_comm_witness,
+                            first_message,
+                            &item.message,
+                        )
+                },
+            )
+            .collect();
+
+        let response = client
+            .post(format!("/ecdsa/sign/{}/batch/second", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&second_messages[..2]).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = client
+            .post(format!("/ecdsa/sign/{}/batch/second", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&second_messages).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let signatures: Vec<party_one::SignatureRecid> =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(signatures.len(), positions.len());
+        // the ephemeral keys of the batch are consumed by its signatures
+        let response = client
+            .post(format!("/ecdsa/sign/{}/batch/second", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&second_messages).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .post(format!("/ecdsa/{}/recover", id))
+            .header(ContentType::JSON)
+            .dispatch();
+        let recovery: KeyRecovery = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(recovery.last_derived_pos, 7);
+    }
+
+    #[test]
+    fn presign_test_one_time_use() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let presign = |body: String| {
+            client
+                .post(format!("/ecdsa/sign/{}/presign", id))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+
+        assert_eq!(presign("[]".to_string()).status(), Status::BadRequest);
+        let ephemeral_keys: Vec<_> = (0..2).map(|_| MasterKey2::sign_first_message()).collect();
+        let first_messages: Vec<_> = ephemeral_keys.iter().map(|keys| &keys.0).collect();
+        let response = presign(serde_json::to_string(&first_messages).unwrap());
+        assert_eq!(response.status(), Status::Ok);
+        let presignatures: Vec<Presignature> =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(presignatures.len(), 2);
+        assert_ne!(presignatures[0].id, presignatures[1].id);
+
+        let (_, eph_comm_witness, eph_ec_key_pair_party2) = &ephemeral_keys[0];
+        let message = BigInt::from(1234u32);
+        let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(4u32));
+        let request = PresignedSignRequest {
+            presignature_id: presignatures[0].id.clone(),
+            message: message.clone(),
+            party_two_sign_message: master_key_2
+                .get_child(vec![x_pos.clone(), y_pos.clone()])
+                .sign_second_message(
+                    eph_ec_key_pair_party2,
+                    eph_comm_witness.clone(),
+                    &presignatures[0].eph_key_gen_first_message_party_one,
+                    &message,
+                ),
+            x_pos_child_key: x_pos,
+            y_pos_child_key: y_pos,
+            transaction: None,
+        };
+        let sign = || {
+            client
+                .post(format!("/ecdsa/sign/{}/presigned", id))
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&request).unwrap())
+                .dispatch()
+        };
+        assert_eq!(sign().status(), Status::Ok);
+        // a presignature is consumed by its first use
+        assert_eq!(sign().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn sign_test_one_time_ephemeral() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+
+        let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        let response = client
+            .post(format!("/ecdsa/sign/{}/first", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(6u32));
+        let sign_second = |message: BigInt| {
+            let request = SignSecondMsgRequest {
+                party_two_sign_message: master_key_2
+                    .get_child(vec![x_pos.clone(), y_pos.clone()])
+                    .sign_second_message(
+                        &eph_ec_key_pair_party2,
+                        eph_comm_witness.clone(),
+                        &sign_party_one_first_message,
+                        &message,
+                    ),
+                message,
+                x_pos_child_key: x_pos.clone(),
+                y_pos_child_key: y_pos.clone(),
+            };
+            client
+                .post(format!("/ecdsa/sign/{}/second", id))
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&request).unwrap())
+                .dispatch()
+                .status()
+        };
+        assert_eq!(sign_second(BigInt::from(1u32)), Status::Ok);
+        // a nonce of party one signing two messages would reveal its share
+        assert_eq!(sign_second(BigInt::from(2u32)), Status::NotFound);
+    }
+
+    #[test]
+    fn rotate_test_one_time_rounds() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let post = |round: &str, body: String| {
+            client
+                .post(format!("/ecdsa/rotate/{}/{}", id, round))
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+
+        let response = post("first", String::new());
+        assert_eq!(response.status(), Status::Ok);
+        let coin_flip_party1_first_message: coin_flip_optimal_rounds::Party1FirstMessage =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let coin_flip_party2_first_message =
+            Rotation2::key_rotate_first_message(&coin_flip_party1_first_message);
+        let body = serde_json::to_string(&coin_flip_party2_first_message).unwrap();
+        let response = post("second", body.clone());
+        assert_eq!(response.status(), Status::Ok);
+        let (coin_flip_party1_second_message, rotation_party1_first_message): (
+            coin_flip_optimal_rounds::Party1SecondMessage,
+            party1::RotationParty1Message1,
+        ) = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        // the coin flip of party one is used once
+        assert_eq!(post("second", body).status(), Status::NotFound);
+
+        let random2 = Rotation2::key_rotate_second_message(
+            &coin_flip_party1_second_message,
+            &coin_flip_party2_first_message,
+            &coin_flip_party1_first_message,
+        );
+        let (rotation_party_two_first_message, party_two_pdl_chal, _) = master_key_2
+            .rotate_first_message(&random2, &rotation_party1_first_message)
+            .unwrap_or_else(|_| panic!("invalid rotation first message"));
+        let response = post(
+            "third",
+            serde_json::to_string(&rotation_party_two_first_message).unwrap(),
+        );
+        assert_eq!(response.status(), Status::Ok);
+
+        let body =
+            serde_json::to_string(&MasterKey2::rotate_second_message(&party_two_pdl_chal)).unwrap();
+        assert_eq!(post("fourth", body.clone()).status(), Status::Ok);
+        // the rotated key is stored once, a second fourth round finds no session
+        let response = post("fourth", body);
+        assert_eq!(response.status(), Status::NotFound);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "not_found");
+    }
+
+    #[test]
+    fn idempotency_test_replay() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let keygen_first = |uri: &str, key: &str, digest: Option<&str>| {
+            let mut request = client
+                .post(uri.to_string())
+                .header(ContentType::JSON)
+                .header(HttpHeader::new(IDEMPOTENCY_KEY_HEADER, key.to_string()));
+            if let Some(digest) = digest {
+                request =
+                    request.header(HttpHeader::new(CONTENT_DIGEST_HEADER, digest.to_string()));
+            }
+            request.dispatch()
+        };
+        let uri = "/ecdsa/keygen/first?new_key=true";
+        let digest = "sha-256=:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=:";
+
+        let response = keygen_first(uri, "key-1", Some(digest));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(REPLAYED_HEADER), None);
+        let first = response.into_string().unwrap();
+        // a retry gets the same key instead of starting another keygen
+        let response = keygen_first(uri, "key-1", Some(digest));
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(REPLAYED_HEADER), Some("true"));
+        assert_eq!(response.into_string().unwrap(), first);
+        let response = keygen_first(uri, "key-2", Some(digest));
+        assert_ne!(response.into_string().unwrap(), first);
+
+        let response = keygen_first("/ecdsa/keygen/first", "key-1", Some(digest));
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "idempotency_mismatch");
+        let response = keygen_first(uri, "key-1", Some("sha-256=:AAAA:"));
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(
+            keygen_first(uri, "key-3", None).status(),
+            Status::BadRequest
+        );
+    }
+
+    #[test]
+    fn idempotency_test_body_digest() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, _) = key_gen(&client);
+        let sign_first = |key: &str, digest: &str, body: &str| {
+            client
+                .post(format!("/ecdsa/sign/{}/first", id))
+                .header(ContentType::JSON)
+                .header(HttpHeader::new(IDEMPOTENCY_KEY_HEADER, key.to_string()))
+                .header(HttpHeader::new(CONTENT_DIGEST_HEADER, digest.to_string()))
+                .body(body.to_string())
+                .dispatch()
+        };
+        let error_code = |response: rocket::local::blocking::LocalResponse| {
+            let status = response.status();
+            let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            (status, error.code)
+        };
+        let body = serde_json::to_string(&MasterKey2::sign_first_message().0).unwrap();
+        let other_body = serde_json::to_string(&MasterKey2::sign_first_message().0).unwrap();
+        let digest = idempotency::content_digest(body.as_bytes());
+
+        let response = sign_first("key-1", &digest, &body);
+        assert_eq!(response.status(), Status::Ok);
+        // the stored response is not replayed for another body under the same digest
+        assert_eq!(
+            error_code(sign_first("key-1", &digest, &other_body)),
+            (
+                Status::UnprocessableEntity,
+                "idempotency_mismatch".to_string()
+            )
+        );
+        let response = sign_first("key-1", &digest, &body);
+        assert_eq!(response.headers().get_one(REPLAYED_HEADER), Some("true"));
+
+        // nor is a body that does not match its digest signed, or its answer stored
+        assert_eq!(
+            error_code(sign_first("key-2", &digest, &other_body)),
+            (
+                Status::UnprocessableEntity,
+                "idempotency_mismatch".to_string()
+            )
+        );
+        let response = sign_first("key-2", &digest, &body);
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(REPLAYED_HEADER), None);
+    }
+
+    #[test]
+    fn idempotency_test_keygen_retry() {
+        env::set_var("issuer", "");
+        env::set_var("audience", "");
+        env::set_var("jwks_path", "");
+        env::set_var("auth_passthrough", "true");
+        env::set_var("db", "memory");
+
+        let server = server::get_server().expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let post = |uri: String, body: String| {
+            client
+                .post(uri)
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        let post_once = |uri: String, key: &str, body: String| {
+            client
+                .post(uri)
+                .header(ContentType::JSON)
+                .header(HttpHeader::new(IDEMPOTENCY_KEY_HEADER, key.to_string()))
+                .header(HttpHeader::new(
+                    CONTENT_DIGEST_HEADER,
+                    idempotency::content_digest(body.as_bytes()),
+                ))
+                .body(body)
+                .dispatch()
+        };
+
+        let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
+            serde_json::from_str(&post(
+                "/ecdsa/keygen/first?new_key=true".to_string(),
+                String::new(),
+            ))
+            .unwrap();
+        let (kg_party_two_first_message, _) = MasterKey2::key_gen_first_message();
+        let kg_party_one_second_message: party1::KeyGenParty1Message2 =
+            serde_json::from_str(&post(
+                format!("/ecdsa/keygen/{}/second", id),
+                serde_json::to_string(&kg_party_two_first_message.d_log_proof).unwrap(),
+            ))
+            .unwrap();
+        let (party_two_second_message, _, party_two_pdl_chal) = MasterKey2::key_gen_second_message(
+            &kg_party_one_first_message,
+            &kg_party_one_second_message,
+        )
+        .unwrap();
+        post(
+            format!("/ecdsa/keygen/{}/third", id),
+            serde_json::to_string(&party_two_second_message.pdl_first_message).unwrap(),
+        );
+        let body =
+            serde_json::to_string(&MasterKey2::key_gen_third_message(&party_two_pdl_chal)).unwrap();
+
+        // the engine rounds consume their state, so only the stored response answers a retry
+        let response = post_once(
+            format!("/ecdsa/keygen/{}/fourth", id),
+            "fourth",
+            body.clone(),
+        );
+        assert_eq!(response.status(), Status::Ok);
+        let fourth = response.into_string().unwrap();
+        let response = post_once(format!("/ecdsa/keygen/{}/fourth", id), "fourth", body);
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one(REPLAYED_HEADER), Some("true"));
+        assert_eq!(response.into_string().unwrap(), fourth);
+
+        let uri = format!("/ecdsa/keygen/{}/chaincode/first", id);
+        let response = post_once(uri.clone(), "chaincode", "{}".to_string());
+        assert_eq!(response.status(), Status::Ok);
+        let chain_code_first = response.into_string().unwrap();
+        let response = post_once(uri, "chaincode", "{}".to_string());
+        assert_eq!(response.headers().get_one(REPLAYED_HEADER), Some("true"));
+        assert_eq!(response.into_string().unwrap(), chain_code_first);
+    }
+
+    #[rocket::async_test]
+    async fn idempotency_test_in_flight_retry() {
+        let idempotency = Arc::new(idempotency::Idempotency::new(
+            Some(std::time::Duration::from_secs(60)),
+            Arc::new(MemoryStore::new()),
+        ));
+        assert!(idempotency
+            .admit("customer", "key-1", "fingerprint")
+            .await
+            .is_ok());
+
+        // a retry waits for the first request, then gets its response
+        let retry = tokio::spawn({
+            let idempotency = idempotency.clone();
+            async move {
+                idempotency
+                    .admit("customer", "key-1", "fingerprint")
+                    .await
+                    .err()
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!retry.is_finished());
+        let response = idempotency::StoredResponse {
+            fingerprint: "fingerprint".to_string(),
+            path: "/ecdsa/keygen/first".to_string(),
+            status: 200,
+            content_type: None,
+            body: b"{}".to_vec(),
+        };
+        idempotency
+            .save("customer", "key-1", response)
+            .await
+            .unwrap();
+        idempotency.release("customer", "key-1");
+        match retry.await.unwrap() {
+            Some(idempotency::Answer::Replay(stored)) => assert_eq!(stored.body, b"{}"),
+            _ => panic!("the retry was not answered with the stored response"),
+        }
+    }
+
+    #[test]
+    fn approval_test_flow() {
+        let dir = env::temp_dir();
+        let policy_path = dir.join(format!(
+            "gotham-approval-policy-{}.toml",
+            std::process::id()
+        ));
+        let approvers_path = dir.join(format!("gotham-approvers-{}.toml", std::process::id()));
+        let audit_path = dir.join(format!("gotham-approval-audit-{}.log", std::process::id()));
+        let _ = fs::remove_file(&audit_path);
+        let _ = fs::remove_file(audit_path.with_extension("log.head"));
+        fs::write(&policy_path, "[default]\nrequire_approval = true\n").unwrap();
+        fs::write(
+            &approvers_path,
+            format!(
+                "[[approvers]]\nname = \"alice\"\ntoken_sha256 = \"{}\"\n",
+                hex::encode(Sha256::digest(b"approver-token"))
+            ),
+        )
+        .unwrap();
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "memory"),
+            ("policy_path", policy_path.to_str().unwrap()),
+            ("approvers_path", approvers_path.to_str().unwrap()),
+            ("audit_log_path", audit_path.to_str().unwrap()),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let store = client.rocket().state::<Arc<dyn Store>>().unwrap().clone();
+        let (id, master_key_2) = key_gen(&client);
+        let sign_second = |message: u32| {
+            let (first_message, eph_comm_witness, eph_ec_key_pair_party2) =
+                MasterKey2::sign_first_message();
+            let response = client
+                .post(format!("/ecdsa/sign/{}/first", id))
+                .body(serde_json::to_string(&first_message).unwrap())
+                .header(ContentType::JSON)
+                .dispatch();
+            let party_one_first_message: party_one::EphKeyGenFirstMsg =
+                serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(5u32));
+            let message = BigInt::from(message);
+            let request = SignSecondMsgRequest {
+                party_two_sign_message: master_key_2
+                    .get_child(vec![x_pos.clone(), y_pos.clone()])
+                    .sign_second_message(
+                        &eph_ec_key_pair_party2,
+                        eph_comm_witness,
+                        &party_one_first_message,
+                        &message,
+                    ),
+                message,
+                x_pos_child_key: x_pos,
+                y_pos_child_key: y_pos,
+            };
+            let response = client
+                .post(format!("/ecdsa/sign/{}/second", id))
+                .body(serde_json::to_string(&request).unwrap())
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(response.status(), Status::Accepted);
+            serde_json::from_str::<ApprovalStatus>(&response.into_string().unwrap()).unwrap()
+        };
+        let decide = |approval: &ApprovalRequest, action: &str, body: String, token: &str| {
+            client
+                .post(format!(
+                    "/admin/approvals/{}/{}/{}",
+                    approval.customer_id, approval.approval_id, action
+                ))
+                .header(ContentType::JSON)
+                .header(HttpHeader::new(
+                    "Authorization",
+                    format!("Bearer {}", token),
+                ))
+                .body(body)
+                .dispatch()
+        };
+        let status = |approval_id: &str| {
+            let response = client
+                .post(format!("/ecdsa/approvals/{}", approval_id))
+                .header(ContentType::JSON)
+                .body("{}")
+                .dispatch();
+            serde_json::from_str::<ApprovalStatus>(&response.into_string().unwrap()).unwrap()
+        };
+
+        let approved = sign_second(1234);
+        assert_eq!(approved.status, ApprovalState::Pending);
+        assert_eq!(status(&approved.approval_id).status, ApprovalState::Pending);
+        let response = client
+            .get("/admin/approvals")
+            .header(HttpHeader::new("Authorization", "Bearer approver-token"))
+            .dispatch();
+        let pending: Vec<ApprovalRequest> =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let request = pending
+            .iter()
+            .find(|request| request.approval_id == approved.approval_id)
+            .unwrap();
+        let decision = |message_hash: &str| format!("{{\"message_hash\":\"{}\"}}", message_hash);
+        assert_eq!(
+            decide(
+                request,
+                "approve",
+                decision(&request.message_hash),
+                "wrong-token"
+            )
+            .status(),
+            Status::Unauthorized
+        );
+        // approvers confirm the message they approve
+        assert_eq!(
+            decide(request, "approve", decision("ab"), "approver-token").status(),
+            Status::UnprocessableEntity
+        );
+        let response = decide(
+            request,
+            "approve",
+            decision(&request.message_hash),
+            "approver-token",
+        );
+        assert_eq!(response.status(), Status::Ok);
+        let response = decide(
+            request,
+            "approve",
+            decision(&request.message_hash),
+            "approver-token",
+        );
+        assert_eq!(response.status(), Status::Conflict);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "approval_closed");
+
+        let signed = status(&approved.approval_id);
+        assert_eq!(signed.status, ApprovalState::Signed);
+        let signature = signed.signature.unwrap();
+        // later requests get the same signature
+        assert_eq!(
+            status(&approved.approval_id).signature.unwrap().r,
+            signature.r
+        );
+
+        let rejected = sign_second(5678);
+        let request = ApprovalRequest {
+            approval_id: rejected.approval_id.clone(),
+            ..request.clone()
+        };
+        let body = format!(
+            "{{\"message_hash\":\"{}\",\"reason\":\"\"}}",
+            rejected.message_hash
+        );
+        assert_eq!(
+            decide(&request, "reject", body, "approver-token").status(),
+            Status::BadRequest
+        );
+        let body = format!(
+            "{{\"message_hash\":\"{}\",\"reason\":\"unknown payee\"}}",
+            rejected.message_hash
+        );
+        assert_eq!(
+            decide(&request, "reject", body, "approver-token").status(),
+            Status::Ok
+        );
+        let status = status(&rejected.approval_id);
+        assert_eq!(status.status, ApprovalState::Rejected);
+        assert_eq!(status.reason.as_deref(), Some("unknown payee"));
+        assert!(status.signature.is_none());
+
+        // decided requests keep no ephemeral keys
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        for approval_id in [&approved.approval_id, &rejected.approval_id] {
+            let key = RecordKey::new(APPROVALS_TABLE, PASSTHROUGH_SUBJECT, approval_id).encode();
+            let value = runtime.block_on(store.get(&key)).unwrap().unwrap();
+            let parked: Record<ParkedSign> = serde_json::from_slice(&value).unwrap();
+            assert!(parked.value.first.is_none());
+        }
+        // and every decision is audited, before the signature
+        let reasons: Vec<(Option<String>, bool)> = fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<AuditEntry>(line).unwrap().record)
+            .map(|record| (record.reason, record.signed))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (Some("approved by alice".to_string()), false),
+                (Some("approved by alice".to_string()), true),
+                (Some("rejected by alice: unknown payee".to_string()), false),
+            ]
+        );
+
+        fs::remove_file(&policy_path).unwrap();
+        fs::remove_file(&approvers_path).unwrap();
+        fs::remove_file(&audit_path).unwrap();
+        let _ = fs::remove_file(audit_path.with_extension("log.head"));
+    }
+
+    #[test]
+    fn approval_test_reauthorized() {
+        let dir = env::temp_dir();
+        let policy_path = dir.join(format!(
+            "gotham-reauthorized-policy-{}.toml",
+            std::process::id()
+        ));
+        let approvers_path = dir.join(format!(
+            "gotham-reauthorized-approvers-{}.toml",
+            std::process::id()
+        ));
+        fs::write(
+            &policy_path,
+            "[default]\nrequire_approval = true\nmax_signatures_per_hour = 1\n",
+        )
+        .unwrap();
+        fs::write(
+            &approvers_path,
+            format!(
+                "[[approvers]]\nname = \"alice\"\ntoken_sha256 = \"{}\"\n",
+                hex::encode(Sha256::digest(b"approver-token"))
+            ),
+        )
+        .unwrap();
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "memory"),
+            ("policy_path", policy_path.to_str().unwrap()),
+            ("approvers_path", approvers_path.to_str().unwrap()),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, master_key_2) = key_gen(&client);
+        let sign_second = |message: u32| {
+            let (first_message, eph_comm_witness, eph_ec_key_pair_party2) =
+                MasterKey2::sign_first_message();
+            let response = client
+                .post(format!("/ecdsa/sign/{}/first", id))
+                .body(serde_json::to_string(&first_message).unwrap())
+                .header(ContentType::JSON)
+                .dispatch();
+            let party_one_first_message: party_one::EphKeyGenFirstMsg =
+                serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(5u32));
+            let message = BigInt::from(message);
+            let request = SignSecondMsgRequest {
+                party_two_sign_message: master_key_2
+                    .get_child(vec![x_pos.clone(), y_pos.clone()])
+                    .sign_second_message(
+                        &eph_ec_key_pair_party2,
+                        eph_comm_witness,
+                        &party_one_first_message,
+                        &message,
+                    ),
+                message,
+                x_pos_child_key: x_pos,
+                y_pos_child_key: y_pos,
+            };
+            let response = client
+                .post(format!("/ecdsa/sign/{}/second", id))
+                .body(serde_json::to_string(&request).unwrap())
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(response.status(), Status::Accepted);
+            serde_json::from_str::<ApprovalStatus>(&response.into_string().unwrap()).unwrap()
+        };
+        let approve = |approval: &ApprovalStatus| {
+            let response = client
+                .get("/admin/approvals")
+                .header(HttpHeader::new("Authorization", "Bearer approver-token"))
+                .dispatch();
+            let pending: Vec<ApprovalRequest> =
+                serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            let request = pending
+                .iter()
+                .find(|request| request.approval_id == approval.approval_id)
+                .unwrap();
+            let response = client
+                .post(format!(
+                    "/admin/approvals/{}/{}/approve",
+                    request.customer_id, request.approval_id
+                ))
+                .header(ContentType::JSON)
+                .header(HttpHeader::new("Authorization", "Bearer approver-token"))
+                .body(format!("{{\"message_hash\":\"{}\"}}", request.message_hash))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+        };
+        let status = |approval_id: &str| {
+            client
+                .post(format!("/ecdsa/approvals/{}", approval_id))
+                .header(ContentType::JSON)
+                .body("{}")
+                .dispatch()
+        };
+
+        // both requests are within the limit while they wait
+        let first = sign_second(1234);
+        let second = sign_second(5678);
+        approve(&first);
+        approve(&second);
+        let response = status(&first.approval_id);
+        assert_eq!(response.status(), Status::Ok);
+        let signed: ApprovalStatus =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(signed.status, ApprovalState::Signed);
+
+        let response = status(&second.approval_id);
+        assert_eq!(response.status(), Status::Forbidden);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "policy_denied");
+        let response = status(&second.approval_id);
+        let failed: ApprovalStatus =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(failed.status, ApprovalState::Failed);
+        assert_eq!(
+            failed.reason.as_deref(),
+            Some("limit of 1 signatures per hour reached")
+        );
+        assert!(failed.signature.is_none());
+
+        fs::remove_file(&policy_path).unwrap();
+        fs::remove_file(&approvers_path).unwrap();
+    }
+
+    #[test]
+    fn key_state_test_revoke() {
+        // metadata written before key states
+        let legacy: KeyMetadata =
+            serde_json::from_str(r#"{"created_at":1,"last_signed_at":null,"frozen":true}"#)
+                .unwrap();
+        assert_eq!(legacy.state(), KeyState::Frozen);
+
+        let approvers_path =
+            env::temp_dir().join(format!("gotham-key-approvers-{}.toml", std::process::id()));
+        fs::write(
+            &approvers_path,
+            format!(
+                "[[approvers]]\nname = \"alice\"\ntoken_sha256 = \"{}\"\n",
+                hex::encode(Sha256::digest(b"admin-token"))
+            ),
+        )
+        .unwrap();
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "memory"),
+            ("approvers_path", approvers_path.to_str().unwrap()),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (id, _) = key_gen(&client);
+        let change = |action: &str, reason: &str, token: &str| {
+            client
+                .post(format!(
+                    "/admin/keys/{}/{}/{}",
+                    PASSTHROUGH_SUBJECT, id, action
+                ))
+                .header(ContentType::JSON)
+                .header(HttpHeader::new(
+                    "Authorization",
+                    format!("Bearer {}", token),
+                ))
+                .body(format!("{{\"reason\":\"{}\"}}", reason))
+                .dispatch()
+        };
+        let sign_first = || {
+            let (eph_key_gen_first_message_party_two, _, _) = MasterKey2::sign_first_message();
+            let response = client
+                .post(format!("/ecdsa/sign/{}/first", id))
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap())
+                .dispatch();
+            (response.status(), response.into_string().unwrap())
+        };
+        let error_code =
+            |(_, body): (Status, String)| serde_json::from_str::<ErrorBody>(&body).unwrap().code;
+
+        assert_eq!(
+            change("freeze", "stolen phone", "wrong-token").status(),
+            Status::Unauthorized
+        );
+        assert_eq!(
+            change("freeze", "", "admin-token").status(),
+            Status::BadRequest
+        );
+        let response = change("freeze", "stolen phone", "admin-token");
+        assert_eq!(response.status(), Status::Ok);
+        let status: KeyStatus = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(status.state, KeyState::Frozen);
+        assert_eq!(status.state_changes[0].actor, "alice");
+        assert_eq!(status.state_changes[0].reason, "stolen phone");
+        assert_eq!(error_code(sign_first()), "key_frozen");
+
+        assert_eq!(
+            change("unfreeze", "false alarm", "admin-token").status(),
+            Status::Ok
+        );
+        assert_eq!(sign_first().0, Status::Ok);
+
+        assert_eq!(
+            change("revoke", "stolen for real", "admin-token").status(),
+            Status::Ok
+        );
+        assert_eq!(error_code(sign_first()), "key_revoked");
+        // a revoked key stays revoked
+        let response = change("unfreeze", "found it", "admin-token");
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = change("revoke", "again", "admin-token");
+        let status: KeyStatus = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(status.state_changes.len(), 3);
+
+        fs::remove_file(&approvers_path).unwrap();
+    }
+
+    #[test]
+    fn deletion_test_tombstone() {
+        let dir = env::temp_dir();
+        let approvers_path = dir.join(format!(
+            "gotham-deletion-approvers-{}.toml",
+            std::process::id()
+        ));
+        let audit_path = dir.join(format!("gotham-deletion-audit-{}.log", std::process::id()));
+        let _ = fs::remove_file(&audit_path);
+        let _ = fs::remove_file(audit_path.with_extension("log.head"));
+        fs::write(
+            &approvers_path,
+            format!(
+                "[[approvers]]\nname = \"alice\"\ntoken_sha256 = \"{}\"\n",
+                hex::encode(Sha256::digest(b"admin-token"))
+            ),
+        )
+        .unwrap();
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "memory"),
+            ("approvers_path", approvers_path.to_str().unwrap()),
+            ("audit_log_path", audit_path.to_str().unwrap()),
+            ("idempotency_window", "3600"),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let (other_id, _) = key_gen(&client);
+        let (id, _) = key_gen(&client);
+        let first_messages =
+            serde_json::to_string(&vec![MasterKey2::sign_first_message().0]).unwrap();
+        let response = client
+            .post(format!("/ecdsa/sign/{}/presign", id))
+            .header(ContentType::JSON)
+            .header(HttpHeader::new(IDEMPOTENCY_KEY_HEADER, "presign-1"))
+            .header(HttpHeader::new(
+                CONTENT_DIGEST_HEADER,
+                idempotency::content_digest(first_messages.as_bytes()),
+            ))
+            .body(first_messages)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let delete = |id: &str, reason: &str| {
+            client
+                .post(format!("/admin/keys/{}/{}/delete", PASSTHROUGH_SUBJECT, id))
+                .header(ContentType::JSON)
+                .header(HttpHeader::new("Authorization", "Bearer admin-token"))
+                .body(format!("{{\"reason\":\"{}\"}}", reason))
+                .dispatch()
+        };
+
+        assert_eq!(delete(&id, "").status(), Status::BadRequest);
+        assert_eq!(
+            delete("no-such-key", "account closed").status(),
+            Status::NotFound
+        );
+        let response = delete(&id, "account closed");
+        assert_eq!(response.status(), Status::Ok);
+        let receipt: DeletionReceipt =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(receipt.actor, "alice");
+        assert_eq!(receipt.records.get("Party1MasterKey"), Some(&1));
+        assert_eq!(receipt.records.get("KeyMetadata"), Some(&1));
+        assert_eq!(receipt.records.get("Presignature"), Some(&1));
+        assert_eq!(receipt.records.get("ActiveKey"), Some(&1));
+        assert_eq!(receipt.records.get(RESPONSES_TABLE), Some(&1));
+        assert_eq!(receipt.record_keys_sha256.len(), 64);
+
+        let response = delete(&id, "account closed");
+        assert_eq!(response.status(), Status::Gone);
+        let error: ErrorBody = serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(error.code, "key_deleted");
+        let (eph_key_gen_first_message_party_two, _, _) = MasterKey2::sign_first_message();
+        let response = client
+            .post(format!("/ecdsa/sign/{}/first", id))
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&eph_key_gen_first_message_party_two).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        // only the tombstone is left of the key, and the other key is untouched
+        let store = client.rocket().state::<Arc<dyn Store>>().unwrap().clone();
+        let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+        let left: Vec<_> = runtime
+            .block_on(store.scan(b""))
+            .unwrap()
+            .into_iter()
+            .filter_map(|(key, _)| RecordKey::decode(&key))
+            .filter(|key| key.id.starts_with(&id))
+            .collect();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].table, TOMBSTONES_TABLE);
+        assert!(runtime
+            .block_on(store.scan(&schema::table_prefix("Party1MasterKey")))
+            .unwrap()
+            .into_iter()
+            .any(|(key, _)| RecordKey::decode(&key).unwrap().id == other_id));
+
+        // the id is never used again
+        let db = PublicGotham::with_store(
+            Box::new(store),
+            Arc::new(PolicyEngine::new(Policy::default())),
+        );
+        let index = DbIndex {
+            customerId: PASSTHROUGH_SUBJECT.to_string(),
+            id: id.clone(),
+        };
+        assert!(runtime
+            .block_on(db.insert(&index, &KeyStruct::Metadata, &KeyMetadata::created_now()))
+            .is_err());
+
+        assert_eq!(audit::verify(&audit_path).unwrap().seq, 1);
+        let entry: AuditEntry =
+            serde_json::from_str(fs::read_to_string(&audit_path).unwrap().trim()).unwrap();
+        assert_eq!(entry.record.decision, Decision::Deleted);
+        assert_eq!(entry.record.deletion, Some(receipt));
+
+        fs::remove_file(&approvers_path).unwrap();
+        fs::remove_file(&audit_path).unwrap();
+    }
+
+    /// Checks `value` against `schema`, resolving references in `document`
+    fn validate(
+        document: &serde_json::Value,
+        schema: &serde_json::Value,
+        value: &serde_json::Value,
+        at: &str,
+    ) -> Result<(), String> {
+        if let Some(reference) = schema.get("$ref").and_then(|reference| reference.as_str()) {
+            let target = document
+                .pointer(reference.trim_start_matches('#'))
+                .ok_or_else(|| format!("{}: no schema {}", at, reference))?;
+            return validate(document, target, value, at);
+        }
+        let typed = match schema.get("type").and_then(|kind| kind.as_str()) {
+            Some("object") => value.is_object(),
+            Some("array") => value.is_array(),
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_u64() || value.is_i64(),
+            Some("boolean") => value.is_boolean(),
+            _ => true,
+        };
+        if !typed {
+            return Err(format!(
+                "{}: {} is not of type {}",
+                at, value, schema["type"]
+            ));
+        }
+        for name in schema
+            .get("required")
+            .and_then(|required| required.as_array())
+            .into_iter()
+            .flatten()
+        {
+            if value.get(name.as_str().unwrap()).is_none() {
+                return Err(format!("{}: missing {}", at, name));
+            }
+        }
+        if let (Some(properties), Some(object)) = (
+            schema
+                .get("properties")
+                .and_then(|properties| properties.as_object()),
+            value.as_object(),
+        ) {
+            for (name, property) in object {
+                let property_schema = properties
+                    .get(name)
+                    .ok_or_else(|| format!("{}: undocumented property {}", at, name))?;
+                validate(
+                    document,
+                    property_schema,
+                    property,
+                    &format!("{}.{}", at, name),
+                )?;
+            }
+        }
+        if let Some(items) = value.as_array() {
+            if let Some(prefix) = schema
+                .get("prefixItems")
+                .and_then(|prefix| prefix.as_array())
+            {
+                if prefix.len() != items.len() {
+                    return Err(format!(
+                        "{}: {} items instead of {}",
+                        at,
+                        items.len(),
+                        prefix.len()
+                    ));
+                }
+                for (index, (item_schema, item)) in prefix.iter().zip(items).enumerate() {
+                    validate(document, item_schema, item, &format!("{}[{}]", at, index))?;
+                }
+            } else if let Some(item_schema) = schema.get("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate(document, item_schema, item, &format!("{}[{}]", at, index))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn references(value: &serde_json::Value, found: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Object(object) => {
+                if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_str())
+                {
+                    found.push(reference.to_string());
+                }
+                object.values().for_each(|value| references(value, found));
+            }
+            serde_json::Value::Array(items) => {
+                items.iter().for_each(|value| references(value, found))
+            }
+            _ => {}
+        }
+    }
+
+    /// Path of a route with its parameters renamed `{}`
+    fn path_template(path: &str) -> String {
+        path.split('/')
+            .map(|segment| {
+                if segment.starts_with('<') || segment.starts_with('{') {
+                    "{}"
+                } else {
+                    segment
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn openapi_test_drift() {
+        let mut settings = get_settings_as_map().unwrap();
+        for (name, value) in [
+            ("issuer", ""),
+            ("audience", ""),
+            ("jwks_path", ""),
+            ("auth_passthrough", "true"),
+            ("db", "memory"),
+        ] {
+            settings.insert(name.to_string(), value.to_string());
+        }
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let client = Client::tracked(server).expect("valid rocket instance");
+        let response = client.get("/openapi.json").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let document: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(document, openapi::document());
+
+        // every keygen, chain code and signing route is documented, and only those
+        let mounted: std::collections::BTreeSet<(String, String)> = client
+            .rocket()
+            .routes()
+            .map(|route| {
+                (
+                    route.method.as_str().to_string(),
+                    route.uri.path().to_string(),
+                )
+            })
+            .filter(|(_, path)| {
+                path.starts_with("/ecdsa/keygen") || path.starts_with("/ecdsa/sign")
+            })
+            .map(|(method, path)| (method, path_template(&path)))
+            .collect();
+        let documented: std::collections::BTreeSet<(String, String)> = document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .map(move |method| (method.to_uppercase(), path_template(path)))
+            })
+            .collect();
+        assert_eq!(mounted, documented);
+
+        let mut found = Vec::new();
+        references(&document, &mut found);
+        for reference in found {
+            assert!(
+                document
+                    .pointer(reference.trim_start_matches('#'))
+                    .is_some(),
+                "no {}",
+                reference
+            );
+        }
+
+        // the messages of a keygen and of signatures match their schemas
+        let post = |path: String, template: &str, body: Option<String>| -> serde_json::Value {
+            let operation = &document["paths"][template]["post"];
+            let mut request = client.post(path.clone()).header(ContentType::JSON);
+            if let Some(body) = body {
+                let schema = &operation["requestBody"]["content"]["application/json"]["schema"];
+                validate(
+                    &document,
+                    schema,
+                    &serde_json::from_str(&body).unwrap(),
+                    "request",
+                )
+                .unwrap_or_else(|e| panic!("{}: {}", path, e));
+                request = request.body(body);
+            }
+            let response = request.dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}", path);
+            let value: serde_json::Value =
+                serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            let schema = &operation["responses"]["200"]["content"]["application/json"]["schema"];
+            validate(&document, schema, &value, "response")
+                .unwrap_or_else(|e| panic!("{}: {}", path, e));
+            value
+        };
+
+        let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
+            serde_json::from_value(post(
+                "/ecdsa/keygen/first".to_string(),
+                "/ecdsa/keygen/first",
+                None,
+            ))
+            .unwrap();
+        let (kg_party_two_first_message, kg_ec_key_pair_party2) =
+            MasterKey2::key_gen_first_message();
+        let kg_party_one_second_message: party1::KeyGenParty1Message2 =
+            serde_json::from_value(post(
+                format!("/ecdsa/keygen/{}/second", id),
+                "/ecdsa/keygen/{id}/second",
+                Some(serde_json::to_string(&kg_party_two_first_message.d_log_proof).unwrap()),
+            ))
+            .unwrap();
+        let (party_two_second_message, party_two_paillier, party_two_pdl_chal) =
+            MasterKey2::key_gen_second_message(
+                &kg_party_one_first_message,
+                &kg_party_one_second_message,
+            )
+            .unwrap();
+        let party_one_third_message: party_one::PDLFirstMessage = serde_json::from_value(post(
+            format!("/ecdsa/keygen/{}/third", id),
+            "/ecdsa/keygen/{id}/third",
+            Some(serde_json::to_string(&party_two_second_message.pdl_first_message).unwrap()),
+        ))
+        .unwrap();
+        let pdl_decom_party2 = MasterKey2::key_gen_third_message(&party_two_pdl_chal);
+        let party_one_pdl_second_message: party_one::PDLSecondMessage =
+            serde_json::from_value(post(
+                format!("/ecdsa/keygen/{}/fourth", id),
+                "/ecdsa/keygen/{id}/fourth",
+                Some(serde_json::to_string(&pdl_decom_party2).unwrap()),
+            ))
+            .unwrap();
+        MasterKey2::key_gen_fourth_message(
+            &party_two_pdl_chal,
+            &party_one_third_message,
+            &party_one_pdl_second_message,
+        )
+        .expect("pdl error party1");
+        post(
+            "/ecdsa/keygen/active".to_string(),
+            "/ecdsa/keygen/active",
+            None,
+        );
+        let cc_party_one_first_message: Party1FirstMessage = serde_json::from_value(post(
+            format!("/ecdsa/keygen/{}/chaincode/first", id),
+            "/ecdsa/keygen/{id}/chaincode/first",
+            None,
+        ))
+        .unwrap();
+        let (cc_party_two_first_message, cc_ec_key_pair2) = ChainCode2::chain_code_first_message();
+        let cc_party_one_second_message: Party1SecondMessage = serde_json::from_value(post(
+            format!("/ecdsa/keygen/{}/chaincode/second", id),
+            "/ecdsa/keygen/{id}/chaincode/second",
+            Some(serde_json::to_string(&cc_party_two_first_message.d_log_proof).unwrap()),
+        ))
+        .unwrap();
+        assert!(ChainCode2::chain_code_second_message(
+            &cc_party_one_first_message,
+            &cc_party_one_second_message
+        )
+        .is_ok());
+        let chain_code = ChainCode2::compute_chain_code(
+            &cc_ec_key_pair2,
+            &cc_party_one_second_message.comm_witness.public_share,
+        )
+        .chain_code;
+        let master_key_2 = MasterKey2::set_master_key(
+            &chain_code,
+            &kg_ec_key_pair_party2,
+            &kg_party_one_second_message
+                .ecdh_second_message
+                .comm_witness
+                .public_share,
+            &party_two_paillier,
+        );
+
+        let (x_pos, y_pos) = (BigInt::from(0u32), BigInt::from(3u32));
+        let child_key = master_key_2.get_child(vec![x_pos.clone(), y_pos.clone()]);
+        let (first_message, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
+            serde_json::from_value(post(
+                format!("/ecdsa/sign/{}/first", id),
+                "/ecdsa/sign/{id}/first",
+                Some(serde_json::to_string(&first_message).unwrap()),
+            ))
+            .unwrap();
+        let message = BigInt::from(1234u32);
+        let request = SignSecondMsgRequest {
+            message: message.clone(),
+            party_two_sign_message: child_key.sign_second_message(
+                &eph_ec_key_pair_party2,
+                eph_comm_witness,
+                &sign_party_one_first_message,
+                &message,
+            ),
+            x_pos_child_key: x_pos.clone(),
+            y_pos_child_key: y_pos.clone(),
+        };
+        post(
+            format!("/ecdsa/sign/{}/second", id),
+            "/ecdsa/sign/{id}/second",
+            Some(serde_json::to_string(&request).unwrap()),
+        );
+
+        let (first_message, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        let items = vec![SignBatchItem {
+            message: message.clone(),
+            x_pos_child_key: x_pos.clone(),
+            y_pos_child_key: y_pos.clone(),
+            eph_key_gen_first_message_party_two: first_message,
+            transaction: None,
+        }];
+        let first_messages: Vec<party_one::EphKeyGenFirstMsg> = serde_json::from_value(post(
+            format!("/ecdsa/sign/{}/batch/first", id),
+            "/ecdsa/sign/{id}/batch/first",
+            Some(serde_json::to_string(&items).unwrap()),
+        ))
+        .unwrap();
+        let sign_messages = vec![child_key.sign_second_message(
+            &eph_ec_key_pair_party2,
+            eph_comm_witness,
+            &first_messages[0],
+            &message,
+        )];
+        post(
+            format!("/ecdsa/sign/{}/batch/second", id),
+            "/ecdsa/sign/{id}/batch/second",
+            Some(serde_json::to_string(&sign_messages).unwrap()),
+        );
+
+        let (first_message, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+        let presignatures: Vec<Presignature> = serde_json::from_value(post(
+            format!("/ecdsa/sign/{}/presign", id),
+            "/ecdsa/sign/{id}/presign",
+            Some(serde_json::to_string(&vec![first_message]).unwrap()),
+        ))
+        .unwrap();
+        let request = PresignedSignRequest {
+            presignature_id: presignatures[0].id.clone(),
+            message: message.clone(),
+            party_two_sign_message: child_key.sign_second_message(
+                &eph_ec_key_pair_party2,
+                eph_comm_witness,
+                &presignatures[0].eph_key_gen_first_message_party_one,
+                &message,
+            ),
+            x_pos_child_key: x_pos,
+            y_pos_child_key: y_pos,
+            transaction: None,
+        };
+        post(
+            format!("/ecdsa/sign/{}/presigned", id),
+            "/ecdsa/sign/{id}/presigned",
+            Some(serde_json::to_string(&request).unwrap()),
+        );
+    }
+
+    async fn session_round(
+        socket: &mut WebSocketStream<MaybeTlsStream<rocket::tokio::net::TcpStream>>,
+        frame: &str,
+    ) -> SessionResponse {
+        socket
+            .send(WsMessage::Text(frame.to_string()))
+            .await
+            .unwrap();
+        match socket.next().await {
+            Some(Ok(WsMessage::Text(text))) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn websocket_test_session() {
+        let mut settings = get_settings_as_map().unwrap();
+        settings.extend(auth_settings());
+        settings.insert("db".to_string(), "memory".to_string());
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let sessions = server.state::<Arc<SessionServer>>().unwrap().clone();
+        let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}{}", listener.local_addr().unwrap(), SESSION_PATH);
+        rocket::tokio::spawn(sessions.serve(listener));
+
+        // the session is authenticated when it opens
+        match tokio_tungstenite::connect_async(url.as_str()).await {
+            Err(WsError::Http(response)) => assert_eq!(response.status().as_u16(), 401),
+            _ => panic!("session opened without a token"),
+        }
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", auth_token("gotham-test", "gotham-city", 600))
+                .parse()
+                .unwrap(),
+        );
+        let (mut socket, _) = tokio_tungstenite::connect_async(request)
+            .await
+            .expect("session opens");
+
+        let response = session_round(&mut socket, "not a request").await;
+        assert_eq!(response.status, 400);
+        let error: ErrorBody = serde_json::from_value(response.body).unwrap();
+        assert_eq!(error.code, "bad_request");
+
+        let response = session_round(&mut socket, r#"{"path": "/ecdsa/keygen/first"}"#).await;
+        assert_eq!(response.status, 200);
+        let id = response.body[0].as_str().unwrap().to_string();
+
+        // a round out of order ends the keygen, whose state only lived in the session
+        let frame = format!(r#"{{"path": "/ecdsa/keygen/{}/chaincode/first"}}"#, id);
+        assert_eq!(session_round(&mut socket, &frame).await.status, 404);
+        let (kg_party_two_first_message, _) = MasterKey2::key_gen_first_message();
+        let frame = serde_json::json!({
+            "path": format!("/ecdsa/keygen/{}/second", id),
+            "body": kg_party_two_first_message.d_log_proof,
+        });
+        assert_eq!(
+            session_round(&mut socket, &frame.to_string()).await.status,
+            404
+        );
+
+        let response = session_round(&mut socket, r#"{"path": "/ecdsa/sign/batch"}"#).await;
+        assert_eq!(response.status, 404);
+        let error: ErrorBody = serde_json::from_value(response.body).unwrap();
+        assert_eq!(error.code, "not_found");
+
+        socket.close(None).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn websocket_test_metrics_and_idempotency() {
+        let mut settings = get_settings_as_map().unwrap();
+        settings.insert("auth_passthrough".to_string(), "true".to_string());
+        settings.insert("db".to_string(), "memory".to_string());
+        settings.insert("idempotency_window".to_string(), "3600".to_string());
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let sessions = server.state::<Arc<SessionServer>>().unwrap().clone();
+        let metrics = server.state::<Arc<Metrics>>().unwrap().clone();
+        let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}{}", listener.local_addr().unwrap(), SESSION_PATH);
+        rocket::tokio::spawn(sessions.serve(listener));
+        let (mut socket, _) = tokio_tungstenite::connect_async(url.as_str())
+            .await
+            .expect("session opens");
+
+        // a retry with the same key is answered with the first response
+        let frame = r#"{"path": "/ecdsa/keygen/first", "idempotency_key": "keygen-1"}"#;
+        let first = session_round(&mut socket, frame).await;
+        assert_eq!(first.status, 200);
+        assert!(!first.replayed);
+        let retry = session_round(&mut socket, frame).await;
+        assert_eq!(retry.status, 200);
+        assert!(retry.replayed);
+        assert_eq!(retry.body, first.body);
+
+        let frame = r#"{"path": "/ecdsa/keygen/active", "idempotency_key": "keygen-1"}"#;
+        let response = session_round(&mut socket, frame).await;
+        assert_eq!(response.status, 422);
+        let error: ErrorBody = serde_json::from_value(response.body).unwrap();
+        assert_eq!(error.code, "idempotency_mismatch");
+
+        // frames are counted under the HTTP route they stand for
+        let encoded = metrics.encode();
+        assert!(encoded
+            .lines()
+            .any(|line| line.starts_with("gotham_requests_total")
+                && line.contains(r#"route="keygen_first""#)
+                && line.ends_with(" 2")));
+        assert!(encoded.contains(r#"outcome="idempotency_mismatch""#));
+
+        socket.close(None).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn websocket_test_token_expiry() {
+        let mut settings = get_settings_as_map().unwrap();
+        settings.extend(auth_settings());
+        settings.insert("db".to_string(), "memory".to_string());
+        let server = server::get_server_with_settings(settings).expect("valid configuration");
+        let sessions = server.state::<Arc<SessionServer>>().unwrap().clone();
+        let listener = rocket::tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap();
+        let url = format!("ws://{}{}", listener.local_addr().unwrap(), SESSION_PATH);
+        rocket::tokio::spawn(sessions.serve(listener));
+
+        let mut request = url.as_str().into_client_request().unwrap();
+        request.headers_mut().insert(
+            "Authorization",
+            format!("Bearer {}", auth_token("gotham-test", "gotham-city", 2))
+                .parse()
+                .unwrap(),
+        );
+        let (mut socket, _) = tokio_tungstenite::connect_async(request)
+            .await
+            .expect("session opens");
+        let response = session_round(&mut socket, r#"{"path": "/ecdsa/keygen/first"}"#).await;
+        assert_eq!(response.status, 200);
+
+        // the session ends with its token rather than when it is idle
+        let closed =
+            rocket::tokio::time::timeout(std::time::Duration::from_secs(10), socket.next())
+                .await
+                .expect("session closes when its token expires");
+        match closed {
+            Some(Ok(WsMessage::Close(Some(frame)))) => assert_eq!(frame.reason, "token expired"),
+            other => panic!("unexpected frame {:?}", other),
+        }
     }
 }
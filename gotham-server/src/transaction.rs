@@ -0,0 +1,316 @@
+//! Unsigned transactions sent along with a signing request
+//!
+//! The second signing round may carry the unsigned transaction whose digest is the message. The
+//! server computes the digest again, refuses a message that is not that digest, and hands the
+//! destinations and amounts of the transaction to the signing policy:
+//!
+//! ```json
+//! {"chain": "bitcoin", "network": "bitcoin", "transaction": "0200...", "input_index": 0, "prevout_amounts": [150000]}
+//! {"chain": "ethereum", "chain_id": 1, "transaction": {"type": "0x02", "to": "0x...", "value": "0x2386f26fc10000", ...}}
+//! ```
+//!
+//! Bitcoin inputs are P2WPKH outputs of the signing child key, signed with `SIGHASH_ALL`. Outputs
+//! paying to the P2WPKH address of a child key listed in `change_positions` are change: they go
+//! back to the customer, and are left out of the destination and value rules of the policy.
+//!
+//! Ethereum calls of the ERC-20 `transfer` and `approve` functions are token payments to their
+//! recipient or spender. Any other call data makes a contract call, whose effects the payments do
+//! not describe.
+
+use std::str::FromStr;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::{Address, Network, SigHashType, Transaction};
+use ethers_core::types::transaction::eip2718::TypedTransaction;
+use ethers_core::types::{NameOrAddress, U256};
+use serde::{Deserialize, Serialize};
+
+use two_party_ecdsa::curv::arithmetic::traits::Converter;
+use two_party_ecdsa::curv::elliptic::curves::secp256_k1::PK;
+use two_party_ecdsa::curv::BigInt;
+
+/// Selector of the ERC-20 `transfer(address,uint256)` function
+const ERC20_TRANSFER: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+/// Selector of the ERC-20 `approve(address,uint256)` function
+const ERC20_APPROVE: [u8; 4] = [0x09, 0x5e, 0xa7, 0xb3];
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransactionError {
+    #[error("invalid transaction: {0}")]
+    Invalid(String),
+    #[error("message {message} is not the digest {digest} of the transaction")]
+    Mismatch { message: String, digest: String },
+}
+
+/// Unsigned transaction whose digest is the message to sign
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "chain", rename_all = "snake_case")]
+pub enum UnsignedTransaction {
+    Bitcoin {
+        /// `bitcoin`, `testnet`, `signet` or `regtest`
+        network: String,
+        /// Consensus encoding of the transaction, hex encoded
+        transaction: String,
+        /// Input signed by the message
+        input_index: usize,
+        /// Amounts, in satoshi, of the outputs spent by every input
+        prevout_amounts: Vec<u64>,
+        /// Derivation positions of the child keys change outputs pay to
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        change_positions: Vec<ChangePosition>,
+    },
+    Ethereum {
+        /// Chain id of the digest (EIP-155), which the JSON of a transaction leaves out
+        #[serde(default)]
+        chain_id: Option<u64>,
+        transaction: TypedTransaction,
+    },
+}
+
+/// Derivation position of a child key of the signing key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangePosition {
+    pub x_pos: BigInt,
+    pub y_pos: BigInt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chain {
+    Bitcoin,
+    Ethereum,
+}
+
+/// Payment of a transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payment {
+    /// Bitcoin address or `0x` prefixed ethereum address, `None` for non standard scripts and
+    /// contract creations
+    pub destination: Option<String>,
+    /// In satoshi for bitcoin, in wei for ethereum, in base units of the token for tokens
+    pub amount: u128,
+    /// Whether the output pays back to a child key of the signing key
+    #[serde(default)]
+    pub change: bool,
+    /// `0x` prefixed address of the ERC-20 contract of a token payment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// What a transaction pays, as seen by the signing policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionSummary {
+    pub chain: Chain,
+    pub payments: Vec<Payment>,
+    /// Whether the transaction calls a contract with data that is not a token payment
+    #[serde(default)]
+    pub contract_call: bool,
+}
+
+impl TransactionSummary {
+    /// Sum of the payments in the currency of the chain, change and tokens left out
+    pub fn value(&self) -> u128 {
+        self.payments
+            .iter()
+            .filter(|payment| !payment.change && payment.token.is_none())
+            .fold(0u128, |sum, payment| sum.saturating_add(payment.amount))
+    }
+}
+
+impl UnsignedTransaction {
+    /// Child keys change outputs pay to
+    pub fn change_positions(&self) -> &[ChangePosition] {
+        match self {
+            UnsignedTransaction::Bitcoin {
+                change_positions, ..
+            } => change_positions,
+            UnsignedTransaction::Ethereum { .. } => &[],
+        }
+    }
+
+    /// Summary of the transaction, when `message` is its digest for the child key `public_key`.
+    /// `change_keys` are the child keys at the [`change_positions`](Self::change_positions).
+    pub fn inspect(
+        &self,
+        message: &BigInt,
+        public_key: &PK,
+        change_keys: &[PK],
+    ) -> Result<TransactionSummary, TransactionError> {
+        let (digest, summary) = match self {
+            UnsignedTransaction::Bitcoin {
+                network,
+                transaction,
+                input_index,
+                prevout_amounts,
+                ..
+            } => bitcoin_digest(
+                network,
+                transaction,
+                *input_index,
+                prevout_amounts,
+                public_key,
+                change_keys,
+            )?,
+            UnsignedTransaction::Ethereum {
+                chain_id,
+                transaction,
+            } => ethereum_digest(*chain_id, transaction)?,
+        };
+        if &BigInt::from(&digest[..]) != message {
+            return Err(TransactionError::Mismatch {
+                message: message.to_hex(),
+                digest: hex::encode(digest),
+            });
+        }
+        Ok(summary)
+    }
+}
+
+fn bitcoin_digest(
+    network: &str,
+    transaction: &str,
+    input_index: usize,
+    prevout_amounts: &[u64],
+    public_key: &PK,
+    change_keys: &[PK],
+) -> Result<(Vec<u8>, TransactionSummary), TransactionError> {
+    let network =
+        Network::from_str(network).map_err(|e| TransactionError::Invalid(e.to_string()))?;
+    let bytes = hex::decode(transaction).map_err(|e| TransactionError::Invalid(e.to_string()))?;
+    let transaction: Transaction =
+        deserialize(&bytes).map_err(|e| TransactionError::Invalid(e.to_string()))?;
+    if prevout_amounts.len() != transaction.input.len() {
+        return Err(TransactionError::Invalid(format!(
+            "{} prevout amounts for {} inputs",
+            prevout_amounts.len(),
+            transaction.input.len()
+        )));
+    }
+    if input_index >= transaction.input.len() {
+        return Err(TransactionError::Invalid(format!(
+            "no input {} in a transaction of {} inputs",
+            input_index,
+            transaction.input.len()
+        )));
+    }
+
+    let bitcoin_key = |key: &PK| {
+        bitcoin::PublicKey::from_slice(&key.serialize())
+            .map_err(|e| TransactionError::Invalid(e.to_string()))
+    };
+    let public_key = bitcoin_key(public_key)?;
+    let change_scripts = change_keys
+        .iter()
+        .map(|key| {
+            Address::p2wpkh(&bitcoin_key(key)?, network)
+                .map(|address| address.script_pubkey())
+                .map_err(|e| TransactionError::Invalid(e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // the script code of a P2WPKH input is the P2PKH script of its key (BIP 143)
+    let script_code = Address::p2pkh(&public_key, network).script_pubkey();
+    let digest = SigHashCache::new(&transaction).signature_hash(
+        input_index,
+        &script_code,
+        prevout_amounts[input_index],
+        SigHashType::All,
+    );
+
+    let payments = transaction
+        .output
+        .iter()
+        .map(|output| Payment {
+            destination: Address::from_script(&output.script_pubkey, network)
+                .map(|address| address.to_string()),
+            amount: u128::from(output.value),
+            change: change_scripts.contains(&output.script_pubkey),
+            token: None,
+        })
+        .collect();
+    Ok((
+        digest[..].to_vec(),
+        TransactionSummary {
+            chain: Chain::Bitcoin,
+            payments,
+            contract_call: false,
+        },
+    ))
+}
+
+fn ethereum_digest(
+    chain_id: Option<u64>,
+    transaction: &TypedTransaction,
+) -> Result<(Vec<u8>, TransactionSummary), TransactionError> {
+    let mut transaction = transaction.clone();
+    if let Some(chain_id) = chain_id {
+        transaction.set_chain_id(chain_id);
+    }
+    let destination = match transaction.to() {
+        Some(NameOrAddress::Address(address)) => Some(format!("0x{}", hex::encode(address))),
+        Some(NameOrAddress::Name(name)) => {
+            return Err(TransactionError::Invalid(format!(
+                "destination {} is not an address",
+                name
+            )))
+        }
+        None => None,
+    };
+    let value = amount(transaction.value().copied().unwrap_or_default())?;
+    let data = transaction.data().map(|data| data.as_ref()).unwrap_or(&[]);
+    let token_payment = match &destination {
+        Some(contract) => erc20_payment(contract, data)?,
+        None => None,
+    };
+
+    let mut payments = Vec::new();
+    // a token payment sends no ether to the token contract
+    if token_payment.is_none() || value > 0 {
+        payments.push(Payment {
+            destination,
+            amount: value,
+            change: false,
+            token: None,
+        });
+    }
+    let contract_call = token_payment.is_none() && !data.is_empty();
+    payments.extend(token_payment);
+    Ok((
+        transaction.sighash().as_bytes().to_vec(),
+        TransactionSummary {
+            chain: Chain::Ethereum,
+            payments,
+            contract_call,
+        },
+    ))
+}
+
+fn amount(value: U256) -> Result<u128, TransactionError> {
+    if value > U256::from(u128::MAX) {
+        return Err(TransactionError::Invalid(format!(
+            "value {} is too large",
+            value
+        )));
+    }
+    Ok(value.as_u128())
+}
+
+/// Token payment of a call of the ERC-20 `transfer` or `approve` function of `contract`, which
+/// pays, or lets spend, an amount to the address of its first argument
+fn erc20_payment(contract: &str, data: &[u8]) -> Result<Option<Payment>, TransactionError> {
+    if data.len() != 4 + 2 * 32 || (data[..4] != ERC20_TRANSFER && data[..4] != ERC20_APPROVE) {
+        return Ok(None);
+    }
+    let (recipient, value) = (&data[4..36], &data[36..68]);
+    if recipient[..12].iter().any(|byte| *byte != 0) {
+        return Err(TransactionError::Invalid(
+            "token recipient is not an address".to_string(),
+        ));
+    }
+    Ok(Some(Payment {
+        destination: Some(format!("0x{}", hex::encode(&recipient[12..]))),
+        amount: amount(U256::from_big_endian(value))?,
+        change: false,
+        token: Some(contract.to_string()),
+    }))
+}
@@ -0,0 +1,922 @@
+//! WebSocket sessions carrying every round of keygen, signing and rotation
+//!
+//! Over HTTP, every round of a protocol pays a round trip, the verification of the bearer token
+//! and the lookup of the state left by the previous round: six requests for a keygen and its
+//! chain code. A session is a single WebSocket connection to `/ecdsa/session` on
+//! `websocket_port`, authenticated once by the bearer token of its upgrade request, over which
+//! the client sends the requests of the protocol routes as text frames
+//!
+//! ```json
+//! {"path": "ecdsa/keygen/<id>/second", "body": {...}}
+//! ```
+//!
+//! each answered, in order, with the status and the body the HTTP route would answer:
+//!
+//! ```json
+//! {"status": 200, "body": {...}}
+//! ```
+//!
+//! A request may carry an `idempotency_key`, used as the `Idempotency-Key` header of the HTTP
+//! routes: the retry of a request with the same key, path and body is answered with the stored
+//! response and `"replayed": true`. Requests are counted in the metrics of their HTTP route.
+//!
+//! Sessions serve keygen (`?new_key=true` included) and its chain code, `ecdsa/keygen/active`,
+//! `sign/first` and `sign/second`, approval status and rotation. The intermediate state of a
+//! protocol stays in the memory of its session rather than in the store, so it is lost with the
+//! connection, and a round sent out of order ends its protocol. The rounds themselves are the
+//! functions of the HTTP routes, fed with that state. Master keys, the signing policy,
+//! approvals, the audit log and the rate limits are those of the HTTP routes. Rocket does not
+//! upgrade connections, so sessions are served by a listener of their own.
+//!
+//! A session lasts as long as the token it was opened with: it is closed with the reason `token
+//! expired` when the token expires, and the client opens another one with a fresh token, starting
+//! again the protocols it was running.
+//!
+//! That listener speaks plain `ws://`, whatever the TLS settings of Rocket: the bearer token and
+//! the key shares cross it in the clear, so it binds `websocket_address`, the loopback address by
+//! default, and is only reachable through a proxy terminating TLS, e.g. nginx forwarding
+//! `wss://gotham.example.com/ecdsa/session` to `ws://127.0.0.1:8001/ecdsa/session`. Behind a
+//! proxy listed in `trusted_proxies`, the `X-Real-IP` header of the upgrade request is the
+//! address the rate limits count, as over HTTP.
+
+use log::{error, info, warn};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::{ContentType, Method, Status};
+use rocket::State;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::{http, Message};
+
+use two_party_ecdsa::curv::cryptographic_primitives::proofs::sigma_dlog::DLogProof;
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+use two_party_ecdsa::party_two;
+
+use gotham_engine::traits::*;
+use gotham_engine::types::*;
+
+use crate::approval::{self, Approvals, SignOutcome};
+use crate::audit::AuditLog;
+use crate::auth::{AuthError, Authorizer, Customer, PASSTHROUGH_SUBJECT};
+use crate::db::{db_index, insert_value, SharedDb};
+use crate::error::{ApiError, ErrorBody, ErrorCode};
+use crate::idempotency::{self, Idempotency, StoredResponse};
+use crate::keygen::{self, KeygenChainCode, KeygenFirst, KeygenShares, KeygenThird};
+use crate::keys;
+use crate::metrics::Metrics;
+use crate::policy::PolicyEngine;
+use crate::rate_limit::{RateLimiter, RouteClass, REAL_IP_HEADER};
+use crate::rotate::{self, RotateCoinFlip, RotateFirst, RotateThird};
+use crate::sign::{self, Ephemeral, SignFirst, SignSecondRequest};
+
+/// Path of the upgrade request opening a session
+pub const SESSION_PATH: &str = "/ecdsa/session";
+/// Time after which a session sending nothing is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// Most protocols a session runs at once
+const MAX_OPEN_PROTOCOLS: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebSocketError {
+    #[error("invalid setting websocket_port: {0}")]
+    InvalidPort(String),
+    #[error("invalid setting websocket_address: {0}")]
+    InvalidAddress(String),
+}
+
+/// Port set by `websocket_port`, `None` when it is empty and sessions are not served
+pub fn port_from_settings(
+    settings: &HashMap<String, String>,
+) -> Result<Option<u16>, WebSocketError> {
+    match settings.get("websocket_port").map(|port| port.trim()) {
+        Some(port) if !port.is_empty() => port
+            .parse::<u16>()
+            .map(Some)
+            .map_err(|e| WebSocketError::InvalidPort(e.to_string())),
+        _ => Ok(None),
+    }
+}
+
+/// Address set by `websocket_address`, the loopback address when it is empty, so that sessions
+/// are only reachable through a local proxy unless another address is chosen
+pub fn address_from_settings(settings: &HashMap<String, String>) -> Result<IpAddr, WebSocketError> {
+    match settings
+        .get("websocket_address")
+        .map(|address| address.trim())
+    {
+        Some(address) if !address.is_empty() => address
+            .parse::<IpAddr>()
+            .map_err(|e| WebSocketError::InvalidAddress(e.to_string())),
+        _ => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    }
+}
+
+/// Request of a protocol route, sent as a text frame
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionRequest {
+    /// Path of the HTTP route, with its query
+    pub path: String,
+    #[serde(default)]
+    pub body: Value,
+    /// Key binding the request to its response, like the `Idempotency-Key` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+/// Answer to a [`SessionRequest`], with the status and body of the HTTP route
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionResponse {
+    pub status: u16,
+    pub body: Value,
+    /// Set when the response is the one stored for the idempotency key of the request
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub replayed: bool,
+}
+
+/// State of a keygen after each of its rounds
+enum Keygen {
+    First(KeygenFirst),
+    Second(KeygenShares),
+    Third(KeygenThird),
+    /// The PDL proof of party two verified
+    Fourth(KeygenShares),
+    ChainCode(KeygenChainCode),
+}
+
+/// State of a rotation after each of its rounds
+enum Rotation {
+    CoinFlip(RotateCoinFlip),
+    First(RotateFirst),
+    Third(RotateFirst, RotateThird),
+}
+
+/// Protocols of a connection, by key id
+struct Session {
+    customer: Customer,
+    ip: String,
+    /// Expiry of the token the session was opened with, `None` without authentication
+    expires: Option<SystemTime>,
+    keygens: HashMap<String, Keygen>,
+    signatures: HashMap<String, SignFirst>,
+    rotations: HashMap<String, Rotation>,
+}
+
+impl Session {
+    fn new(customer: Customer, ip: String, expires: Option<SystemTime>) -> Self {
+        Session {
+            customer,
+            ip,
+            expires,
+            keygens: HashMap::new(),
+            signatures: HashMap::new(),
+            rotations: HashMap::new(),
+        }
+    }
+
+    /// Time the session may still wait for a request, until it is idle or its token expires
+    fn timeout(&self) -> Duration {
+        match self.expires {
+            Some(expires) => expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .min(IDLE_TIMEOUT),
+            None => IDLE_TIMEOUT,
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires
+            .map_or(false, |expires| expires <= SystemTime::now())
+    }
+
+    /// Refuses to start another protocol when too many are running
+    fn check_capacity(&self) -> Result<(), ApiError> {
+        let open = self.keygens.len() + self.signatures.len() + self.rotations.len();
+        if open >= MAX_OPEN_PROTOCOLS {
+            return Err(ApiError::bad_request(format!(
+                "A session runs at most {} protocols at once",
+                MAX_OPEN_PROTOCOLS
+            )));
+        }
+        Ok(())
+    }
+}
+
+type Answer = Result<(Status, Value), ApiError>;
+
+fn answer<T: Serialize>(status: Status, value: T) -> Answer {
+    serde_json::to_value(value)
+        .map(|value| (status, value))
+        .map_err(|e| ApiError::internal(format!("Cannot serialize response: {}", e)))
+}
+
+fn ok<T: Serialize>(value: T) -> Answer {
+    answer(Status::Ok, value)
+}
+
+fn body<T: DeserializeOwned>(request: &SessionRequest) -> Result<T, ApiError> {
+    serde_json::from_value(request.body.clone())
+        .map_err(|e| ApiError::bad_request(format!("Invalid body of {}: {}", request.path, e)))
+}
+
+/// Error of a round whose previous round did not run in the session
+fn out_of_order(protocol: &str, id: &str) -> ApiError {
+    ApiError::not_found(format!(
+        "No {} of key {} to continue in this session",
+        protocol, id
+    ))
+}
+
+fn query_value<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn error_body(error: ApiError) -> (u16, ErrorBody) {
+    let request_id = uuid::Uuid::new_v4().to_string();
+    if error.status.code >= 500 {
+        error!("Session request {} failed: {}", request_id, error);
+    }
+    (
+        error.status.code,
+        ErrorBody {
+            code: error.code.as_str().to_string(),
+            message: error.message,
+            status: error.status.code,
+            request_id,
+        },
+    )
+}
+
+fn session_response(answer: Answer) -> SessionResponse {
+    match answer {
+        Ok((status, body)) => SessionResponse {
+            status: status.code,
+            body,
+            replayed: false,
+        },
+        Err(e) => {
+            let (status, body) = error_body(e);
+            SessionResponse {
+                status,
+                body: serde_json::to_value(body).expect("error bodies serialize"),
+                replayed: false,
+            }
+        }
+    }
+}
+
+/// Name of the HTTP route serving `path`, labelling the metrics of its requests
+fn route_name(path: &str) -> &'static str {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["ecdsa", "keygen", "active"] => "active_key",
+        ["ecdsa", "keygen", "first"] => "keygen_first",
+        ["ecdsa", "keygen", _, "second"] => "keygen_second",
+        ["ecdsa", "keygen", _, "third"] => "keygen_third",
+        ["ecdsa", "keygen", _, "fourth"] => "keygen_fourth",
+        ["ecdsa", "keygen", _, "chaincode", "first"] => "chain_code_first",
+        ["ecdsa", "keygen", _, "chaincode", "second"] => "chain_code_second",
+        ["ecdsa", "sign", _, "first"] => "sign_first",
+        ["ecdsa", "sign", _, "second"] => "sign_second",
+        ["ecdsa", "approvals", _] => "approval_status",
+        ["ecdsa", "rotate", _, "first"] => "rotate_first",
+        ["ecdsa", "rotate", _, "second"] => "rotate_second",
+        ["ecdsa", "rotate", _, "third"] => "rotate_third",
+        ["ecdsa", "rotate", _, "fourth"] => "rotate_fourth",
+        _ => "unmatched",
+    }
+}
+
+/// Outcome counted in the metrics: `ok`, or the error code of the response
+fn outcome(response: &SessionResponse) -> &str {
+    if response.status < 400 {
+        return "ok";
+    }
+    response
+        .body
+        .get("code")
+        .and_then(Value::as_str)
+        .unwrap_or("http_error")
+}
+
+/// Close frame of a session whose token expired
+fn token_expired() -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::Policy,
+        reason: "token expired".into(),
+    }
+}
+
+/// Refusal of an upgrade request
+fn handshake_error(error: ApiError) -> ErrorResponse {
+    let (status, body) = error_body(error);
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Some(
+            serde_json::to_string(&body).expect("error bodies serialize"),
+        ))
+        .expect("valid error response")
+}
+
+/// Listener of WebSocket sessions, sharing its state with the HTTP routes
+pub struct SessionServer {
+    /// `Db` of the HTTP routes, whose lock serializes the rounds of both
+    db: SharedDb,
+    authorizer: Authorizer,
+    policy: Arc<PolicyEngine>,
+    audit: AuditLog,
+    approvals: Arc<Approvals>,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    idempotency: Arc<Idempotency>,
+}
+
+impl SessionServer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        db: SharedDb,
+        authorizer: Authorizer,
+        policy: Arc<PolicyEngine>,
+        audit: AuditLog,
+        approvals: Arc<Approvals>,
+        rate_limiter: Arc<RateLimiter>,
+        metrics: Arc<Metrics>,
+        idempotency: Arc<Idempotency>,
+    ) -> Self {
+        SessionServer {
+            db,
+            authorizer,
+            policy,
+            audit,
+            approvals,
+            rate_limiter,
+            metrics,
+            idempotency,
+        }
+    }
+
+    /// Serves sessions on `address`, until the runtime shuts down
+    pub fn spawn(self: Arc<Self>, address: SocketAddr) {
+        tokio::spawn(async move {
+            match TcpListener::bind(address).await {
+                Ok(listener) => {
+                    info!("Serving WebSocket sessions on {} without TLS", address);
+                    self.serve(listener).await
+                }
+                Err(e) => error!("Cannot serve WebSocket sessions on {}: {}", address, e),
+            }
+        });
+    }
+
+    /// Serves the connections of `listener`, each in a task of its own
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    let server = self.clone();
+                    tokio::spawn(async move { server.session(stream, peer).await });
+                }
+                Err(e) => warn!("Cannot accept WebSocket connection: {}", e),
+            }
+        }
+    }
+
+    fn db(&self) -> &State<SharedDb> {
+        (&self.db).into()
+    }
+
+    /// Customer of an upgrade request, and the expiry of its token
+    fn authenticate(&self, request: &Request) -> Result<(Customer, Option<SystemTime>), ApiError> {
+        if request.uri().path() != SESSION_PATH {
+            return Err(ApiError::not_found(format!(
+                "Unknown route '{}'.",
+                request.uri()
+            )));
+        }
+        if self.authorizer.is_passthrough() {
+            return Ok((
+                Customer {
+                    id: PASSTHROUGH_SUBJECT.to_string(),
+                },
+                None,
+            ));
+        }
+        let unauthorized = |e: AuthError| {
+            ApiError::new(Status::Unauthorized, ErrorCode::Unauthorized, e.to_string())
+        };
+        let token = request
+            .headers()
+            .get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or_else(|| unauthorized(AuthError::MissingToken))?;
+        self.authorizer
+            .verify(token)
+            .map(|claims| {
+                let expires = UNIX_EPOCH + Duration::from_secs(claims.exp as u64);
+                (Customer { id: claims.sub }, Some(expires))
+            })
+            .map_err(unauthorized)
+    }
+
+    async fn session(&self, stream: TcpStream, peer: SocketAddr) {
+        let mut authenticated = None;
+        let mut real_ip = None;
+        let handshake =
+            tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+                real_ip = request
+                    .headers()
+                    .get(REAL_IP_HEADER)
+                    .and_then(|header| header.to_str().ok())
+                    .map(|header| header.to_string());
+                match self.authenticate(request) {
+                    Ok(customer) => {
+                        authenticated = Some(customer);
+                        Ok(response)
+                    }
+                    Err(e) => {
+                        warn!("Rejected WebSocket session from {}: {}", peer, e);
+                        Err(handshake_error(e))
+                    }
+                }
+            })
+            .await;
+        let (mut socket, (customer, expires)) = match (handshake, authenticated) {
+            (Ok(socket), Some(authenticated)) => (socket, authenticated),
+            (Err(e), _) => {
+                warn!("WebSocket handshake with {} failed: {}", peer, e);
+                return;
+            }
+            (Ok(_), None) => return,
+        };
+        info!("WebSocket session of {} from {}", customer.id, peer);
+
+        // the rate limits count the client behind a trusted proxy, as over HTTP
+        let ip = self
+            .rate_limiter
+            .client_ip(Some(peer.ip()), real_ip.as_deref())
+            .unwrap_or_else(|| peer.ip());
+        let mut session = Session::new(customer, ip.to_string(), expires);
+        let mut close = None;
+        loop {
+            let message = match tokio::time::timeout(session.timeout(), socket.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(Some(Err(e))) => {
+                    warn!("WebSocket session from {} failed: {}", peer, e);
+                    break;
+                }
+                Ok(None) => break,
+                Err(_) if session.is_expired() => {
+                    info!("Closing WebSocket session from {}: token expired", peer);
+                    close = Some(token_expired());
+                    break;
+                }
+                Err(_) => {
+                    info!("Closing idle WebSocket session from {}", peer);
+                    break;
+                }
+            };
+            // the token may have expired while the request was on its way
+            if session.is_expired() {
+                info!("Closing WebSocket session from {}: token expired", peer);
+                close = Some(token_expired());
+                break;
+            }
+            let frame = match message {
+                Message::Text(text) => text.into_bytes(),
+                Message::Binary(bytes) => bytes,
+                Message::Close(_) => break,
+                // pings are answered while reading
+                _ => continue,
+            };
+            let response = self.respond(&mut session, &frame).await;
+            let response = serde_json::to_string(&response).expect("session responses serialize");
+            if let Err(e) = socket.send(Message::Text(response)).await {
+                warn!("WebSocket session from {} failed: {}", peer, e);
+                break;
+            }
+        }
+        let _ = socket.close(close).await;
+    }
+
+    /// Answers a frame, timed and counted like the HTTP request it stands for
+    async fn respond(&self, session: &mut Session, frame: &[u8]) -> SessionResponse {
+        let start = self.metrics.start_request();
+        let (route, response) = match serde_json::from_slice::<SessionRequest>(frame) {
+            Ok(request) => {
+                let route = route_name(&request.path);
+                (route, self.respond_once(session, request).await)
+            }
+            Err(e) => (
+                "unmatched",
+                session_response(Err(ApiError::bad_request(format!(
+                    "Invalid session request: {}",
+                    e
+                )))),
+            ),
+        };
+        self.metrics
+            .finish_request(route, start, outcome(&response));
+        response
+    }
+
+    /// Answers a request, or the retry of a request sent with the same idempotency key with the
+    /// response stored for it, as the `IdempotencyKeys` fairing does over HTTP
+    async fn respond_once(
+        &self,
+        session: &mut Session,
+        request: SessionRequest,
+    ) -> SessionResponse {
+        let path = format!("/{}", request.path.trim_start_matches('/'));
+        if let Some(class) = RouteClass::of(path.split('?').next().unwrap_or_default()) {
+            if let Err(e) = self.check_rate_limit(class, session) {
+                return session_response(Err(e));
+            }
+        }
+        let key = match &request.idempotency_key {
+            Some(key) if self.idempotency.is_enabled() => key.clone(),
+            _ => return session_response(self.route(session, request).await),
+        };
+        if !idempotency::is_valid_key(&key) {
+            return session_response(Err(ApiError::bad_request(format!(
+                "idempotency_key must be 1 to {} printable ASCII characters",
+                idempotency::MAX_KEY_LENGTH
+            ))));
+        }
+        let customer_id = session.customer.id.clone();
+        let body = serde_json::to_vec(&request.body).expect("JSON values serialize");
+        let fingerprint =
+            idempotency::fingerprint(Method::Post, &path, &idempotency::content_digest(&body));
+        match self
+            .idempotency
+            .admit(&customer_id, &key, &fingerprint)
+            .await
+        {
+            Ok(()) => {}
+            Err(idempotency::Answer::Replay(stored)) => {
+                return SessionResponse {
+                    status: stored.status,
+                    body: serde_json::from_slice(&stored.body).unwrap_or_else(|_| {
+                        Value::String(String::from_utf8_lossy(&stored.body).into_owned())
+                    }),
+                    replayed: true,
+                }
+            }
+            Err(idempotency::Answer::Reject(status, code, message)) => {
+                return session_response(Err(ApiError::new(status, code, message)))
+            }
+        }
+
+        let response = session_response(self.route(session, request).await);
+        if idempotency::is_stored_status(Status::new(response.status)) {
+            let stored = StoredResponse {
+                fingerprint,
+                path: path.split('?').next().unwrap_or_default().to_string(),
+                status: response.status,
+                content_type: Some(ContentType::JSON.to_string()),
+                body: serde_json::to_vec(&response.body).expect("JSON values serialize"),
+            };
+            if let Err(e) = self.idempotency.save(&customer_id, &key, stored).await {
+                error!(
+                    "Cannot store the response of idempotency key {}: {}",
+                    key, e
+                );
+            }
+        }
+        self.idempotency.release(&customer_id, &key);
+        response
+    }
+
+    async fn route(&self, session: &mut Session, request: SessionRequest) -> Answer {
+        let (path, query) = match request.path.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (request.path.as_str(), None),
+        };
+        let path = path.trim_start_matches('/');
+        let segments: Vec<&str> = path.split('/').collect();
+        match segments.as_slice() {
+            ["ecdsa", "keygen", "active"] => {
+                let customer = Customer {
+                    id: session.customer.id.clone(),
+                };
+                ok(keygen::active_key(self.db(), customer).await?.into_inner())
+            }
+            ["ecdsa", "keygen", "first"] => {
+                let new_key = query_value(query, "new_key") == Some("true");
+                self.keygen_first(session, new_key).await
+            }
+            ["ecdsa", "keygen", id, "second"] => self.keygen_second(session, id, body(&request)?),
+            ["ecdsa", "keygen", id, "third"] => self.keygen_third(session, id, body(&request)?),
+            ["ecdsa", "keygen", id, "fourth"] => self.keygen_fourth(session, id, body(&request)?),
+            ["ecdsa", "keygen", id, "chaincode", "first"] => self.chain_code_first(session, id),
+            ["ecdsa", "keygen", id, "chaincode", "second"] => {
+                self.chain_code_second(session, id, body(&request)?).await
+            }
+            ["ecdsa", "sign", id, "first"] => self.sign_first(session, id, body(&request)?).await,
+            ["ecdsa", "sign", id, "second"] => self.sign_second(session, id, body(&request)?).await,
+            ["ecdsa", "approvals", approval_id] => {
+                let wait = query_value(query, "wait")
+                    .map(|wait| {
+                        wait.parse::<u64>()
+                            .map_err(|e| ApiError::bad_request(format!("Invalid wait: {}", e)))
+                    })
+                    .transpose()?;
+                let customer = Customer {
+                    id: session.customer.id.clone(),
+                };
+                let status = approval::approval_status(
+                    self.db(),
+                    (&self.policy).into(),
+                    (&self.audit).into(),
+                    (&self.approvals).into(),
+                    customer,
+                    approval_id.to_string(),
+                    wait,
+                )
+                .await?;
+                ok(status.into_inner())
+            }
+            ["ecdsa", "rotate", id, "first"] => self.rotate_first(session, id).await,
+            ["ecdsa", "rotate", id, "second"] => {
+                self.rotate_second(session, id, body(&request)?).await
+            }
+            ["ecdsa", "rotate", id, "third"] => {
+                self.rotate_third(session, id, body(&request)?).await
+            }
+            ["ecdsa", "rotate", id, "fourth"] => {
+                self.rotate_fourth(session, id, body(&request)?).await
+            }
+            _ => Err(ApiError::not_found(format!(
+                "Unknown session route '{}'.",
+                request.path
+            ))),
+        }
+    }
+
+    /// Applies the rate limits of the HTTP routes to every round
+    fn check_rate_limit(&self, class: RouteClass, session: &Session) -> Result<(), ApiError> {
+        self.rate_limiter
+            .check(
+                class,
+                Some(&session.customer.id),
+                Some(&session.ip),
+                Instant::now(),
+            )
+            .map_err(|wait| {
+                let retry_after = (wait.as_secs_f64().ceil() as u64).max(1);
+                ApiError::new(
+                    Status::TooManyRequests,
+                    ErrorCode::RateLimited,
+                    format!("Too many requests, retry in {} seconds", retry_after),
+                )
+            })
+    }
+
+    /// Master key `id` of the customer, when it may sign or rotate
+    async fn usable_master_key(
+        &self,
+        customer: &Customer,
+        id: &str,
+    ) -> Result<MasterKey1, ApiError> {
+        let db = self.db.lock().await;
+        keys::usable_master_key(db.as_ref(), &db_index(customer, id)).await
+    }
+
+    async fn keygen_first(&self, session: &mut Session, new_key: bool) -> Answer {
+        session.check_capacity()?;
+        {
+            let db = self.db.lock().await;
+            keygen::ensure_keygen_allowed(db.as_ref(), &session.customer, new_key).await?;
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        let (key_gen_first_message, first) = keygen::first_round();
+        session.keygens.insert(id.clone(), Keygen::First(first));
+        ok((id, key_gen_first_message))
+    }
+
+    fn keygen_second(&self, session: &mut Session, id: &str, d_log_proof: DLogProof) -> Answer {
+        let first = match session.keygens.remove(id) {
+            Some(Keygen::First(first)) => first,
+            _ => return Err(out_of_order("keygen", id)),
+        };
+        let (key_gen_second_message, shares) = keygen::second_round(first, &d_log_proof);
+        session
+            .keygens
+            .insert(id.to_string(), Keygen::Second(shares));
+        ok(key_gen_second_message)
+    }
+
+    fn keygen_third(
+        &self,
+        session: &mut Session,
+        id: &str,
+        party2_pdl_first_message: party_two::PDLFirstMessage,
+    ) -> Answer {
+        let shares = match session.keygens.remove(id) {
+            Some(Keygen::Second(shares)) => shares,
+            _ => return Err(out_of_order("keygen", id)),
+        };
+        let (pdl_first_message, third) = keygen::third_round(shares, party2_pdl_first_message);
+        session.keygens.insert(id.to_string(), Keygen::Third(third));
+        ok(pdl_first_message)
+    }
+
+    fn keygen_fourth(
+        &self,
+        session: &mut Session,
+        id: &str,
+        party2_pdl_second_message: party_two::PDLSecondMessage,
+    ) -> Answer {
+        let third = match session.keygens.remove(id) {
+            Some(Keygen::Third(third)) => third,
+            _ => return Err(out_of_order("keygen", id)),
+        };
+        let (pdl_second_message, shares) =
+            keygen::fourth_round(id, third, &party2_pdl_second_message)?;
+        session
+            .keygens
+            .insert(id.to_string(), Keygen::Fourth(shares));
+        ok(pdl_second_message)
+    }
+
+    fn chain_code_first(&self, session: &mut Session, id: &str) -> Answer {
+        let shares = match session.keygens.remove(id) {
+            Some(Keygen::Fourth(shares)) => shares,
+            _ => return Err(out_of_order("keygen", id)),
+        };
+        let (chain_code_first_message, chain_code) = keygen::chain_code_first_round(shares);
+        session
+            .keygens
+            .insert(id.to_string(), Keygen::ChainCode(chain_code));
+        ok(chain_code_first_message)
+    }
+
+    /// Last keygen round, storing the master key
+    async fn chain_code_second(
+        &self,
+        session: &mut Session,
+        id: &str,
+        d_log_proof: DLogProof,
+    ) -> Answer {
+        let chain_code = match session.keygens.remove(id) {
+            Some(Keygen::ChainCode(chain_code)) => chain_code,
+            _ => return Err(out_of_order("keygen", id)),
+        };
+        let (chain_code_second_message, master_key) =
+            keygen::chain_code_second_round(chain_code, &d_log_proof);
+
+        let db = self.db.lock().await;
+        let key = db_index(&session.customer, id);
+        insert_value(
+            db.as_ref(),
+            &key,
+            &EcdsaStruct::Party1MasterKey,
+            &master_key,
+        )
+        .await?;
+        ok(chain_code_second_message)
+    }
+
+    async fn sign_first(
+        &self,
+        session: &mut Session,
+        id: &str,
+        eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+    ) -> Answer {
+        if !session.signatures.contains_key(id) {
+            session.check_capacity()?;
+        }
+        self.usable_master_key(&session.customer, id).await?;
+        let (sign_party_one_first_message, first) =
+            sign::first_round(eph_key_gen_first_message_party_two);
+        session.signatures.insert(id.to_string(), first);
+        ok(sign_party_one_first_message)
+    }
+
+    async fn sign_second(
+        &self,
+        session: &mut Session,
+        id: &str,
+        request: SignSecondRequest,
+    ) -> Answer {
+        let first = session
+            .signatures
+            .remove(id)
+            .ok_or_else(|| out_of_order("signature", id))?;
+        let (request, transaction) = request.into_parts();
+        let outcome = sign::sign_audited(
+            self.db(),
+            &self.policy,
+            &self.audit,
+            &self.approvals,
+            &session.customer,
+            id,
+            request,
+            transaction,
+            Ephemeral::InMemory(first),
+        )
+        .await?;
+        match outcome {
+            SignOutcome::Signed(signature) => ok(signature),
+            SignOutcome::Pending(approval) => answer(Status::Accepted, approval),
+        }
+    }
+
+    async fn rotate_first(&self, session: &mut Session, id: &str) -> Answer {
+        if !session.rotations.contains_key(id) {
+            session.check_capacity()?;
+        }
+        // make sure the key exists before committing to a coin flip
+        self.usable_master_key(&session.customer, id).await?;
+        let (party1_coin_flip_first_message, coin_flip) = rotate::first_round();
+        session
+            .rotations
+            .insert(id.to_string(), Rotation::CoinFlip(coin_flip));
+        ok(party1_coin_flip_first_message)
+    }
+
+    async fn rotate_second(
+        &self,
+        session: &mut Session,
+        id: &str,
+        party2_first_message: coin_flip_optimal_rounds::Party2FirstMessage,
+    ) -> Answer {
+        let coin_flip = match session.rotations.remove(id) {
+            Some(Rotation::CoinFlip(coin_flip)) => coin_flip,
+            _ => return Err(out_of_order("rotation", id)),
+        };
+        let party_one_master_key = self.usable_master_key(&session.customer, id).await?;
+        let (messages, first) =
+            rotate::second_round(&party_one_master_key, coin_flip, &party2_first_message);
+        session
+            .rotations
+            .insert(id.to_string(), Rotation::First(first));
+        ok(messages)
+    }
+
+    async fn rotate_third(
+        &self,
+        session: &mut Session,
+        id: &str,
+        rotation_party_two_first_message: party_two::PDLFirstMessage,
+    ) -> Answer {
+        let first = match session.rotations.remove(id) {
+            Some(Rotation::First(first)) => first,
+            _ => return Err(out_of_order("rotation", id)),
+        };
+        self.usable_master_key(&session.customer, id).await?;
+        let (rotation_party_one_second_message, third) =
+            rotate::third_round(&first, rotation_party_two_first_message);
+        session
+            .rotations
+            .insert(id.to_string(), Rotation::Third(first, third));
+        ok(rotation_party_one_second_message)
+    }
+
+    /// Last rotation round, replacing the master key once party two's PDL proof verifies
+    async fn rotate_fourth(
+        &self,
+        session: &mut Session,
+        id: &str,
+        rotation_party_two_second_message: party_two::PDLSecondMessage,
+    ) -> Answer {
+        let (first, third) = match session.rotations.remove(id) {
+            Some(Rotation::Third(first, third)) => (first, third),
+            _ => return Err(out_of_order("rotation", id)),
+        };
+        let db = self.db.lock().await;
+        let key = db_index(&session.customer, id);
+        let party_one_master_key = keys::usable_master_key(db.as_ref(), &key).await?;
+        let (rotation_party_one_third_message, party_one_master_key_rotated) =
+            rotate::fourth_round(
+                id,
+                party_one_master_key,
+                first,
+                third,
+                &rotation_party_two_second_message,
+            )?;
+        insert_value(
+            db.as_ref(),
+            &key,
+            &EcdsaStruct::Party1MasterKey,
+            &party_one_master_key_rotated,
+        )
+        .await?;
+        ok(rotation_party_one_third_message)
+    }
+}
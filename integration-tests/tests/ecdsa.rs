@@ -1,14 +1,26 @@
-use client_lib::{ecdsa, ClientShim};
+use client_lib::websocket::WebSocketClient;
+use client_lib::{ecdsa, ClientError, ClientShim};
 use rand::rngs::mock::StepRng;
 use rand::Rng;
+use rocket::http::{ContentType, Header};
 use rocket::serde::{DeserializeOwned, Serialize};
-use rocket::Rocket;
+use rocket::tokio::net::TcpListener;
+use rocket::{Build, Rocket};
 use secp256k1::{ecdsa::Signature, Message, SECP256K1};
+use server_lib::admin::Admin;
+use server_lib::auth::PASSTHROUGH_SUBJECT;
+use server_lib::keys::KeyState;
 use server_lib::server;
-use std::collections::HashMap;
+use server_lib::sessions::session_tables;
+use server_lib::storage::schema::RecordKey;
+use server_lib::storage::Store;
+use server_lib::websocket::SessionServer;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use two_party_ecdsa::curv::arithmetic::big_gmp::BigInt;
 use two_party_ecdsa::curv::arithmetic::traits::Converter;
 use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
+use two_party_ecdsa::party_one;
 
 // #[rocket::async_test]
 // async fn test_ecdsa_network() {
@@ -108,13 +120,12 @@ use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
 // #[rocket::async_test]
 fn integration_test_ecdsa_key_signing() {
     let mut rng = StepRng::new(0, 1);
-    let settings = HashMap::<String, String>::from([("db_name".into(), "testEcdsaSigning".into())]);
-    let rocket = server::get_server();
+    let rocket = test_server();
     let client = RocketClient::new(rocket);
 
     let client_shim =
         ClientShim::new_with_client("http://localhost:8008".to_string(), None, client);
-    let ps: ecdsa::PrivateShare = ecdsa::get_master_key(&client_shim);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
     let x_pos = BigInt::from(1);
     let y_pos = BigInt::from(2);
 
@@ -150,6 +161,282 @@ fn integration_test_ecdsa_key_signing() {
     }
 }
 
+#[test]
+fn integration_test_ecdsa_key_rotation() {
+    let mut rng = StepRng::new(0, 1);
+    let rocket = test_server();
+    let client = RocketClient::new(rocket);
+
+    let client_shim =
+        ClientShim::new_with_client("http://localhost:8008".to_string(), None, client);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    let ps_rotated = ecdsa::rotate_master_key(&client_shim, &ps).expect("ECDSA rotation failed");
+
+    assert_eq!(ps.id, ps_rotated.id);
+    assert_eq!(ps.master_key.public.q, ps_rotated.master_key.public.q);
+    assert_ne!(
+        serde_json::to_string(&ps.master_key.private).unwrap(),
+        serde_json::to_string(&ps_rotated.master_key.private).unwrap()
+    );
+
+    let x_pos = BigInt::from(1);
+    let y_pos = BigInt::from(2);
+
+    let child_master_key = ps_rotated
+        .master_key
+        .get_child(vec![x_pos.clone(), y_pos.clone()]);
+    let pk = child_master_key.public.q.get_element();
+
+    let mut msg_buf = [0u8; 32];
+    rng.fill(&mut msg_buf);
+    let msg: BigInt = BigInt::from(&msg_buf[..]);
+
+    let signature = ecdsa::sign(
+        &client_shim,
+        msg,
+        &child_master_key,
+        x_pos,
+        y_pos,
+        &ps_rotated.id,
+    )
+    .expect("ECDSA signature with rotated share failed");
+
+    let r = BigInt::to_vec(&signature.r);
+    let s = BigInt::to_vec(&signature.s);
+    let msg = Message::from_slice(&msg_buf).unwrap();
+
+    let mut sig = [0u8; 64];
+    sig[32 - r.len()..32].copy_from_slice(&r);
+    sig[32 + 32 - s.len()..].copy_from_slice(&s);
+
+    let sig = Signature::from_compact(&sig).unwrap();
+
+    SECP256K1.verify_ecdsa(&msg, &sig, &pk).unwrap();
+}
+
+#[test]
+fn integration_test_ecdsa_active_key() {
+    let rocket = test_server();
+    let client = RocketClient::new(rocket);
+
+    let client_shim =
+        ClientShim::new_with_client("http://localhost:8008".to_string(), None, client);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    assert_eq!(
+        ecdsa::get_active_key_id(&client_shim).unwrap(),
+        Some(ps.id.clone())
+    );
+
+    let refused: Result<(String, party_one::KeyGenFirstMsg), ClientError> =
+        client_shim.post("ecdsa/keygen/first");
+    assert!(refused.is_err());
+    assert_eq!(
+        ecdsa::get_active_key_id(&client_shim).unwrap(),
+        Some(ps.id.clone())
+    );
+
+    let ps_new: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    assert_ne!(ps.id, ps_new.id);
+    assert_eq!(
+        ecdsa::get_active_key_id(&client_shim).unwrap(),
+        Some(ps_new.id)
+    );
+}
+
+#[test]
+fn integration_test_ecdsa_error_codes() {
+    let rocket = test_server();
+    let client = RocketClient::new(rocket);
+
+    let client_shim =
+        ClientShim::new_with_client("http://localhost:8008".to_string(), None, client);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    let child_master_key = ps
+        .master_key
+        .get_child(vec![BigInt::from(1), BigInt::from(2)]);
+
+    let error = ecdsa::sign(
+        &client_shim,
+        BigInt::from(1234),
+        &child_master_key,
+        BigInt::from(1),
+        BigInt::from(2),
+        "unknown-key",
+    )
+    .expect_err("signing with an unknown key succeeded");
+    let error = error
+        .downcast_ref::<ClientError>()
+        .expect("not a client error");
+    assert_eq!(error.code(), Some("not_found"));
+    match error {
+        ClientError::Server(e) => {
+            assert_eq!(e.status, 404);
+            assert!(!e.request_id.is_empty());
+        }
+        _ => panic!("unexpected error {}", error),
+    }
+}
+
+#[test]
+fn integration_test_ecdsa_rate_limited_sign() {
+    // a signature is two requests, which the fairings only limit when they see the sign paths
+    let mut settings = test_settings();
+    settings.insert("rate_limit_sign_customer".to_string(), "2/3600".to_string());
+    let rocket = server::get_server_with_settings(settings).expect("valid configuration");
+    let client = RocketClient::new(rocket);
+
+    let client_shim =
+        ClientShim::new_with_client("http://localhost:8008".to_string(), None, client);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    let child_master_key = ps
+        .master_key
+        .get_child(vec![BigInt::from(1), BigInt::from(2)]);
+    let sign = || {
+        ecdsa::sign(
+            &client_shim,
+            BigInt::from(1234),
+            &child_master_key,
+            BigInt::from(1),
+            BigInt::from(2),
+            &ps.id,
+        )
+    };
+
+    sign().expect("ECDSA signature failed");
+    let error = sign().expect_err("signing over the limit succeeded");
+    assert_eq!(
+        error.downcast_ref::<ClientError>().and_then(|e| e.code()),
+        Some("rate_limited")
+    );
+}
+
+#[test]
+fn integration_test_ecdsa_frozen_key() {
+    let rocket = test_server();
+    let client = RocketClient::new(rocket);
+    let store = client.0.rocket().state::<Arc<dyn Store>>().unwrap().clone();
+
+    let client_shim =
+        ClientShim::new_with_client("http://localhost:8008".to_string(), None, client);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    let admin = Admin::new(Box::new(store));
+    rocket::tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(admin.set_state(
+            PASSTHROUGH_SUBJECT,
+            &ps.id,
+            KeyState::Frozen,
+            "stolen phone",
+            "ops",
+        ))
+        .unwrap();
+    let child_master_key = ps
+        .master_key
+        .get_child(vec![BigInt::from(1), BigInt::from(2)]);
+
+    let error = ecdsa::sign(
+        &client_shim,
+        BigInt::from(1234),
+        &child_master_key,
+        BigInt::from(1),
+        BigInt::from(2),
+        &ps.id,
+    )
+    .expect_err("signing with a frozen key succeeded");
+    match error.downcast_ref::<ClientError>() {
+        Some(ClientError::KeyFrozen(e)) => assert_eq!(e.status, 403),
+        _ => panic!("unexpected error {}", error),
+    }
+}
+
+#[test]
+fn integration_test_ecdsa_websocket_session() {
+    let mut rng = StepRng::new(0, 1);
+    let rocket = test_server();
+    let sessions = rocket.state::<Arc<SessionServer>>().unwrap().clone();
+    let store = rocket.state::<Arc<dyn Store>>().unwrap().clone();
+    let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+    let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let address = listener.local_addr().unwrap();
+    runtime.spawn(sessions.serve(listener));
+
+    let refused = WebSocketClient::connect(&format!("ws://{}/ecdsa/other", address), None);
+    assert_eq!(
+        refused.err().and_then(|e| e.code().map(String::from)),
+        Some("not_found".to_string())
+    );
+
+    let client = WebSocketClient::connect(&format!("ws://{}/ecdsa/session", address), None)
+        .expect("session opens");
+    let client_shim = ClientShim::new_with_client(String::new(), None, client);
+    let ps: ecdsa::PrivateShare = ecdsa::get_new_master_key(&client_shim);
+    let ps = ecdsa::rotate_master_key(&client_shim, &ps).expect("ECDSA rotation failed");
+
+    let x_pos = BigInt::from(1);
+    let y_pos = BigInt::from(2);
+    let child_master_key = ps.master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+    let pk = child_master_key.public.q.get_element();
+    for _ in 0..2 {
+        let mut msg_buf = [0u8; 32];
+        rng.fill(&mut msg_buf);
+        let msg: BigInt = BigInt::from(&msg_buf[..]);
+
+        let signature = ecdsa::sign(
+            &client_shim,
+            msg,
+            &child_master_key,
+            x_pos.clone(),
+            y_pos.clone(),
+            &ps.id,
+        )
+        .expect("ECDSA signature failed");
+
+        let r = BigInt::to_vec(&signature.r);
+        let s = BigInt::to_vec(&signature.s);
+        let msg = Message::from_slice(&msg_buf).unwrap();
+
+        let mut sig = [0u8; 64];
+        sig[32 - r.len()..32].copy_from_slice(&r);
+        sig[32 + 32 - s.len()..].copy_from_slice(&s);
+
+        let sig = Signature::from_compact(&sig).unwrap();
+
+        SECP256K1.verify_ecdsa(&msg, &sig, &pk).unwrap();
+    }
+
+    // the rounds kept their state in the session, never in the store
+    let session_tables: HashSet<String> = session_tables().into_iter().collect();
+    let records = runtime.block_on(store.scan(b"")).unwrap();
+    assert!(records
+        .iter()
+        .filter_map(|(key, _)| RecordKey::decode(key))
+        .filter(|key| key.id == ps.id)
+        .all(|key| !session_tables.contains(&key.table)));
+
+    // a round sent out of order is refused with the error of the HTTP route
+    let error = ecdsa::sign(
+        &client_shim,
+        BigInt::from(1234),
+        &child_master_key,
+        x_pos,
+        y_pos,
+        "unknown-key",
+    )
+    .expect_err("signing with an unknown key succeeded");
+    assert_eq!(
+        error.downcast_ref::<ClientError>().and_then(|e| e.code()),
+        Some("not_found")
+    );
+
+    // the key is the active key of the customer over HTTP too
+    let http_shim = ClientShim::new_with_client(
+        "http://localhost:8008".to_string(),
+        None,
+        RocketClient::new(rocket),
+    );
+    assert_eq!(ecdsa::get_active_key_id(&http_shim).unwrap(), Some(ps.id));
+}
+
 // #[test]
 // fn integration_test_ecdsa_long() {
 //     let mut rng = StepRng::new(0, 1);
@@ -192,6 +479,19 @@ fn integration_test_ecdsa_key_signing() {
 //     }
 // }
 
+/// Settings of a server keeping its records in memory, so that tests running in parallel share
+/// no database
+fn test_settings() -> HashMap<String, String> {
+    HashMap::from([
+        ("db".to_string(), "memory".to_string()),
+        ("auth_passthrough".to_string(), "true".to_string()),
+    ])
+}
+
+fn test_server() -> Rocket<Build> {
+    server::get_server_with_settings(test_settings()).expect("valid configuration")
+}
+
 struct RocketClient(pub rocket::local::blocking::Client);
 
 impl RocketClient {
@@ -207,13 +507,35 @@ impl client_lib::Client for RocketClient {
         uri: &str,
         _: Option<String>,
         body: T,
-    ) -> Option<V> {
-        self.0
+    ) -> Result<V, ClientError> {
+        let response = self.0.post(["/", uri].concat()).json(&body).dispatch();
+        let status = response.status().code;
+        let body = response.into_string().unwrap_or_default();
+        client_lib::decode_response(status, &body)
+    }
+
+    fn post_idempotent<V: DeserializeOwned, T: Serialize>(
+        &self,
+        _: &str,
+        uri: &str,
+        _: Option<String>,
+        idempotency_key: &str,
+        body: T,
+    ) -> Result<V, ClientError> {
+        let body = serde_json::to_vec(&body).unwrap();
+        let response = self
+            .0
             .post(["/", uri].concat())
-            .json(&body)
-            .dispatch()
-            .into_string()
-            .map(|s| serde_json::from_str(&s).ok())
-            .flatten()
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", idempotency_key.to_string()))
+            .header(Header::new(
+                "Content-Digest",
+                client_lib::content_digest(&body),
+            ))
+            .body(body)
+            .dispatch();
+        let status = response.status().code;
+        let body = response.into_string().unwrap_or_default();
+        client_lib::decode_response(status, &body)
     }
 }
@@ -1,3 +1,3 @@
 #!/usr/bin/env bash
 
-cd ./gotham-server && cargo run --release
\ No newline at end of file
+cd ./gotham-server && auth_passthrough=true cargo run --release
\ No newline at end of file